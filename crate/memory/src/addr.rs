@@ -4,6 +4,10 @@ pub type VirtAddr = usize;
 pub type PhysAddr = usize;
 
 pub const PAGE_SIZE: usize = 1 << 12;
+/// Size of a huge page mapped by an entry of the second last level page table
+pub const HUGE_PAGE_SIZE: usize = 1 << 21;
+/// Size of a huge page mapped by an entry of the third last level page table
+pub const GIGANTIC_PAGE_SIZE: usize = 1 << 30;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Page {
@@ -0,0 +1,242 @@
+//! Buddy allocator of physical frames
+//!
+//! Free blocks of `2^order` frames are kept in a list per order.
+//! The lists are linked through an array with an entry per frame,
+//! so the allocator never needs the heap nor touches the frames themselves.
+
+/// Blocks are at most `2^(MAX_ORDER - 1)` frames
+pub const MAX_ORDER: usize = 11;
+
+const NONE: u32 = u32::MAX;
+/// The frame is the head of a free block, of the order in the low bits
+const FREE: u8 = 0x80;
+
+/// Per frame state of the buddy allocator
+#[derive(Debug, Clone, Copy)]
+pub struct FrameInfo {
+    next: u32,
+    prev: u32,
+    state: u8,
+}
+
+impl FrameInfo {
+    pub const EMPTY: FrameInfo = FrameInfo {
+        next: NONE,
+        prev: NONE,
+        state: 0,
+    };
+}
+
+/// Buddy allocator managing frames `[base, base + info.len())`
+pub struct BuddyAllocator {
+    base: usize,
+    info: &'static mut [FrameInfo],
+    free_list: [u32; MAX_ORDER],
+    free_blocks: [usize; MAX_ORDER],
+    total: usize,
+    free: usize,
+}
+
+impl BuddyAllocator {
+    /// Create an allocator with no free frames, able to manage frames from `base`
+    /// up to the length of `info`.
+    pub fn new(base: usize, info: &'static mut [FrameInfo]) -> Self {
+        assert!(info.len() < NONE as usize, "too many frames");
+        for frame in info.iter_mut() {
+            *frame = FrameInfo::EMPTY;
+        }
+        BuddyAllocator {
+            base,
+            info,
+            free_list: [NONE; MAX_ORDER],
+            free_blocks: [0; MAX_ORDER],
+            total: 0,
+            free: 0,
+        }
+    }
+
+    /// Add frames in `range` to the allocator
+    pub fn insert(&mut self, range: core::ops::Range<usize>) {
+        assert!(
+            range.start >= self.base && range.end <= self.base + self.info.len(),
+            "frames out of range"
+        );
+        let mut frame = range.start;
+        while frame < range.end {
+            // the largest aligned block fitting in the rest of the range
+            let mut order = (frame.trailing_zeros() as usize).min(MAX_ORDER - 1);
+            while frame + (1 << order) > range.end {
+                order -= 1;
+            }
+            self.total += 1 << order;
+            self.free_block(frame, order);
+            frame += 1 << order;
+        }
+    }
+
+    /// Allocate a block of `2^order` frames aligned to its size, return its first frame
+    pub fn alloc(&mut self, order: usize) -> Option<usize> {
+        let found = (order..MAX_ORDER).find(|&o| self.free_list[o] != NONE)?;
+        let frame = self.base + self.free_list[found] as usize;
+        self.remove(frame, found);
+        // return the upper halves of the block until it is small enough
+        for o in (order..found).rev() {
+            self.push(frame + (1 << o), o);
+        }
+        self.free -= 1 << order;
+        Some(frame)
+    }
+
+    /// Free the block of `2^order` frames at `frame`
+    pub fn dealloc(&mut self, frame: usize, order: usize) {
+        assert!(
+            self.contains(frame) && self.contains(frame + (1 << order) - 1),
+            "frame out of range"
+        );
+        self.free_block(frame, order);
+    }
+
+    /// Allocate `count` contiguous frames aligned to `2^align_log2` frames.
+    /// The frames can be freed one by one.
+    pub fn alloc_contiguous(&mut self, count: usize, align_log2: usize) -> Option<usize> {
+        if count == 0 {
+            return None;
+        }
+        let order = (count.next_power_of_two().trailing_zeros() as usize).max(align_log2);
+        if order >= MAX_ORDER {
+            return None;
+        }
+        let frame = self.alloc(order)?;
+        // give back the unused tail
+        for tail in frame + count..frame + (1 << order) {
+            self.free_block(tail, 0);
+        }
+        Some(frame)
+    }
+
+    /// Test whether `frame` is managed by the allocator
+    pub fn contains(&self, frame: usize) -> bool {
+        frame >= self.base && frame < self.base + self.info.len()
+    }
+
+    /// The number of frames added to the allocator
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// The number of free frames
+    pub fn free(&self) -> usize {
+        self.free
+    }
+
+    /// The number of free blocks of `2^order` frames
+    pub fn free_blocks(&self, order: usize) -> usize {
+        self.free_blocks[order]
+    }
+
+    /// The permille of free frames unusable for blocks of `2^order` frames,
+    /// 0 when free memory is not fragmented.
+    pub fn fragmentation(&self, order: usize) -> usize {
+        if self.free == 0 {
+            return 0;
+        }
+        let usable: usize = (order..MAX_ORDER).map(|o| self.free_blocks[o] << o).sum();
+        (self.free - usable) * 1000 / self.free
+    }
+
+    /// Free a block, merging it with its free buddies
+    fn free_block(&mut self, mut frame: usize, mut order: usize) {
+        self.free += 1 << order;
+        while order < MAX_ORDER - 1 {
+            let buddy = frame ^ (1 << order);
+            if buddy < self.base
+                || buddy >= self.base + self.info.len()
+                || self.info[buddy - self.base].state != FREE | order as u8
+            {
+                break;
+            }
+            self.remove(buddy, order);
+            frame = frame.min(buddy);
+            order += 1;
+        }
+        self.push(frame, order);
+    }
+
+    fn push(&mut self, frame: usize, order: usize) {
+        let idx = (frame - self.base) as u32;
+        let head = self.free_list[order];
+        self.info[idx as usize] = FrameInfo {
+            next: head,
+            prev: NONE,
+            state: FREE | order as u8,
+        };
+        if head != NONE {
+            self.info[head as usize].prev = idx;
+        }
+        self.free_list[order] = idx;
+        self.free_blocks[order] += 1;
+    }
+
+    fn remove(&mut self, frame: usize, order: usize) {
+        let idx = frame - self.base;
+        let FrameInfo { next, prev, .. } = self.info[idx];
+        if prev == NONE {
+            self.free_list[order] = next;
+        } else {
+            self.info[prev as usize].next = next;
+        }
+        if next != NONE {
+            self.info[next as usize].prev = prev;
+        }
+        self.info[idx] = FrameInfo::EMPTY;
+        self.free_blocks[order] -= 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::boxed::Box;
+    use alloc::vec;
+
+    fn allocator(base: usize, count: usize) -> BuddyAllocator {
+        let info = Box::leak(vec![FrameInfo::EMPTY; count].into_boxed_slice());
+        BuddyAllocator::new(base, info)
+    }
+
+    #[test]
+    fn alloc_and_merge() {
+        let mut buddy = allocator(0x400, 0x400);
+        buddy.insert(0x400..0x800);
+        assert_eq!(buddy.total(), 0x400);
+        assert_eq!(buddy.free_blocks(10), 1);
+        let a = buddy.alloc(0).unwrap();
+        let b = buddy.alloc(0).unwrap();
+        assert_eq!(a ^ b, 1);
+        assert_eq!(buddy.free(), 0x3fe);
+        assert_eq!(buddy.free_blocks(10), 0);
+        assert_eq!(buddy.free_blocks(9), 1);
+        buddy.dealloc(a, 0);
+        buddy.dealloc(b, 0);
+        assert_eq!(buddy.free(), 0x400);
+        assert_eq!(buddy.free_blocks(10), 1);
+        assert_eq!(buddy.fragmentation(10), 0);
+    }
+
+    #[test]
+    fn contiguous() {
+        let mut buddy = allocator(0, 64);
+        buddy.insert(3..64);
+        let frame = buddy.alloc_contiguous(5, 4).unwrap();
+        assert_eq!(frame % 16, 0);
+        assert_eq!(buddy.free(), 61 - 5);
+        // free it frame by frame
+        for f in frame..frame + 5 {
+            buddy.dealloc(f, 0);
+        }
+        assert_eq!(buddy.free(), 61);
+        assert!(buddy.alloc_contiguous(32, 0).is_some());
+        assert!(buddy.alloc_contiguous(32, 0).is_none());
+        assert!(buddy.fragmentation(4) > 0);
+    }
+}
@@ -19,11 +19,18 @@
 //! so we need to maintain the count of write and read reference.
 //! When page fault occurs, if the read reference count is 0 and the write reference count is 1，
 //! The copy process should be skipped and the entry is mark as writable directly.
+//!
+//! `CowFrames` applies the same scheme to frames shared between page tables,
+//! which happens when a memory set is cloned on fork.
 
+use super::memory_set::{handler::FrameAllocator, MemoryAttr};
 use super::paging::*;
 use super::*;
 use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::fmt::{Debug, Error, Formatter};
 use core::ops::{Deref, DerefMut};
+use spin::Mutex;
 
 /// Wrapper for page table, supporting shared map & copy-on-write
 pub struct CowExt<T: PageTable> {
@@ -208,6 +215,126 @@ impl FrameRcMap {
     }
 }
 
+/// Reference counts of frames shared copy-on-write between page tables
+///
+/// Shared by all clones of a memory handler. A frame is in the map from being shared
+/// until its last page table entry releases it or takes it over.
+#[derive(Default, Clone)]
+pub struct CowFrames(Arc<Mutex<BTreeMap<Frame, usize>>>);
+
+impl CowFrames {
+    /// Map the present page `addr` of `src_pt` to the same frame in `pt`,
+    /// and make both entries copy-on-write.
+    /// Return false if the page table doesn't support shared entries.
+    pub fn share(
+        &self,
+        pt: &mut dyn PageTable,
+        src_pt: &mut dyn PageTable,
+        addr: VirtAddr,
+        attr: &MemoryAttr,
+    ) -> bool {
+        let entry = src_pt.get_entry(addr).expect("failed to get entry");
+        entry.set_shared(true);
+        if !(entry.readonly_shared() || entry.writable_shared()) {
+            return false;
+        }
+        // make it readonly and shared
+        attr.apply(entry);
+        let target = entry.target();
+        *self.0.lock().entry(target / PAGE_SIZE).or_insert(1) += 1;
+
+        let entry = pt.map(addr, target);
+        entry.set_shared(true);
+        attr.apply(entry);
+        true
+    }
+
+    /// Give the page `addr` its own frame on write.
+    /// The last reference to a frame takes it over without copying.
+    /// Fail with `AccessDenied` if it is not a copy-on-write page, or `NoMemory`.
+    pub fn unshare(
+        &self,
+        pt: &mut dyn PageTable,
+        addr: VirtAddr,
+        allocator: &impl FrameAllocator,
+    ) -> VMResult<()> {
+        let addr = addr & !(PAGE_SIZE - 1);
+        let entry = pt.get_entry(addr).expect("failed to get entry");
+        if !entry.present() || !entry.writable_shared() {
+            return Err(VMError::AccessDenied);
+        }
+        let frame = entry.target() / PAGE_SIZE;
+        let execute = entry.execute();
+        let mut rc_map = self.0.lock();
+        match rc_map.get(&frame).cloned() {
+            Some(count) if count > 1 => {
+                let target = allocator.alloc().ok_or(VMError::NoMemory)?;
+                rc_map.insert(frame, count - 1);
+                drop(rc_map);
+                let data = pt.get_page_slice_mut(addr);
+                let entry = pt.get_entry(addr).unwrap();
+                entry.set_target(target);
+                entry.update();
+                pt.get_page_slice_mut(addr).copy_from_slice(data);
+                pt.flush_cache_copy_user(addr, addr + PAGE_SIZE, execute);
+            }
+            _ => {
+                rc_map.remove(&frame);
+            }
+        }
+        let entry = pt.get_entry(addr).unwrap();
+        entry.clear_shared();
+        entry.set_writable(true);
+        entry.update();
+        Ok(())
+    }
+
+    /// The number of page table entries mapping the frame of the present page `addr`
+    pub fn map_count(&self, pt: &mut dyn PageTable, addr: VirtAddr) -> usize {
+        let entry = pt.get_entry(addr).expect("failed to get entry");
+        if !(entry.readonly_shared() || entry.writable_shared()) {
+            return 1;
+        }
+        let frame = entry.target() / PAGE_SIZE;
+        self.0.lock().get(&frame).cloned().unwrap_or(1)
+    }
+
+    /// Drop the reference of the present page `addr` to its frame,
+    /// and free the frame if it is the last one.
+    pub fn release(&self, pt: &mut dyn PageTable, addr: VirtAddr, allocator: &impl FrameAllocator) {
+        let entry = pt.get_entry(addr).expect("failed to get entry");
+        let target = entry.target();
+        if entry.readonly_shared() || entry.writable_shared() {
+            entry.clear_shared();
+            entry.update();
+            let mut rc_map = self.0.lock();
+            match rc_map.get(&(target / PAGE_SIZE)).cloned() {
+                Some(count) if count > 1 => {
+                    rc_map.insert(target / PAGE_SIZE, count - 1);
+                    return;
+                }
+                _ => {
+                    rc_map.remove(&(target / PAGE_SIZE));
+                }
+            }
+        }
+        allocator.dealloc(target);
+    }
+
+    /// Whether the frame at `target` is shared copy-on-write by `share`
+    pub fn contains(&self, target: PhysAddr) -> bool {
+        self.0.lock().contains_key(&(target / PAGE_SIZE))
+    }
+}
+
+impl Debug for CowFrames {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        f.debug_struct("CowFrames")
+            .field("shared", &self.0.lock().len())
+            .finish()
+    }
+}
+
 pub mod test {
     use super::*;
 
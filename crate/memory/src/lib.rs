@@ -7,16 +7,24 @@ use log::*;
 extern crate alloc;
 
 mod addr;
+pub mod buddy;
 pub mod cow;
 pub mod memory_set;
 pub mod no_mmu;
 pub mod paging;
-//pub mod swap;
+pub mod swap;
 
 pub use crate::addr::*;
 
+#[derive(Debug, PartialEq, Eq)]
 pub enum VMError {
     InvalidPtr,
+    /// No room to map, e.g. an area can't grow in place
+    NoMemory,
+    /// The access is more than the area allows, e.g. writing a read-only shared file
+    AccessDenied,
+    /// Reading or writing the file behind the memory failed
+    IOError,
 }
 
 pub type VMResult<T> = Result<T, VMError>;
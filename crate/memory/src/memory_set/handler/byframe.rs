@@ -3,6 +3,7 @@ use super::*;
 #[derive(Debug, Clone)]
 pub struct ByFrame<T: FrameAllocator> {
     allocator: T,
+    cow: CowFrames,
 }
 
 impl<T: FrameAllocator> MemoryHandler for ByFrame<T> {
@@ -11,14 +12,19 @@ impl<T: FrameAllocator> MemoryHandler for ByFrame<T> {
     }
 
     fn map(&self, pt: &mut dyn PageTable, addr: VirtAddr, attr: &MemoryAttr) {
-        let target = self.allocator.alloc().expect("failed to allocate frame");
+        self.try_map(pt, addr, attr)
+            .expect("failed to allocate frame");
+    }
+
+    fn try_map(&self, pt: &mut dyn PageTable, addr: VirtAddr, attr: &MemoryAttr) -> VMResult<()> {
+        let target = self.allocator.alloc().ok_or(VMError::NoMemory)?;
         let entry = pt.map(addr, target);
         attr.apply(entry);
+        Ok(())
     }
 
     fn unmap(&self, pt: &mut dyn PageTable, addr: VirtAddr) {
-        let target = pt.get_entry(addr).expect("fail to get entry").target();
-        self.allocator.dealloc(target);
+        self.cow.release(pt, addr, &self.allocator);
         pt.unmap(addr);
     }
 
@@ -29,18 +35,59 @@ impl<T: FrameAllocator> MemoryHandler for ByFrame<T> {
         addr: VirtAddr,
         attr: &MemoryAttr,
     ) {
-        self.map(pt, addr, attr);
+        self.try_clone_map(pt, src_pt, addr, attr)
+            .expect("failed to allocate frame");
+    }
+
+    fn try_clone_map(
+        &self,
+        pt: &mut dyn PageTable,
+        src_pt: &mut dyn PageTable,
+        addr: VirtAddr,
+        attr: &MemoryAttr,
+    ) -> VMResult<()> {
+        if self.cow.share(pt, src_pt, addr, attr) {
+            return Ok(());
+        }
+        self.try_map(pt, addr, attr)?;
         let data = src_pt.get_page_slice_mut(addr);
         pt.get_page_slice_mut(addr).copy_from_slice(data);
+        Ok(())
+    }
+
+    fn handle_page_fault_ext(
+        &self,
+        pt: &mut dyn PageTable,
+        addr: VirtAddr,
+        access: super::AccessType,
+    ) -> VMResult<()> {
+        if !access.write {
+            return Err(VMError::AccessDenied);
+        }
+        self.cow.unshare(pt, addr, &self.allocator)
+    }
+
+    fn map_count(&self, pt: &mut dyn PageTable, addr: VirtAddr) -> usize {
+        self.cow.map_count(pt, addr)
     }
 
-    fn handle_page_fault(&self, _pt: &mut dyn PageTable, _addr: VirtAddr) -> bool {
-        false
+    fn discard(&self, pt: &mut dyn PageTable, addr: VirtAddr) {
+        let entry = pt.get_entry(addr).expect("failed to get entry");
+        if entry.writable_shared() {
+            // don't zero the frame shared with others
+            if self.cow.unshare(pt, addr, &self.allocator).is_err() {
+                return;
+            }
+        }
+        pt.get_page_slice_mut(addr).iter_mut().for_each(|x| *x = 0);
     }
 }
 
 impl<T: FrameAllocator> ByFrame<T> {
     pub fn new(allocator: T) -> Self {
-        ByFrame { allocator }
+        ByFrame {
+            allocator,
+            cow: CowFrames::default(),
+        }
     }
 }
@@ -3,6 +3,7 @@ use super::*;
 #[derive(Debug, Clone)]
 pub struct Delay<T: FrameAllocator> {
     allocator: T,
+    cow: CowFrames,
 }
 
 impl<T: FrameAllocator> MemoryHandler for Delay<T> {
@@ -19,10 +20,11 @@ impl<T: FrameAllocator> MemoryHandler for Delay<T> {
     fn unmap(&self, pt: &mut dyn PageTable, addr: VirtAddr) {
         let entry = pt.get_entry(addr).expect("failed to get entry");
         if entry.present() {
-            self.allocator.dealloc(entry.target());
+            self.cow.release(pt, addr, &self.allocator);
         }
 
         // PageTable::unmap requires page to be present
+        let entry = pt.get_entry(addr).expect("failed to get entry");
         entry.set_present(true);
         pt.unmap(addr);
     }
@@ -34,18 +36,21 @@ impl<T: FrameAllocator> MemoryHandler for Delay<T> {
         addr: VirtAddr,
         attr: &MemoryAttr,
     ) {
-        let entry = src_pt.get_entry(addr).expect("failed to get entry");
-        if entry.present() {
-            // eager map and copy data
+        let present = src_pt
+            .get_entry(addr)
+            .expect("failed to get entry")
+            .present();
+        if !present {
+            // delay map
+            self.map(pt, addr, attr);
+        } else if !self.cow.share(pt, src_pt, addr, attr) {
+            // eager map and copy data if copy-on-write is unsupported
             let data = src_pt.get_page_slice_mut(addr);
             let target = self.allocator.alloc().expect("failed to alloc frame");
             let entry = pt.map(addr, target);
             attr.apply(entry);
             pt.get_page_slice_mut(addr).copy_from_slice(data);
             pt.flush_cache_copy_user(addr, addr + data.len(), attr.execute);
-        } else {
-            // delay map
-            self.map(pt, addr, attr);
         }
     }
 
@@ -54,18 +59,21 @@ impl<T: FrameAllocator> MemoryHandler for Delay<T> {
         pt: &mut dyn PageTable,
         addr: VirtAddr,
         access: super::AccessType,
-    ) -> bool {
+    ) -> VMResult<()> {
         let entry = pt.get_entry(addr).expect("failed to get entry");
         if entry.present() {
             // permission check.
             if access.check_access(entry) {
-                return true;
+                return Ok(());
+            }
+            if access.write && entry.writable_shared() {
+                return self.cow.unshare(pt, addr, &self.allocator);
             }
             // permisison check failed.
             error!("Permission check failed at 0x{:x}.", addr);
-            return false;
+            return Err(VMError::AccessDenied);
         }
-        let frame = self.allocator.alloc().expect("failed to alloc frame");
+        let frame = self.allocator.alloc().ok_or(VMError::NoMemory)?;
         entry.set_target(frame);
         entry.set_present(true);
         entry.update();
@@ -76,12 +84,33 @@ impl<T: FrameAllocator> MemoryHandler for Delay<T> {
             *x = 0;
         }
         pt.flush_cache_copy_user(addr, addr + len, false);
+        Ok(())
+    }
+
+    fn map_count(&self, pt: &mut dyn PageTable, addr: VirtAddr) -> usize {
+        self.cow.map_count(pt, addr)
+    }
+
+    fn discard(&self, pt: &mut dyn PageTable, addr: VirtAddr) {
+        let entry = pt.get_entry(addr).expect("failed to get entry");
+        if entry.present() {
+            self.cow.release(pt, addr, &self.allocator);
+            let entry = pt.get_entry(addr).expect("failed to get entry");
+            entry.set_present(false);
+            entry.update();
+        }
+    }
+
+    fn swappable(&self) -> bool {
         true
     }
 }
 
 impl<T: FrameAllocator> Delay<T> {
     pub fn new(allocator: T) -> Self {
-        Delay { allocator }
+        Delay {
+            allocator,
+            cow: CowFrames::default(),
+        }
     }
 }
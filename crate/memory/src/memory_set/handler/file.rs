@@ -8,10 +8,21 @@ pub struct File<F, T> {
     pub file_start: usize,
     pub file_end: usize,
     pub allocator: T,
+    cow: CowFrames,
 }
 
 pub trait Read: Clone + Send + Sync + 'static {
-    fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize;
+    /// Read the file at `offset` into `buf`, failing with `IOError` if the file can't be read
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> VMResult<usize>;
+
+    /// Get the frame caching the page at page aligned `offset` and keep it in memory,
+    /// if the file has a page cache.
+    fn get_page(&self, _offset: usize) -> VMResult<Option<PhysAddr>> {
+        Ok(None)
+    }
+
+    /// Release the page got from `get_page`
+    fn put_page(&self, _offset: usize) {}
 }
 
 impl<F: Read, T: FrameAllocator> MemoryHandler for File<F, T> {
@@ -26,10 +37,8 @@ impl<F: Read, T: FrameAllocator> MemoryHandler for File<F, T> {
     }
 
     fn unmap(&self, pt: &mut dyn PageTable, addr: usize) {
+        self.release(pt, addr);
         let entry = pt.get_entry(addr).expect("failed to get entry");
-        if entry.present() {
-            self.allocator.dealloc(entry.target());
-        }
 
         // PageTable::unmap requires page to be present
         entry.set_present(true);
@@ -44,15 +53,23 @@ impl<F: Read, T: FrameAllocator> MemoryHandler for File<F, T> {
         attr: &MemoryAttr,
     ) {
         let entry = src_pt.get_entry(addr).expect("failed to get entry");
-        if entry.present() && !attr.readonly {
-            // eager map and copy data
+        let present = entry.present();
+        if present && self.is_cached(entry) {
+            // map the cached page too, which can't be evicted while mapped
+            let target = entry.target();
+            let _ = self.file.get_page(self.file_offset(addr));
+            let entry = pt.map(addr, target);
+            entry.set_shared(true);
+            attr.apply(entry);
+        } else if present && !self.cow.share(pt, src_pt, addr, attr) {
+            // eager map and copy data if copy-on-write is unsupported
             let data = src_pt.get_page_slice_mut(addr);
             let target = self.allocator.alloc().expect("failed to alloc frame");
             let entry = pt.map(addr, target);
             attr.apply(entry);
             pt.get_page_slice_mut(addr).copy_from_slice(data);
             pt.flush_cache_copy_user(addr, addr + data.len(), attr.execute);
-        } else {
+        } else if !present {
             // delay map
             self.map(pt, addr, attr);
         }
@@ -63,45 +80,190 @@ impl<F: Read, T: FrameAllocator> MemoryHandler for File<F, T> {
         pt: &mut dyn PageTable,
         addr: usize,
         access: super::AccessType,
-    ) -> bool {
+    ) -> VMResult<()> {
         let addr = addr & !(PAGE_SIZE - 1);
         let entry = pt.get_entry(addr).expect("failed to get entry");
         if entry.present() {
             // permission check.
             if access.check_access(entry) {
-                return true;
+                return Ok(());
+            }
+            if access.write && entry.writable_shared() {
+                if self.is_cached(entry) {
+                    return self.copy_on_write(pt, addr);
+                }
+                return self.cow.unshare(pt, addr, &self.allocator);
             }
             // permisison check failed.
             error!(
                 "Permission check failed at 0x{:x}, access = {:?}.",
                 addr, access
             );
-            return false;
+            return Err(VMError::AccessDenied);
         }
         let execute = entry.execute();
-        let frame = self.allocator.alloc().expect("failed to alloc frame");
+        if self.map_cached(pt, addr)? {
+            return Ok(());
+        }
+        let entry = pt.get_entry(addr).expect("failed to get entry");
+        let frame = self.allocator.alloc().ok_or(VMError::NoMemory)?;
         entry.set_target(frame);
         entry.set_present(true);
         entry.update();
 
-        let read_size = self.fill_data(pt, addr);
+        let read_size = match self.fill_data(pt, addr) {
+            Ok(read_size) => read_size,
+            Err(err) => {
+                // leave the page for the next fault to try again
+                let entry = pt.get_entry(addr).unwrap();
+                entry.set_present(false);
+                entry.update();
+                self.allocator.dealloc(frame);
+                return Err(err);
+            }
+        };
         pt.flush_cache_copy_user(addr, addr + read_size, execute);
-        true
+        Ok(())
+    }
+
+    fn map_count(&self, pt: &mut dyn PageTable, addr: usize) -> usize {
+        self.cow.map_count(pt, addr)
+    }
+
+    fn discard(&self, pt: &mut dyn PageTable, addr: usize) {
+        self.release(pt, addr);
+        let entry = pt.get_entry(addr).expect("failed to get entry");
+        entry.set_present(false);
+        entry.update();
+    }
+
+    fn prefetch(&self, pt: &mut dyn PageTable, addr: usize) {
+        let present = pt.get_entry(addr).expect("failed to get entry").present();
+        if !present {
+            self.handle_page_fault(pt, addr);
+        }
+    }
+
+    fn needs_read(&self, pt: &mut dyn PageTable, addr: usize) -> bool {
+        // pages of the file cache may be read already, but can't be told apart here
+        !pt.get_entry(addr).expect("failed to get entry").present()
+    }
+
+    fn rebase(&self, old_start: usize, new_start: usize) -> Box<dyn MemoryHandler> {
+        Box::new(File {
+            file: self.file.clone(),
+            mem_start: new_start,
+            file_start: self.file_start + old_start - self.mem_start,
+            file_end: self.file_end,
+            allocator: self.allocator.clone(),
+            cow: self.cow.clone(),
+        })
     }
 }
 
 impl<F: Read, T: FrameAllocator> File<F, T> {
-    fn fill_data(&self, pt: &mut dyn PageTable, addr: VirtAddr) -> usize {
+    /// Map `[file_start, file_end)` of `file` at `mem_start`
+    pub fn new(
+        file: F,
+        mem_start: usize,
+        file_start: usize,
+        file_end: usize,
+        allocator: T,
+    ) -> Self {
+        File {
+            file,
+            mem_start,
+            file_start,
+            file_end,
+            allocator,
+            cow: CowFrames::default(),
+        }
+    }
+
+    fn file_offset(&self, addr: VirtAddr) -> usize {
+        addr + self.file_start - self.mem_start
+    }
+
+    /// Map the page of the file cache at page aligned `addr`, shared readonly.
+    /// Only pages within the file range are mapped, so the rest of the area is zero filled.
+    /// Return false if the page is not cached.
+    fn map_cached(&self, pt: &mut dyn PageTable, addr: VirtAddr) -> VMResult<bool> {
+        let offset = self.file_offset(addr);
+        if offset & (PAGE_SIZE - 1) != 0 || offset < self.file_start {
+            return Ok(false);
+        }
+        if offset + PAGE_SIZE > self.file_end {
+            return Ok(false);
+        }
+        let entry = pt.get_entry(addr).expect("failed to get entry");
+        entry.set_shared(entry.writable());
+        if !(entry.readonly_shared() || entry.writable_shared()) {
+            // shared entry is not supported
+            return Ok(false);
+        }
+        let frame = match self.file.get_page(offset) {
+            Ok(Some(frame)) => frame,
+            result => {
+                let entry = pt.get_entry(addr).unwrap();
+                entry.clear_shared();
+                return result.map(|_| false);
+            }
+        };
+        entry.set_target(frame);
+        entry.set_writable(false);
+        entry.set_present(true);
+        entry.update();
+        Ok(true)
+    }
+
+    /// Copy the cached page of `addr` to a private frame on write
+    fn copy_on_write(&self, pt: &mut dyn PageTable, addr: VirtAddr) -> VMResult<()> {
+        let frame = self.allocator.alloc().ok_or(VMError::NoMemory)?;
+        let execute = pt.get_entry(addr).unwrap().execute();
+        let data = pt.get_page_slice_mut(addr);
+        let entry = pt.get_entry(addr).unwrap();
+        entry.set_target(frame);
+        entry.clear_shared();
+        entry.set_writable(true);
+        entry.update();
+        pt.get_page_slice_mut(addr).copy_from_slice(data);
+        pt.flush_cache_copy_user(addr, addr + PAGE_SIZE, execute);
+        self.file.put_page(self.file_offset(addr));
+        Ok(())
+    }
+
+    /// Free the frame of `addr` or release the cached page
+    fn release(&self, pt: &mut dyn PageTable, addr: VirtAddr) {
+        let entry = pt.get_entry(addr).expect("failed to get entry");
+        if !entry.present() {
+            return;
+        }
+        if self.is_cached(entry) {
+            entry.clear_shared();
+            entry.update();
+            self.file.put_page(self.file_offset(addr));
+        } else {
+            self.cow.release(pt, addr, &self.allocator);
+        }
+    }
+
+    /// Whether the page is a cached page of the file,
+    /// rather than a private frame, which may be shared copy-on-write
+    fn is_cached(&self, entry: &dyn Entry) -> bool {
+        (entry.readonly_shared() || entry.writable_shared()) && !self.cow.contains(entry.target())
+    }
+
+    fn fill_data(&self, pt: &mut dyn PageTable, addr: VirtAddr) -> VMResult<usize> {
         let data = pt.get_page_slice_mut(addr);
-        let file_offset = addr + self.file_start - self.mem_start;
+        let file_offset = self.file_offset(addr);
         let read_size = (self.file_end as isize - file_offset as isize)
             .min(PAGE_SIZE as isize)
             .max(0) as usize;
-        let read_size = self.file.read_at(file_offset, &mut data[..read_size]);
+        let read_size = self.file.read_at(file_offset, &mut data[..read_size])?;
         if read_size != PAGE_SIZE {
             data[read_size..].iter_mut().for_each(|x| *x = 0);
         }
-        read_size
+        Ok(read_size)
     }
 }
 
@@ -0,0 +1,126 @@
+use super::*;
+
+/// Eagerly map zeroed huge pages of `HUGE_PAGE_SIZE`,
+/// falling back to frames of `PAGE_SIZE` if huge pages are not available.
+/// The area should be aligned to `HUGE_PAGE_SIZE`.
+#[derive(Debug, Clone)]
+pub struct Huge<T: FrameAllocator> {
+    allocator: T,
+}
+
+impl<T: FrameAllocator> MemoryHandler for Huge<T> {
+    fn box_clone(&self) -> Box<dyn MemoryHandler> {
+        Box::new(self.clone())
+    }
+
+    fn map(&self, pt: &mut dyn PageTable, addr: VirtAddr, attr: &MemoryAttr) {
+        self.try_map(pt, addr, attr)
+            .expect("failed to allocate frame");
+    }
+
+    fn try_map(&self, pt: &mut dyn PageTable, addr: VirtAddr, attr: &MemoryAttr) -> VMResult<()> {
+        if pt.page_size(addr) > PAGE_SIZE {
+            // mapped with the huge page at its start
+            return Ok(());
+        }
+        if addr % HUGE_PAGE_SIZE == 0 && self.map_huge(pt, addr, attr) {
+            return Ok(());
+        }
+        let target = self.allocator.alloc().ok_or(VMError::NoMemory)?;
+        let entry = pt.map(addr, target);
+        attr.apply(entry);
+        pt.get_page_slice_mut(addr).iter_mut().for_each(|x| *x = 0);
+        Ok(())
+    }
+
+    fn unmap(&self, pt: &mut dyn PageTable, addr: VirtAddr) {
+        let size = pt.page_size(addr);
+        if size > PAGE_SIZE {
+            // areas are split at huge pages, so the whole page is in the area
+            let target = pt.get_entry(addr).expect("failed to get entry").target();
+            for offset in (0..size).step_by(PAGE_SIZE) {
+                self.allocator.dealloc(target + offset);
+            }
+            pt.unmap(addr);
+            return;
+        }
+        // the rest of an unmapped huge page is not mapped
+        let target = match pt.get_entry(addr) {
+            Some(entry) if entry.present() => entry.target(),
+            _ => return,
+        };
+        self.allocator.dealloc(target);
+        pt.unmap(addr);
+    }
+
+    fn clone_map(
+        &self,
+        pt: &mut dyn PageTable,
+        src_pt: &mut dyn PageTable,
+        addr: VirtAddr,
+        attr: &MemoryAttr,
+    ) {
+        self.try_clone_map(pt, src_pt, addr, attr)
+            .expect("failed to allocate frame");
+    }
+
+    fn try_clone_map(
+        &self,
+        pt: &mut dyn PageTable,
+        src_pt: &mut dyn PageTable,
+        addr: VirtAddr,
+        attr: &MemoryAttr,
+    ) -> VMResult<()> {
+        self.try_map(pt, addr, attr)?;
+        let data = src_pt.get_page_slice_mut(addr);
+        pt.get_page_slice_mut(addr).copy_from_slice(data);
+        Ok(())
+    }
+
+    fn handle_page_fault_ext(
+        &self,
+        _pt: &mut dyn PageTable,
+        _addr: VirtAddr,
+        _access: super::AccessType,
+    ) -> VMResult<()> {
+        // huge pages are mapped eagerly, so only a denied access faults
+        Err(VMError::AccessDenied)
+    }
+
+    fn discard(&self, pt: &mut dyn PageTable, addr: VirtAddr) {
+        pt.get_page_slice_mut(addr).iter_mut().for_each(|x| *x = 0);
+    }
+}
+
+impl<T: FrameAllocator> Huge<T> {
+    pub fn new(allocator: T) -> Self {
+        Huge { allocator }
+    }
+
+    /// Map a huge page at `addr` to contiguous frames
+    fn map_huge(&self, pt: &mut dyn PageTable, addr: VirtAddr, attr: &MemoryAttr) -> bool {
+        let count = HUGE_PAGE_SIZE / PAGE_SIZE;
+        let target = match self
+            .allocator
+            .alloc_contiguous(count, count.trailing_zeros() as usize)
+        {
+            Some(target) => target,
+            None => return false,
+        };
+        match pt.map_huge(addr, target, HUGE_PAGE_SIZE) {
+            Some(entry) => attr.apply(entry),
+            None => {
+                for offset in (0..HUGE_PAGE_SIZE).step_by(PAGE_SIZE) {
+                    self.allocator.dealloc(target + offset);
+                }
+                return false;
+            }
+        }
+        for offset in (0..HUGE_PAGE_SIZE).step_by(PAGE_SIZE) {
+            pt.get_page_slice_mut(addr + offset)
+                .iter_mut()
+                .for_each(|x| *x = 0);
+        }
+        true
+    }
+}
@@ -33,6 +33,11 @@ impl MemoryHandler for Linear {
     fn handle_page_fault(&self, _pt: &mut dyn PageTable, _addr: VirtAddr) -> bool {
         false
     }
+
+    fn rebase(&self, old_start: VirtAddr, new_start: VirtAddr) -> Box<dyn MemoryHandler> {
+        let offset = self.offset + old_start as isize - new_start as isize;
+        Box::new(Linear::new(offset))
+    }
 }
 
 impl Linear {
@@ -1,4 +1,5 @@
 use super::*;
+use crate::cow::CowFrames;
 #[derive(Copy, Clone, Debug)]
 pub struct AccessType {
     pub write: bool,
@@ -48,6 +49,13 @@ pub trait MemoryHandler: Debug + Send + Sync + 'static {
     /// Should set page flags here instead of in `page_fault_handler`
     fn map(&self, pt: &mut dyn PageTable, addr: VirtAddr, attr: &MemoryAttr);
 
+    /// Map `addr` like `map`, failing with `NoMemory` instead of panicking when out of frames.
+    /// Handlers allocating frames in `map` should override it.
+    fn try_map(&self, pt: &mut dyn PageTable, addr: VirtAddr, attr: &MemoryAttr) -> VMResult<()> {
+        self.map(pt, addr, attr);
+        Ok(())
+    }
+
     /// Unmap `addr` in the page table
     fn unmap(&self, pt: &mut dyn PageTable, addr: VirtAddr);
 
@@ -60,21 +68,76 @@ pub trait MemoryHandler: Debug + Send + Sync + 'static {
         attr: &MemoryAttr,
     );
 
+    /// Clone map `addr` like `clone_map`, failing with `NoMemory` instead of panicking
+    /// when out of frames. Handlers allocating frames in `clone_map` should override it.
+    fn try_clone_map(
+        &self,
+        pt: &mut dyn PageTable,
+        src_pt: &mut dyn PageTable,
+        addr: VirtAddr,
+        attr: &MemoryAttr,
+    ) -> VMResult<()> {
+        self.clone_map(pt, src_pt, addr, attr);
+        Ok(())
+    }
+
     /// Handle page fault on `addr`
     /// Return true if success, false if error
     fn handle_page_fault(&self, pt: &mut dyn PageTable, addr: VirtAddr) -> bool {
         self.handle_page_fault_ext(pt, addr, AccessType::unknown())
+            .is_ok()
     }
 
-    /// Handle page fault on `addr` and access type `access`
-    /// Return true if success (or should-retry), false if error
+    /// Handle page fault on `addr` and access type `access`.
+    /// Fail with `NoMemory` only if frames run out, so that the caller may free some and retry.
     fn handle_page_fault_ext(
         &self,
         pt: &mut dyn PageTable,
         addr: VirtAddr,
         _access: AccessType,
-    ) -> bool {
-        self.handle_page_fault(pt, addr)
+    ) -> VMResult<()> {
+        match self.handle_page_fault(pt, addr) {
+            true => Ok(()),
+            false => Err(VMError::InvalidPtr),
+        }
+    }
+
+    /// Drop the data of `addr`, so that it is zero-filled or reloaded on next access.
+    /// Used by `madvise(MADV_DONTNEED)`.
+    fn discard(&self, _pt: &mut dyn PageTable, _addr: VirtAddr) {}
+
+    /// Load the data of `addr` in advance if it is backed by a file.
+    /// Used by `madvise(MADV_WILLNEED)`.
+    fn prefetch(&self, _pt: &mut dyn PageTable, _addr: VirtAddr) {}
+
+    /// Whether filling the non-present page `addr` on a page fault reads a file.
+    /// Used to count major page faults.
+    fn needs_read(&self, _pt: &mut dyn PageTable, _addr: VirtAddr) -> bool {
+        false
+    }
+
+    /// The number of page table entries mapping the frame of the present page `addr`.
+    /// Used to count shared pages and the proportional set size.
+    fn map_count(&self, _pt: &mut dyn PageTable, _addr: VirtAddr) -> usize {
+        1
+    }
+
+    /// Whether pages can be swapped out.
+    /// If so, `discard` should free the frame, and a page fault should allocate a new one.
+    fn swappable(&self) -> bool {
+        false
+    }
+
+    /// Write the data of `addr` back to the file if it is dirty.
+    /// Used by `msync`.
+    fn sync(&self, _pt: &mut dyn PageTable, _addr: VirtAddr) -> VMResult<()> {
+        Ok(())
+    }
+
+    /// Create the handler for the area moved from `old_start` to `new_start`,
+    /// whose pages keep their data. Used by `mremap`.
+    fn rebase(&self, _old_start: VirtAddr, _new_start: VirtAddr) -> Box<dyn MemoryHandler> {
+        self.box_clone()
     }
 }
 
@@ -93,12 +156,16 @@ pub trait FrameAllocator: Debug + Clone + Send + Sync + 'static {
 mod byframe;
 mod delay;
 mod file;
+mod huge;
 mod linear;
 mod shared;
+mod shared_file;
 //mod swap;
 
 pub use self::byframe::ByFrame;
 pub use self::delay::Delay;
 pub use self::file::{File, Read};
+pub use self::huge::Huge;
 pub use self::linear::Linear;
 pub use self::shared::{Shared, SharedGuard};
+pub use self::shared_file::{CachedPage, FilePages, SharedFile, Write};
@@ -87,7 +87,7 @@ impl<T: FrameAllocator> MemoryHandler for Shared<T> {
             let mut init_start_virt_addr = self.start_virt_addr.lock();
             *init_start_virt_addr = Some(addr);
         }
-        let addr_offset = addr - self.start_virt_addr.lock().unwrap();
+        let addr_offset = addr.wrapping_sub(self.start_virt_addr.lock().unwrap());
         let phys_addr_opt = self.guard.lock().get(addr_offset);
         if phys_addr_opt.is_none() {
             // not mapped yet
@@ -122,7 +122,7 @@ impl<T: FrameAllocator> MemoryHandler for Shared<T> {
 
     fn handle_page_fault(&self, pt: &mut dyn PageTable, addr: VirtAddr) -> bool {
         let entry = pt.get_entry(addr).expect("failed to get entry");
-        let addr_offset = addr - self.start_virt_addr.lock().unwrap();
+        let addr_offset = addr.wrapping_sub(self.start_virt_addr.lock().unwrap());
         let phys_addr_opt = self.guard.lock().get(addr_offset);
         if entry.present() {
             // not a delay case
@@ -150,6 +150,24 @@ impl<T: FrameAllocator> MemoryHandler for Shared<T> {
         }
         true
     }
+
+    fn map_count(&self, _pt: &mut dyn PageTable, _addr: VirtAddr) -> usize {
+        // every mapping of the shared memory holds the guard
+        Arc::strong_count(&self.guard)
+    }
+
+    fn rebase(&self, old_start: VirtAddr, new_start: VirtAddr) -> Box<dyn MemoryHandler> {
+        // offsets in the guard are kept, so the start address may wrap around
+        let start_virt_addr = self
+            .start_virt_addr
+            .lock()
+            .map(|start| start.wrapping_add(new_start).wrapping_sub(old_start));
+        Box::new(Shared {
+            allocator: self.allocator.clone(),
+            start_virt_addr: Arc::new(Mutex::new(start_virt_addr)),
+            guard: self.guard.clone(),
+        })
+    }
 }
 
 impl<T: FrameAllocator> Shared<T> {
@@ -0,0 +1,324 @@
+use super::*;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use spin::Mutex;
+
+pub trait Write: Read {
+    /// Write `buf` to the file at `offset`, failing if the file can't take it
+    fn write_at(&self, offset: usize, buf: &[u8]) -> VMResult<usize>;
+}
+
+/// A page of a file held in a frame
+#[derive(Debug)]
+pub struct CachedPage {
+    pub frame: PhysAddr,
+    /// size of valid data from the file
+    pub size: usize,
+    /// number of page table entries mapping the frame
+    pub mapped: usize,
+    /// modified by file writes and not written back yet
+    pub dirty: bool,
+}
+
+/// Frames holding the pages of a file,
+/// shared by all `MAP_SHARED` mappings of the file, and file I/O if used as a page cache.
+pub struct FilePages<F, T: FrameAllocator> {
+    file: F,
+    allocator: T,
+    // page offset in file -> page
+    pages: BTreeMap<usize, CachedPage>,
+}
+
+impl<F, T: FrameAllocator> FilePages<F, T> {
+    pub fn new(file: F, allocator: T) -> Self {
+        FilePages {
+            file,
+            allocator,
+            pages: BTreeMap::new(),
+        }
+    }
+
+    pub fn file(&self) -> &F {
+        &self.file
+    }
+
+    /// Get the cached page at page aligned `offset`
+    pub fn get(&mut self, offset: usize) -> Option<&mut CachedPage> {
+        self.pages.get_mut(&offset)
+    }
+
+    /// Cache the page at page aligned `offset` in a new frame.
+    /// `fill` should fill the frame with data from the file, and return the size read.
+    /// Fail with `NoMemory` if out of frames, or with the error of `fill`, caching nothing.
+    pub fn load(
+        &mut self,
+        offset: usize,
+        fill: impl FnOnce(&F, PhysAddr) -> VMResult<usize>,
+    ) -> VMResult<&mut CachedPage> {
+        if !self.pages.contains_key(&offset) {
+            let frame = self.allocator.alloc().ok_or(VMError::NoMemory)?;
+            let size = match fill(&self.file, frame) {
+                Ok(size) => size,
+                Err(err) => {
+                    self.allocator.dealloc(frame);
+                    return Err(err);
+                }
+            };
+            let page = CachedPage {
+                frame,
+                size,
+                mapped: 0,
+                dirty: false,
+            };
+            self.pages.insert(offset, page);
+        }
+        Ok(self.pages.get_mut(&offset).unwrap())
+    }
+
+    /// Drop the page at `offset` and free its frame if it is not mapped.
+    /// Dirty data should be written back before.
+    pub fn evict(&mut self, offset: usize) -> bool {
+        match self.pages.get(&offset) {
+            Some(page) if page.mapped == 0 => {
+                self.allocator.dealloc(page.frame);
+                self.pages.remove(&offset);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Iterate over the cached pages by offset
+    pub fn iter(&self) -> impl Iterator<Item = (&usize, &CachedPage)> {
+        self.pages.iter()
+    }
+
+    /// Iterate over the cached pages by offset mutably
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&usize, &mut CachedPage)> {
+        self.pages.iter_mut()
+    }
+}
+
+impl<F, T: FrameAllocator> Drop for FilePages<F, T> {
+    fn drop(&mut self) {
+        for page in self.pages.values() {
+            self.allocator.dealloc(page.frame);
+        }
+    }
+}
+
+impl<F, T: FrameAllocator> Debug for FilePages<F, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        f.debug_struct("FilePages")
+            .field("pages", &self.pages)
+            .finish()
+    }
+}
+
+/// Delay mapping a page to a frame shared with other mappings of the file.
+/// Dirty pages are written back to the file on `sync` and `unmap`.
+#[derive(Clone)]
+pub struct SharedFile<F, T: FrameAllocator> {
+    pages: Arc<Mutex<FilePages<F, T>>>,
+    mem_start: VirtAddr,
+    file_start: usize,
+}
+
+impl<F: Write, T: FrameAllocator> MemoryHandler for SharedFile<F, T> {
+    fn box_clone(&self) -> Box<dyn MemoryHandler> {
+        Box::new(self.clone())
+    }
+
+    fn map(&self, pt: &mut dyn PageTable, addr: VirtAddr, attr: &MemoryAttr) {
+        let offset = self.file_offset(addr);
+        match self.pages.lock().get(offset) {
+            Some(page) => {
+                // page already loaded by other mappings or file I/O
+                page.mapped += 1;
+                let entry = pt.map(addr, page.frame);
+                entry.clear_dirty();
+                attr.apply(entry);
+            }
+            None => {
+                let entry = pt.map(addr, 0);
+                entry.set_present(false);
+                attr.apply(entry);
+            }
+        }
+    }
+
+    fn unmap(&self, pt: &mut dyn PageTable, addr: VirtAddr) {
+        self.release(pt, addr);
+        // frames are freed when evicted or all users of the file are dropped
+        let entry = pt.get_entry(addr).expect("failed to get entry");
+        // PageTable::unmap requires page to be present
+        entry.set_present(true);
+        pt.unmap(addr);
+    }
+
+    fn clone_map(
+        &self,
+        pt: &mut dyn PageTable,
+        _src_pt: &mut dyn PageTable,
+        addr: VirtAddr,
+        attr: &MemoryAttr,
+    ) {
+        // share the frames with the source
+        self.map(pt, addr, attr);
+    }
+
+    fn handle_page_fault_ext(
+        &self,
+        pt: &mut dyn PageTable,
+        addr: VirtAddr,
+        access: super::AccessType,
+    ) -> VMResult<()> {
+        let addr = addr & !(PAGE_SIZE - 1);
+        let entry = pt.get_entry(addr).expect("failed to get entry");
+        if entry.present() {
+            // permission check.
+            if access.check_access(entry) {
+                return Ok(());
+            }
+            // permisison check failed.
+            error!(
+                "Permission check failed at 0x{:x}, access = {:?}.",
+                addr, access
+            );
+            return Err(VMError::AccessDenied);
+        }
+        let offset = self.file_offset(addr);
+        let mut pages = self.pages.lock();
+        let page = pages.load(offset, |file, frame| {
+            let entry = pt.get_entry(addr).unwrap();
+            let execute = entry.execute();
+            entry.set_target(frame);
+            entry.set_present(true);
+            entry.update();
+            let data = pt.get_page_slice_mut(addr);
+            let read_size = match file.read_at(offset, data) {
+                Ok(read_size) => read_size,
+                Err(err) => {
+                    let entry = pt.get_entry(addr).unwrap();
+                    entry.set_present(false);
+                    entry.update();
+                    return Err(err);
+                }
+            };
+            data[read_size..].iter_mut().for_each(|x| *x = 0);
+            pt.flush_cache_copy_user(addr, addr + PAGE_SIZE, execute);
+            Ok(read_size)
+        })?;
+        page.mapped += 1;
+        let entry = pt.get_entry(addr).unwrap();
+        entry.set_target(page.frame);
+        entry.set_present(true);
+        entry.clear_dirty();
+        entry.update();
+        Ok(())
+    }
+
+    fn discard(&self, pt: &mut dyn PageTable, addr: VirtAddr) {
+        // the frame is kept for other users, only drop the mapping here
+        self.release(pt, addr);
+        let entry = pt.get_entry(addr).expect("failed to get entry");
+        entry.set_present(false);
+        entry.update();
+    }
+
+    fn prefetch(&self, pt: &mut dyn PageTable, addr: VirtAddr) {
+        let present = pt.get_entry(addr).expect("failed to get entry").present();
+        if !present {
+            self.handle_page_fault(pt, addr);
+        }
+    }
+
+    fn sync(&self, pt: &mut dyn PageTable, addr: VirtAddr) -> VMResult<()> {
+        self.write_back(pt, addr)
+    }
+
+    fn needs_read(&self, _pt: &mut dyn PageTable, addr: VirtAddr) -> bool {
+        self.pages.lock().get(self.file_offset(addr)).is_none()
+    }
+
+    fn map_count(&self, _pt: &mut dyn PageTable, addr: VirtAddr) -> usize {
+        match self.pages.lock().get(self.file_offset(addr)) {
+            Some(page) => page.mapped,
+            None => 1,
+        }
+    }
+
+    fn rebase(&self, old_start: VirtAddr, new_start: VirtAddr) -> Box<dyn MemoryHandler> {
+        Box::new(SharedFile {
+            pages: self.pages.clone(),
+            mem_start: new_start,
+            file_start: self.file_start + old_start - self.mem_start,
+        })
+    }
+}
+
+impl<F: Write, T: FrameAllocator> SharedFile<F, T> {
+    /// Map the file from page aligned `file_start` at page aligned `mem_start`
+    pub fn new(pages: Arc<Mutex<FilePages<F, T>>>, mem_start: VirtAddr, file_start: usize) -> Self {
+        SharedFile {
+            pages,
+            mem_start,
+            file_start,
+        }
+    }
+
+    fn file_offset(&self, addr: VirtAddr) -> usize {
+        (addr & !(PAGE_SIZE - 1)) + self.file_start - self.mem_start
+    }
+
+    /// Write the page of `addr` back to the file if it is dirty.
+    /// The page stays dirty if the write fails.
+    fn write_back(&self, pt: &mut dyn PageTable, addr: VirtAddr) -> VMResult<()> {
+        let entry = pt.get_entry(addr).expect("failed to get entry");
+        if !entry.present() {
+            return Ok(());
+        }
+        let dirty = entry.dirty();
+        entry.clear_dirty();
+        entry.update();
+        let offset = self.file_offset(addr);
+        let mut pages = self.pages.lock();
+        let page = pages.get(offset).expect("mapped page not cached");
+        if !(dirty || page.dirty) {
+            return Ok(());
+        }
+        page.dirty = false;
+        // don't extend the file with the tail of the page
+        let size = page.size;
+        let data = pt.get_page_slice_mut(addr);
+        if let Err(err) = pages.file().write_at(offset, &data[..size]) {
+            pages.get(offset).unwrap().dirty = true;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Write back and stop mapping the page of `addr`
+    fn release(&self, pt: &mut dyn PageTable, addr: VirtAddr) {
+        if !pt.get_entry(addr).expect("failed to get entry").present() {
+            return;
+        }
+        if self.write_back(pt, addr).is_err() {
+            // nobody is left to tell, the data stays in the cache for file I/O
+            warn!("failed to write back the page at {:#x}", addr);
+        }
+        let offset = self.file_offset(addr);
+        if let Some(page) = self.pages.lock().get(offset) {
+            page.mapped -= 1;
+        }
+    }
+}
+
+impl<F, T: FrameAllocator> Debug for SharedFile<F, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        f.debug_struct("SharedFileHandler")
+            .field("mem_start", &self.mem_start)
+            .field("file_start", &self.file_start)
+            .finish()
+    }
+}
@@ -3,8 +3,10 @@
 use alloc::{boxed::Box, vec::Vec};
 use core::fmt::{Debug, Error, Formatter};
 use core::mem::size_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::paging::*;
+use crate::swap::{clear_swap_entry, get_swap_entry, set_swap_entry, Swap, SwapError, Swapper};
 
 use super::*;
 
@@ -18,8 +20,20 @@ pub struct MemoryArea {
     start_addr: VirtAddr,
     end_addr: VirtAddr,
     attr: MemoryAttr,
-    handler: Box<dyn MemoryHandler>,
+    pub(crate) handler: Box<dyn MemoryHandler>,
     name: &'static str,
+    /// Pages are populated and never swapped out
+    pub(crate) locked: bool,
+    /// The area can be made writable, not set for shared mappings of read-only files
+    may_write: bool,
+    /// Shared by the areas split from the same one, which can be merged again
+    origin: usize,
+}
+
+/// A new `MemoryArea::origin`
+fn new_origin() -> usize {
+    static NEXT_ORIGIN: AtomicUsize = AtomicUsize::new(0);
+    NEXT_ORIGIN.fetch_add(1, Ordering::Relaxed)
 }
 
 impl MemoryArea {
@@ -27,9 +41,20 @@ impl MemoryArea {
     pub fn contains(&self, addr: VirtAddr) -> bool {
         addr >= self.start_addr && addr < self.end_addr
     }
+    /// Get the range [`start_addr`, `end_addr`) of the area
+    pub fn range(&self) -> (VirtAddr, VirtAddr) {
+        (self.start_addr, self.end_addr)
+    }
+    /// Get the name of the area
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
     /// Check the array is within the readable memory.
     /// Return the size of space covered in the area.
     fn check_read_array<S>(&self, ptr: *const S, count: usize) -> usize {
+        if self.attr.inaccessible {
+            return 0;
+        }
         // page align
         let min_bound = (ptr as usize).max(Page::of_addr(self.start_addr).start_address());
         let max_bound = unsafe { ptr.add(count) as usize }
@@ -63,9 +88,43 @@ impl MemoryArea {
             self.handler.map(pt, page.start_address(), &self.attr);
         }
     }
-    /// Unmap all pages in the area from page table `pt`
-    fn unmap(&self, pt: &mut dyn PageTable) {
+    /// Map all pages in the area like `map`,
+    /// unmapping them again if the handler runs out of frames
+    fn try_map(&self, pt: &mut dyn PageTable) -> VMResult<()> {
+        for page in Page::range_of(self.start_addr, self.end_addr) {
+            let addr = page.start_address();
+            if let Err(err) = self.handler.try_map(pt, addr, &self.attr) {
+                for page in Page::range_of(self.start_addr, addr) {
+                    self.handler.unmap(pt, page.start_address());
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+    /// Unmap all pages in the area from page table `pt`,
+    /// and free the swapped out pages in `swap`
+    /// Clone map all pages in the area from `src_pt` to `pt`,
+    /// unmapping them again if the handler runs out of frames
+    fn try_clone_map(&self, pt: &mut dyn PageTable, src_pt: &mut dyn PageTable) -> VMResult<()> {
         for page in Page::range_of(self.start_addr, self.end_addr) {
+            let addr = page.start_address();
+            if let Err(err) = self.handler.try_clone_map(pt, src_pt, addr, &self.attr) {
+                for page in Page::range_of(self.start_addr, addr) {
+                    self.handler.unmap(pt, page.start_address());
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+    fn unmap(&self, pt: &mut dyn PageTable, mut swap: Option<&mut Swap>) {
+        for page in Page::range_of(self.start_addr, self.end_addr) {
+            if let Some(swap) = swap.as_mut() {
+                if self.handler.swappable() {
+                    swap.forget(pt, page.start_address());
+                }
+            }
             self.handler.unmap(pt, page.start_address());
         }
     }
@@ -77,6 +136,7 @@ pub struct MemoryAttr {
     user: bool,
     readonly: bool,
     execute: bool,
+    inaccessible: bool,
     mmio: u8,
 }
 
@@ -97,6 +157,19 @@ impl MemoryAttr {
         self.execute = true;
         self
     }
+    pub fn noexec(mut self) -> Self {
+        self.execute = false;
+        self
+    }
+    /// Forbid any access from user, used for `PROT_NONE` guard pages.
+    pub fn inaccessible(mut self) -> Self {
+        self.inaccessible = true;
+        self
+    }
+    pub fn accessible(mut self) -> Self {
+        self.inaccessible = false;
+        self
+    }
     pub fn mmio(mut self, value: u8) -> Self {
         self.mmio = value;
         self
@@ -104,14 +177,44 @@ impl MemoryAttr {
     /// Apply the attributes to page table entry, then update it.
     /// NOTE: You may need to set present manually.
     pub fn apply(&self, entry: &mut dyn Entry) {
-        entry.set_user(self.user);
-        entry.set_writable(!self.readonly);
+        entry.set_user(self.user && !self.inaccessible);
+        if entry.readonly_shared() || entry.writable_shared() {
+            // copy-on-write pages stay readonly until written
+            entry.set_shared(!self.readonly);
+            entry.set_writable(false);
+        } else {
+            entry.set_writable(!self.readonly);
+        }
         entry.set_execute(self.execute);
         entry.set_mmio(self.mmio);
         entry.update();
     }
 }
 
+/// Page counts of memory areas, used for memory statistics
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PageStats {
+    /// Pages in memory
+    pub resident: usize,
+    /// Resident pages also mapped by other page tables
+    pub shared: usize,
+    /// Pages swapped out
+    pub swapped: usize,
+    /// Proportional set size in bytes,
+    /// where each resident page is divided among the page tables mapping it
+    pub pss: usize,
+}
+
+impl PageStats {
+    /// Add the counts of `other` to `self`
+    pub fn add(&mut self, other: &PageStats) {
+        self.resident += other.resident;
+        self.shared += other.shared;
+        self.swapped += other.swapped;
+        self.pss += other.pss;
+    }
+}
+
 /// A set of memory space with multiple memory areas with associated page table
 /// NOTE: Don't remove align(64), or you will fail to run MIPS.
 /// Temporary solution for rv64
@@ -119,6 +222,13 @@ impl MemoryAttr {
 pub struct MemorySet<T: PageTableExt> {
     areas: Vec<MemoryArea>,
     page_table: T,
+    /// Lock areas pushed in the future, set by `mlockall(MCL_FUTURE)`
+    lock_future: bool,
+    swap: Option<Swap>,
+    /// Page faults handled without I/O
+    minor_faults: usize,
+    /// Page faults handled by reading a file or the swap device
+    major_faults: usize,
 }
 
 impl<T: PageTableExt> MemorySet<T> {
@@ -127,6 +237,10 @@ impl<T: PageTableExt> MemorySet<T> {
         MemorySet {
             areas: Vec::new(),
             page_table: T::new(),
+            lock_future: false,
+            swap: None,
+            minor_faults: 0,
+            major_faults: 0,
         }
     }
     /// Create a new `MemorySet` for kernel remap
@@ -134,6 +248,10 @@ impl<T: PageTableExt> MemorySet<T> {
         MemorySet {
             areas: Vec::new(),
             page_table: T::new_bare(),
+            lock_future: false,
+            swap: None,
+            minor_faults: 0,
+            major_faults: 0,
         }
     }
     /// Check the pointer is within the readable memory
@@ -193,15 +311,77 @@ impl<T: PageTableExt> MemorySet<T> {
             .find(|area| area.is_overlap_with(start_addr, end_addr))
             .is_none()
     }
+    /// Test if [`start_addr`, `end_addr`) is fully covered by areas
+    fn test_covered_area(&self, start_addr: usize, end_addr: usize) -> bool {
+        let mut addr = start_addr;
+        for area in self.areas.iter() {
+            if area.end_addr <= addr {
+                continue;
+            }
+            if area.start_addr > addr {
+                return false;
+            }
+            addr = area.end_addr;
+            if addr >= end_addr {
+                break;
+            }
+        }
+        addr >= end_addr
+    }
+    /// Split the area containing `addr` into `[start, addr)` and `[addr, end)`.
+    /// Do nothing if `addr` is not in any area or is the start of one.
+    fn split_at(&mut self, addr: VirtAddr) {
+        self.split_huge_page_at(addr);
+        let idx = self
+            .areas
+            .iter()
+            .position(|area| area.contains(addr) && area.start_addr != addr);
+        if let Some(i) = idx {
+            let area = &mut self.areas[i];
+            let right = MemoryArea {
+                start_addr: addr,
+                end_addr: area.end_addr,
+                attr: area.attr,
+                handler: area.handler.box_clone(),
+                name: area.name,
+                locked: area.locked,
+                may_write: area.may_write,
+                origin: area.origin,
+            };
+            area.end_addr = addr;
+            self.areas.insert(i + 1, right);
+        }
+    }
+    /// Split the huge page containing `addr` if `addr` is inside it
+    fn split_huge_page_at(&mut self, addr: VirtAddr) {
+        if addr % self.page_table.page_size(addr) != 0 {
+            paging::split_huge_page(&mut self.page_table, addr);
+        }
+    }
+
     /// Add an area to this set
     pub fn push(
+        &mut self,
+        start_addr: VirtAddr,
+        end_addr: VirtAddr,
+        attr: MemoryAttr,
+        handler: impl MemoryHandler,
+        name: &'static str,
+    ) {
+        self.try_push(start_addr, end_addr, attr, handler, name)
+            .expect("failed to allocate frame");
+    }
+
+    /// Add an area to this set like `push`,
+    /// failing with `NoMemory` if its pages can't be mapped
+    pub fn try_push(
         &mut self,
         mut start_addr: VirtAddr,
         mut end_addr: VirtAddr,
         attr: MemoryAttr,
         handler: impl MemoryHandler,
         name: &'static str,
-    ) {
+    ) -> VMResult<()> {
         start_addr = start_addr & !(PAGE_SIZE - 1);
         end_addr = (end_addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
         assert!(start_addr < end_addr, "invalid memory area");
@@ -215,17 +395,76 @@ impl<T: PageTableExt> MemorySet<T> {
             attr,
             handler: Box::new(handler),
             name,
+            locked: self.lock_future,
+            may_write: true,
+            origin: new_origin(),
+        };
+        area.try_map(&mut self.page_table)?;
+        self.insert_area(area);
+        if self.lock_future {
+            self.populate(start_addr, end_addr);
+        }
+        Ok(())
+    }
+
+    /// Back `[start_addr, end_addr)` inside an area with `handler` instead,
+    /// keeping its attributes. All pages in the range must not be touched yet.
+    pub fn replace(
+        &mut self,
+        start_addr: VirtAddr,
+        end_addr: VirtAddr,
+        handler: impl MemoryHandler,
+        name: &'static str,
+    ) -> VMResult<()> {
+        if !self
+            .areas
+            .iter()
+            .any(|area| area.start_addr <= start_addr && end_addr <= area.end_addr)
+        {
+            return Err(VMError::InvalidPtr);
+        }
+        for page in Page::range_of(start_addr, end_addr) {
+            if let Some(entry) = self.page_table.get_entry(page.start_address()) {
+                if entry.present() || entry.swapped() {
+                    return Err(VMError::InvalidPtr);
+                }
+            }
+        }
+        self.split_at(start_addr);
+        self.split_at(end_addr);
+        let idx = self
+            .areas
+            .iter()
+            .position(|area| area.start_addr == start_addr)
+            .unwrap();
+        let old = self.areas.remove(idx);
+        old.unmap(&mut self.page_table, self.swap.as_mut());
+        let area = MemoryArea {
+            start_addr,
+            end_addr,
+            attr: old.attr,
+            handler: Box::new(handler),
+            name,
+            locked: old.locked,
+            may_write: old.may_write,
+            origin: new_origin(),
         };
         area.map(&mut self.page_table);
-        // keep order by start address
+        self.insert_area(area);
+        Ok(())
+    }
+
+    /// Insert `area` keeping the order by start address, return its index
+    fn insert_area(&mut self, area: MemoryArea) -> usize {
         let idx = self
             .areas
             .iter()
             .enumerate()
-            .find(|(_, other)| start_addr < other.start_addr)
+            .find(|(_, other)| area.start_addr < other.start_addr)
             .map(|(i, _)| i)
             .unwrap_or(self.areas.len());
         self.areas.insert(idx, area);
+        idx
     }
 
     /// Remove the area `[start_addr, end_addr)` from `MemorySet`
@@ -234,7 +473,7 @@ impl<T: PageTableExt> MemorySet<T> {
         for i in 0..self.areas.len() {
             if self.areas[i].start_addr == start_addr && self.areas[i].end_addr == end_addr {
                 let area = self.areas.remove(i);
-                area.unmap(&mut self.page_table);
+                area.unmap(&mut self.page_table, self.swap.as_mut());
                 return;
             }
         }
@@ -245,13 +484,15 @@ impl<T: PageTableExt> MemorySet<T> {
     /// and split existed ones when necessary.
     pub fn pop_with_split(&mut self, start_addr: VirtAddr, end_addr: VirtAddr) {
         assert!(start_addr <= end_addr, "invalid memory area");
+        self.split_huge_page_at(start_addr);
+        self.split_huge_page_at(end_addr);
         let mut i = 0;
         while i < self.areas.len() {
             if self.areas[i].is_overlap_with(start_addr, end_addr) {
                 if self.areas[i].start_addr >= start_addr && self.areas[i].end_addr <= end_addr {
                     // subset
                     let area = self.areas.remove(i);
-                    area.unmap(&mut self.page_table);
+                    area.unmap(&mut self.page_table, self.swap.as_mut());
                     i = i.wrapping_sub(1);
                 } else if self.areas[i].start_addr >= start_addr
                     && self.areas[i].start_addr < end_addr
@@ -264,14 +505,20 @@ impl<T: PageTableExt> MemorySet<T> {
                         attr: area.attr,
                         handler: area.handler.box_clone(),
                         name: area.name,
+                        locked: area.locked,
+                        may_write: area.may_write,
+                        origin: area.origin,
                     };
-                    dead_area.unmap(&mut self.page_table);
+                    dead_area.unmap(&mut self.page_table, self.swap.as_mut());
                     let new_area = MemoryArea {
                         start_addr: end_addr,
                         end_addr: area.end_addr,
                         attr: area.attr,
                         handler: area.handler,
                         name: area.name,
+                        locked: area.locked,
+                        may_write: area.may_write,
+                        origin: area.origin,
                     };
                     self.areas.insert(i, new_area);
                 } else if self.areas[i].end_addr <= end_addr && self.areas[i].end_addr > start_addr
@@ -284,14 +531,20 @@ impl<T: PageTableExt> MemorySet<T> {
                         attr: area.attr,
                         handler: area.handler.box_clone(),
                         name: area.name,
+                        locked: area.locked,
+                        may_write: area.may_write,
+                        origin: area.origin,
                     };
-                    dead_area.unmap(&mut self.page_table);
+                    dead_area.unmap(&mut self.page_table, self.swap.as_mut());
                     let new_area = MemoryArea {
                         start_addr: area.start_addr,
                         end_addr: start_addr,
                         attr: area.attr,
                         handler: area.handler,
                         name: area.name,
+                        locked: area.locked,
+                        may_write: area.may_write,
+                        origin: area.origin,
                     };
                     self.areas.insert(i, new_area);
                 } else {
@@ -303,14 +556,20 @@ impl<T: PageTableExt> MemorySet<T> {
                         attr: area.attr,
                         handler: area.handler.box_clone(),
                         name: area.name,
+                        locked: area.locked,
+                        may_write: area.may_write,
+                        origin: area.origin,
                     };
-                    dead_area.unmap(&mut self.page_table);
+                    dead_area.unmap(&mut self.page_table, self.swap.as_mut());
                     let new_area_left = MemoryArea {
                         start_addr: area.start_addr,
                         end_addr: start_addr,
                         attr: area.attr,
                         handler: area.handler.box_clone(),
                         name: area.name,
+                        locked: area.locked,
+                        may_write: area.may_write,
+                        origin: area.origin,
                     };
                     self.areas.insert(i, new_area_left);
                     let new_area_right = MemoryArea {
@@ -319,6 +578,9 @@ impl<T: PageTableExt> MemorySet<T> {
                         attr: area.attr,
                         handler: area.handler,
                         name: area.name,
+                        locked: area.locked,
+                        may_write: area.may_write,
+                        origin: area.origin,
                     };
                     self.areas.insert(i + 1, new_area_right);
                     i += 1;
@@ -328,6 +590,420 @@ impl<T: PageTableExt> MemorySet<T> {
         }
     }
 
+    /// Split areas at `start_addr` and `end_addr`, then apply `f` to each area in between.
+    ///
+    /// Return `Err` without changing anything if some page in the range is not mapped.
+    fn update_areas(
+        &mut self,
+        start_addr: VirtAddr,
+        end_addr: VirtAddr,
+        mut f: impl FnMut(&mut MemoryArea, &mut T),
+    ) -> VMResult<()> {
+        let start_addr = start_addr & !(PAGE_SIZE - 1);
+        let end_addr = (end_addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        assert!(start_addr <= end_addr, "invalid memory area");
+        if !self.test_covered_area(start_addr, end_addr) {
+            return Err(VMError::InvalidPtr);
+        }
+        self.split_at(start_addr);
+        self.split_at(end_addr);
+        let Self {
+            ref mut page_table,
+            ref mut areas,
+            ..
+        } = self;
+        for area in areas
+            .iter_mut()
+            .filter(|area| area.start_addr >= start_addr && area.end_addr <= end_addr)
+        {
+            f(area, page_table);
+        }
+        self.merge_areas();
+        Ok(())
+    }
+
+    /// Merge neighbouring areas split from the same one that are the same again
+    fn merge_areas(&mut self) {
+        let mut i = 1;
+        while i < self.areas.len() {
+            let (left, right) = (&self.areas[i - 1], &self.areas[i]);
+            if left.end_addr == right.start_addr
+                && left.origin == right.origin
+                && left.attr == right.attr
+                && left.name == right.name
+                && left.locked == right.locked
+                && left.may_write == right.may_write
+            {
+                let right = self.areas.remove(i);
+                self.areas[i - 1].end_addr = right.end_addr;
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Apply `f` to each page in `[start_addr, end_addr)` with the area it belongs to.
+    ///
+    /// Return `Err` without changing anything if some page in the range is not mapped.
+    fn for_each_page(
+        &mut self,
+        start_addr: VirtAddr,
+        end_addr: VirtAddr,
+        mut f: impl FnMut(&MemoryArea, &mut T, VirtAddr),
+    ) -> VMResult<()> {
+        let start_addr = start_addr & !(PAGE_SIZE - 1);
+        let end_addr = (end_addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        assert!(start_addr <= end_addr, "invalid memory area");
+        if !self.test_covered_area(start_addr, end_addr) {
+            return Err(VMError::InvalidPtr);
+        }
+        let Self {
+            ref mut page_table,
+            ref areas,
+            ..
+        } = self;
+        for area in areas
+            .iter()
+            .filter(|area| area.is_overlap_with(start_addr, end_addr))
+        {
+            let begin = area.start_addr.max(start_addr);
+            let end = area.end_addr.min(end_addr);
+            for page in Page::range_of(begin, end) {
+                f(area, page_table, page.start_address());
+            }
+        }
+        Ok(())
+    }
+
+    /// Update the attribute of `[start_addr, end_addr)` with `f`
+    /// and split existed areas when necessary.
+    /// Page table entries of the range are updated as well.
+    ///
+    /// Return `Err` without changing anything if some page in the range is not mapped,
+    /// or would become writable while its area can't be.
+    pub fn protect(
+        &mut self,
+        start_addr: VirtAddr,
+        end_addr: VirtAddr,
+        f: impl Fn(MemoryAttr) -> MemoryAttr,
+    ) -> VMResult<()> {
+        if self
+            .areas
+            .iter()
+            .filter(|area| area.is_overlap_with(start_addr, end_addr))
+            .any(|area| !area.may_write && !f(area.attr).readonly)
+        {
+            return Err(VMError::AccessDenied);
+        }
+        self.update_areas(start_addr, end_addr, |area, page_table| {
+            area.attr = f(area.attr);
+            for page in Page::range_of(area.start_addr, area.end_addr) {
+                if let Some(entry) = page_table.get_entry(page.start_address()) {
+                    area.attr.apply(entry);
+                }
+            }
+        })
+    }
+
+    /// Forbid `protect` to make `[start_addr, end_addr)` writable,
+    /// and split existed areas when necessary.
+    pub fn forbid_write(&mut self, start_addr: VirtAddr, end_addr: VirtAddr) -> VMResult<()> {
+        self.update_areas(start_addr, end_addr, |area, _| {
+            area.may_write = false;
+        })
+    }
+
+    /// Drop the data of pages in `[start_addr, end_addr)`,
+    /// which will be zero-filled or reloaded from file on next access.
+    /// Fail with `AccessDenied` if the range has pages of locked areas, dropping nothing.
+    pub fn discard(&mut self, start_addr: VirtAddr, end_addr: VirtAddr) -> VMResult<()> {
+        let locked = self
+            .areas
+            .iter()
+            .any(|area| area.locked && area.is_overlap_with(start_addr, end_addr));
+        if locked {
+            return Err(VMError::AccessDenied);
+        }
+        let mut swap = self.swap.take();
+        let ret = self.for_each_page(start_addr, end_addr, |area, page_table, addr| {
+            if let Some(swap) = swap.as_mut() {
+                if area.handler.swappable() {
+                    swap.forget(page_table, addr);
+                }
+            }
+            area.handler.discard(page_table, addr);
+        });
+        self.swap = swap;
+        ret
+    }
+
+    /// Load file-backed pages in `[start_addr, end_addr)` in advance.
+    pub fn prefetch(&mut self, start_addr: VirtAddr, end_addr: VirtAddr) -> VMResult<()> {
+        self.for_each_page(start_addr, end_addr, |area, page_table, addr| {
+            if !area.attr.inaccessible {
+                area.handler.prefetch(page_table, addr);
+            }
+        })
+    }
+
+    /// Write dirty pages of shared file mappings in `[start_addr, end_addr)` back to the files.
+    /// Return `Err(IOError)` if writing some page failed, which stays dirty.
+    pub fn sync(&mut self, start_addr: VirtAddr, end_addr: VirtAddr) -> VMResult<()> {
+        let mut ret = Ok(());
+        self.for_each_page(start_addr, end_addr, |area, page_table, addr| {
+            // keep writing the other pages after a failure
+            if let Err(err) = area.handler.sync(page_table, addr) {
+                ret = Err(err);
+            }
+        })?;
+        ret
+    }
+
+    /// Get whether each page in `[start_addr, end_addr)` is resident in memory.
+    pub fn residency(&mut self, start_addr: VirtAddr, end_addr: VirtAddr) -> VMResult<Vec<bool>> {
+        let mut residency = Vec::new();
+        self.for_each_page(start_addr, end_addr, |_, page_table, addr| {
+            let present = match page_table.get_entry(addr) {
+                Some(entry) => entry.present(),
+                None => false,
+            };
+            residency.push(present);
+        })?;
+        Ok(residency)
+    }
+
+    /// Count the pages of each area, in the order of `iter`.
+    pub fn area_stats(&mut self) -> Vec<PageStats> {
+        let Self {
+            ref mut page_table,
+            ref areas,
+            ..
+        } = self;
+        areas
+            .iter()
+            .map(|area| {
+                let mut stats = PageStats::default();
+                for page in Page::range_of(area.start_addr, area.end_addr) {
+                    let addr = page.start_address();
+                    let entry = match page_table.get_entry(addr) {
+                        Some(entry) => entry,
+                        None => continue,
+                    };
+                    if !entry.present() {
+                        if get_swap_entry(entry).is_some() {
+                            stats.swapped += 1;
+                        }
+                        continue;
+                    }
+                    let count = area.handler.map_count(page_table, addr).max(1);
+                    stats.resident += 1;
+                    if count > 1 {
+                        stats.shared += 1;
+                    }
+                    stats.pss += PAGE_SIZE / count;
+                }
+                stats
+            })
+            .collect()
+    }
+
+    /// Count the pages of all areas
+    pub fn stats(&mut self) -> PageStats {
+        let mut stats = PageStats::default();
+        for area in self.area_stats().iter() {
+            stats.add(area);
+        }
+        stats
+    }
+
+    /// Get the number of minor and major page faults handled
+    pub fn faults(&self) -> (usize, usize) {
+        (self.minor_faults, self.major_faults)
+    }
+
+    /// Lock pages in `[start_addr, end_addr)` in memory,
+    /// and split existed areas when necessary.
+    pub fn lock(&mut self, start_addr: VirtAddr, end_addr: VirtAddr) -> VMResult<()> {
+        self.update_areas(start_addr, end_addr, |area, _| {
+            area.locked = true;
+        })?;
+        self.populate(start_addr, end_addr);
+        Ok(())
+    }
+
+    /// Unlock pages in `[start_addr, end_addr)`,
+    /// and split existed areas when necessary.
+    pub fn unlock(&mut self, start_addr: VirtAddr, end_addr: VirtAddr) -> VMResult<()> {
+        self.update_areas(start_addr, end_addr, |area, _| {
+            area.locked = false;
+        })
+    }
+
+    /// Lock all current areas if `current`,
+    /// and all areas pushed later if `future`.
+    pub fn lock_all(&mut self, current: bool, future: bool) {
+        if current {
+            let mut ranges = Vec::new();
+            for area in self.areas.iter_mut() {
+                area.locked = true;
+                ranges.push((area.start_addr, area.end_addr));
+            }
+            for (start_addr, end_addr) in ranges {
+                self.populate(start_addr, end_addr);
+            }
+        }
+        if future {
+            self.lock_future = true;
+        }
+    }
+
+    /// Fault in all pages in `[start_addr, end_addr)` which are not present yet,
+    /// except for inaccessible ones.
+    fn populate(&mut self, start_addr: VirtAddr, end_addr: VirtAddr) {
+        for page in Page::range_of(start_addr, end_addr) {
+            let addr = page.start_address();
+            let present = match self.page_table.get_entry(addr) {
+                Some(entry) => entry.present(),
+                None => true,
+            };
+            if !present {
+                self.handle_page_fault(addr);
+            }
+        }
+    }
+
+    /// Unlock all areas, and stop locking future areas.
+    pub fn unlock_all(&mut self) {
+        for area in self.areas.iter_mut() {
+            area.locked = false;
+        }
+        self.lock_future = false;
+    }
+
+    /// Resize the mapping `[old_addr, old_addr + old_size)` to `new_size`.
+    /// It is moved to `new_addr` if given, or to a free area
+    /// if it can't grow in place and `may_move` is set.
+    /// Page table entries are moved along with the area, so no data is copied.
+    /// Return the start address of the resized mapping.
+    ///
+    /// The old mapping must lie in a single area, and `new_addr` must not overlap with it.
+    pub fn remap(
+        &mut self,
+        old_addr: VirtAddr,
+        old_size: usize,
+        new_size: usize,
+        new_addr: Option<VirtAddr>,
+        may_move: bool,
+    ) -> VMResult<VirtAddr> {
+        let old_size = (old_size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let new_size = (new_size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let old_end = old_addr + old_size;
+        assert!(
+            old_addr & (PAGE_SIZE - 1) == 0 && new_size != 0,
+            "invalid memory area"
+        );
+        match self.areas.iter().find(|area| area.contains(old_addr)) {
+            Some(area) if area.end_addr >= old_end => {}
+            _ => return Err(VMError::InvalidPtr),
+        }
+        if new_size < old_size {
+            self.pop_with_split(old_addr + new_size, old_end);
+        }
+        let size = old_size.min(new_size);
+        if let Some(new_addr) = new_addr {
+            assert!(new_addr & (PAGE_SIZE - 1) == 0, "invalid memory area");
+            self.pop_with_split(new_addr, new_addr + new_size);
+            let idx = self.move_area(old_addr, old_addr + size, new_addr);
+            self.grow_area(idx, new_addr + new_size);
+            return Ok(new_addr);
+        }
+        if new_size <= old_size {
+            return Ok(old_addr);
+        }
+        let idx = self
+            .areas
+            .iter()
+            .position(|area| area.contains(old_addr))
+            .unwrap();
+        if self.areas[idx].end_addr == old_end && self.test_free_area(old_end, old_addr + new_size)
+        {
+            self.grow_area(idx, old_addr + new_size);
+            return Ok(old_addr);
+        }
+        if !may_move {
+            return Err(VMError::NoMemory);
+        }
+        let new_addr = self.find_free_area(old_addr, new_size);
+        let idx = self.move_area(old_addr, old_end, new_addr);
+        self.grow_area(idx, new_addr + new_size);
+        Ok(new_addr)
+    }
+
+    /// Move `[start_addr, end_addr)` inside an area to a free area at `new_start`.
+    /// Return the index of the moved area.
+    fn move_area(
+        &mut self,
+        start_addr: VirtAddr,
+        end_addr: VirtAddr,
+        new_start: VirtAddr,
+    ) -> usize {
+        self.split_at(start_addr);
+        self.split_at(end_addr);
+        let idx = self
+            .areas
+            .iter()
+            .position(|area| area.start_addr == start_addr)
+            .unwrap();
+        let area = self.areas.remove(idx);
+        for page in Page::range_of(start_addr, end_addr) {
+            let addr = page.start_address();
+            let new_addr = addr - start_addr + new_start;
+            paging::split_huge_page(&mut self.page_table, addr);
+            let present = match self.page_table.get_entry(addr) {
+                Some(entry) => entry.present(),
+                None => false,
+            };
+            move_entry(&mut self.page_table, addr, new_addr);
+            // swapped out pages are moved along with their swap entries
+            if let Some(swap) = self.swap.as_mut() {
+                if present && area.handler.swappable() {
+                    swap.manager.remove(addr);
+                    swap.manager.push(new_addr);
+                }
+            }
+        }
+        let new_area = MemoryArea {
+            start_addr: new_start,
+            end_addr: new_start + (end_addr - start_addr),
+            attr: area.attr,
+            handler: area.handler.rebase(start_addr, new_start),
+            name: area.name,
+            locked: area.locked,
+            may_write: area.may_write,
+            origin: new_origin(),
+        };
+        self.insert_area(new_area)
+    }
+
+    /// Enlarge the area at `idx` to `end_addr`, the new range must be free
+    fn grow_area(&mut self, idx: usize, end_addr: VirtAddr) {
+        let Self {
+            ref mut page_table,
+            ref mut areas,
+            ..
+        } = self;
+        let area = &mut areas[idx];
+        let old_end = area.end_addr;
+        for page in Page::range_of(area.end_addr, end_addr) {
+            area.handler
+                .map(page_table, page.start_address(), &area.attr);
+        }
+        area.end_addr = area.end_addr.max(end_addr);
+        if area.locked {
+            self.populate(old_end, end_addr);
+        }
+    }
+
     /// Get iterator of areas
     pub fn iter(&self) -> impl Iterator<Item = &MemoryArea> {
         self.areas.iter()
@@ -352,10 +1028,11 @@ impl<T: PageTableExt> MemorySet<T> {
         let Self {
             ref mut page_table,
             ref mut areas,
+            ref mut swap,
             ..
         } = self;
         for area in areas.iter() {
-            area.unmap(page_table);
+            area.unmap(page_table, swap.as_mut());
         }
         areas.clear();
     }
@@ -376,47 +1053,273 @@ impl<T: PageTableExt> MemorySet<T> {
         &mut self.page_table
     }
 
+    /// Handle page fault on `addr` and access type `access`,
+    /// and count it as a major fault if the page is read from a file or swapped in.
     pub fn handle_page_fault_ext(&mut self, addr: VirtAddr, access: handler::AccessType) -> bool {
-        let area = self.areas.iter().find(|area| area.contains(addr));
-        match area {
-            Some(area) => area
-                .handler
-                .handle_page_fault_ext(&mut self.page_table, addr, access),
+        let page = addr & !(PAGE_SIZE - 1);
+        let major = match self.areas.iter().find(|area| area.contains(addr)) {
+            Some(area) => match self.page_table.get_entry(page) {
+                Some(entry) if !entry.present() => {
+                    get_swap_entry(entry).is_some()
+                        || area.handler.needs_read(&mut self.page_table, page)
+                }
+                _ => false,
+            },
             None => false,
+        };
+        if self.fill_page(addr, access).is_err() {
+            return false;
+        }
+        match major {
+            true => self.major_faults += 1,
+            false => self.minor_faults += 1,
+        }
+        true
+    }
+
+    fn fill_page(&mut self, addr: VirtAddr, access: handler::AccessType) -> VMResult<()> {
+        let Self {
+            ref mut page_table,
+            ref areas,
+            ref mut swap,
+            ..
+        } = self;
+        let area = match areas.iter().find(|area| area.contains(addr)) {
+            Some(area) if !area.attr.inaccessible => area,
+            _ => return Err(VMError::InvalidPtr),
+        };
+        let swap = match swap {
+            Some(swap) => swap,
+            None => return area.handler.handle_page_fault_ext(page_table, addr, access),
+        };
+        let page = addr & !(PAGE_SIZE - 1);
+        loop {
+            let token = match page_table.get_entry(page) {
+                Some(entry) if !entry.present() => get_swap_entry(entry),
+                _ => return area.handler.handle_page_fault_ext(page_table, addr, access),
+            };
+            let ret = match token {
+                Some(token) => match swap.swap_in(area, page_table, page, token) {
+                    Ok(()) => Ok(()),
+                    Err(SwapError::NoMemory) => Err(VMError::NoMemory),
+                    Err(_) => Err(VMError::IOError),
+                },
+                None => area.handler.handle_page_fault_ext(page_table, addr, access),
+            };
+            match ret {
+                Ok(()) => {
+                    if area.handler.swappable() {
+                        swap.manager.push(page);
+                    }
+                    return Ok(());
+                }
+                // only running out of frames is helped by swapping out
+                Err(VMError::NoMemory) => {}
+                Err(err) => return Err(err),
+            }
+            // make room and retry
+            if swap.swap_out(areas, page_table).is_err() {
+                return Err(VMError::NoMemory);
+            }
         }
     }
     pub fn handle_page_fault(&mut self, addr: VirtAddr) -> bool {
-        let area = self.areas.iter().find(|area| area.contains(addr));
-        match area {
-            Some(area) => area.handler.handle_page_fault(&mut self.page_table, addr),
-            None => false,
+        self.handle_page_fault_ext(addr, handler::AccessType::unknown())
+    }
+
+    /// Enable swapping out pages of this set with `swap`
+    pub fn enable_swap(&mut self, mut swap: Swap) {
+        for area in self.areas.iter().filter(|area| area.handler.swappable()) {
+            for page in Page::range_of(area.start_addr, area.end_addr) {
+                if let Some(entry) = self.page_table.get_entry(page.start_address()) {
+                    if entry.present() {
+                        swap.manager.push(page.start_address());
+                    }
+                }
+            }
         }
+        self.swap = Some(swap);
     }
 
-    pub fn clone(&mut self) -> Self {
+    /// Let the swap manager update its state from accessed bits, called periodically
+    pub fn swap_tick(&mut self) {
+        if let Some(swap) = self.swap.as_mut() {
+            swap.manager.tick(&mut self.page_table);
+        }
+    }
+
+    /// Swap in all pages of this set.
+    /// Pages are not swapped out again until `swap_in_all` returns.
+    pub fn swap_in_all(&mut self) -> VMResult<()> {
+        let mut swapped = Vec::new();
+        for area in self.areas.iter() {
+            for page in Page::range_of(area.start_addr, area.end_addr) {
+                if let Some(entry) = self.page_table.get_entry(page.start_address()) {
+                    if get_swap_entry(entry).is_some() {
+                        swapped.push(page.start_address());
+                    }
+                }
+            }
+        }
+        let Self {
+            ref mut page_table,
+            ref areas,
+            ref mut swap,
+            ..
+        } = self;
+        let swap = match swap {
+            Some(swap) => swap,
+            None => return Ok(()),
+        };
+        for addr in swapped {
+            let area = areas.iter().find(|area| area.contains(addr)).unwrap();
+            let token = get_swap_entry(page_table.get_entry(addr).unwrap()).unwrap();
+            swap.swap_in(area, page_table, addr, token)
+                .map_err(|_| VMError::NoMemory)?;
+            swap.manager.push(addr);
+        }
+        Ok(())
+    }
+
+    /// Swap in all pages and disable swapping for this set
+    pub fn disable_swap(&mut self) -> VMResult<()> {
+        self.swap_in_all()?;
+        self.swap = None;
+        Ok(())
+    }
+
+    /// Copy the memory set for a new process.
+    /// Fails if the swapped out pages can't be copied on the swap device,
+    /// or with `NoMemory` if frames run out copying the mapped ones.
+    pub fn clone(&mut self) -> VMResult<Self> {
         let mut new_page_table = T::new();
         let Self {
             ref mut page_table,
             ref areas,
+            ref swap,
             ..
         } = self;
-        for area in areas.iter() {
-            for page in Page::range_of(area.start_addr, area.end_addr) {
-                area.handler.clone_map(
-                    &mut new_page_table,
-                    page_table,
-                    page.start_address(),
-                    &area.attr,
-                );
+        // swapped out pages are copied first, so nothing is mapped for the child if it fails
+        let mut copies = Vec::new();
+        if let Some(swap) = swap {
+            let mut swapper = swap.swapper.lock();
+            for area in areas.iter() {
+                for page in Page::range_of(area.start_addr, area.end_addr) {
+                    let addr = page.start_address();
+                    let entry = match page_table.get_entry(addr) {
+                        Some(entry) if get_swap_entry(entry).is_some() => entry,
+                        _ => continue,
+                    };
+                    match copy_swap_entry(&mut *swapper, entry) {
+                        Ok(token) => copies.push((addr, token)),
+                        Err(err) => {
+                            for (_, token) in copies {
+                                swapper.swap_free(token);
+                            }
+                            return Err(err);
+                        }
+                    }
+                }
             }
         }
-        MemorySet {
-            areas: areas.clone(),
+        for (i, area) in areas.iter().enumerate() {
+            if let Err(err) = area.try_clone_map(&mut new_page_table, page_table) {
+                for area in areas[..i].iter() {
+                    area.unmap(&mut new_page_table, None);
+                }
+                if let Some(swap) = swap {
+                    let mut swapper = swap.swapper.lock();
+                    for (_, token) in copies {
+                        swapper.swap_free(token);
+                    }
+                }
+                return Err(err);
+            }
+        }
+        for (addr, token) in copies {
+            let entry = new_page_table.get_entry(addr).expect("failed to get entry");
+            set_swap_entry(entry, token);
+        }
+        // memory locks are not inherited by child
+        let mut areas = areas.clone();
+        for area in areas.iter_mut() {
+            area.locked = false;
+        }
+        Ok(MemorySet {
+            areas,
             page_table: new_page_table,
+            lock_future: false,
+            swap: swap.clone(),
+            minor_faults: 0,
+            major_faults: 0,
+        })
+    }
+}
+
+/// Copy the swapped out page of `entry` to new space on the device, returning its token.
+/// The page of `entry` stays swapped out, maybe moved to another place.
+fn copy_swap_entry(swapper: &mut dyn Swapper, entry: &mut dyn Entry) -> VMResult<usize> {
+    let token = get_swap_entry(entry).unwrap();
+    let mut data = [0u8; PAGE_SIZE];
+    // take the space of the copy before reading, which frees the space of the page
+    let copy = swapper.swap_out(&data).map_err(|_| VMError::NoMemory)?;
+    if swapper.swap_in(token, &mut data).is_err() {
+        swapper.swap_free(copy);
+        return Err(VMError::IOError);
+    }
+    match (swapper.swap_out(&data), swapper.swap_update(copy, &data)) {
+        (Ok(token), Ok(())) => {
+            set_swap_entry(entry, token);
+            Ok(copy)
+        }
+        (Ok(token), Err(())) => {
+            set_swap_entry(entry, token);
+            swapper.swap_free(copy);
+            Err(VMError::IOError)
+        }
+        // the page keeps the space of the copy
+        (Err(()), Ok(())) => {
+            set_swap_entry(entry, copy);
+            Err(VMError::IOError)
+        }
+        (Err(()), Err(())) => {
+            error!("lost the swapped out page {}", token);
+            swapper.swap_free(copy);
+            clear_swap_entry(entry);
+            Err(VMError::IOError)
         }
     }
 }
 
+/// Move the page table entry of `old_addr` to `new_addr`, keeping its target and flags
+fn move_entry(pt: &mut dyn PageTable, old_addr: VirtAddr, new_addr: VirtAddr) {
+    let entry = pt.get_entry(old_addr).expect("failed to get entry");
+    let target = entry.target();
+    let present = entry.present();
+    let writable = entry.writable();
+    let user = entry.user();
+    let execute = entry.execute();
+    let mmio = entry.mmio();
+    let swapped = entry.swapped();
+    let writable_shared = entry.writable_shared();
+    let readonly_shared = entry.readonly_shared();
+    // PageTable::unmap requires page to be present
+    entry.set_present(true);
+    pt.unmap(old_addr);
+
+    let entry = pt.map(new_addr, target);
+    entry.set_present(present);
+    entry.set_writable(writable);
+    entry.set_user(user);
+    entry.set_execute(execute);
+    entry.set_mmio(mmio);
+    entry.set_swapped(swapped);
+    if writable_shared || readonly_shared {
+        entry.set_shared(writable_shared);
+    }
+    entry.update();
+}
+
 impl<T: PageTableExt> Drop for MemorySet<T> {
     fn drop(&mut self) {
         self.clear();
@@ -428,3 +1331,752 @@ impl<T: PageTableExt> Debug for MemorySet<T> {
         f.debug_list().entries(self.areas.iter()).finish()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::handler::{
+        ByFrame, Delay, File, FilePages, FrameAllocator, Huge, Linear, Read, SharedFile, Write,
+    };
+    use super::*;
+    use crate::swap::{mock_swapper::MockSwapper, FifoSwapManager};
+    use alloc::sync::Arc;
+    use spin::Mutex;
+
+    #[derive(Debug, Clone)]
+    struct MockFrameAlloc(Arc<Mutex<Vec<PhysAddr>>>);
+
+    impl MockFrameAlloc {
+        fn new() -> Self {
+            Self::with_frames(15)
+        }
+        fn with_frames(count: usize) -> Self {
+            let frames = (1..=count).map(|i| i * PAGE_SIZE).collect();
+            MockFrameAlloc(Arc::new(Mutex::new(frames)))
+        }
+        fn free_count(&self) -> usize {
+            self.0.lock().len()
+        }
+    }
+
+    impl FrameAllocator for MockFrameAlloc {
+        fn alloc(&self) -> Option<PhysAddr> {
+            self.0.lock().pop()
+        }
+        fn alloc_contiguous(&self, _size: usize, _align_log2: usize) -> Option<PhysAddr> {
+            None
+        }
+        fn dealloc(&self, target: PhysAddr) {
+            self.0.lock().push(target);
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct MockFile(Arc<Mutex<Vec<u8>>>);
+
+    impl Read for MockFile {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> VMResult<usize> {
+            let data = self.0.lock();
+            // a file truncated under the mapping
+            if offset > data.len() {
+                return Err(VMError::IOError);
+            }
+            let len = buf.len().min(data.len() - offset);
+            buf[..len].copy_from_slice(&data[offset..offset + len]);
+            Ok(len)
+        }
+    }
+
+    impl Write for MockFile {
+        fn write_at(&self, offset: usize, buf: &[u8]) -> VMResult<usize> {
+            let mut data = self.0.lock();
+            // a file truncated under the mapping
+            if offset + buf.len() > data.len() {
+                return Err(VMError::IOError);
+            }
+            data[offset..offset + buf.len()].copy_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    /// A file whose first page is cached in frame 0xf000
+    #[derive(Debug, Clone)]
+    struct MockCachedFile {
+        file: MockFile,
+        pinned: Arc<Mutex<usize>>,
+    }
+
+    impl Read for MockCachedFile {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> VMResult<usize> {
+            self.file.read_at(offset, buf)
+        }
+        fn get_page(&self, offset: usize) -> VMResult<Option<PhysAddr>> {
+            if offset != 0 {
+                return Ok(None);
+            }
+            *self.pinned.lock() += 1;
+            Ok(Some(0xf000))
+        }
+        fn put_page(&self, offset: usize) {
+            assert_eq!(offset, 0);
+            *self.pinned.lock() -= 1;
+        }
+    }
+
+    fn new_memory_set() -> MemorySet<MockPageTable> {
+        let mut ms = MemorySet::new();
+        ms.push(
+            0x1000,
+            0x5000,
+            MemoryAttr::default().user(),
+            Linear::new(0),
+            "test",
+        );
+        ms
+    }
+
+    #[test]
+    fn protect_split() {
+        let mut ms = new_memory_set();
+        ms.protect(0x2000, 0x3000, |attr| attr.readonly()).unwrap();
+        let ranges: Vec<_> = ms.iter().map(|a| (a.start_addr, a.end_addr)).collect();
+        assert_eq!(
+            ranges,
+            [(0x1000, 0x2000), (0x2000, 0x3000), (0x3000, 0x5000)]
+        );
+        assert!(ms.areas[1].attr.readonly);
+        assert!(!ms.areas[2].attr.readonly);
+
+        let pt = ms.get_page_table_mut();
+        assert!(pt.get_entry(0x1000).unwrap().writable());
+        assert!(!pt.get_entry(0x2000).unwrap().writable());
+        assert!(pt.get_entry(0x3000).unwrap().writable());
+
+        // merged again once the attributes are the same
+        ms.protect(0x2000, 0x3000, |attr| attr.writable()).unwrap();
+        let ranges: Vec<_> = ms.iter().map(|a| (a.start_addr, a.end_addr)).collect();
+        assert_eq!(ranges, [(0x1000, 0x5000)]);
+    }
+
+    #[test]
+    fn protect_none() {
+        let mut ms = new_memory_set();
+        ms.protect(0x4000, 0x5000, |attr| attr.inaccessible())
+            .unwrap();
+        assert!(!ms.get_page_table_mut().get_entry(0x4000).unwrap().user());
+        assert!(!ms.handle_page_fault(0x4000));
+        assert!(unsafe { ms.check_read_array(0x4000 as *const u8, 1) }.is_err());
+        assert!(unsafe { ms.check_read_array(0x3000 as *const u8, 1) }.is_ok());
+    }
+
+    #[test]
+    fn protect_unmapped() {
+        let mut ms = new_memory_set();
+        assert!(ms.protect(0x4000, 0x6000, |attr| attr.readonly()).is_err());
+        assert_eq!(ms.iter().count(), 1);
+        assert!(ms
+            .get_page_table_mut()
+            .get_entry(0x4000)
+            .unwrap()
+            .writable());
+    }
+
+    #[test]
+    fn protect_forbidden_write() {
+        let mut ms = new_memory_set();
+        ms.protect(0x1000, 0x5000, |attr| attr.readonly()).unwrap();
+        ms.forbid_write(0x2000, 0x3000).unwrap();
+        assert_eq!(
+            ms.protect(0x1000, 0x5000, |attr| attr.writable()),
+            Err(VMError::AccessDenied)
+        );
+        assert!(ms.areas.iter().all(|area| area.attr.readonly));
+        ms.protect(0x2000, 0x3000, |attr| attr.execute()).unwrap();
+        ms.protect(0x3000, 0x5000, |attr| attr.writable()).unwrap();
+        assert!(ms
+            .get_page_table_mut()
+            .get_entry(0x3000)
+            .unwrap()
+            .writable());
+    }
+
+    #[test]
+    fn lock_and_discard() {
+        let alloc = MockFrameAlloc::new();
+        let mut ms = MemorySet::<MockPageTable>::new();
+        let attr = MemoryAttr::default().user();
+        ms.push(0x1000, 0x4000, attr, Delay::new(alloc.clone()), "test");
+        assert_eq!(ms.residency(0x1000, 0x4000).unwrap(), [false; 3]);
+        assert!(ms.residency(0x1000, 0x5000).is_err());
+
+        ms.lock(0x1000, 0x2000).unwrap();
+        assert!(ms.handle_page_fault(0x2000));
+        assert_eq!(ms.residency(0x1000, 0x4000).unwrap(), [true, true, false]);
+        assert_eq!(alloc.free_count(), 13);
+
+        // nothing is dropped if locked pages are in the range
+        assert_eq!(ms.discard(0x1000, 0x4000), Err(VMError::AccessDenied));
+        assert_eq!(ms.residency(0x1000, 0x4000).unwrap(), [true, true, false]);
+        ms.discard(0x2000, 0x4000).unwrap();
+        assert_eq!(ms.residency(0x1000, 0x4000).unwrap(), [true, false, false]);
+        assert_eq!(alloc.free_count(), 14);
+
+        ms.unlock_all();
+        ms.discard(0x1000, 0x2000).unwrap();
+        assert_eq!(alloc.free_count(), 15);
+    }
+
+    #[test]
+    fn lock_future() {
+        let alloc = MockFrameAlloc::new();
+        let mut ms = MemorySet::<MockPageTable>::new();
+        let attr = MemoryAttr::default().user();
+        ms.lock_all(false, true);
+        ms.push(0x1000, 0x3000, attr, Delay::new(alloc.clone()), "test");
+        assert_eq!(ms.residency(0x1000, 0x3000).unwrap(), [true; 2]);
+        assert_eq!(alloc.free_count(), 13);
+
+        ms.unlock_all();
+        ms.push(0x3000, 0x4000, attr, Delay::new(alloc.clone()), "test");
+        assert_eq!(ms.residency(0x3000, 0x4000).unwrap(), [false]);
+    }
+
+    #[test]
+    fn remap_in_place() {
+        let mut ms = new_memory_set();
+        assert_eq!(
+            ms.remap(0x1000, 0x4000, 0x6000, None, false).unwrap(),
+            0x1000
+        );
+        assert_eq!(ms.areas[0].end_addr, 0x7000);
+        assert_eq!(
+            ms.get_page_table_mut().get_entry(0x6000).unwrap().target(),
+            0x6000
+        );
+
+        assert_eq!(
+            ms.remap(0x1000, 0x6000, 0x2000, None, false).unwrap(),
+            0x1000
+        );
+        assert_eq!(ms.areas[0].end_addr, 0x3000);
+
+        ms.push(
+            0x4000,
+            0x5000,
+            MemoryAttr::default(),
+            Linear::new(0),
+            "test",
+        );
+        assert!(ms.remap(0x1000, 0x2000, 0x4000, None, false).is_err());
+        assert!(ms.remap(0x2000, 0x3000, 0x3000, None, true).is_err());
+    }
+
+    #[test]
+    fn remap_move() {
+        let alloc = MockFrameAlloc::new();
+        let mut ms = MemorySet::<MockPageTable>::new();
+        let attr = MemoryAttr::default().user();
+        ms.push(0x1000, 0x3000, attr, Delay::new(alloc.clone()), "test");
+        ms.push(0x3000, 0x4000, attr, Delay::new(alloc.clone()), "test");
+        assert!(ms.handle_page_fault(0x1000));
+        ms.get_page_table_mut().get_page_slice_mut(0x1000)[0] = 42;
+
+        let new_addr = ms.remap(0x1000, 0x2000, 0x3000, None, true).unwrap();
+        assert_eq!(new_addr, 0x4000);
+        assert_eq!(ms.residency(0x4000, 0x7000).unwrap(), [true, false, false]);
+        assert!(ms.residency(0x1000, 0x3000).is_err());
+        assert_eq!(ms.get_page_table_mut().get_page_slice_mut(0x4000)[0], 42);
+        assert_eq!(alloc.free_count(), 14);
+
+        ms.remap(0x4000, 0x1000, 0x1000, Some(0x1000), true)
+            .unwrap();
+        assert_eq!(ms.get_page_table_mut().get_page_slice_mut(0x1000)[0], 42);
+        let ranges: Vec<_> = ms.iter().map(|a| (a.start_addr, a.end_addr)).collect();
+        assert_eq!(
+            ranges,
+            [(0x1000, 0x2000), (0x3000, 0x4000), (0x5000, 0x7000)]
+        );
+    }
+
+    #[test]
+    fn remap_linear() {
+        let mut ms = new_memory_set();
+        ms.remap(0x2000, 0x1000, 0x1000, Some(0x8000), true)
+            .unwrap();
+        assert_eq!(
+            ms.get_page_table_mut().get_entry(0x8000).unwrap().target(),
+            0x2000
+        );
+        // the rebased handler maps new pages continuously
+        ms.remap(0x8000, 0x1000, 0x2000, None, false).unwrap();
+        assert_eq!(
+            ms.get_page_table_mut().get_entry(0x9000).unwrap().target(),
+            0x3000
+        );
+    }
+
+    #[test]
+    fn shared_file() {
+        let alloc = MockFrameAlloc::new();
+        let file = MockFile(Arc::new(Mutex::new(vec![1; PAGE_SIZE + 10])));
+        let pages = Arc::new(Mutex::new(FilePages::new(file.clone(), alloc.clone())));
+        let mut ms = MemorySet::<MockPageTable>::new();
+        let attr = MemoryAttr::default().user();
+        ms.push(
+            0x1000,
+            0x3000,
+            attr,
+            SharedFile::new(pages.clone(), 0x1000, 0),
+            "a",
+        );
+        ms.push(
+            0x4000,
+            0x5000,
+            attr,
+            SharedFile::new(pages.clone(), 0x4000, PAGE_SIZE),
+            "b",
+        );
+
+        // both mappings see the same frame
+        assert!(ms.handle_page_fault(0x2000));
+        assert!(ms.handle_page_fault(0x4000));
+        let pt = ms.get_page_table_mut();
+        assert_eq!(pt.read(0x4009), 1);
+        assert_eq!(pt.read(0x400a), 0);
+        pt.write(0x2000, 2);
+        pt.write(0x200a, 3);
+        assert_eq!(pt.read(0x4000), 2);
+        assert_eq!(alloc.free_count(), 14);
+
+        // only the dirty mapping is written back, and the file is not extended
+        ms.sync(0x4000, 0x5000).unwrap();
+        assert_eq!(file.0.lock()[PAGE_SIZE], 1);
+        ms.sync(0x1000, 0x3000).unwrap();
+        assert_eq!(file.0.lock()[PAGE_SIZE], 2);
+        assert_eq!(file.0.lock().len(), PAGE_SIZE + 10);
+
+        ms.get_page_table_mut().write(0x4001, 4);
+        ms.pop(0x4000, 0x5000);
+        assert_eq!(file.0.lock()[PAGE_SIZE + 1], 4);
+
+        // failed write back is reported, and retried on the next sync
+        file.0.lock().truncate(10);
+        ms.get_page_table_mut().write(0x2001, 5);
+        assert_eq!(ms.sync(0x1000, 0x3000), Err(VMError::IOError));
+        file.0.lock().resize(PAGE_SIZE + 10, 0);
+        ms.sync(0x1000, 0x3000).unwrap();
+        assert_eq!(file.0.lock()[PAGE_SIZE + 1], 5);
+        drop(ms);
+        drop(pages);
+        assert_eq!(alloc.free_count(), 15);
+    }
+
+    #[test]
+    fn unreadable_file() {
+        let alloc = MockFrameAlloc::new();
+        let file = MockFile(Arc::new(Mutex::new(vec![1; 10])));
+        let pages = Arc::new(Mutex::new(FilePages::new(file.clone(), alloc.clone())));
+        let mut ms = MemorySet::<MockPageTable>::new();
+        let attr = MemoryAttr::default().user();
+        ms.push(
+            0x1000,
+            0x3000,
+            attr,
+            SharedFile::new(pages.clone(), 0x1000, 0),
+            "shared",
+        );
+        let handler = File::new(file.clone(), 0x4000, 0, 2 * PAGE_SIZE, alloc.clone());
+        ms.push(0x4000, 0x6000, attr, handler, "private");
+
+        // pages the file can't give are neither mapped nor cached
+        assert!(ms.handle_page_fault(0x1000));
+        assert!(!ms.handle_page_fault(0x2000));
+        assert!(!ms.handle_page_fault(0x5000));
+        assert_eq!(pages.lock().iter().count(), 1);
+        assert_eq!(ms.residency(0x1000, 0x3000).unwrap(), [true, false]);
+        assert_eq!(ms.residency(0x4000, 0x6000).unwrap(), [false, false]);
+        assert_eq!(alloc.free_count(), 14);
+
+        // and read on the next fault
+        file.0.lock().resize(2 * PAGE_SIZE, 2);
+        assert!(ms.handle_page_fault(0x2000));
+        assert!(ms.handle_page_fault(0x5000));
+        assert_eq!(ms.get_page_table_mut().read(0x2000), 2);
+        assert_eq!(ms.get_page_table_mut().read(0x5000), 2);
+    }
+
+    #[test]
+    fn page_stats() {
+        let alloc = MockFrameAlloc::new();
+        let file = MockFile(Arc::new(Mutex::new(vec![1; 2 * PAGE_SIZE])));
+        let pages = Arc::new(Mutex::new(FilePages::new(file.clone(), alloc.clone())));
+        let mut ms = MemorySet::<MockPageTable>::new();
+        let attr = MemoryAttr::default().user();
+        ms.push(0x1000, 0x3000, attr, Delay::new(alloc.clone()), "anon");
+        ms.push(
+            0x4000,
+            0x6000,
+            attr,
+            SharedFile::new(pages.clone(), 0x4000, 0),
+            "a",
+        );
+        ms.push(
+            0x8000,
+            0x9000,
+            attr,
+            SharedFile::new(pages.clone(), 0x8000, 0),
+            "b",
+        );
+        assert_eq!(ms.stats(), PageStats::default());
+
+        // only the first fault on a page of the file reads it
+        assert!(ms.handle_page_fault(0x1000));
+        assert!(ms.handle_page_fault(0x4000));
+        assert!(ms.handle_page_fault(0x8000));
+        assert_eq!(ms.faults(), (2, 1));
+
+        let stats = ms.area_stats();
+        assert_eq!(
+            stats[0],
+            PageStats {
+                resident: 1,
+                shared: 0,
+                swapped: 0,
+                pss: PAGE_SIZE,
+            }
+        );
+        assert_eq!(stats[1], stats[2]);
+        assert_eq!(stats[1].shared, 1);
+        assert_eq!(stats[1].pss, PAGE_SIZE / 2);
+        let total = ms.stats();
+        assert_eq!(total.resident, 3);
+        assert_eq!(total.pss, 2 * PAGE_SIZE);
+    }
+
+    #[test]
+    fn file_cache() {
+        let alloc = MockFrameAlloc::with_frames(4);
+        let file = MockCachedFile {
+            file: MockFile(Arc::new(Mutex::new(vec![1; PAGE_SIZE * 2]))),
+            pinned: Arc::new(Mutex::new(0)),
+        };
+        let mut ms = MemorySet::<MockPageTable>::new();
+        let attr = MemoryAttr::default().user();
+        // the cache frame, seen by the kernel
+        ms.push(0xf000, 0x10000, attr, Linear::new(0), "cache");
+        ms.get_page_table_mut().write(0xf000, 5);
+        ms.push(
+            0x1000,
+            0x3000,
+            attr,
+            File::new(file.clone(), 0x1000, 0, PAGE_SIZE + 10, alloc.clone()),
+            "file",
+        );
+
+        // a page within the file maps the cached frame
+        assert!(ms.handle_page_fault(0x1000));
+        let pt = ms.get_page_table_mut();
+        assert_eq!(pt.read(0x1000), 5);
+        assert_eq!(pt.get_entry(0x1000).unwrap().target(), 0xf000);
+        assert_eq!(*file.pinned.lock(), 1);
+        assert_eq!(alloc.free_count(), 4);
+        // a partial page is copied
+        assert!(ms.handle_page_fault(0x2000));
+        let pt = ms.get_page_table_mut();
+        assert_eq!(pt.read(0x2009), 1);
+        assert_eq!(pt.read(0x200a), 0);
+        assert_eq!(alloc.free_count(), 3);
+
+        // the cached page is shared on clone, and the private one copy-on-write
+        let mut ms2 = ms.clone().unwrap();
+        assert_eq!(*file.pinned.lock(), 2);
+        assert_eq!(alloc.free_count(), 3);
+        let target = ms.get_page_table_mut().get_entry(0x2000).unwrap().target();
+        let pt2 = ms2.get_page_table_mut();
+        assert_eq!(pt2.get_entry(0x2000).unwrap().target(), target);
+        assert!(ms2.handle_page_fault_ext(0x2000, handler::AccessType::write(true)));
+        let pt2 = ms2.get_page_table_mut();
+        assert_ne!(pt2.get_entry(0x2000).unwrap().target(), target);
+        assert_eq!(alloc.free_count(), 2);
+        ms2.pop(0x1000, 0x3000);
+        assert_eq!(*file.pinned.lock(), 1);
+        assert_eq!(alloc.free_count(), 3);
+
+        // write copies the page and releases the cache
+        assert!(ms.handle_page_fault_ext(0x1000, handler::AccessType::write(true)));
+        let pt = ms.get_page_table_mut();
+        pt.write(0x1001, 6);
+        assert_eq!(pt.read(0x1000), 5);
+        assert_ne!(pt.get_entry(0x1000).unwrap().target(), 0xf000);
+        assert_eq!(pt.read(0xf001), 0);
+        assert_eq!(*file.pinned.lock(), 0);
+        assert_eq!(alloc.free_count(), 2);
+
+        ms.pop(0x1000, 0x3000);
+        assert_eq!(alloc.free_count(), 4);
+    }
+
+    #[test]
+    fn replace_huge() {
+        let alloc = MockFrameAlloc::with_frames(4);
+        let mut ms = MemorySet::<MockPageTable>::new();
+        let attr = MemoryAttr::default().user();
+        ms.push(0x1000, 0x4000, attr, Delay::new(alloc.clone()), "anon");
+        assert!(ms.handle_page_fault(0x1000));
+        assert!(ms
+            .replace(0x1000, 0x2000, Huge::new(alloc.clone()), "huge")
+            .is_err());
+
+        // huge pages are not supported by the mock, so pages are mapped eagerly instead
+        ms.replace(0x2000, 0x4000, Huge::new(alloc.clone()), "huge")
+            .unwrap();
+        assert_eq!(alloc.free_count(), 1);
+        assert_eq!(ms.get_page_table_mut().read(0x3000), 0);
+        assert_eq!(ms.iter().count(), 2);
+
+        ms.pop_with_split(0x1000, 0x3000);
+        assert_eq!(alloc.free_count(), 3);
+        ms.clear();
+        assert_eq!(alloc.free_count(), 4);
+    }
+
+    #[test]
+    fn try_push_no_memory() {
+        let alloc = MockFrameAlloc::with_frames(2);
+        let mut ms = MemorySet::<MockPageTable>::new();
+        let attr = MemoryAttr::default().user();
+        assert_eq!(
+            ms.try_push(0x1000, 0x4000, attr, Huge::new(alloc.clone()), "huge"),
+            Err(VMError::NoMemory)
+        );
+        assert_eq!(alloc.free_count(), 2);
+        assert_eq!(ms.iter().count(), 0);
+        assert!(!ms.get_page_table_mut().get_entry(0x1000).unwrap().present());
+
+        ms.try_push(0x1000, 0x3000, attr, Huge::new(alloc.clone()), "huge")
+            .unwrap();
+        assert_eq!(alloc.free_count(), 0);
+    }
+
+    #[test]
+    fn clone_no_memory() {
+        let alloc = MockFrameAlloc::with_frames(4);
+        let mut ms = MemorySet::<MockPageTable>::new();
+        let attr = MemoryAttr::default().user();
+        ms.push(0x1000, 0x2000, attr, Huge::new(alloc.clone()), "huge");
+        ms.push(0x2000, 0x4000, attr, Huge::new(alloc.clone()), "huge");
+        assert_eq!(alloc.free_count(), 1);
+
+        // frames copied before running out are freed
+        assert_eq!(ms.clone().unwrap_err(), VMError::NoMemory);
+        assert_eq!(alloc.free_count(), 1);
+
+        ms.pop(0x2000, 0x4000);
+        let child = ms.clone().unwrap();
+        assert_eq!(alloc.free_count(), 2);
+        drop(child);
+        assert_eq!(alloc.free_count(), 3);
+    }
+
+    #[test]
+    fn swap() {
+        let alloc = MockFrameAlloc::with_frames(2);
+        let swapper = Arc::new(Mutex::new(MockSwapper::default()));
+        let mut ms = MemorySet::<MockPageTable>::new();
+        let attr = MemoryAttr::default().user();
+        ms.push(0x1000, 0x5000, attr, Delay::new(alloc.clone()), "test");
+        ms.enable_swap(Swap::new(FifoSwapManager::default(), swapper.clone()));
+
+        // the earliest pages are swapped out to make room
+        for i in 1..5 {
+            assert!(ms.handle_page_fault(i * PAGE_SIZE));
+            ms.get_page_table_mut().get_page_slice_mut(i * PAGE_SIZE)[0] = i as u8;
+        }
+        assert_eq!(
+            ms.residency(0x1000, 0x5000).unwrap(),
+            [false, false, true, true]
+        );
+        assert_eq!(swapper.lock().count(), 2);
+        assert_eq!(alloc.free_count(), 0);
+
+        assert!(ms.handle_page_fault(0x1000));
+        assert_eq!(ms.get_page_table_mut().get_page_slice_mut(0x1000)[0], 1);
+        assert_eq!(
+            ms.residency(0x1000, 0x5000).unwrap(),
+            [true, false, false, true]
+        );
+
+        // locked pages are never swapped out
+        ms.lock(0x4000, 0x5000).unwrap();
+        assert!(ms.handle_page_fault(0x2000));
+        assert!(ms.handle_page_fault(0x3000));
+        assert_eq!(
+            ms.residency(0x1000, 0x5000).unwrap(),
+            [false, false, true, true]
+        );
+        assert_eq!(ms.get_page_table_mut().get_page_slice_mut(0x3000)[0], 3);
+
+        ms.swap_in_all().unwrap_err();
+        assert_eq!(ms.discard(0x3000, 0x5000), Err(VMError::AccessDenied));
+        assert_eq!(
+            ms.residency(0x1000, 0x5000).unwrap(),
+            [false, false, true, true]
+        );
+        ms.unlock_all();
+        ms.discard(0x3000, 0x5000).unwrap();
+        ms.disable_swap().unwrap();
+        assert_eq!(
+            ms.residency(0x1000, 0x5000).unwrap(),
+            [true, true, false, false]
+        );
+        assert_eq!(ms.get_page_table_mut().get_page_slice_mut(0x2000)[0], 2);
+        assert_eq!(swapper.lock().count(), 0);
+    }
+
+    #[test]
+    fn swap_only_out_of_frames() {
+        let alloc = MockFrameAlloc::with_frames(2);
+        let swapper = Arc::new(Mutex::new(MockSwapper::default()));
+        let mut ms = MemorySet::<MockPageTable>::new();
+        let attr = MemoryAttr::default().user().readonly();
+        ms.push(0x1000, 0x3000, attr, Delay::new(alloc.clone()), "test");
+        ms.enable_swap(Swap::new(FifoSwapManager::default(), swapper.clone()));
+        assert!(ms.handle_page_fault(0x1000));
+        assert!(ms.handle_page_fault(0x2000));
+
+        // a denied access fails without swapping out the working set
+        let write = handler::AccessType::write(true);
+        assert!(!ms.handle_page_fault_ext(0x1000, write));
+        assert_eq!(swapper.lock().count(), 0);
+        assert_eq!(ms.residency(0x1000, 0x3000).unwrap(), [true, true]);
+        assert!(!ms.handle_page_fault(0x3000));
+        assert_eq!(swapper.lock().count(), 0);
+    }
+
+    #[test]
+    fn swap_unmap() {
+        let alloc = MockFrameAlloc::with_frames(2);
+        let swapper = Arc::new(Mutex::new(MockSwapper::default()));
+        let mut ms = MemorySet::<MockPageTable>::new();
+        let attr = MemoryAttr::default().user();
+        ms.push(0x1000, 0x4000, attr, Delay::new(alloc.clone()), "test");
+        ms.enable_swap(Swap::new(FifoSwapManager::default(), swapper.clone()));
+        for i in 1..4 {
+            assert!(ms.handle_page_fault(i * PAGE_SIZE));
+        }
+        ms.discard(0x3000, 0x4000).unwrap();
+        assert_eq!(swapper.lock().count(), 1);
+
+        // the child gets its own copy of the swapped out page
+        let child = ms.clone().unwrap();
+        assert_eq!(swapper.lock().count(), 2);
+        drop(child);
+        assert_eq!(swapper.lock().count(), 1);
+
+        ms.pop(0x1000, 0x4000);
+        assert_eq!(swapper.lock().count(), 0);
+        assert_eq!(alloc.free_count(), 2);
+    }
+
+    /// A swap device with room for `limit` pages
+    #[derive(Default)]
+    struct SmallSwapper {
+        inner: MockSwapper,
+        limit: usize,
+    }
+
+    impl Swapper for SmallSwapper {
+        fn swap_out(&mut self, data: &[u8]) -> Result<usize, ()> {
+            if self.inner.count() == self.limit {
+                return Err(());
+            }
+            self.inner.swap_out(data)
+        }
+        fn swap_update(&mut self, token: usize, data: &[u8]) -> Result<(), ()> {
+            self.inner.swap_update(token, data)
+        }
+        fn swap_in(&mut self, token: usize, data: &mut [u8]) -> Result<(), ()> {
+            self.inner.swap_in(token, data)
+        }
+        fn swap_free(&mut self, token: usize) {
+            self.inner.swap_free(token)
+        }
+    }
+
+    #[test]
+    fn clone_swap_full() {
+        let alloc = MockFrameAlloc::with_frames(1);
+        let swapper = Arc::new(Mutex::new(SmallSwapper {
+            limit: 2,
+            ..SmallSwapper::default()
+        }));
+        let mut ms = MemorySet::<MockPageTable>::new();
+        let attr = MemoryAttr::default().user();
+        ms.push(0x1000, 0x4000, attr, Delay::new(alloc.clone()), "test");
+        ms.enable_swap(Swap::new(FifoSwapManager::default(), swapper.clone()));
+        for i in 1..4 {
+            assert!(ms.handle_page_fault(i * PAGE_SIZE));
+            ms.get_page_table_mut().get_page_slice_mut(i * PAGE_SIZE)[0] = i as u8;
+        }
+        assert_eq!(swapper.lock().inner.count(), 2);
+
+        // no room for copies, the parent keeps its pages
+        assert_eq!(ms.clone().unwrap_err(), VMError::NoMemory);
+        assert_eq!(swapper.lock().inner.count(), 2);
+        ms.discard(0x3000, 0x4000).unwrap();
+        for i in 1..3 {
+            assert!(ms.handle_page_fault(i * PAGE_SIZE));
+            assert_eq!(
+                ms.get_page_table_mut().get_page_slice_mut(i * PAGE_SIZE)[0],
+                i as u8
+            );
+        }
+    }
+
+    #[test]
+    fn clone_cow() {
+        let alloc = MockFrameAlloc::new();
+        let mut ms = MemorySet::<MockPageTable>::new();
+        let attr = MemoryAttr::default().user();
+        ms.push(0x1000, 0x3000, attr, Delay::new(alloc.clone()), "delay");
+        ms.push(0x3000, 0x4000, attr, ByFrame::new(alloc.clone()), "frame");
+        assert!(ms.handle_page_fault(0x1000));
+        ms.get_page_table_mut().get_page_slice_mut(0x1000)[0] = 42;
+        assert_eq!(alloc.free_count(), 13);
+
+        // frames are shared readonly.
+        // MockPageTable has its own physical memory, so only the parent data is checked.
+        let mut child = ms.clone().unwrap();
+        assert_eq!(alloc.free_count(), 13);
+        let target = ms.get_page_table_mut().get_entry(0x1000).unwrap().target();
+        let entry = child.get_page_table_mut().get_entry(0x1000).unwrap();
+        assert_eq!(entry.target(), target);
+        assert!(!entry.writable());
+        assert!(!ms
+            .get_page_table_mut()
+            .get_entry(0x3000)
+            .unwrap()
+            .writable());
+
+        // the first writer gets a copy, the last one takes over the frame
+        let write = handler::AccessType::write(true);
+        assert!(child.handle_page_fault_ext(0x1000, write));
+        assert_eq!(alloc.free_count(), 12);
+        let entry = child.get_page_table_mut().get_entry(0x1000).unwrap();
+        assert_ne!(entry.target(), target);
+        assert!(entry.writable());
+        assert!(ms.handle_page_fault_ext(0x1000, write));
+        assert_eq!(alloc.free_count(), 12);
+        let entry = ms.get_page_table_mut().get_entry(0x1000).unwrap();
+        assert_eq!(entry.target(), target);
+        assert!(entry.writable());
+        assert_eq!(ms.get_page_table_mut().get_page_slice_mut(0x1000)[0], 42);
+
+        // readonly pages are not copied on write
+        ms.protect(0x3000, 0x4000, |attr| attr.readonly()).unwrap();
+        assert!(!ms.handle_page_fault_ext(0x3000, write));
+        drop(child);
+        assert_eq!(alloc.free_count(), 13);
+        drop(ms);
+        assert_eq!(alloc.free_count(), 15);
+    }
+}
@@ -16,13 +16,31 @@ pub trait PageTable {
     /// Return the page table entry of the mapped virual address
     fn map(&mut self, addr: VirtAddr, target: PhysAddr) -> &mut dyn Entry;
 
-    /// Unmap a page of virual address `addr`
+    /// Unmap a page of virual address `addr`, or the huge page containing it
     fn unmap(&mut self, addr: VirtAddr);
 
-    /// Get the page table entry of a page of virual address `addr`
+    /// Get the page table entry of a page of virual address `addr`,
+    /// or the entry of the huge page containing it.
     /// If its page do not exist, return `None`
     fn get_entry(&mut self, addr: VirtAddr) -> Option<&mut dyn Entry>;
 
+    /// Map a huge page of `size` bytes at virtual address `addr` to physical address `target`,
+    /// both aligned to `size`, which is `HUGE_PAGE_SIZE` or `GIGANTIC_PAGE_SIZE`.
+    /// Return `None` if pages of this size are not supported.
+    fn map_huge(
+        &mut self,
+        _addr: VirtAddr,
+        _target: PhysAddr,
+        _size: usize,
+    ) -> Option<&mut dyn Entry> {
+        None
+    }
+
+    /// Size of the page containing `addr`, larger than `PAGE_SIZE` in huge pages
+    fn page_size(&mut self, _addr: VirtAddr) -> usize {
+        PAGE_SIZE
+    }
+
     /// Get a mutable reference of the content of a page of virtual address `addr`
     fn get_page_slice_mut<'a>(&mut self, addr: VirtAddr) -> &'a mut [u8];
 
@@ -42,6 +60,35 @@ pub trait PageTable {
     }
 }
 
+/// Map the huge page containing `addr` with pages of `PAGE_SIZE` to the same frames,
+/// so that part of it can be unmapped or moved
+pub fn split_huge_page(pt: &mut dyn PageTable, addr: VirtAddr) {
+    let size = pt.page_size(addr);
+    if size == PAGE_SIZE {
+        return;
+    }
+    let start = addr & !(size - 1);
+    let entry = pt.get_entry(start).expect("failed to get entry");
+    let target = entry.target();
+    let present = entry.present();
+    let writable = entry.writable();
+    let user = entry.user();
+    let execute = entry.execute();
+    let mmio = entry.mmio();
+    // PageTable::unmap requires page to be present
+    entry.set_present(true);
+    pt.unmap(start);
+    for offset in (0..size).step_by(PAGE_SIZE) {
+        let entry = pt.map(start + offset, target + offset);
+        entry.set_present(present);
+        entry.set_writable(writable);
+        entry.set_user(user);
+        entry.set_execute(execute);
+        entry.set_mmio(mmio);
+        entry.update();
+    }
+}
+
 /// Page Table Entry
 pub trait Entry {
     /// Make all changes take effect.
@@ -0,0 +1,81 @@
+//! Implememnt the swap manager with the aging page replacement algorithm
+//!
+//! An approximation of LRU: each page has an 8-bit age,
+//! which is shifted right on every tick with the accessed bit shifted in from the left.
+//! The page with the smallest age is the victim, the earliest pushed one if tied.
+
+use super::*;
+use alloc::{collections::VecDeque, vec::Vec};
+
+#[derive(Debug, Default, Clone)]
+pub struct AgingSwapManager {
+    // (addr, age), in the order of pushing
+    deque: VecDeque<(VirtAddr, u8)>,
+}
+
+impl SwapManager for AgingSwapManager {
+    fn box_clone(&self) -> Box<dyn SwapManager> {
+        Box::new(self.clone())
+    }
+
+    fn tick(&mut self, page_table: &mut dyn PageTable) {
+        for (addr, age) in self.deque.iter_mut() {
+            let entry = page_table.get_entry(*addr).expect("failed to get entry");
+            *age >>= 1;
+            if entry.accessed() {
+                *age |= 0x80;
+                entry.clear_accessed();
+                entry.update();
+            }
+        }
+    }
+
+    fn push(&mut self, addr: VirtAddr) {
+        trace!("SwapManager push vaddr: {:x?}", addr);
+        self.deque.push_back((addr, 0));
+    }
+
+    fn remove(&mut self, addr: VirtAddr) {
+        trace!("SwapManager remove vaddr: {:x?}", addr);
+        if let Some(id) = self.deque.iter().position(|&(x, _)| x == addr) {
+            self.deque.remove(id);
+        }
+    }
+
+    fn pop(&mut self, page_table: &mut dyn PageTable) -> Option<VirtAddr> {
+        // pages accessed since the last tick count as the youngest
+        let mut ages = Vec::with_capacity(self.deque.len());
+        for &(addr, age) in self.deque.iter() {
+            let entry = page_table.get_entry(addr).expect("failed to get entry");
+            ages.push(((entry.accessed() as u16) << 8) | age as u16);
+        }
+        // the first one of the minimums
+        let victim = (0..ages.len()).min_by_key(|&id| ages[id]);
+        victim
+            .and_then(|id| self.deque.remove(id))
+            .map(|(addr, _)| addr)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::swap::test::*;
+
+    #[test]
+    #[rustfmt::skip]
+    fn test() {
+        use self::MemOp::{R, W};
+        let ops = [
+            R(0x1000), R(0x2000), R(0x3000), R(0x4000),
+            R(0x1000), R(0x2000), R(0x5000), R(0x1000),
+            R(0x2000), R(0x3000), R(0x4000), R(0x5000),
+            W(0x1000), W(0x3000), W(0x6000), R(0x1000)];
+        let pgfault_count = [
+            1, 2, 3, 4,
+            4, 4, 5, 5,
+            5, 6, 7, 8,
+            9, 9, 10, 10];
+        test_manager(AgingSwapManager::default(), &ops, &pgfault_count);
+    }
+}
@@ -1,76 +1,69 @@
 //! Implememnt the swap manager with the enhanced clock page replacement algorithm
+//!
+//! Pages are scanned in a circle by their (accessed, dirty) bits:
+//! (1, _) pages get a second chance with accessed bit cleared,
+//! (0, 1) pages get one more round with dirty bit cleared, as they cost a write,
+//! and the first (0, 0) page is the victim.
 
-use alloc::collections::VecDeque;
 use super::*;
-use crate::paging::Entry;
+use alloc::collections::VecDeque;
 
-#[derive(Default)]
+#[derive(Debug, Default, Clone)]
 pub struct EnhancedClockSwapManager {
     clock_ptr: usize,
     deque: VecDeque<VirtAddr>,
 }
 
-// FIXME: It's unusable. But can pass a simple test.
 impl SwapManager for EnhancedClockSwapManager {
-    fn tick(&mut self) {
+    fn box_clone(&self) -> Box<dyn SwapManager> {
+        Box::new(self.clone())
     }
 
-    fn push(&mut self, addr: usize) {
-        let pos = if self.clock_ptr == 0 {self.deque.len()} else {self.clock_ptr};
-        self.deque.insert(pos, addr);
+    fn tick(&mut self, _: &mut dyn PageTable) {}
+
+    fn push(&mut self, addr: VirtAddr) {
+        trace!("SwapManager push vaddr: {:x?}", addr);
+        // insert behind the clock hand, so it's the last to be scanned
+        self.deque.insert(self.clock_ptr, addr);
+        self.move_next();
     }
 
-    fn remove(&mut self, addr: usize) {
-        let id = self.deque.iter()
-            .position(|&x| x == addr)
-            .expect("address not found");
-        if id < self.clock_ptr {
-            self.clock_ptr -= 1;
+    fn remove(&mut self, addr: VirtAddr) {
+        trace!("SwapManager remove vaddr: {:x?}", addr);
+        if let Some(id) = self.deque.iter().position(|&x| x == addr) {
+            self.remove_at(id);
         }
-        self.deque.remove(id);
     }
 
-    fn pop<T, S>(&mut self, page_table: &mut T, _swapper: &mut S) -> Option<VirtAddr>
-        where T: PageTable, S: Swapper
-    {
-        loop {
+    fn pop(&mut self, page_table: &mut dyn PageTable) -> Option<VirtAddr> {
+        // all pages are (0, 0) after two rounds at most
+        for _ in 0..self.deque.len() * 3 {
             let addr = self.deque[self.clock_ptr];
-            // FIXME: Once define `slice`, all modifies of `entry` below will fail.
-            //        Then the loop will never stop.
-            //        The reason may be `get_page_slice_mut()` contains unsafe operation,
-            //        which lead the compiler to do a wrong optimization.
-//            let slice = page_table.get_page_slice_mut(addr);
-            let entry = page_table.get_entry(addr).unwrap();
-//            println!("{:#x} , {}, {}", addr, entry.accessed(), entry.dirty());
-
+            let entry = page_table.get_entry(addr).expect("failed to get entry");
             match (entry.accessed(), entry.dirty()) {
-                (true, _) => {
-                    entry.clear_accessed();
-                },
-                (false, true) => {
-                    // FIXME:
-//                    if let Ok(token) = swapper.swap_out(slice) {
-//                        entry.clear_dirty();
-//                    }
-                },
-                _ => {
-                    return self.remove_current();
-                }
+                (true, _) => entry.clear_accessed(),
+                (false, true) => entry.clear_dirty(),
+                (false, false) => return self.remove_at(self.clock_ptr),
             }
+            entry.update();
             self.move_next();
         }
+        None
     }
 }
 
-
 impl EnhancedClockSwapManager {
-    fn remove_current(&mut self) -> Option<VirtAddr> {
-        let addr = self.deque.remove(self.clock_ptr);
+    fn remove_at(&mut self, id: usize) -> Option<VirtAddr> {
+        let addr = self.deque.remove(id);
+        if id < self.clock_ptr {
+            self.clock_ptr -= 1;
+        }
         if self.clock_ptr == self.deque.len() {
             self.clock_ptr = 0;
         }
-        return addr;
+        addr
     }
+
     fn move_next(&mut self) {
         self.clock_ptr += 1;
         if self.clock_ptr == self.deque.len() {
@@ -82,9 +75,10 @@ impl EnhancedClockSwapManager {
 #[cfg(test)]
 mod test {
     use super::*;
-    use swap::test::*;
+    use crate::swap::test::*;
 
     #[test]
+    #[rustfmt::skip]
     fn test() {
         use self::MemOp::{R, W};
         let ops = [
@@ -3,50 +3,42 @@
 use super::*;
 use alloc::collections::VecDeque;
 
-#[derive(Default)]
+#[derive(Debug, Default, Clone)]
 pub struct FifoSwapManager {
-    deque: VecDeque<Frame>,
+    deque: VecDeque<VirtAddr>,
 }
 
 impl SwapManager for FifoSwapManager {
-    fn tick(&mut self) {}
+    fn box_clone(&self) -> Box<dyn SwapManager> {
+        Box::new(self.clone())
+    }
+
+    fn tick(&mut self, _: &mut dyn PageTable) {}
 
-    fn push(&mut self, frame: Frame) {
-        info!(
-            "SwapManager push token: {:x?} vaddr: {:x?}",
-            frame.get_token(),
-            frame.get_virtaddr()
-        );
-        self.deque.push_back(frame);
+    fn push(&mut self, addr: VirtAddr) {
+        trace!("SwapManager push vaddr: {:x?}", addr);
+        self.deque.push_back(addr);
     }
 
-    fn remove(&mut self, token: usize, addr: VirtAddr) {
-        info!("SwapManager remove token: {:x?} vaddr: {:x?}", token, addr);
-        let id = self
-            .deque
-            .iter()
-            .position(|ref x| x.get_virtaddr() == addr && x.get_token() == token)
-            .expect("address not found");
-        self.deque.remove(id);
-        //info!("SwapManager remove token finished: {:x?} vaddr: {:x?}", token, addr);
+    fn remove(&mut self, addr: VirtAddr) {
+        trace!("SwapManager remove vaddr: {:x?}", addr);
+        if let Some(id) = self.deque.iter().position(|&x| x == addr) {
+            self.deque.remove(id);
+        }
     }
 
-    fn pop<T, S>(&mut self, _: &mut T, _: &mut S) -> Option<Frame>
-    where
-        T: PageTable,
-        S: Swapper,
-    {
+    fn pop(&mut self, _: &mut dyn PageTable) -> Option<VirtAddr> {
         self.deque.pop_front()
     }
 }
 
-/*
 #[cfg(test)]
 mod test {
     use super::*;
-    use swap::test::*;
+    use crate::swap::test::*;
 
     #[test]
+    #[rustfmt::skip]
     fn test() {
         use self::MemOp::{R, W};
         let ops = [
@@ -62,4 +54,3 @@ mod test {
         test_manager(FifoSwapManager::default(), &ops, &pgfault_count);
     }
 }
-*/
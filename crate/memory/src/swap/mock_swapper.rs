@@ -39,9 +39,19 @@ impl Swapper for MockSwapper {
         }
         Ok(())
     }
+    fn swap_free(&mut self, token: usize) {
+        self.map.remove(&token);
+    }
 }
 
 impl MockSwapper {
+    /*
+     **  @brief  get the number of pages stored on the mock device
+     **  @retval usize                the number of pages
+     */
+    pub fn count(&self) -> usize {
+        self.map.len()
+    }
     /*
      **  @brief  allocate an unused id for location on the mock device
      **  @retval usize                the allocated location id
@@ -1,64 +1,73 @@
-//! Swap extension for page table
+//! Swap extension for memory set
 //! and generic interface for swap manager and swapper
 //!
-//! To use the SwapExt, make a wrapper over the original apge table using swap manager and swapper
-//! Like: SwapExt::new(origin_page_table,swap_manager,swapper)
-//! Invoke page_fault_handler() on the SwapExt to run the swap process
-//! If the method above returns true, a page is swapped in, else do your own things.
+//! To enable swapping for a `MemorySet`, create a `Swap` with a swap manager and a swapper,
+//! then pass it to `MemorySet::enable_swap`.
+//! When a page fault handler runs out of frames, a page chosen by the swap manager
+//! is written to the swapper, and its page table entry becomes a swap entry:
+//! a non-present entry with the swapped bit set, whose target is the token on the device.
+//! Only pages of areas whose handler is `swappable` are swapped.
 
-use super::addr::Frame;
+use super::memory_set::{handler::AccessType, MemoryArea};
 use super::paging::*;
 use super::*;
-use core::ops::{Deref, DerefMut};
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::fmt::{Debug, Error, Formatter};
+use spin::Mutex;
 
-//pub use self::fifo::FifoSwapManager;
-//pub use self::enhanced_clock::EnhancedClockSwapManager;
+pub use self::aging::AgingSwapManager;
+pub use self::enhanced_clock::EnhancedClockSwapManager;
+pub use self::fifo::FifoSwapManager;
+pub use self::working_set::WorkingSetSwapManager;
 
+pub mod aging;
+pub mod enhanced_clock;
 pub mod fifo;
-//mod enhanced_clock;
 pub mod mock_swapper;
-//#[cfg(test)]
-//mod mock_swapper;
+pub mod working_set;
 
-/// Manage all swappable pages, decide which to swap out
-pub trait SwapManager {
-    //type Inactive: InactivePageTable;
+/// Manage all swappable pages of a memory set, decide which to swap out
+pub trait SwapManager: Debug + Send + Sync + 'static {
+    fn box_clone(&self) -> Box<dyn SwapManager>;
     /*
      **  @brief  update intarnal state pre tick
-     **          Called when tick interrupt occured
+     **          Called periodically by timer interrupt
+     **  @param  page_table: &mut dyn PageTable
+     **                               the page table, to check and clear accessed bits
      **  @retval none
      */
-    fn tick(&mut self);
+    fn tick(&mut self, page_table: &mut dyn PageTable);
     /*
      **  @brief  update intarnal state when page is pushed into memory
      **          Called when map a swappable page into the memory
-     **  @param  frame: Frame       the Frame recording the swappable frame info
+     **  @param  addr: VirtAddr       the virual address of the page
      **  @retval none
      */
-    fn push(&mut self, frame: Frame);
+    fn push(&mut self, addr: VirtAddr);
     /*
      **  @brief  update intarnal state when page is removed from memory
      **          Called to delete the addr entry from the swap manager
-     **  @param  token: usize         the inactive page table token for the virtual address
      **  @param  addr: VirtAddr       the virual address of the page removed from memory
      **  @retval none
      */
-    fn remove(&mut self, token: usize, addr: VirtAddr);
+    fn remove(&mut self, addr: VirtAddr);
     /*
      **  @brief  select swap out victim when there is need to swap out a page
-     **          (The params is only used by `EnhancedClockSwapManager` currently)
-     **  @param  page_table: &mut T   the current page table
-     **  @param  swapper: &mut S      the swapper used
-     **  @retval Option<Frame>     the Frame of the victim page, if present
+     **  @param  page_table: &mut dyn PageTable
+     **                               the page table, to check accessed and dirty bits
+     **  @retval Option<VirtAddr>     the virtual address of the victim page, if present
      */
-    fn pop<T, S>(&mut self, page_table: &mut T, swapper: &mut S) -> Option<Frame>
-    where
-        T: PageTable,
-        S: Swapper;
+    fn pop(&mut self, page_table: &mut dyn PageTable) -> Option<VirtAddr>;
+}
+
+impl Clone for Box<dyn SwapManager> {
+    fn clone(&self) -> Box<dyn SwapManager> {
+        self.box_clone()
+    }
 }
 
 /// Implement swap in & out execution
-pub trait Swapper {
+pub trait Swapper: Send + 'static {
     /*
      **  @brief  Allocate space on device and write data to it
      **  @param  data: &[u8]          the data to write to the device
@@ -79,332 +88,171 @@ pub trait Swapper {
      **  @retval Result<(), ()>       the execute result
      */
     fn swap_in(&mut self, token: usize, data: &mut [u8]) -> Result<(), ()>;
+    /*
+     **  @brief  Deallocate the space without reading the data.
+     **  @param  token: usize         the token indicating the location on the device
+     **  @retval none
+     */
+    fn swap_free(&mut self, token: usize);
 }
 
-/// Wrapper for page table, supporting swap functions
-pub struct SwapExt<T: PageTable, M: SwapManager, S: Swapper> {
-    page_table: T,
-    swap_manager: M,
-    swapper: S,
+#[derive(Debug, Eq, PartialEq)]
+pub enum SwapError {
+    /// no free frame to swap in a page
+    NoMemory,
+    /// there are no page to be swapped out
+    NoSwapped,
+    /// swap failed due to IO error while interact with device, or the device is full
+    IOError,
 }
 
-impl<T: PageTable, M: SwapManager, S: Swapper> SwapExt<T, M, S> {
-    /*
-     **  @brief  create a swap extension
-     **  @param  page_table: T        the inner page table
-     **  @param  swap_manager: M      the SwapManager used
-     **  @param  swapper: S           the Swapper used
-     **  @retval SwapExt              the swap extension created
-     */
-    pub fn new(page_table: T, swap_manager: M, swapper: S) -> Self {
-        SwapExt {
-            page_table,
-            swap_manager,
+/// Swap state of a memory set
+#[derive(Clone)]
+pub struct Swap {
+    pub(crate) manager: Box<dyn SwapManager>,
+    pub(crate) swapper: Arc<Mutex<dyn Swapper>>,
+}
+
+impl Swap {
+    pub fn new(manager: impl SwapManager, swapper: Arc<Mutex<dyn Swapper>>) -> Self {
+        Swap {
+            manager: Box::new(manager),
             swapper,
         }
     }
 
-    /*
-     **  @brief set a page swappable
-     **  @param pt: *mut T2           the raw pointer for the target page's inactive page table
-     **  @param addr: VirtAddr        the target page's virtual address
-     */
-    pub unsafe fn set_swappable<T2: InactivePageTable>(&mut self, pt: *mut T2, addr: VirtAddr) {
-        let Self {
-            ref mut page_table,
-            ref mut swap_manager,
-            ..
-        } = self;
-        let targetpt = &mut *(pt);
-        let pttoken = {
-            info!(
-                "SET_SWAPPABLE: the target page table token is {:x?}, addr is {:x?}",
-                targetpt.token(),
-                addr
-            );
-            targetpt.token()
-        };
-        targetpt.with(|| {
-            let entry = page_table
-                .get_entry(addr)
-                .expect("failed to get page entry when set swappable");
-            if entry.present() {
-                let frame = Frame::new(pt as usize, addr, pttoken);
-                swap_manager.push(frame);
-            }
-        });
-        /*
-        let token = unsafe{
-            (*pt).token()
-        };
-        let frame = Frame::new(pt as usize, addr, token);
-        self.swap_manager.push(frame);
-        */
-    }
-
-    /*
-     **  @brief remove a page (given virtual address) from swappable pages, if the page is swapped, swap in at first
-     **  @param pt: *mut T2           the raw pointer for the target page's inactive page table
-     **  @param addr: VirtAddr        the target page's virtual address
-     **  @param alloc_frame:          the function to alloc a free physical frame for once
-     */
-    pub unsafe fn remove_from_swappable<T2: InactivePageTable>(
+    /// Write the page chosen by the swap manager to the swapper and free its frame.
+    pub(crate) fn swap_out(
         &mut self,
-        pt: *mut T2,
-        addr: VirtAddr,
-        alloc_frame: impl FnOnce() -> PhysAddr,
-    ) {
-        //info!("come into remove_from swappable");
-        let Self {
-            ref mut page_table,
-            ref mut swap_manager,
-            ref mut swapper,
-        } = self;
-        let targetpt = &mut *(pt);
-        let pttoken = {
-            info!(
-                "SET_UNSWAPPABLE: the target page table token is {:x?}, addr is {:x?}",
-                targetpt.token(),
-                addr
-            );
-            targetpt.token()
-        };
-        //info!("try to change pagetable");
-        targetpt.with(|| {
-            let token = {
-                let entry = page_table.get_entry(addr).unwrap();
-                if !entry.swapped() {
-                    if entry.present() {
-                        // if the addr isn't indicating a swapped page, panic occured here
-                        swap_manager.remove(pttoken, addr);
-                    }
-                    return;
+        areas: &[MemoryArea],
+        pt: &mut dyn PageTable,
+    ) -> Result<(), SwapError> {
+        // pages of locked areas are given back to the manager
+        let mut skipped = Vec::new();
+        let ret = loop {
+            let addr = match self.manager.pop(pt) {
+                Some(addr) => addr,
+                None => break Err(SwapError::NoSwapped),
+            };
+            let area = match areas.iter().find(|area| area.contains(addr)) {
+                Some(area) if area.handler.swappable() => area,
+                _ => continue,
+            };
+            if area.locked {
+                skipped.push(addr);
+                continue;
+            }
+            match pt.get_entry(addr) {
+                Some(entry) if entry.present() => {}
+                _ => continue,
+            }
+            let data = pt.get_page_slice_mut(addr);
+            let token = match self.swapper.lock().swap_out(data) {
+                Ok(token) => token,
+                Err(()) => {
+                    skipped.push(addr);
+                    break Err(SwapError::IOError);
                 }
-                let token = entry.target() / PAGE_SIZE;
-                let frame = alloc_frame();
-                entry.set_target(frame);
-                entry.set_swapped(false);
-                entry.set_present(true);
-                entry.update();
-                token
             };
-            info!("swap in vaddr {:x?} at remove from swappable.", addr);
-            let data = page_table.get_page_slice_mut(addr);
-            swapper.swap_in(token, data).unwrap();
-        });
-        trace!("come out of femove_from swappable");
-    }
-
-    /*
-     **  @brief  map the virtual address to a target physics address as swappable
-     **  @param  addr: VirtAddr       the virual address to map
-     **  @param  target: VirtAddr     the target physics address
-     **  @retval none
-     */
-    /*
-    pub fn map_to_swappable(&mut self, addr: VirtAddr, target: PhysAddr) -> &mut T::Entry {
-        self.swap_manager.push(addr);
-        self.map(addr, target)
-    }*/
-
-    /*
-     **  @brief  Swap out any one of the swapped pages
-     **  @retval Result<PhysAddr, SwapError>
-     **                               the physics address of released frame if success,
-     **                               the error if failed
-     */
-    pub fn swap_out_any<T2: InactivePageTable>(&mut self) -> Result<PhysAddr, SwapError> {
-        info!("COME in to swap_out_any");
-        let victim: Option<Frame> = {
-            let Self {
-                ref mut page_table,
-                ref mut swap_manager,
-                ref mut swapper,
-            } = self;
-            swap_manager.pop(page_table, swapper)
+            trace!("swap out {:#x} to {}", addr, token);
+            area.handler.discard(pt, addr);
+            set_swap_entry(pt.get_entry(addr).unwrap(), token);
+            break Ok(());
         };
-        info!("swap out page {}", victim.unwrap().get_virtaddr());
-        match victim {
-            None => Err(SwapError::NoSwapped),
-            Some(frame) => self.swap_out::<T2>(&frame),
+        for addr in skipped {
+            self.manager.push(addr);
         }
-    }
-
-    /*
-     **  @brief  Swap out page
-     **  @param  frame: Frame       the Frame of page recording the page info
-     **  @retval Result<PhysAddr, SwapError>
-     **                               the physics address of the original map target frame if success,
-     **                               the error if failed
-     */
-    fn swap_out<T2: InactivePageTable>(&mut self, frame: &Frame) -> Result<PhysAddr, SwapError> {
-        let Self {
-            ref mut page_table,
-            ref mut swapper,
-            ..
-        } = self;
-        let ret = unsafe {
-            let pt = &mut *(frame.get_page_table() as *mut T2);
-            pt.with(|| {
-                //use core::slice;
-                //let data = unsafe { slice::from_raw_parts_mut((frame.virtaddr & !(PAGE_SIZE - 1)) as *mut u8, PAGE_SIZE) };
-                let data = page_table.get_page_slice_mut(frame.get_virtaddr());
-                let entry = page_table
-                    .get_entry(frame.get_virtaddr())
-                    .ok_or(SwapError::NotMapped)?;
-                if entry.swapped() {
-                    return Err(SwapError::AlreadySwapped);
-                }
-                //assert!(!entry.swapped(), "Page already swapped!");
-                let token = swapper.swap_out(data).map_err(|_| SwapError::IOError)?;
-                //let token = swapper.swap_out(data).unwrap();
-                let target = entry.target();
-                entry.set_target(token * PAGE_SIZE);
-                entry.set_swapped(true);
-                entry.set_present(false);
-                entry.update();
-                Ok(target)
-            })
-        };
         ret
     }
-    /*
-     **  @brief  map the virtual address to a target physics address and then swap in page data, noted that the page should be in the current page table
-     **  @param pt: *mut T2           the raw pointer for the swapping page's inactive page table
-     **  @param  addr: VirtAddr       the virual address of beginning of page
-     **  @param  target: PhysAddr       the target physics address
-     **  @retval Result<()), SwapError>
-     **                               the execute result, and the error if failed
-     */
-    fn swap_in<T2: InactivePageTable>(
+
+    /// Allocate a frame for the swapped out page `addr` in `area`, and read its data back.
+    pub(crate) fn swap_in(
         &mut self,
-        pt: *mut T2,
+        area: &MemoryArea,
+        pt: &mut dyn PageTable,
         addr: VirtAddr,
-        target: PhysAddr,
+        token: usize,
     ) -> Result<(), SwapError> {
-        info!("come in to swap in");
-        let entry = self
-            .page_table
-            .get_entry(addr)
-            .ok_or(SwapError::NotMapped)?;
-        if !entry.swapped() {
-            return Err(SwapError::NotSwapped);
+        trace!("swap in {:#x} from {}", addr, token);
+        clear_swap_entry(pt.get_entry(addr).unwrap());
+        let filled = area
+            .handler
+            .handle_page_fault_ext(pt, addr, AccessType::unknown());
+        if let Err(err) = filled {
+            set_swap_entry(pt.get_entry(addr).unwrap(), token);
+            return Err(match err {
+                VMError::NoMemory => SwapError::NoMemory,
+                _ => SwapError::IOError,
+            });
         }
-        let token = entry.target() / PAGE_SIZE;
-        entry.set_target(target);
-        entry.set_swapped(false);
-        entry.set_present(true);
-        entry.update();
-        let data = self.page_table.get_page_slice_mut(addr);
-        self.swapper
-            .swap_in(token, data)
-            .map_err(|_| SwapError::IOError)?;
-        let pttoken = unsafe { (*pt).token() };
-        let frame = Frame::new(pt as usize, addr, pttoken);
-;
-        self.swap_manager.push(frame);
+        let execute = pt.get_entry(addr).unwrap().execute();
+        let data = pt.get_page_slice_mut(addr);
+        if self.swapper.lock().swap_in(token, data).is_err() {
+            error!("failed to swap in {:#x} from {}", addr, token);
+            area.handler.discard(pt, addr);
+            set_swap_entry(pt.get_entry(addr).unwrap(), token);
+            return Err(SwapError::IOError);
+        }
+        pt.flush_cache_copy_user(addr, addr + PAGE_SIZE, execute);
         Ok(())
     }
-    /*
-     **  @brief  execute the frame delayed allocate and  swap process for page fault
-     **          This function must be called whenever PageFault happens.
-     **  @param  pt: *mut T2          the raw pointer for the target page's inactive page table (exactly the current page table)
-     **  @param  addr: VirtAddr       the virual address of the page fault
-     **  @param  swapin: bool         whether to set the page swappable if delayed allocate a frame for a page
-     **  @param  alloc_frame: impl FnOnce() -> PhysAddr
-     **                               the page allocation function
-     **                               that allocate a page and returns physics address
-     **                               of beginning of the page
-     **  @retval bool                 whether swap in happens.
-     */
-    pub fn page_fault_handler<T2: InactivePageTable>(
-        &mut self,
-        pt: *mut T2,
-        addr: VirtAddr,
-        swapin: bool,
-        alloc_frame: impl FnOnce() -> PhysAddr,
-    ) -> bool {
-        // handle page delayed allocating
-        {
-            info!("try handling delayed frame allocator");
-            let need_alloc = {
-                let entry = self.page_table.get_entry(addr).expect("fail to get entry");
-                //info!("got entry!");
-                !entry.present() && !entry.swapped()
-            };
-            info!("need_alloc got");
-            if need_alloc {
-                info!("need_alloc!");
-                let frame = alloc_frame();
-                {
-                    let entry = self.page_table.get_entry(addr).unwrap();
-                    entry.set_target(frame);
-                    //let new_entry = self.page_table.map(addr, frame);
-                    entry.set_present(true);
-                    entry.update();
-                }
-                if swapin {
-                    unsafe {
-                        self.set_swappable(pt, addr & 0xfffff000);
-                    }
-                }
-                //area.get_flags().apply(new_entry); this instruction may be used when hide attr is used
-                info!("allocated successfully");
-                return true;
-            }
-            info!("not need alloc!");
-        }
-        // now we didn't attach the cow so the present will be false when swapped(), to enable the cow some changes will be needed
-        match self.page_table.get_entry(addr) {
-            // infact the get_entry(addr) should not be None here
-            None => return false,
-            Some(entry) => {
-                if !entry.swapped() {
-                    return false;
-                }
-            }
+
+    /// Drop the page `addr` which is going to be unmapped or discarded:
+    /// free its space on the device if swapped out, or stop tracking it.
+    pub(crate) fn forget(&mut self, pt: &mut dyn PageTable, addr: VirtAddr) {
+        let entry = match pt.get_entry(addr) {
+            Some(entry) => entry,
+            None => return,
+        };
+        if let Some(token) = get_swap_entry(entry) {
+            self.swapper.lock().swap_free(token);
+            clear_swap_entry(entry);
+        } else if entry.present() {
+            self.manager.remove(addr);
         }
-        // Allocate a frame, if failed, swap out a page
-        let frame = alloc_frame();
-        self.swap_in(pt, addr, frame).ok().unwrap();
-        true
     }
 }
 
-pub enum SwapError {
-    /// attempt to swap out a page that is already swapped out
-    AlreadySwapped,
-    ///
-    NotMapped,
-    /// attempt to swap in a page that is already in the memory
-    NotSwapped,
-    /// there are no page to be swapped out
-    NoSwapped,
-    /// swap failed due to IO error while interact with device
-    IOError,
+impl Debug for Swap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        f.debug_struct("Swap")
+            .field("manager", &self.manager)
+            .finish()
+    }
 }
 
-impl<T: PageTable, M: SwapManager, S: Swapper> Deref for SwapExt<T, M, S> {
-    type Target = T;
+/// Turn `entry` into a swap entry of `token`
+pub fn set_swap_entry(entry: &mut dyn Entry, token: usize) {
+    entry.set_target(token * PAGE_SIZE);
+    entry.set_present(false);
+    entry.set_swapped(true);
+    entry.update();
+}
 
-    fn deref(&self) -> &<Self as Deref>::Target {
-        &self.page_table
+/// Get the token of a swap entry
+pub fn get_swap_entry(entry: &dyn Entry) -> Option<usize> {
+    if !entry.present() && entry.swapped() {
+        Some(entry.target() / PAGE_SIZE)
+    } else {
+        None
     }
 }
 
-impl<T: PageTable, M: SwapManager, S: Swapper> DerefMut for SwapExt<T, M, S> {
-    fn deref_mut(&mut self) -> &mut <Self as Deref>::Target {
-        &mut self.page_table
-    }
+/// Turn a swap entry back into a normal non-present entry
+pub fn clear_swap_entry(entry: &mut dyn Entry) {
+    entry.set_target(0);
+    entry.set_swapped(false);
+    entry.update();
 }
 
-/*
 #[cfg(test)]
-mod test {
-    use super::*;
+pub mod test {
     use super::mock_swapper::MockSwapper;
-    use alloc::{sync::Arc, boxed::Box};
-    use core::cell::RefCell;
+    use super::*;
+    use crate::memory_set::handler::{Delay, FrameAllocator};
+    use crate::memory_set::{MemoryAttr, MemorySet};
+    use alloc::{rc::Rc, vec::Vec};
+    use core::cell::Cell;
     use paging::MockPageTable;
 
     #[derive(Debug)]
@@ -413,53 +261,54 @@ mod test {
         W(usize),
     }
 
-    struct FrameAlloc(usize);
+    #[derive(Debug, Clone)]
+    struct FrameAlloc(Arc<Mutex<Vec<PhysAddr>>>);
 
-    impl FrameAlloc {
-        fn alloc(&mut self) -> Option<PhysAddr> {
-            if self.0 == 0 {
-                return None;
-            }
-            self.0 -= 1;
-            Some((self.0 + 1) * PAGE_SIZE)
+    impl FrameAllocator for FrameAlloc {
+        fn alloc(&self) -> Option<PhysAddr> {
+            self.0.lock().pop()
+        }
+        fn alloc_contiguous(&self, _size: usize, _align_log2: usize) -> Option<PhysAddr> {
+            unimplemented!()
+        }
+        fn dealloc(&self, target: PhysAddr) {
+            self.0.lock().push(target);
         }
-    }
-
-    unsafe fn clone<'a, 'b, T>(x: &'a mut T) -> &'b mut T {
-        &mut *(x as *mut T)
     }
 
     /// Test framework with different SwapManagers.
+    /// Replay `ops` on 7 pages with 4 frames, ticking after each op,
+    /// and check the total page fault count after each op.
     /// See `fifo::test` mod for example.
-    pub fn test_manager(swap_manager: impl 'static + SwapManager, ops: &[MemOp], pgfault_count: &[u8]) {
+    pub fn test_manager(swap_manager: impl SwapManager, ops: &[MemOp], pgfault_count: &[usize]) {
         use self::MemOp::{R, W};
-        let page_fault_count = Arc::new(RefCell::new(0u8));
-
-        let mut pt = SwapExt::new(MockPageTable::new(), swap_manager, MockSwapper::default());
+        let frames = (1..=4).map(|i| i * PAGE_SIZE).collect();
+        let alloc = FrameAlloc(Arc::new(Mutex::new(frames)));
+        let swapper = Arc::new(Mutex::new(MockSwapper::default()));
+        let mut ms = MemorySet::<MockPageTable>::new();
+        let attr = MemoryAttr::default().user();
+        ms.push(0x1000, 0x8000, attr, Delay::new(alloc), "test");
+        ms.enable_swap(Swap::new(swap_manager, swapper));
 
         // Move to closure
-        let pt0 = unsafe{ clone(&mut pt) };
+        let ms0 = unsafe { &mut *(&mut ms as *mut MemorySet<MockPageTable>) };
+        let page_fault_count = Rc::new(Cell::new(0usize));
         let page_fault_count1 = page_fault_count.clone();
-        let mut alloc = FrameAlloc(4);
-
-        pt.set_handler(Box::new(move |_, addr: VirtAddr| {
-            *page_fault_count1.borrow_mut() += 1;
-            if pt0.page_fault_handler(addr, || alloc.alloc()) {
-                return;
-            }
-            // The page is not mapped, map it to a new frame, if no more frame, swap out.
-            let target = alloc.alloc().or_else(|| pt0.swap_out_any().ok())
-                .expect("no more frame in both allocator and swap_manager");
-            pt0.map_to_swappable(addr, target);
-        }));
+        ms.get_page_table_mut()
+            .set_handler(Box::new(move |_, addr: VirtAddr| {
+                page_fault_count1.set(page_fault_count1.get() + 1);
+                assert!(ms0.handle_page_fault(addr));
+            }));
 
         for (op, &count) in ops.iter().zip(pgfault_count.iter()) {
             match op {
-                R(addr) => { pt.read(*addr); }
-                W(addr) => pt.write(*addr, 0),
+                R(addr) => {
+                    ms.get_page_table_mut().read(*addr);
+                }
+                W(addr) => ms.get_page_table_mut().write(*addr, 0),
             }
-            assert_eq!(*(*page_fault_count).borrow(), count);
+            ms.swap_tick();
+            assert_eq!(page_fault_count.get(), count, "after {:?}", op);
         }
     }
 }
-*/
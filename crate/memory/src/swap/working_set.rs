@@ -0,0 +1,130 @@
+//! Implememnt the swap manager with the working set clock (WSClock) page replacement algorithm
+//!
+//! Each page records the virtual time of its last use, which is updated on ticks
+//! from the accessed bit. Pages are scanned in a circle:
+//! accessed pages are in the working set and get a second chance,
+//! pages unused for more than `window` ticks are out of the working set,
+//! and the first clean one of them is the victim,
+//! while dirty ones get one more round with dirty bit cleared, as they cost a write.
+//! If all pages are in the working set, the least recently used one is the victim.
+
+use super::*;
+use alloc::collections::VecDeque;
+
+#[derive(Debug, Clone)]
+pub struct WorkingSetSwapManager {
+    /// size of the working set window in ticks
+    window: usize,
+    /// virtual time, increased on every tick
+    time: usize,
+    clock_ptr: usize,
+    // (addr, time of last use)
+    deque: VecDeque<(VirtAddr, usize)>,
+}
+
+impl SwapManager for WorkingSetSwapManager {
+    fn box_clone(&self) -> Box<dyn SwapManager> {
+        Box::new(self.clone())
+    }
+
+    fn tick(&mut self, page_table: &mut dyn PageTable) {
+        self.time += 1;
+        for (addr, last_use) in self.deque.iter_mut() {
+            let entry = page_table.get_entry(*addr).expect("failed to get entry");
+            if entry.accessed() {
+                *last_use = self.time;
+                entry.clear_accessed();
+                entry.update();
+            }
+        }
+    }
+
+    fn push(&mut self, addr: VirtAddr) {
+        trace!("SwapManager push vaddr: {:x?}", addr);
+        // insert behind the clock hand, so it's the last to be scanned
+        self.deque.insert(self.clock_ptr, (addr, self.time));
+        self.move_next();
+    }
+
+    fn remove(&mut self, addr: VirtAddr) {
+        trace!("SwapManager remove vaddr: {:x?}", addr);
+        if let Some(id) = self.deque.iter().position(|&(x, _)| x == addr) {
+            self.remove_at(id);
+        }
+    }
+
+    fn pop(&mut self, page_table: &mut dyn PageTable) -> Option<VirtAddr> {
+        // all pages out of the working set are clean after one round
+        for _ in 0..self.deque.len() * 2 {
+            let (addr, last_use) = self.deque[self.clock_ptr];
+            let entry = page_table.get_entry(addr).expect("failed to get entry");
+            if entry.accessed() {
+                self.deque[self.clock_ptr].1 = self.time;
+                entry.clear_accessed();
+                entry.update();
+            } else if self.time - last_use > self.window {
+                if !entry.dirty() {
+                    return self.remove_at(self.clock_ptr);
+                }
+                entry.clear_dirty();
+                entry.update();
+            }
+            self.move_next();
+        }
+        let id = (0..self.deque.len()).min_by_key(|&id| self.deque[id].1)?;
+        self.remove_at(id)
+    }
+}
+
+impl WorkingSetSwapManager {
+    /// Create a manager with a working set window of `window` ticks
+    pub fn new(window: usize) -> Self {
+        WorkingSetSwapManager {
+            window,
+            time: 0,
+            clock_ptr: 0,
+            deque: VecDeque::new(),
+        }
+    }
+
+    fn remove_at(&mut self, id: usize) -> Option<VirtAddr> {
+        let (addr, _) = self.deque.remove(id)?;
+        if id < self.clock_ptr {
+            self.clock_ptr -= 1;
+        }
+        if self.clock_ptr == self.deque.len() {
+            self.clock_ptr = 0;
+        }
+        Some(addr)
+    }
+
+    fn move_next(&mut self) {
+        self.clock_ptr += 1;
+        if self.clock_ptr == self.deque.len() {
+            self.clock_ptr = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::swap::test::*;
+
+    #[test]
+    #[rustfmt::skip]
+    fn test() {
+        use self::MemOp::{R, W};
+        let ops = [
+            W(0x1000), R(0x2000), R(0x3000), R(0x4000),
+            R(0x3000), R(0x4000), R(0x5000), R(0x1000),
+            R(0x2000), R(0x3000), R(0x5000), R(0x1000),
+            R(0x2000), R(0x3000), R(0x4000)];
+        let pgfault_count = [
+            1, 2, 3, 4,
+            4, 4, 5, 5,
+            6, 7, 7, 7,
+            7, 7, 8];
+        test_manager(WorkingSetSwapManager::new(2), &ops, &pgfault_count);
+    }
+}
@@ -3,11 +3,15 @@ fn main() {
     println!("cargo:rerun-if-env-changed=SMP");
     println!("cargo:rerun-if-env-changed=BOARD");
     println!("cargo:rerun-if-env-changed=USER_IMG");
+    println!("cargo:rerun-if-env-changed=INITRAMFS");
 
     let _arch: String = std::env::var("ARCH").unwrap();
     if let Ok(user_img) = std::env::var("USER_IMG") {
         println!("cargo:rerun-if-changed={}", user_img);
     }
+    if let Ok(initramfs) = std::env::var("INITRAMFS") {
+        println!("cargo:rerun-if-changed={}", initramfs);
+    }
 
     // for shorter #[cfg] check
     let target = std::env::var("TARGET").unwrap();
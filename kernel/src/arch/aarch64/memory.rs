@@ -2,7 +2,7 @@
 
 use super::paging::MMIOType;
 use crate::consts::{KERNEL_OFFSET, MEMORY_OFFSET};
-use crate::memory::{init_heap, kernel_offset, Linear, MemoryAttr, MemorySet, FRAME_ALLOCATOR};
+use crate::memory::{init_heap, kernel_offset, Linear, MemoryAttr, MemorySet};
 use crate::sync::SpinNoIrqLock as Mutex;
 use aarch64::paging::frame::PhysFrame as Frame;
 use aarch64::regs::*;
@@ -27,15 +27,14 @@ pub fn init_other() {
 }
 
 fn init_frame_allocator() {
-    use bitmap_allocator::BitAlloc;
     use core::ops::Range;
 
     let end = super::board::probe_memory()
         .expect("failed to find memory map")
         .1;
     let start = kernel_offset(_end as usize) + MEMORY_OFFSET + PAGE_SIZE;
-    let mut ba = FRAME_ALLOCATOR.lock();
-    ba.insert(to_range(start, end));
+    let range = to_range(start, end);
+    crate::memory::init_frame_allocator(|| core::iter::once(range.clone()));
     info!("FrameAllocator init end");
 
     /// Transform memory area `[start, end)` to integer range for `FrameAllocator`
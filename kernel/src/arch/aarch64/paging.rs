@@ -7,7 +7,7 @@ use aarch64::paging::{
     mapper::{MappedPageTable, Mapper},
     memory_attribute::*,
     page_table::{PageTable as Aarch64PageTable, PageTableEntry, PageTableFlags as EF},
-    FrameAllocator, FrameDeallocator, Page as PageAllSizes, Size2MiB, Size4KiB,
+    FrameAllocator, FrameDeallocator, Page as PageAllSizes, PageSize, Size1GiB, Size2MiB, Size4KiB,
 };
 use aarch64::translation::{invalidate_tlb_vaddr, local_invalidate_tlb_all};
 use aarch64::translation::{ttbr_el1_read, ttbr_el1_write};
@@ -15,6 +15,7 @@ use aarch64::{align_down, align_up, PhysAddr, ALIGN_2MIB};
 use core::mem::ManuallyDrop;
 use log::*;
 use rcore_memory::paging::*;
+use rcore_memory::{GIGANTIC_PAGE_SIZE, HUGE_PAGE_SIZE, PAGE_SIZE};
 
 type Page = PageAllSizes<Size4KiB>;
 
@@ -46,31 +47,80 @@ impl PageTable for PageTableImpl {
     }
 
     fn unmap(&mut self, addr: usize) {
-        self.page_table
-            .unmap(Page::of_addr(addr as u64))
-            .unwrap()
-            .1
-            .flush();
+        match self.page_size(addr) {
+            GIGANTIC_PAGE_SIZE => {
+                let page = PageAllSizes::<Size1GiB>::of_addr(addr as u64);
+                self.page_table.unmap(page).unwrap().1.flush();
+            }
+            HUGE_PAGE_SIZE => {
+                let page = PageAllSizes::<Size2MiB>::of_addr(addr as u64);
+                self.page_table.unmap(page).unwrap().1.flush();
+            }
+            _ => {
+                let page = Page::of_addr(addr as u64);
+                self.page_table.unmap(page).unwrap().1.flush();
+            }
+        }
     }
 
     fn get_entry(&mut self, vaddr: usize) -> Option<&mut dyn Entry> {
         let page = Page::of_addr(vaddr as u64);
-        if let Ok(e) = self.page_table.get_entry_mut(page) {
-            let e = unsafe { &mut *(e as *mut PageTableEntry) };
-            self.entry = Some(PageEntry(e, page));
-            Some(self.entry.as_mut().unwrap())
+        let e = if let Some((e, _)) = self.huge_entry(vaddr) {
+            e
+        } else if let Ok(e) = self.page_table.get_entry_mut(page) {
+            e as *mut PageTableEntry
         } else {
-            None
-        }
+            return None;
+        };
+        let e = unsafe { &mut *e };
+        self.entry = Some(PageEntry(e, page));
+        Some(self.entry.as_mut().unwrap())
     }
 
     fn get_page_slice_mut<'a>(&mut self, addr: usize) -> &'a mut [u8] {
-        let frame = self
-            .page_table
-            .translate_page(Page::of_addr(addr as u64))
-            .unwrap();
-        let vaddr = phys_to_virt(frame.start_address().as_u64() as usize);
-        unsafe { core::slice::from_raw_parts_mut(vaddr as *mut u8, 0x1000) }
+        let size = self.page_size(addr);
+        let target = self.get_entry(addr).expect("fail to get entry").target();
+        // the 4K slice of a huge page
+        let paddr = target + (addr & (size - 1) & !(PAGE_SIZE - 1));
+        let vaddr = phys_to_virt(paddr);
+        unsafe { core::slice::from_raw_parts_mut(vaddr as *mut u8, PAGE_SIZE) }
+    }
+
+    fn map_huge(&mut self, addr: usize, target: usize, size: usize) -> Option<&mut dyn Entry> {
+        let flags = EF::default_block() | EF::PXN | EF::UXN;
+        let attr = MairNormal::attr_value();
+        match size {
+            HUGE_PAGE_SIZE => unsafe {
+                self.page_table
+                    .map_to(
+                        PageAllSizes::<Size2MiB>::of_addr(addr as u64),
+                        Frame::<Size2MiB>::of_addr(target as u64),
+                        flags,
+                        attr,
+                        &mut FrameAllocatorForAarch64,
+                    )
+                    .ok()?
+                    .flush();
+            },
+            GIGANTIC_PAGE_SIZE => unsafe {
+                self.page_table
+                    .map_to(
+                        PageAllSizes::<Size1GiB>::of_addr(addr as u64),
+                        Frame::<Size1GiB>::of_addr(target as u64),
+                        flags,
+                        attr,
+                        &mut FrameAllocatorForAarch64,
+                    )
+                    .ok()?
+                    .flush();
+            },
+            _ => return None,
+        }
+        self.get_entry(addr)
+    }
+
+    fn page_size(&mut self, addr: usize) -> usize {
+        self.huge_entry(addr).map_or(PAGE_SIZE, |(_, size)| size)
     }
 
     fn flush_cache_copy_user(&mut self, start: usize, end: usize, execute: bool) {
@@ -257,24 +307,42 @@ impl PageTableImpl {
         ttbr_el1_write(1, Frame::of_addr(self.token() as u64));
         local_invalidate_tlb_all();
     }
+    /// Get the block entry of the huge page containing `addr` and its size
+    fn huge_entry(&mut self, addr: usize) -> Option<(*mut PageTableEntry, usize)> {
+        // a block descriptor is valid, but not a table
+        fn is_block(e: &PageTableEntry) -> bool {
+            let flags = e.flags();
+            flags.contains(EF::VALID) && !flags.contains(EF::TABLE_OR_PAGE)
+        }
+        let addr = addr as u64;
+        if let Ok(e) = self
+            .page_table
+            .get_entry_mut(PageAllSizes::<Size1GiB>::of_addr(addr))
+        {
+            if is_block(e) {
+                return Some((e as *mut _, Size1GiB::SIZE as usize));
+            }
+        }
+        if let Ok(e) = self
+            .page_table
+            .get_entry_mut(PageAllSizes::<Size2MiB>::of_addr(addr))
+        {
+            if is_block(e) {
+                return Some((e as *mut _, Size2MiB::SIZE as usize));
+            }
+        }
+        None
+    }
     /// Map physical memory [start, end)
     /// to virtual space [phys_to_virt(start), phys_to_virt(end))
     pub fn map_physical_memory(&mut self, start: usize, end: usize) {
         info!("mapping physical memory");
         let aligned_start = align_down(start as u64, ALIGN_2MIB);
         let aligned_end = align_up(end as u64, ALIGN_2MIB);
-        let flags = EF::default_block() | EF::UXN | EF::PXN;
-        let attr = MairNormal::attr_value();
         for frame in Frame::<Size2MiB>::range_of(aligned_start, aligned_end) {
-            let paddr = frame.start_address();
-            let vaddr = phys_to_virt(paddr.as_u64() as usize);
-            let page = PageAllSizes::<Size2MiB>::of_addr(vaddr as u64);
-            unsafe {
-                self.page_table
-                    .map_to(page, frame, flags, attr, &mut FrameAllocatorForAarch64)
-                    .expect("failed to map physical memory")
-                    .flush();
-            }
+            let paddr = frame.start_address().as_u64() as usize;
+            self.map_huge(phys_to_virt(paddr), paddr, HUGE_PAGE_SIZE)
+                .expect("failed to map physical memory");
         }
     }
 }
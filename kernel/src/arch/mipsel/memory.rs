@@ -1,6 +1,6 @@
 use crate::arch::paging::*;
 use crate::consts::{KERNEL_OFFSET, MEMORY_END, MEMORY_OFFSET};
-use crate::memory::{init_heap, FRAME_ALLOCATOR};
+use crate::memory::init_heap;
 use mips::registers::cp0;
 use rcore_memory::PAGE_SIZE;
 
@@ -23,15 +23,13 @@ pub fn init_other() {
 }
 
 fn init_frame_allocator() {
-    use bitmap_allocator::BitAlloc;
     use core::ops::Range;
 
-    let mut ba = FRAME_ALLOCATOR.lock();
     let range = to_range(
         (end as usize) - KERNEL_OFFSET + MEMORY_OFFSET + PAGE_SIZE,
         MEMORY_END,
     );
-    ba.insert(range);
+    crate::memory::init_frame_allocator(|| core::iter::once(range.clone()));
 
     info!("frame allocator: init end");
 
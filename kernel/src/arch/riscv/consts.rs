@@ -23,6 +23,10 @@ pub const USER_STACK_SIZE: usize = 0x10000;
 pub const KSEG2_START: usize = 0xfe80_0000;
 #[cfg(target_arch = "riscv64")]
 pub const KSEG2_START: usize = 0xffff_fe80_0000_0000;
+#[cfg(target_arch = "riscv32")]
+pub const KSEG2_SIZE: usize = 0x0100_0000;
+#[cfg(target_arch = "riscv64")]
+pub const KSEG2_SIZE: usize = 1 << 30;
 
 pub const MAX_DTB_SIZE: usize = 0x2000;
 
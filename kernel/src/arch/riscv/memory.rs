@@ -1,5 +1,5 @@
 use crate::consts::{KERNEL_OFFSET, MEMORY_END, MEMORY_OFFSET};
-use crate::memory::{init_heap, MemorySet, FRAME_ALLOCATOR};
+use crate::memory::{init_heap, MemorySet};
 use core::mem;
 use log::*;
 use rcore_memory::PAGE_SIZE;
@@ -13,7 +13,11 @@ pub fn init(dtb: usize) {
         sstatus::set_sum();
     }
     // initialize heap and Frame allocator
-    init_frame_allocator();
+    let initrd = crate::drivers::device_tree::initrd(dtb).filter(|r| r.start >= MEMORY_OFFSET);
+    init_frame_allocator(initrd.clone());
+    if let Some(initrd) = initrd {
+        crate::fs::initramfs::set_boot_image(initrd);
+    }
     init_heap();
     remap_the_kernel(dtb);
 }
@@ -26,16 +30,29 @@ pub fn init_other() {
     }
 }
 
-fn init_frame_allocator() {
-    use bitmap_allocator::BitAlloc;
+/// Init the frame allocator with the memory after the kernel, except the initial ramdisk
+fn init_frame_allocator(initrd: Option<core::ops::Range<usize>>) {
     use core::ops::Range;
 
-    let mut ba = FRAME_ALLOCATOR.lock();
     let range = to_range(
         (end as usize) - KERNEL_OFFSET + MEMORY_OFFSET + PAGE_SIZE,
         MEMORY_END,
     );
-    ba.insert(range);
+    // the initial ramdisk is kept until it's unpacked
+    let hole = match initrd {
+        Some(initrd) => {
+            let hole = to_range(initrd.start, initrd.end);
+            hole.start.max(range.start).min(range.end)..hole.end.max(range.start).min(range.end)
+        }
+        None => range.end..range.end,
+    };
+    let below = range.start..hole.start;
+    let above = hole.end..range.end;
+    crate::memory::init_frame_allocator(|| {
+        core::iter::once(below.clone())
+            .chain(core::iter::once(above.clone()))
+            .filter(|r| r.start < r.end)
+    });
 
     info!("frame allocator: init end");
 
@@ -3,6 +3,7 @@ use crate::memory::{alloc_frame, dealloc_frame, phys_to_virt};
 use core::mem::ManuallyDrop;
 use log::*;
 use rcore_memory::paging::*;
+use rcore_memory::{GIGANTIC_PAGE_SIZE, HUGE_PAGE_SIZE, PAGE_SIZE};
 use riscv::addr::*;
 use riscv::asm::{sfence_vma, sfence_vma_all};
 use riscv::paging::MapperFlushable;
@@ -48,6 +49,13 @@ impl PageTable for PageTableImpl {
     }
 
     fn unmap(&mut self, addr: usize) {
+        if let Some((entry, _)) = self.huge_entry(addr) {
+            entry.set_unused();
+            unsafe {
+                sfence_vma(0, addr);
+            }
+            return;
+        }
         let page = Page::of_addr(VirtAddr::new(addr));
         let (_, flush) = self.page_table.unmap(page).unwrap();
         flush.flush();
@@ -55,6 +63,10 @@ impl PageTable for PageTableImpl {
 
     fn get_entry(&mut self, vaddr: usize) -> Option<&mut dyn Entry> {
         let page = Page::of_addr(VirtAddr::new(vaddr));
+        if let Some((e, _)) = self.huge_entry(vaddr) {
+            self.entry = Some(PageEntry(e, page));
+            return Some(self.entry.as_mut().unwrap());
+        }
         if let Ok(e) = self.page_table.ref_entry(page.clone()) {
             let e = unsafe { &mut *(e as *mut PageTableEntry) };
             self.entry = Some(PageEntry(e, page));
@@ -64,18 +76,81 @@ impl PageTable for PageTableImpl {
         }
     }
 
+    #[cfg(target_arch = "riscv64")]
+    fn map_huge(&mut self, addr: usize, target: usize, size: usize) -> Option<&mut dyn Entry> {
+        let flags = EF::VALID | EF::READABLE | EF::WRITABLE;
+        let frame = Frame::of_addr(PhysAddr::new_u64(target as u64));
+        let root = self.root_table();
+        let entry = &mut root[(addr >> 30) & 0x1ff];
+        match size {
+            GIGANTIC_PAGE_SIZE if entry.is_unused() => entry.set(frame, flags),
+            HUGE_PAGE_SIZE => {
+                if entry.is_unused() {
+                    let table_frame = alloc_frame()?;
+                    let table = unsafe { &mut *(phys_to_virt(table_frame) as *mut RvPageTable) };
+                    table.zero();
+                    entry.set(
+                        Frame::of_addr(PhysAddr::new_u64(table_frame as u64)),
+                        EF::VALID,
+                    );
+                } else if is_leaf(entry) {
+                    return None;
+                }
+                let table = entry_table(entry);
+                let entry = &mut table[(addr >> 21) & 0x1ff];
+                if !entry.is_unused() {
+                    return None;
+                }
+                entry.set(frame, flags);
+            }
+            _ => return None,
+        }
+        unsafe {
+            sfence_vma(0, addr);
+        }
+        self.get_entry(addr)
+    }
+
+    fn page_size(&mut self, addr: usize) -> usize {
+        match self.huge_entry(addr) {
+            Some((_, size)) => size,
+            None => PAGE_SIZE,
+        }
+    }
+
     fn get_page_slice_mut<'a>(&mut self, addr: usize) -> &'a mut [u8] {
-        let frame = self
-            .page_table
-            .translate_page(Page::of_addr(VirtAddr::new(addr)))
-            .unwrap();
-        let vaddr = frame.start_address().as_usize() + PHYSICAL_MEMORY_OFFSET;
+        let paddr = match self.huge_entry(addr) {
+            Some((entry, size)) => {
+                entry.addr::<PhysAddr>().as_usize() + (addr & (size - 1) & !(PAGE_SIZE - 1))
+            }
+            None => self
+                .page_table
+                .translate_page(Page::of_addr(VirtAddr::new(addr)))
+                .unwrap()
+                .start_address()
+                .as_usize(),
+        };
+        let vaddr = paddr + PHYSICAL_MEMORY_OFFSET;
         unsafe { core::slice::from_raw_parts_mut(vaddr as *mut u8, 0x1000) }
     }
 
     fn flush_cache_copy_user(&mut self, _start: usize, _end: usize, _execute: bool) {}
 }
 
+/// Whether the entry maps a page rather than points to the next level table
+#[cfg(target_arch = "riscv64")]
+fn is_leaf(entry: &PageTableEntry) -> bool {
+    entry
+        .flags()
+        .intersects(EF::READABLE | EF::WRITABLE | EF::EXECUTABLE)
+}
+
+/// The next level table pointed by `entry`
+#[cfg(target_arch = "riscv64")]
+fn entry_table(entry: &PageTableEntry) -> &'static mut RvPageTable {
+    unsafe { &mut *(phys_to_virt(entry.addr::<PhysAddr>().as_usize()) as *mut RvPageTable) }
+}
+
 /// implementation for the Entry trait in /crate/memory/src/paging/mod.rs
 impl Entry for PageEntry {
     fn update(&mut self) {
@@ -169,6 +244,37 @@ impl PageTableImpl {
             entry: None,
         })
     }
+
+    /// The root table, accessed through the linear mapping
+    fn root_table(&self) -> &'static mut RvPageTable {
+        unsafe {
+            &mut *(phys_to_virt(self.root_frame.start_address().as_usize()) as *mut RvPageTable)
+        }
+    }
+
+    /// Get the entry of the huge page containing `addr` and its size, if mapped by one
+    #[cfg(target_arch = "riscv64")]
+    fn huge_entry(&self, addr: usize) -> Option<(&'static mut PageTableEntry, usize)> {
+        let entry = &mut self.root_table()[(addr >> 30) & 0x1ff];
+        if !entry.flags().contains(EF::VALID) {
+            return None;
+        }
+        if is_leaf(entry) {
+            return Some((entry, GIGANTIC_PAGE_SIZE));
+        }
+        let entry = &mut entry_table(entry)[(addr >> 21) & 0x1ff];
+        if entry.flags().contains(EF::VALID) && is_leaf(entry) {
+            return Some((entry, HUGE_PAGE_SIZE));
+        }
+        None
+    }
+
+    /// Megapages of Sv32 are not used for user memory
+    #[cfg(target_arch = "riscv32")]
+    fn huge_entry(&self, _addr: usize) -> Option<(&'static mut PageTableEntry, usize)> {
+        None
+    }
+
     /// The method for getting the kernel page table.
     /// In riscv kernel page table and user page table are the same table. However you have to do the initialization.
     pub unsafe fn kernel_table() -> ManuallyDrop<Self> {
@@ -277,6 +277,7 @@ pub const SYS_PWRITEV2: usize = 287;
 pub const SYS_PKEY_MPROTECT: usize = 288;
 pub const SYS_PKEY_ALLOC: usize = 289;
 pub const SYS_PKEY_FREE: usize = 290;
+pub const SYS_OPENAT2: usize = 437;
 pub const SYS_SYSRISCV: usize = SYS_ARCH_SPECIFIC_SYSCALL;
 pub const SYS_RISCV_FLUSH_ICACHE: usize = SYS_SYSRISCV + 15;
 
@@ -27,7 +27,7 @@ static mut CPUS: [Option<Cpu>; MAX_CPU_NUM] = [
 pub struct Cpu {
     gdt: GlobalDescriptorTable,
     tss: TaskStateSegment,
-    double_fault_stack: [u8; 0x100],
+    double_fault_stack: [u8; DOUBLE_FAULT_STACK_SIZE],
     preemption_disabled: AtomicBool, // TODO: check this on timer(). This is currently unavailable since related code is in rcore_thread.
     ipi_handler_queue: Mutex<Vec<IPIEventItem>>,
     id: usize,
@@ -42,7 +42,7 @@ impl Cpu {
         Cpu {
             gdt: GlobalDescriptorTable::new(),
             tss: TaskStateSegment::new(),
-            double_fault_stack: [0u8; 0x100],
+            double_fault_stack: [0u8; DOUBLE_FAULT_STACK_SIZE],
             preemption_disabled: AtomicBool::new(false),
             ipi_handler_queue: Mutex::new(vec![]),
             id: 0,
@@ -81,7 +81,8 @@ impl Cpu {
         use x86_64::instructions::tables::load_tss;
 
         // Set the stack when DoubleFault occurs
-        let stack_top = VirtAddr::new(self.double_fault_stack.as_ptr() as u64 + 0x100);
+        let stack_top =
+            VirtAddr::new(self.double_fault_stack.as_ptr() as u64 + DOUBLE_FAULT_STACK_SIZE as u64);
         self.tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] = stack_top;
 
         // GDT
@@ -113,6 +114,8 @@ impl Cpu {
 }
 
 pub const DOUBLE_FAULT_IST_INDEX: usize = 0;
+/// Large enough to report a kernel stack overflow
+const DOUBLE_FAULT_STACK_SIZE: usize = 0x2000;
 
 // Copied from xv6 x86_64
 const KCODE: Descriptor = Descriptor::UserSegment(0x0020980000000000); // EXECUTABLE | USER_SEGMENT | PRESENT | LONG_MODE
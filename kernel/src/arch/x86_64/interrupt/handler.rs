@@ -110,6 +110,8 @@ pub extern "C" fn trap_handler(tf: &mut TrapFrame) {
 }
 
 fn double_fault(tf: &TrapFrame) {
+    // a page fault on the guard page of a kernel stack can't push the trap frame
+    crate::memory::check_kernel_stack_guard(Cr2::read().as_u64() as usize);
     error!("\nEXCEPTION: Double Fault\n{:#x?}", tf);
     loop {}
 }
@@ -1,9 +1,11 @@
+use super::consts::PHYSICAL_MEMORY_PM4;
 use super::paging::PageTableImpl;
-use crate::memory::FRAME_ALLOCATOR;
-use bitmap_allocator::BitAlloc;
+use crate::memory::phys_to_virt;
 use rboot::{BootInfo, MemoryType};
 use rcore_memory::paging::*;
-use rcore_memory::PAGE_SIZE;
+use rcore_memory::{GIGANTIC_PAGE_SIZE, HUGE_PAGE_SIZE, PAGE_SIZE};
+use x86_64::instructions::tlb;
+use x86_64::structures::paging::PageTable as x86PageTable;
 use x86_64::{
     registers::control::{Cr2, Cr3, Cr3Flags},
     structures::paging::PhysFrame,
@@ -12,19 +14,59 @@ use x86_64::{
 
 pub fn init(boot_info: &BootInfo) {
     init_frame_allocator(boot_info);
+    init_physical_memory_map(boot_info);
     info!("memory: init end");
 }
 
 /// Init FrameAllocator and insert all 'Usable' regions from BootInfo.
 fn init_frame_allocator(boot_info: &BootInfo) {
-    let mut ba = FRAME_ALLOCATOR.lock();
-    for region in boot_info.memory_map.clone().iter {
-        if region.ty == MemoryType::CONVENTIONAL {
-            let start_frame = region.phys_start as usize / PAGE_SIZE;
-            let end_frame = start_frame + region.page_count as usize;
-            ba.insert(start_frame..end_frame);
+    let regions = || {
+        boot_info
+            .memory_map
+            .clone()
+            .iter
+            .filter(|region| region.ty == MemoryType::CONVENTIONAL)
+            .map(|region| {
+                let start_frame = region.phys_start as usize / PAGE_SIZE;
+                let end_frame = start_frame + region.page_count as usize;
+                start_frame..end_frame
+            })
+    };
+    crate::memory::init_frame_allocator(regions);
+}
+
+/// Remap the physical memory at `PHYSICAL_MEMORY_OFFSET` with huge pages,
+/// instead of the 4 KiB pages mapped by the bootloader.
+fn init_physical_memory_map(boot_info: &BootInfo) {
+    // MMIO regions such as local APIC are below 4 GiB
+    let end = boot_info
+        .memory_map
+        .clone()
+        .iter
+        .map(|region| region.phys_start as usize + region.page_count as usize * PAGE_SIZE)
+        .max()
+        .unwrap_or(0)
+        .max(1 << 32);
+    // build the mapping in a new table, then install it in the active one
+    let mut page_table = PageTableImpl::new_bare();
+    for size in [GIGANTIC_PAGE_SIZE, HUGE_PAGE_SIZE].iter().cloned() {
+        let mapped = (0..end).step_by(size).all(|paddr| {
+            page_table
+                .map_huge(phys_to_virt(paddr), paddr, size)
+                .is_some()
+        });
+        if mapped {
+            info!("memory: physical memory mapped with {:#x} byte pages", size);
+            let table = unsafe { &mut *(phys_to_virt(page_table.token()) as *mut x86PageTable) };
+            let entry = table[PHYSICAL_MEMORY_PM4].clone();
+            let active =
+                unsafe { &mut *(phys_to_virt(PageTableImpl::active_token()) as *mut x86PageTable) };
+            active[PHYSICAL_MEMORY_PM4].set_addr(entry.addr(), entry.flags());
+            tlb::flush_all();
+            return;
         }
     }
+    warn!("memory: huge pages not supported, keep the physical memory map of the bootloader");
 }
 
 /// The method for initializing kernel virtual memory space, a memory space of 512 GiB.
@@ -3,12 +3,13 @@ use crate::memory::{alloc_frame, dealloc_frame, phys_to_virt};
 use core::mem::ManuallyDrop;
 use log::*;
 use rcore_memory::paging::*;
+use rcore_memory::{GIGANTIC_PAGE_SIZE, HUGE_PAGE_SIZE, PAGE_SIZE};
 use x86_64::instructions::tlb;
 use x86_64::registers::control::{Cr3, Cr3Flags};
 use x86_64::structures::paging::{
     frame::PhysFrame as Frame,
-    mapper::{MappedPageTable, Mapper},
-    page::{Page, PageRange, Size4KiB},
+    mapper::{MappedPageTable, Mapper, MapperAllSizes},
+    page::{Page, PageRange, PageSize, Size1GiB, Size2MiB, Size4KiB},
     page_table::{PageTable as x86PageTable, PageTableEntry, PageTableFlags as EF},
     FrameAllocator, FrameDeallocator,
 };
@@ -65,31 +66,72 @@ impl PageTable for PageTableImpl {
     }
 
     fn unmap(&mut self, addr: usize) {
-        self.0.unmap(Page::of_addr(addr)).unwrap().1.flush();
+        let vaddr = VirtAddr::new(addr as u64);
+        match self.page_size(addr) {
+            GIGANTIC_PAGE_SIZE => {
+                let page = Page::<Size1GiB>::containing_address(vaddr);
+                self.0.unmap(page).unwrap().1.flush();
+            }
+            HUGE_PAGE_SIZE => {
+                let page = Page::<Size2MiB>::containing_address(vaddr);
+                self.0.unmap(page).unwrap().1.flush();
+            }
+            _ => self.0.unmap(Page::of_addr(addr)).unwrap().1.flush(),
+        }
         flush_tlb_all(addr);
     }
 
     fn get_entry(&mut self, addr: usize) -> Option<&mut dyn Entry> {
-        let mut page_table = frame_to_page_table(self.2);
-        for level in 0..4 {
-            let index = (addr >> (12 + (3 - level) * 9)) & 0o777;
-            let entry = unsafe { &mut (&mut *page_table)[index] };
-            if level == 3 {
-                let page = Page::of_addr(addr);
-                self.1 = Some(PageEntry(entry, page, self.2));
-                return Some(self.1.as_mut().unwrap());
-            }
-            if !entry.flags().contains(EF::PRESENT) {
-                return None;
+        let (entry, _) = walk(self.2, addr)?;
+        let page = Page::of_addr(addr);
+        self.1 = Some(PageEntry(entry, page, self.2));
+        Some(self.1.as_mut().unwrap())
+    }
+
+    fn map_huge(&mut self, addr: usize, target: usize, size: usize) -> Option<&mut dyn Entry> {
+        let flags = EF::PRESENT | EF::WRITABLE | EF::NO_EXECUTE;
+        let vaddr = VirtAddr::new(addr as u64);
+        let paddr = PhysAddr::new(target as u64);
+        unsafe {
+            match size {
+                GIGANTIC_PAGE_SIZE if gigantic_page_supported() => self
+                    .0
+                    .map_to(
+                        Page::<Size1GiB>::containing_address(vaddr),
+                        Frame::<Size1GiB>::containing_address(paddr),
+                        flags,
+                        &mut FrameAllocatorForX86,
+                    )
+                    .ok()?
+                    .flush(),
+                HUGE_PAGE_SIZE => self
+                    .0
+                    .map_to(
+                        Page::<Size2MiB>::containing_address(vaddr),
+                        Frame::<Size2MiB>::containing_address(paddr),
+                        flags,
+                        &mut FrameAllocatorForX86,
+                    )
+                    .ok()?
+                    .flush(),
+                _ => return None,
             }
-            page_table = frame_to_page_table(entry.frame().unwrap());
         }
-        unreachable!();
+        flush_tlb_all(addr);
+        self.get_entry(addr)
+    }
+
+    fn page_size(&mut self, addr: usize) -> usize {
+        match walk(self.2, addr) {
+            Some((_, size)) => size,
+            None => PAGE_SIZE,
+        }
     }
 
     fn get_page_slice_mut<'a>(&mut self, addr: usize) -> &'a mut [u8] {
-        let frame = self.0.translate_page(Page::of_addr(addr)).unwrap();
-        let vaddr = phys_to_virt(frame.start_address().as_u64() as usize);
+        let addr = addr & !(PAGE_SIZE - 1);
+        let paddr = self.0.translate_addr(VirtAddr::new(addr as u64)).unwrap();
+        let vaddr = phys_to_virt(paddr.as_u64() as usize);
         unsafe { core::slice::from_raw_parts_mut(vaddr as *mut u8, 0x1000) }
     }
 
@@ -101,6 +143,39 @@ fn frame_to_page_table(frame: Frame) -> *mut x86PageTable {
     vaddr as *mut x86PageTable
 }
 
+/// Find the last level entry of `addr` in the page table at `root`,
+/// or the entry of the huge page containing it, along with the page size
+fn walk(root: Frame, addr: usize) -> Option<(&'static mut PageTableEntry, usize)> {
+    let mut page_table = frame_to_page_table(root);
+    for level in 0..4 {
+        let index = (addr >> (12 + (3 - level) * 9)) & 0o777;
+        let entry = unsafe { &mut (&mut *page_table)[index] };
+        if level == 3 {
+            return Some((entry, Size4KiB::SIZE as usize));
+        }
+        if !entry.flags().contains(EF::PRESENT) {
+            return None;
+        }
+        if entry.flags().contains(EF::HUGE_PAGE) {
+            let size = if level == 1 {
+                Size1GiB::SIZE
+            } else {
+                Size2MiB::SIZE
+            };
+            return Some((entry, size as usize));
+        }
+        page_table = frame_to_page_table(entry.frame().unwrap());
+    }
+    unreachable!();
+}
+
+/// Whether 1 GiB pages are supported by the CPU
+fn gigantic_page_supported() -> bool {
+    raw_cpuid::CpuId::new()
+        .get_extended_function_info()
+        .map_or(false, |info| info.has_1gib_pages())
+}
+
 impl Entry for PageEntry {
     fn update(&mut self) {
         use x86_64::instructions::tlb::flush;
@@ -171,7 +246,7 @@ impl Entry for PageEntry {
                     (self.1.start_address().as_u64() as usize >> (12 + (3 - level) * 9)) & 0o777;
                 let entry = unsafe { &mut (&mut *page_table)[index] };
                 entry.set_flags(entry.flags() | EF::USER_ACCESSIBLE);
-                if level == 3 {
+                if level == 3 || entry.flags().contains(EF::HUGE_PAGE) {
                     return;
                 }
                 page_table = frame_to_page_table(entry.frame().unwrap());
@@ -336,6 +336,7 @@ pub const SYS_PKEY_FREE: usize = 331;
 pub const SYS_STATX: usize = 332;
 pub const SYS_IO_PGETEVENTS: usize = 333;
 pub const SYS_RSEQ: usize = 334;
+pub const SYS_OPENAT2: usize = 437;
 
 // custom temporary syscall
 pub const SYS_MAP_PCI_DEVICE: usize = 999;
@@ -56,48 +56,29 @@ pub fn lr() -> usize {
     ptr
 }
 
-// Print the backtrace starting from the caller
-pub fn backtrace() {
+/// Walk the stack from the caller of `walk`,
+/// calling `f` with the PC and FP of each frame until it returns false.
+#[inline(never)]
+fn walk(f: &mut dyn FnMut(usize, usize) -> bool) {
     unsafe {
         let mut current_pc = lr();
         let mut current_fp = fp();
-        let mut stack_num = 0;
 
-        // adjust sp to the top address of backtrace() function
+        // adjust sp to the top address of walk() function
         #[cfg(target_arch = "mips")]
         {
-            let func_base = backtrace as *const isize;
+            let func_base = walk as *const isize;
             let sp_offset = (*func_base << 16) >> 16;
             current_fp = ((current_fp as isize) - sp_offset) as usize;
         }
 
-        println!("=== BEGIN rCore stack trace ===");
-
         while current_pc >= stext as usize
             && current_pc <= etext as usize
             && current_fp as usize != 0
         {
-            // print current backtrace
-            match size_of::<usize>() {
-                4 => {
-                    println!(
-                        "#{:02} PC: {:#010X} FP: {:#010X}",
-                        stack_num,
-                        current_pc - size_of::<usize>(),
-                        current_fp
-                    );
-                }
-                _ => {
-                    println!(
-                        "#{:02} PC: {:#018X} FP: {:#018X}",
-                        stack_num,
-                        current_pc - size_of::<usize>(),
-                        current_fp
-                    );
-                }
+            if !f(current_pc - size_of::<usize>(), current_fp) {
+                break;
             }
-
-            stack_num = stack_num + 1;
             #[cfg(riscv)]
             {
                 current_fp = *(current_fp as *const usize).offset(-2);
@@ -174,6 +155,46 @@ pub fn backtrace() {
                 current_pc = *(current_fp as *const usize).offset(1);
             }
         }
-        println!("=== END rCore stack trace ===");
+    }
+}
+
+fn print_frame(num: usize, pc: usize, fp: usize) {
+    match size_of::<usize>() {
+        4 => println!("#{:02} PC: {:#010X} FP: {:#010X}", num, pc, fp),
+        _ => println!("#{:02} PC: {:#018X} FP: {:#018X}", num, pc, fp),
+    }
+}
+
+// Print the backtrace starting from the caller
+pub fn backtrace() {
+    println!("=== BEGIN rCore stack trace ===");
+    let mut stack_num = 0;
+    walk(&mut |pc, fp| {
+        print_frame(stack_num, pc, fp);
+        stack_num += 1;
+        true
+    });
+    println!("=== END rCore stack trace ===");
+}
+
+/// Save the PCs of the backtrace starting from the caller in `pcs`,
+/// return the number of frames saved.
+pub fn capture(pcs: &mut [usize]) -> usize {
+    let mut count = 0;
+    walk(&mut |pc, _| {
+        if count == pcs.len() {
+            return false;
+        }
+        pcs[count] = pc;
+        count += 1;
+        true
+    });
+    count
+}
+
+/// Print PCs saved by `capture`
+pub fn print(pcs: &[usize]) {
+    for (num, &pc) in pcs.iter().enumerate() {
+        print_frame(num, pc, 0);
     }
 }
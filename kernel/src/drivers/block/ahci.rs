@@ -40,6 +40,12 @@ impl Driver for AHCIDriver {
 }
 impl BlockDriver for AHCIDriver {
     fn read_block(&self, block_id: usize, buf: &mut [u8]) -> bool {
+        if buf.len() < BLOCK_SIZE {
+            return false;
+        }
+        // copied from the DMA buffer
+        #[cfg(feature = "kasan")]
+        crate::kasan::check_write(buf.as_ptr() as usize, BLOCK_SIZE);
         let mut driver = self.0.lock();
         driver.read_block(block_id, buf);
         true
@@ -49,6 +55,9 @@ impl BlockDriver for AHCIDriver {
         if buf.len() < BLOCK_SIZE {
             return false;
         }
+        // copied to the DMA buffer
+        #[cfg(feature = "kasan")]
+        crate::kasan::check_read(buf.as_ptr() as usize, BLOCK_SIZE);
         let mut driver = self.0.lock();
         driver.write_block(block_id, buf);
         true
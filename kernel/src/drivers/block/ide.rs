@@ -43,6 +43,11 @@ impl Driver for IDEDriver {
 
 impl BlockDriver for IDEDriver {
     fn read_block(&self, block_id: usize, buf: &mut [u8]) -> bool {
+        if buf.len() < BLOCK_SIZE {
+            return false;
+        }
+        #[cfg(feature = "kasan")]
+        crate::kasan::check_write(buf.as_ptr() as usize, BLOCK_SIZE);
         let mut driver = self.0.lock();
         let buf = unsafe { slice::from_raw_parts_mut(buf.as_ptr() as *mut u32, BLOCK_SIZE / 4) };
         driver.read(block_id as u64, 1, buf).is_ok()
@@ -52,6 +57,8 @@ impl BlockDriver for IDEDriver {
         if buf.len() < BLOCK_SIZE {
             return false;
         }
+        #[cfg(feature = "kasan")]
+        crate::kasan::check_read(buf.as_ptr() as usize, BLOCK_SIZE);
         let mut driver = self.0.lock();
         let buf = unsafe { slice::from_raw_parts(buf.as_ptr() as *mut u32, BLOCK_SIZE / 4) };
         driver.write(block_id as u64, 1, buf).is_ok()
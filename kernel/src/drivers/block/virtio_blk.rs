@@ -35,10 +35,16 @@ impl Driver for VirtIOBlkDriver {
 
 impl BlockDriver for VirtIOBlkDriver {
     fn read_block(&self, block_id: usize, buf: &mut [u8]) -> bool {
+        // copied from the DMA buffer
+        #[cfg(feature = "kasan")]
+        crate::kasan::check_write(buf.as_ptr() as usize, buf.len());
         self.0.lock().read_block(block_id, buf).is_ok()
     }
 
     fn write_block(&self, block_id: usize, buf: &[u8]) -> bool {
+        // copied to the DMA buffer
+        #[cfg(feature = "kasan")]
+        crate::kasan::check_read(buf.as_ptr() as usize, buf.len());
         self.0.lock().write_block(block_id, buf).is_ok()
     }
 }
@@ -4,12 +4,19 @@ use super::serial::uart16550;
 use super::CMDLINE;
 use crate::memory::phys_to_virt;
 use alloc::{collections::BTreeMap, string::String, sync::Arc};
+use core::ops::Range;
 use core::slice;
 use device_tree::{DeviceTree, Node};
 use spin::RwLock;
 
 const DEVICE_TREE_MAGIC: u32 = 0xd00dfeed;
 
+/// Tokens of the structure block
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+
 lazy_static! {
     /// Compatible lookup
     pub static ref DEVICE_TREE_REGISTRY: RwLock<BTreeMap<&'static str, fn(&Node)>> =
@@ -57,3 +64,62 @@ pub fn init(dtb: usize) {
         }
     }
 }
+
+/// The physical memory of the initial ramdisk given in `/chosen` by the bootloader.
+/// It's needed before the heap is ready, so it walks the structure block by hand.
+pub fn initrd(dtb: usize) -> Option<Range<usize>> {
+    let be32 = |offset: usize| u32::from_be(unsafe { *((dtb + offset) as *const u32) });
+    let cstr = |addr: usize| unsafe {
+        let len = (0..).find(|&i| *((addr + i) as *const u8) == 0).unwrap();
+        slice::from_raw_parts(addr as *const u8, len)
+    };
+    if be32(0) != DEVICE_TREE_MAGIC {
+        return None;
+    }
+    let strings = dtb + be32(12) as usize;
+    let mut offset = be32(8) as usize;
+    let mut depth = 0;
+    let mut in_chosen = false;
+    let (mut start, mut end) = (None, None);
+    loop {
+        let token = be32(offset);
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = cstr(dtb + offset);
+                offset += (name.len() + 4) & !3;
+                depth += 1;
+                // the root is at depth 1
+                if depth == 2 {
+                    in_chosen = name == b"chosen";
+                }
+            }
+            FDT_END_NODE => depth -= 1,
+            FDT_PROP => {
+                let len = be32(offset) as usize;
+                let name = cstr(strings + be32(offset + 4) as usize);
+                let value = offset + 8;
+                offset = value + ((len + 3) & !3);
+                if !in_chosen || depth != 2 {
+                    continue;
+                }
+                let number = match len {
+                    4 => be32(value) as u64,
+                    8 => (be32(value) as u64) << 32 | be32(value + 4) as u64,
+                    _ => continue,
+                };
+                match name {
+                    b"linux,initrd-start" => start = Some(number as usize),
+                    b"linux,initrd-end" => end = Some(number as usize),
+                    _ => {}
+                }
+            }
+            FDT_NOP => {}
+            _ => break,
+        }
+    }
+    match (start, end) {
+        (Some(start), Some(end)) if start < end => Some(start..end),
+        _ => None,
+    }
+}
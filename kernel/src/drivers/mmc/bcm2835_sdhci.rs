@@ -1549,6 +1549,8 @@ impl BlockDriver for SDHCIDriver {
         if buf.len() < BLOCK_SIZE {
             return false;
         }
+        #[cfg(feature = "kasan")]
+        crate::kasan::check_write(buf.as_ptr() as usize, BLOCK_SIZE);
         let buf = unsafe { slice::from_raw_parts_mut(buf.as_ptr() as *mut u32, BLOCK_SIZE / 4) };
         self.0.lock().read_block(block_id as u32, 1, buf).is_ok()
     }
@@ -1557,6 +1559,8 @@ impl BlockDriver for SDHCIDriver {
         if buf.len() < BLOCK_SIZE {
             return false;
         }
+        #[cfg(feature = "kasan")]
+        crate::kasan::check_read(buf.as_ptr() as usize, BLOCK_SIZE);
         let buf = unsafe { slice::from_raw_parts(buf.as_ptr() as *mut u32, BLOCK_SIZE / 4) };
         self.0.lock().write_block(block_id as u32, 1, buf).is_ok()
     }
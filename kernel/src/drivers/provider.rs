@@ -1,5 +1,5 @@
 pub use crate::arch::paging::PageTableImpl;
-use crate::memory::{alloc_frame_contiguous, dealloc_frame, phys_to_virt, virt_to_phys};
+use crate::memory::{alloc_frame_contiguous_in, dealloc_frame, phys_to_virt, virt_to_phys, Zone};
 use isomorphic_drivers::provider;
 use rcore_memory::PAGE_SIZE;
 
@@ -24,7 +24,8 @@ impl provider::Provider for Provider {
 
 #[no_mangle]
 extern "C" fn virtio_dma_alloc(pages: usize) -> PhysAddr {
-    let paddr = alloc_frame_contiguous(pages, 0).unwrap();
+    // devices may only address 32 bits
+    let paddr = alloc_frame_contiguous_in(pages, 0, Zone::Dma32).unwrap();
     trace!("alloc DMA: paddr={:#x}, pages={}", paddr, pages);
     paddr
 }
@@ -0,0 +1,690 @@
+//! Files and directories of ext2
+
+use super::structs::*;
+use super::Ext2FileSystem;
+use crate::syscall::TimeSpec;
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::any::Any;
+use rcore_fs::vfs::*;
+use spin::RwLock;
+
+pub struct Ext2INode {
+    id: u32,
+    disk: RwLock<DiskINode>,
+    fs: Arc<Ext2FileSystem>,
+}
+
+impl Ext2INode {
+    pub(super) fn new(id: u32, disk: DiskINode, fs: Arc<Ext2FileSystem>) -> Self {
+        Ext2INode {
+            id,
+            disk: RwLock::new(disk),
+            fs,
+        }
+    }
+
+    /// The block group of this inode, where its blocks are preferably allocated
+    fn group(&self) -> usize {
+        (self.id - 1) as usize / self.fs.inodes_per_group as usize
+    }
+
+    /// 512B sectors in a block, the unit of `DiskINode::blocks`
+    fn sectors_per_block(&self) -> u32 {
+        (self.fs.block_size / 512) as u32
+    }
+
+    /// The slot in the inode and the index in each level of indirect blocks
+    /// leading to the `index`th block of the file
+    fn block_path(&self, index: usize) -> Result<(usize, Vec<usize>)> {
+        if index < NDIR_BLOCKS {
+            return Ok((index, Vec::new()));
+        }
+        let ptrs = self.fs.block_size / 4;
+        let mut index = index - NDIR_BLOCKS;
+        let mut span = ptrs;
+        for level in 1..=3 {
+            if index < span {
+                let mut path = vec![0; level];
+                for i in (0..level).rev() {
+                    path[i] = index % ptrs;
+                    index /= ptrs;
+                }
+                return Ok((NDIR_BLOCKS + level - 1, path));
+            }
+            index -= span;
+            if level < 3 {
+                span *= ptrs;
+            }
+        }
+        Err(FsError::InvalidParam)
+    }
+
+    /// The block holding the `index`th block of the file, 0 for a hole
+    fn get_block(&self, disk: &DiskINode, index: usize) -> Result<u32> {
+        let (slot, path) = self.block_path(index)?;
+        let mut block = disk.block[slot];
+        for &i in path.iter() {
+            if block == 0 {
+                break;
+            }
+            block = self.fs.read_ptr(block, i)?;
+        }
+        Ok(block)
+    }
+
+    /// The block holding the `index`th block of the file, allocating it and
+    /// indirect blocks on the way if missing
+    fn get_or_alloc_block(&self, disk: &mut DiskINode, index: usize) -> Result<u32> {
+        let (slot, path) = self.block_path(index)?;
+        if disk.block[slot] == 0 {
+            disk.block[slot] = self.fs.alloc_block(self.group())?;
+            disk.blocks += self.sectors_per_block();
+        }
+        let mut block = disk.block[slot];
+        for &i in path.iter() {
+            let mut next = self.fs.read_ptr(block, i)?;
+            if next == 0 {
+                next = self.fs.alloc_block(self.group())?;
+                self.fs.write_ptr(block, i, next)?;
+                disk.blocks += self.sectors_per_block();
+            }
+            block = next;
+        }
+        Ok(block)
+    }
+
+    /// Free the blocks from the `from`th block of the file in the tree under `block`,
+    /// which has `level` levels of indirect blocks and starts at the `base`th block.
+    /// Returns the number of blocks freed.
+    fn free_tree(&self, block: u32, level: u32, base: usize, from: usize) -> Result<u32> {
+        if block == 0 {
+            return Ok(0);
+        }
+        let mut freed = 0;
+        if level > 0 {
+            let ptrs = self.fs.block_size / 4;
+            let span = ptrs.pow(level - 1);
+            let mut buf = vec![0u8; self.fs.block_size];
+            let offset = block as usize * self.fs.block_size;
+            self.fs.read(offset, &mut buf)?;
+            for i in 0..ptrs {
+                let child_base = base + i * span;
+                let child = read_u32(&buf, i * 4);
+                if child_base + span <= from || child == 0 {
+                    continue;
+                }
+                freed += self.free_tree(child, level - 1, child_base, from)?;
+                if child_base >= from {
+                    write_u32(&mut buf, i * 4, 0);
+                }
+            }
+            if base < from {
+                // partly kept
+                self.fs.write(offset, &buf)?;
+            }
+        }
+        if base >= from {
+            self.fs.free_block(block)?;
+            freed += 1;
+        }
+        Ok(freed)
+    }
+
+    /// Free the blocks from the `from`th block of the file
+    fn free_blocks(&self, disk: &mut DiskINode, from: usize) -> Result<()> {
+        let mut freed = 0;
+        for i in from.min(NDIR_BLOCKS)..NDIR_BLOCKS {
+            if disk.block[i] != 0 {
+                self.fs.free_block(disk.block[i])?;
+                disk.block[i] = 0;
+                freed += 1;
+            }
+        }
+        let ptrs = self.fs.block_size / 4;
+        let mut base = NDIR_BLOCKS;
+        for level in 1..=3 {
+            let slot = NDIR_BLOCKS + level - 1;
+            freed += self.free_tree(disk.block[slot], level as u32, base, from)?;
+            if base >= from {
+                disk.block[slot] = 0;
+            }
+            if level < 3 {
+                base += ptrs.pow(level as u32);
+            }
+        }
+        disk.blocks -= freed * self.sectors_per_block();
+        Ok(())
+    }
+
+    /// Change the size of a regular file
+    fn truncate(&self, disk: &mut DiskINode, len: usize) -> Result<()> {
+        let block_size = self.fs.block_size;
+        if len < disk.size as usize {
+            self.free_blocks(disk, (len + block_size - 1) / block_size)?;
+            if len % block_size != 0 {
+                // zero the tail of the last block, which is read back if the file grows again
+                let block = self.get_block(disk, len / block_size)?;
+                if block != 0 {
+                    let zeros = vec![0u8; block_size - len % block_size];
+                    self.fs
+                        .write(block as usize * block_size + len % block_size, &zeros)?;
+                }
+            }
+        }
+        disk.size = len as u64;
+        Ok(())
+    }
+
+    fn read_data(&self, disk: &DiskINode, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let size = disk.size as usize;
+        if offset >= size {
+            return Ok(0);
+        }
+        let end = size.min(offset + buf.len());
+        let block_size = self.fs.block_size;
+        let mut pos = offset;
+        while pos < end {
+            let len = (pos / block_size + 1) * block_size;
+            let len = len.min(end) - pos;
+            let dst = &mut buf[pos - offset..pos - offset + len];
+            match self.get_block(disk, pos / block_size)? {
+                0 => dst.iter_mut().for_each(|x| *x = 0),
+                block => self
+                    .fs
+                    .read(block as usize * block_size + pos % block_size, dst)?,
+            }
+            pos += len;
+        }
+        Ok(end - offset)
+    }
+
+    fn write_data(&self, disk: &mut DiskINode, offset: usize, buf: &[u8]) -> Result<usize> {
+        let end = offset + buf.len();
+        let block_size = self.fs.block_size;
+        let mut pos = offset;
+        while pos < end {
+            let len = (pos / block_size + 1) * block_size;
+            let len = len.min(end) - pos;
+            let block = self.get_or_alloc_block(disk, pos / block_size)?;
+            self.fs.write(
+                block as usize * block_size + pos % block_size,
+                &buf[pos - offset..pos - offset + len],
+            )?;
+            pos += len;
+            if pos as u64 > disk.size {
+                disk.size = pos as u64;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    /// Read the whole directory and parse its entries, including free ones
+    fn dir_entries(&self, disk: &DiskINode) -> Result<(Vec<u8>, Vec<DirEntry>)> {
+        let mut data = vec![0u8; disk.size as usize];
+        self.read_data(disk, 0, &mut data)?;
+        match parse_dir_entries(&data) {
+            Ok(entries) => Ok((data, entries)),
+            Err(offset) => {
+                warn!("ext2: broken entry in directory {} at {}", self.id, offset);
+                Err(FsError::DeviceError)
+            }
+        }
+    }
+
+    fn find_entry(&self, disk: &DiskINode, name: &str) -> Result<Option<u32>> {
+        let (_, entries) = self.dir_entries(disk)?;
+        Ok(entries
+            .iter()
+            .find(|entry| entry.inode != 0 && entry.name == name)
+            .map(|entry| entry.inode))
+    }
+
+    fn add_entry(&self, disk: &mut DiskINode, name: &str, ino: u32, type_: FileType) -> Result<()> {
+        let needed = dirent_size(name.len());
+        let (mut data, entries) = self.dir_entries(disk)?;
+        for entry in entries.iter() {
+            let used = match entry.inode {
+                0 => 0,
+                _ => dirent_size(entry.name.len()),
+            };
+            if entry.rec_len < used + needed {
+                continue;
+            }
+            let start = entry.offset;
+            if used != 0 {
+                // split the free space at the end of the entry
+                write_u16(&mut data, start + 4, used as u16);
+            }
+            let rec_len = entry.rec_len - used;
+            self.fs
+                .write_dirent(&mut data[start + used..], ino, rec_len, name, type_);
+            self.write_data(disk, start, &data[start..start + entry.rec_len])?;
+            return Ok(());
+        }
+        // append a block for the entry
+        let block_size = self.fs.block_size;
+        let mut block = vec![0u8; block_size];
+        self.fs
+            .write_dirent(&mut block, ino, block_size, name, type_);
+        let end = disk.size as usize;
+        self.write_data(disk, end, &block)?;
+        Ok(())
+    }
+
+    /// Remove the entry `name`, returning the inode it refers to
+    fn remove_entry(&self, disk: &mut DiskINode, name: &str) -> Result<u32> {
+        let (mut data, entries) = self.dir_entries(disk)?;
+        let index = entries
+            .iter()
+            .position(|entry| entry.inode != 0 && entry.name == name)
+            .ok_or(FsError::EntryNotFound)?;
+        let entry = &entries[index];
+        if entry.offset % self.fs.block_size == 0 {
+            // the first entry of a block is kept as a free entry
+            write_u32(&mut data, entry.offset, 0);
+            self.write_data(disk, entry.offset, &data[entry.offset..entry.offset + 8])?;
+        } else {
+            // merge into the previous entry of the same block
+            let prev = &entries[index - 1];
+            write_u16(
+                &mut data,
+                prev.offset + 4,
+                (prev.rec_len + entry.rec_len) as u16,
+            );
+            self.write_data(disk, prev.offset, &data[prev.offset..prev.offset + 8])?;
+        }
+        Ok(entry.inode)
+    }
+
+    /// Point the entry `name` to `ino`
+    fn set_entry(&self, disk: &mut DiskINode, name: &str, ino: u32) -> Result<()> {
+        let (_, entries) = self.dir_entries(disk)?;
+        let entry = entries
+            .iter()
+            .find(|entry| entry.inode != 0 && entry.name == name)
+            .ok_or(FsError::EntryNotFound)?;
+        self.write_data(disk, entry.offset, &ino.to_le_bytes())?;
+        Ok(())
+    }
+
+    fn is_empty_dir(&self, disk: &DiskINode) -> Result<bool> {
+        let (_, entries) = self.dir_entries(disk)?;
+        Ok(entries
+            .iter()
+            .all(|entry| entry.inode == 0 || entry.name == "." || entry.name == ".."))
+    }
+
+    fn parent(&self) -> Result<u32> {
+        let disk = self.disk.read();
+        self.find_entry(&disk, "..")?.ok_or(FsError::EntryNotFound)
+    }
+
+    fn write_back(&self, disk: &mut DiskINode) -> Result<()> {
+        self.fs.write_disk_inode(self.id, disk)
+    }
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN || name.contains('/') {
+        return Err(FsError::InvalidParam);
+    }
+    Ok(())
+}
+
+fn check_dir(disk: &DiskINode) -> Result<()> {
+    if disk.type_() != FileType::Dir {
+        return Err(FsError::NotDir);
+    }
+    if disk.links_count == 0 {
+        return Err(FsError::DirRemoved);
+    }
+    Ok(())
+}
+
+impl INode for Ext2INode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let disk = self.disk.read();
+        if disk.type_() == FileType::Dir {
+            return Err(FsError::IsDir);
+        }
+        if disk.is_fast_symlink() {
+            let data = disk.inline_data();
+            let size = (disk.size as usize).min(FAST_SYMLINK_SIZE);
+            if offset >= size {
+                return Ok(0);
+            }
+            let len = buf.len().min(size - offset);
+            buf[..len].copy_from_slice(&data[offset..offset + len]);
+            return Ok(len);
+        }
+        self.read_data(&disk, offset, buf)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.fs.check_writable()?;
+        let mut disk = self.disk.write();
+        if disk.type_() == FileType::Dir {
+            return Err(FsError::IsDir);
+        }
+        if disk.is_fast_symlink() {
+            let end = offset + buf.len();
+            let size = disk.size as usize;
+            let mut data = disk.inline_data();
+            if end <= FAST_SYMLINK_SIZE {
+                data[offset..end].copy_from_slice(buf);
+                disk.set_inline_data(&data);
+                disk.size = size.max(end) as u64;
+                self.write_back(&mut disk)?;
+                return Ok(buf.len());
+            }
+            // too long to keep inline, move to a block
+            disk.block = [0; N_BLOCKS];
+            disk.size = 0;
+            self.write_data(&mut disk, 0, &data[..size])?;
+        }
+        let len = self.write_data(&mut disk, offset, buf)?;
+        self.write_back(&mut disk)?;
+        Ok(len)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: true,
+            write: true,
+            error: false,
+        })
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        let disk = self.disk.read();
+        let type_ = disk.type_();
+        let time = |sec: u32| Timespec {
+            sec: sec as i64,
+            nsec: 0,
+        };
+        Ok(Metadata {
+            dev: self.fs.dev,
+            inode: self.id as usize,
+            size: disk.size as usize,
+            blk_size: self.fs.block_size,
+            blocks: disk.blocks as usize,
+            atime: time(disk.atime),
+            mtime: time(disk.mtime),
+            ctime: time(disk.ctime),
+            type_,
+            mode: disk.mode & 0o7777,
+            nlinks: disk.links_count as usize,
+            uid: disk.uid as usize,
+            gid: disk.gid as usize,
+            rdev: match type_ {
+                // device numbers are kept in the first block pointer
+                FileType::CharDevice | FileType::BlockDevice => disk.block[0] as usize,
+                _ => 0,
+            },
+        })
+    }
+
+    fn set_metadata(&self, metadata: &Metadata) -> Result<()> {
+        self.fs.check_writable()?;
+        let mut disk = self.disk.write();
+        disk.atime = metadata.atime.sec as u32;
+        disk.mtime = metadata.mtime.sec as u32;
+        disk.ctime = metadata.ctime.sec as u32;
+        disk.mode = (disk.mode & !0o7777) | (metadata.mode & 0o7777);
+        disk.uid = metadata.uid as u32;
+        disk.gid = metadata.gid as u32;
+        self.write_back(&mut disk)
+    }
+
+    fn sync_all(&self) -> Result<()> {
+        self.fs.sync()
+    }
+
+    fn sync_data(&self) -> Result<()> {
+        self.fs.sync()
+    }
+
+    fn resize(&self, len: usize) -> Result<()> {
+        self.fs.check_writable()?;
+        let mut disk = self.disk.write();
+        if disk.type_() != FileType::File {
+            return Err(FsError::NotFile);
+        }
+        self.truncate(&mut disk, len)?;
+        self.write_back(&mut disk)
+    }
+
+    fn create(&self, name: &str, type_: FileType, mode: u32) -> Result<Arc<dyn INode>> {
+        self.fs.check_writable()?;
+        check_name(name)?;
+        let mut disk = self.disk.write();
+        check_dir(&disk)?;
+        if self.find_entry(&disk, name)?.is_some() {
+            return Err(FsError::EntryExist);
+        }
+        let inode = self.fs.new_inode(type_, mode as u16, self.group())?;
+        {
+            let mut child = inode.disk.write();
+            let now = TimeSpec::get_epoch().sec as u32;
+            child.atime = now;
+            child.mtime = now;
+            child.ctime = now;
+            if type_ == FileType::Dir {
+                let block_size = self.fs.block_size;
+                let mut block = vec![0u8; block_size];
+                let dot_len = dirent_size(1);
+                self.fs
+                    .write_dirent(&mut block, inode.id, dot_len, ".", FileType::Dir);
+                self.fs.write_dirent(
+                    &mut block[dot_len..],
+                    self.id,
+                    block_size - dot_len,
+                    "..",
+                    FileType::Dir,
+                );
+                inode.write_data(&mut child, 0, &block)?;
+                child.links_count = 2;
+                disk.links_count += 1;
+            } else {
+                child.links_count = 1;
+            }
+            inode.write_back(&mut child)?;
+        }
+        self.add_entry(&mut disk, name, inode.id, type_)?;
+        self.write_back(&mut disk)?;
+        Ok(inode)
+    }
+
+    fn link(&self, name: &str, other: &Arc<dyn INode>) -> Result<()> {
+        self.fs.check_writable()?;
+        check_name(name)?;
+        let other = other
+            .as_any_ref()
+            .downcast_ref::<Ext2INode>()
+            .ok_or(FsError::NotSameFs)?;
+        if !Arc::ptr_eq(&self.fs, &other.fs) {
+            return Err(FsError::NotSameFs);
+        }
+        if other.id == self.id {
+            return Err(FsError::IsDir);
+        }
+        let mut disk = self.disk.write();
+        check_dir(&disk)?;
+        if self.find_entry(&disk, name)?.is_some() {
+            return Err(FsError::EntryExist);
+        }
+        let mut child = other.disk.write();
+        if child.type_() == FileType::Dir {
+            return Err(FsError::IsDir);
+        }
+        child.links_count += 1;
+        other.write_back(&mut child)?;
+        self.add_entry(&mut disk, name, other.id, child.type_())?;
+        self.write_back(&mut disk)
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        self.fs.check_writable()?;
+        if name == "." || name == ".." {
+            return Err(FsError::IsDir);
+        }
+        let mut disk = self.disk.write();
+        check_dir(&disk)?;
+        let ino = self
+            .find_entry(&disk, name)?
+            .ok_or(FsError::EntryNotFound)?;
+        let child = self.fs.get_inode(ino)?;
+        let mut child_disk = child.disk.write();
+        if child_disk.type_() == FileType::Dir {
+            if !child.is_empty_dir(&child_disk)? {
+                return Err(FsError::DirNotEmpty);
+            }
+            // the entry and its "."
+            child_disk.links_count = 0;
+            // its ".."
+            disk.links_count -= 1;
+        } else {
+            child_disk.links_count -= 1;
+        }
+        self.remove_entry(&mut disk, name)?;
+        child.write_back(&mut child_disk)?;
+        self.write_back(&mut disk)
+    }
+
+    fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> Result<()> {
+        self.fs.check_writable()?;
+        check_name(new_name)?;
+        if old_name == "." || old_name == ".." {
+            return Err(FsError::IsDir);
+        }
+        let target = target
+            .as_any_ref()
+            .downcast_ref::<Ext2INode>()
+            .ok_or(FsError::NotSameFs)?;
+        if !Arc::ptr_eq(&self.fs, &target.fs) {
+            return Err(FsError::NotSameFs);
+        }
+        let ino = {
+            let disk = self.disk.read();
+            check_dir(&disk)?;
+            self.find_entry(&disk, old_name)?
+                .ok_or(FsError::EntryNotFound)?
+        };
+        let child = self.fs.get_inode(ino)?;
+        let child_type = child.disk.read().type_();
+        if child_type == FileType::Dir {
+            // a directory can't be moved into itself
+            let mut dir = target.id;
+            while dir != ROOT_INO {
+                if dir == ino {
+                    return Err(FsError::InvalidParam);
+                }
+                dir = self.fs.get_inode(dir)?.parent()?;
+            }
+        }
+        let existing = {
+            let disk = target.disk.read();
+            check_dir(&disk)?;
+            target.find_entry(&disk, new_name)?
+        };
+        match existing {
+            Some(existing) if existing == ino => return Ok(()),
+            Some(existing) => {
+                let existing_type = self.fs.get_inode(existing)?.disk.read().type_();
+                match (child_type, existing_type) {
+                    (FileType::Dir, FileType::Dir) => {}
+                    (FileType::Dir, _) => return Err(FsError::NotDir),
+                    (_, FileType::Dir) => return Err(FsError::IsDir),
+                    _ => {}
+                }
+                target.unlink(new_name)?;
+            }
+            None => {}
+        }
+
+        if self.id == target.id {
+            let mut disk = self.disk.write();
+            self.add_entry(&mut disk, new_name, ino, child_type)?;
+            self.remove_entry(&mut disk, old_name)?;
+            return self.write_back(&mut disk);
+        }
+        // lock in the order of inode numbers
+        let (mut disk, mut target_disk) = if self.id < target.id {
+            let disk = self.disk.write();
+            (disk, target.disk.write())
+        } else {
+            let target_disk = target.disk.write();
+            (self.disk.write(), target_disk)
+        };
+        target.add_entry(&mut target_disk, new_name, ino, child_type)?;
+        self.remove_entry(&mut disk, old_name)?;
+        if child_type == FileType::Dir {
+            let mut child_disk = child.disk.write();
+            child.set_entry(&mut child_disk, "..", target.id)?;
+            child.write_back(&mut child_disk)?;
+            disk.links_count -= 1;
+            target_disk.links_count += 1;
+        }
+        target.write_back(&mut target_disk)?;
+        self.write_back(&mut disk)
+    }
+
+    fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
+        let ino = {
+            let disk = self.disk.read();
+            check_dir(&disk)?;
+            self.find_entry(&disk, name)?
+                .ok_or(FsError::EntryNotFound)?
+        };
+        Ok(self.fs.get_inode(ino)?)
+    }
+
+    fn get_entry(&self, id: usize) -> Result<String> {
+        let disk = self.disk.read();
+        if disk.type_() != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        let (_, entries) = self.dir_entries(&disk)?;
+        entries
+            .into_iter()
+            .filter(|entry| entry.inode != 0)
+            .nth(id)
+            .map(|entry| entry.name)
+            .ok_or(FsError::EntryNotFound)
+    }
+
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        self.fs.clone()
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Drop for Ext2INode {
+    /// Free the inode and its blocks once it has no links and is no longer open
+    fn drop(&mut self) {
+        let mut disk = self.disk.write();
+        if disk.links_count == 0 && !self.fs.read_only {
+            let dir = disk.type_() == FileType::Dir;
+            // without blocks in use, the pointers hold a symbolic link or device number
+            let result = if disk.blocks != 0 {
+                self.free_blocks(&mut disk, 0)
+            } else {
+                Ok(())
+            };
+            disk.size = 0;
+            // e2fsck takes a deletion time below the inode count for an orphan list link
+            disk.dtime = TimeSpec::get_epoch().sec as u32;
+            let result = result
+                .and_then(|_| self.write_back(&mut disk))
+                .and_then(|_| self.fs.free_inode(self.id, dir));
+            if let Err(e) = result {
+                warn!("ext2: failed to free inode {}: {:?}", self.id, e);
+            }
+        }
+        drop(disk);
+        self.fs.forget_inode(self.id);
+    }
+}
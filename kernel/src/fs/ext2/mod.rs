@@ -0,0 +1,384 @@
+//! ext2 file system
+//!
+//! Metadata is written through to the device as soon as it changes, so dirty data only
+//! lives in the block cache below, and `sync` just flushes the device.
+
+mod inode;
+mod structs;
+
+pub use self::inode::Ext2INode;
+
+use self::structs::*;
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use rcore_fs::dev::Device;
+use rcore_fs::vfs::*;
+use spin::{Mutex, RwLock};
+
+/// Block and inode counters, updated with the bitmaps
+struct AllocState {
+    super_block: SuperBlock,
+    groups: Vec<GroupDesc>,
+}
+
+pub struct Ext2FileSystem {
+    device: Arc<dyn Device>,
+    dev: usize,
+    block_size: usize,
+    inode_size: usize,
+    inodes_per_group: u32,
+    /// Whether directory entries record the file type
+    filetype: bool,
+    /// Writing is refused with read-only compatible features not understood here
+    read_only: bool,
+    /// First block of the inode table of each group
+    inode_tables: Vec<u32>,
+    alloc: Mutex<AllocState>,
+    /// Opened inodes, so each has one in-memory copy
+    inodes: RwLock<BTreeMap<u32, Weak<Ext2INode>>>,
+    /// The root inode as last read or written, to open it again without reading the disk
+    root: Mutex<Vec<u8>>,
+    self_ptr: RwLock<Weak<Ext2FileSystem>>,
+}
+
+impl Ext2FileSystem {
+    /// Load an ext2 file system from `device`
+    pub fn open(device: Arc<dyn Device>) -> Result<Arc<Self>> {
+        let mut raw = vec![0; SUPER_BLOCK_SIZE];
+        read_device(&*device, SUPER_BLOCK_OFFSET, &mut raw)?;
+        let super_block = SuperBlock::parse(raw).ok_or(FsError::WrongFs)?;
+        if super_block.magic != MAGIC {
+            return Err(FsError::WrongFs);
+        }
+        if super_block.feature_incompat & !FEATURE_INCOMPAT_FILETYPE != 0 {
+            warn!(
+                "ext2: unsupported incompatible features {:#x}",
+                super_block.feature_incompat
+            );
+            return Err(FsError::WrongFs);
+        }
+        let supported = FEATURE_RO_COMPAT_SPARSE_SUPER | FEATURE_RO_COMPAT_LARGE_FILE;
+        let read_only = super_block.feature_ro_compat & !supported != 0;
+        if read_only {
+            warn!(
+                "ext2: unsupported read-only compatible features {:#x}, writing is refused",
+                super_block.feature_ro_compat
+            );
+        }
+
+        let block_size = super_block.block_size();
+        let mut raw = vec![0; super_block.group_count() * GROUP_DESC_SIZE];
+        let table = (super_block.first_data_block as usize + 1) * block_size;
+        read_device(&*device, table, &mut raw)?;
+        let groups: Vec<_> = raw.chunks(GROUP_DESC_SIZE).map(GroupDesc::parse).collect();
+        info!(
+            "ext2: {} blocks of {} bytes, {} inodes, {} groups",
+            super_block.blocks_count,
+            block_size,
+            super_block.inodes_count,
+            groups.len()
+        );
+
+        let fs = Arc::new(Ext2FileSystem {
+            device,
+            dev: super::alloc_dev(),
+            block_size,
+            inode_size: super_block.inode_size as usize,
+            inodes_per_group: super_block.inodes_per_group,
+            filetype: super_block.feature_incompat & FEATURE_INCOMPAT_FILETYPE != 0,
+            read_only,
+            inode_tables: groups.iter().map(|group| group.inode_table).collect(),
+            alloc: Mutex::new(AllocState {
+                super_block,
+                groups,
+            }),
+            inodes: RwLock::new(BTreeMap::new()),
+            root: Mutex::new(Vec::new()),
+            self_ptr: RwLock::new(Weak::new()),
+        });
+        *fs.self_ptr.write() = Arc::downgrade(&fs);
+        let mut root = fs.read_disk_inode(ROOT_INO)?;
+        if root.type_() != FileType::Dir {
+            return Err(FsError::WrongFs);
+        }
+        *fs.root.lock() = root.to_bytes().to_vec();
+        Ok(fs)
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        read_device(&*self.device, offset, buf)
+    }
+
+    fn write(&self, offset: usize, buf: &[u8]) -> Result<()> {
+        match self.device.write_at(offset, buf) {
+            Ok(len) if len == buf.len() => Ok(()),
+            _ => Err(FsError::DeviceError),
+        }
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(FsError::NotSupported);
+        }
+        Ok(())
+    }
+
+    /// Read the `index`th block pointer in the indirect block `block`
+    fn read_ptr(&self, block: u32, index: usize) -> Result<u32> {
+        let mut buf = [0u8; 4];
+        self.read(block as usize * self.block_size + index * 4, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn write_ptr(&self, block: u32, index: usize, ptr: u32) -> Result<()> {
+        self.write(
+            block as usize * self.block_size + index * 4,
+            &ptr.to_le_bytes(),
+        )
+    }
+
+    fn write_super_block(&self, state: &mut AllocState) -> Result<()> {
+        self.write(SUPER_BLOCK_OFFSET, state.super_block.to_bytes())
+    }
+
+    fn write_group(&self, state: &AllocState, group: usize) -> Result<()> {
+        let table = (state.super_block.first_data_block as usize + 1) * self.block_size;
+        let offset = table + group * GROUP_DESC_SIZE;
+        let mut raw = [0u8; GROUP_DESC_SIZE];
+        self.read(offset, &mut raw)?;
+        state.groups[group].write(&mut raw);
+        self.write(offset, &raw)
+    }
+
+    /// Set the first clear bit below `count` in the bitmap `block`, returning its index
+    fn take_bit(&self, block: u32, count: usize) -> Result<Option<usize>> {
+        let mut bitmap = vec![0u8; self.block_size];
+        let offset = block as usize * self.block_size;
+        self.read(offset, &mut bitmap)?;
+        let bit = match (0..count).find(|&i| bitmap[i / 8] & (1 << (i % 8)) == 0) {
+            Some(bit) => bit,
+            None => return Ok(None),
+        };
+        bitmap[bit / 8] |= 1 << (bit % 8);
+        self.write(offset + bit / 8, &bitmap[bit / 8..bit / 8 + 1])?;
+        Ok(Some(bit))
+    }
+
+    fn clear_bit(&self, block: u32, bit: usize) -> Result<()> {
+        let offset = block as usize * self.block_size + bit / 8;
+        let mut byte = [0u8];
+        self.read(offset, &mut byte)?;
+        byte[0] &= !(1 << (bit % 8));
+        self.write(offset, &byte)
+    }
+
+    /// Allocate a zeroed block, preferring the group `goal`
+    fn alloc_block(&self, goal: usize) -> Result<u32> {
+        let mut state = self.alloc.lock();
+        let count = state.groups.len();
+        let per_group = state.super_block.blocks_per_group as usize;
+        let first = state.super_block.first_data_block as usize;
+        let blocks = state.super_block.blocks_count as usize;
+        for group in (0..count).map(|i| (goal + i) % count) {
+            if state.groups[group].free_blocks_count == 0 {
+                continue;
+            }
+            let start = first + group * per_group;
+            let bitmap = state.groups[group].block_bitmap;
+            let bit = match self.take_bit(bitmap, per_group.min(blocks - start))? {
+                Some(bit) => bit,
+                None => continue,
+            };
+            state.groups[group].free_blocks_count -= 1;
+            state.super_block.free_blocks_count -= 1;
+            self.write_group(&state, group)?;
+            self.write_super_block(&mut state)?;
+            drop(state);
+
+            let block = (start + bit) as u32;
+            let zeros = vec![0u8; self.block_size];
+            self.write(block as usize * self.block_size, &zeros)?;
+            return Ok(block);
+        }
+        Err(FsError::NoDeviceSpace)
+    }
+
+    fn free_block(&self, block: u32) -> Result<()> {
+        let mut state = self.alloc.lock();
+        let index = (block - state.super_block.first_data_block) as usize;
+        let per_group = state.super_block.blocks_per_group as usize;
+        let group = index / per_group;
+        self.clear_bit(state.groups[group].block_bitmap, index % per_group)?;
+        state.groups[group].free_blocks_count += 1;
+        state.super_block.free_blocks_count += 1;
+        self.write_group(&state, group)?;
+        self.write_super_block(&mut state)
+    }
+
+    /// Allocate an inode number, preferring the group `goal`
+    fn alloc_inode(&self, goal: usize, dir: bool) -> Result<u32> {
+        let mut state = self.alloc.lock();
+        let count = state.groups.len();
+        let per_group = self.inodes_per_group as usize;
+        let first_ino = state.super_block.first_ino;
+        for group in (0..count).map(|i| (goal + i) % count) {
+            if state.groups[group].free_inodes_count == 0 {
+                continue;
+            }
+            let bitmap = state.groups[group].inode_bitmap;
+            let bit = match self.take_bit(bitmap, per_group)? {
+                Some(bit) => bit,
+                None => continue,
+            };
+            let ino = (group * per_group + bit + 1) as u32;
+            if ino < first_ino {
+                // reserved inodes are marked in use by mke2fs, so the bitmap is broken
+                warn!("ext2: reserved inode {} is free in bitmap", ino);
+                continue;
+            }
+            state.groups[group].free_inodes_count -= 1;
+            if dir {
+                state.groups[group].used_dirs_count += 1;
+            }
+            state.super_block.free_inodes_count -= 1;
+            self.write_group(&state, group)?;
+            self.write_super_block(&mut state)?;
+            return Ok(ino);
+        }
+        Err(FsError::NoDeviceSpace)
+    }
+
+    fn free_inode(&self, ino: u32, dir: bool) -> Result<()> {
+        let mut state = self.alloc.lock();
+        let index = (ino - 1) as usize;
+        let per_group = self.inodes_per_group as usize;
+        let group = index / per_group;
+        self.clear_bit(state.groups[group].inode_bitmap, index % per_group)?;
+        state.groups[group].free_inodes_count += 1;
+        if dir {
+            state.groups[group].used_dirs_count -= 1;
+        }
+        state.super_block.free_inodes_count += 1;
+        self.write_group(&state, group)?;
+        self.write_super_block(&mut state)
+    }
+
+    fn inode_offset(&self, ino: u32) -> usize {
+        let index = (ino - 1) as usize;
+        let per_group = self.inodes_per_group as usize;
+        let table = self.inode_tables[index / per_group] as usize;
+        table * self.block_size + index % per_group * self.inode_size
+    }
+
+    fn read_disk_inode(&self, ino: u32) -> Result<DiskINode> {
+        let mut raw = vec![0; self.inode_size];
+        self.read(self.inode_offset(ino), &mut raw)?;
+        Ok(DiskINode::parse(raw))
+    }
+
+    fn write_disk_inode(&self, ino: u32, disk: &mut DiskINode) -> Result<()> {
+        let raw = disk.to_bytes();
+        self.write(self.inode_offset(ino), raw)?;
+        if ino == ROOT_INO {
+            *self.root.lock() = raw.to_vec();
+        }
+        Ok(())
+    }
+
+    fn wrap_inode(&self, id: u32, disk: DiskINode) -> Arc<Ext2INode> {
+        let fs = self.self_ptr.read().upgrade().unwrap();
+        let inode = Arc::new(Ext2INode::new(id, disk, fs));
+        self.inodes.write().insert(id, Arc::downgrade(&inode));
+        inode
+    }
+
+    /// Get the inode `ino`, reading it from disk if not opened
+    fn get_inode(&self, ino: u32) -> Result<Arc<Ext2INode>> {
+        if ino == 0 || ino > self.inodes_per_group * self.inode_tables.len() as u32 {
+            return Err(FsError::EntryNotFound);
+        }
+        if let Some(inode) = self.inodes.read().get(&ino).and_then(|weak| weak.upgrade()) {
+            return Ok(inode);
+        }
+        let disk = self.read_disk_inode(ino)?;
+        Ok(self.wrap_inode(ino, disk))
+    }
+
+    /// Allocate an inode of `type_`, near the group `goal`
+    fn new_inode(&self, type_: FileType, mode: u16, goal: usize) -> Result<Arc<Ext2INode>> {
+        let ino = self.alloc_inode(goal, type_ == FileType::Dir)?;
+        let mut disk = DiskINode::new(type_, mode, self.inode_size);
+        self.write_disk_inode(ino, &mut disk)?;
+        Ok(self.wrap_inode(ino, disk))
+    }
+
+    /// Forget the inode `ino` when its last reference is dropped
+    fn forget_inode(&self, ino: u32) {
+        let mut inodes = self.inodes.write();
+        if inodes
+            .get(&ino)
+            .map_or(false, |weak| weak.strong_count() == 0)
+        {
+            inodes.remove(&ino);
+        }
+    }
+
+    /// Write a directory entry at the start of `buf`
+    fn write_dirent(&self, buf: &mut [u8], ino: u32, rec_len: usize, name: &str, type_: FileType) {
+        write_u32(buf, 0, ino);
+        write_u16(buf, 4, rec_len as u16);
+        buf[6] = name.len() as u8;
+        buf[7] = if self.filetype {
+            type_to_dirent(type_)
+        } else {
+            0
+        };
+        buf[8..8 + name.len()].copy_from_slice(name.as_bytes());
+    }
+}
+
+impl FileSystem for Ext2FileSystem {
+    fn sync(&self) -> Result<()> {
+        self.device.sync().map_err(|_| FsError::DeviceError)
+    }
+
+    fn root_inode(&self) -> Arc<dyn INode> {
+        let root = self.root.lock();
+        if let Some(inode) = self
+            .inodes
+            .read()
+            .get(&ROOT_INO)
+            .and_then(|weak| weak.upgrade())
+        {
+            return inode;
+        }
+        self.wrap_inode(ROOT_INO, DiskINode::parse(root.clone()))
+    }
+
+    fn info(&self) -> FsInfo {
+        let state = self.alloc.lock();
+        let super_block = &state.super_block;
+        FsInfo {
+            bsize: self.block_size,
+            frsize: self.block_size,
+            blocks: super_block.blocks_count as usize,
+            bfree: super_block.free_blocks_count as usize,
+            bavail: super_block
+                .free_blocks_count
+                .saturating_sub(super_block.r_blocks_count) as usize,
+            files: super_block.inodes_count as usize,
+            ffree: super_block.free_inodes_count as usize,
+            namemax: MAX_NAME_LEN,
+        }
+    }
+}
+
+fn read_device(device: &dyn Device, offset: usize, buf: &mut [u8]) -> Result<()> {
+    match device.read_at(offset, buf) {
+        Ok(len) if len == buf.len() => Ok(()),
+        _ => Err(FsError::DeviceError),
+    }
+}
@@ -0,0 +1,350 @@
+//! On-disk structures of ext2, all little endian
+
+use alloc::{string::String, vec::Vec};
+use rcore_fs::vfs::FileType;
+
+pub const MAGIC: u16 = 0xef53;
+/// Offset of the super block from the start of the device
+pub const SUPER_BLOCK_OFFSET: usize = 1024;
+pub const SUPER_BLOCK_SIZE: usize = 1024;
+pub const GROUP_DESC_SIZE: usize = 32;
+pub const ROOT_INO: u32 = 2;
+/// Size of inodes in revision 0 file systems
+pub const GOOD_OLD_INODE_SIZE: usize = 128;
+/// First non-reserved inode in revision 0 file systems
+pub const GOOD_OLD_FIRST_INO: u32 = 11;
+pub const MAX_NAME_LEN: usize = 255;
+/// Blocks are at most 64 KiB, `1024 << 6`
+const MAX_LOG_BLOCK_SIZE: u32 = 6;
+
+/// Directory entries record the file type
+pub const FEATURE_INCOMPAT_FILETYPE: u32 = 0x2;
+/// Backups of the super block are only kept in some groups
+pub const FEATURE_RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+/// Files may be larger than 4GiB
+pub const FEATURE_RO_COMPAT_LARGE_FILE: u32 = 0x2;
+
+/// Number of direct blocks in an inode
+pub const NDIR_BLOCKS: usize = 12;
+/// Number of block pointers in an inode, direct, indirect, double and triple indirect
+pub const N_BLOCKS: usize = 15;
+/// Symbolic links shorter than this are kept in the block pointers
+pub const FAST_SYMLINK_SIZE: usize = N_BLOCKS * 4;
+
+const S_IFMT: u16 = 0o170000;
+const S_IFSOCK: u16 = 0o140000;
+const S_IFLNK: u16 = 0o120000;
+const S_IFREG: u16 = 0o100000;
+const S_IFBLK: u16 = 0o060000;
+const S_IFDIR: u16 = 0o040000;
+const S_IFCHR: u16 = 0o020000;
+const S_IFIFO: u16 = 0o010000;
+
+pub fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+pub fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+pub fn write_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+pub fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// The super block, only the fields in use are parsed
+pub struct SuperBlock {
+    pub inodes_count: u32,
+    pub blocks_count: u32,
+    pub r_blocks_count: u32,
+    pub free_blocks_count: u32,
+    pub free_inodes_count: u32,
+    pub first_data_block: u32,
+    pub log_block_size: u32,
+    pub blocks_per_group: u32,
+    pub inodes_per_group: u32,
+    pub magic: u16,
+    pub rev_level: u32,
+    pub first_ino: u32,
+    pub inode_size: u16,
+    pub feature_incompat: u32,
+    pub feature_ro_compat: u32,
+    /// The block as read, written back with the counters updated
+    raw: Vec<u8>,
+}
+
+impl SuperBlock {
+    /// Parse the super block, `None` if its geometry makes no sense
+    pub fn parse(raw: Vec<u8>) -> Option<Self> {
+        let rev_level = read_u32(&raw, 76);
+        let (first_ino, inode_size) = if rev_level == 0 {
+            (GOOD_OLD_FIRST_INO, GOOD_OLD_INODE_SIZE as u16)
+        } else {
+            (read_u32(&raw, 84), read_u16(&raw, 88))
+        };
+        let (feature_incompat, feature_ro_compat) = if rev_level == 0 {
+            (0, 0)
+        } else {
+            (read_u32(&raw, 96), read_u32(&raw, 100))
+        };
+        let super_block = SuperBlock {
+            inodes_count: read_u32(&raw, 0),
+            blocks_count: read_u32(&raw, 4),
+            r_blocks_count: read_u32(&raw, 8),
+            free_blocks_count: read_u32(&raw, 12),
+            free_inodes_count: read_u32(&raw, 16),
+            first_data_block: read_u32(&raw, 20),
+            log_block_size: read_u32(&raw, 24),
+            blocks_per_group: read_u32(&raw, 32),
+            inodes_per_group: read_u32(&raw, 40),
+            magic: read_u16(&raw, 56),
+            rev_level,
+            first_ino,
+            inode_size,
+            feature_incompat,
+            feature_ro_compat,
+            raw,
+        };
+        let valid = super_block.log_block_size <= MAX_LOG_BLOCK_SIZE
+            && super_block.blocks_per_group != 0
+            && super_block.inodes_per_group != 0
+            && super_block.blocks_count > super_block.first_data_block
+            && super_block.inode_size as usize >= GOOD_OLD_INODE_SIZE
+            && super_block.inode_size as usize <= super_block.block_size()
+            && super_block.inode_size.is_power_of_two()
+            // inode numbers of all groups fit in 32 bits
+            && super_block.group_count() as u64 * super_block.inodes_per_group as u64
+                <= u32::max_value() as u64;
+        if valid {
+            Some(super_block)
+        } else {
+            None
+        }
+    }
+
+    pub fn to_bytes(&mut self) -> &[u8] {
+        write_u32(&mut self.raw, 12, self.free_blocks_count);
+        write_u32(&mut self.raw, 16, self.free_inodes_count);
+        &self.raw
+    }
+
+    pub fn block_size(&self) -> usize {
+        1024 << self.log_block_size
+    }
+
+    pub fn group_count(&self) -> usize {
+        let blocks = (self.blocks_count - self.first_data_block) as usize;
+        let per_group = self.blocks_per_group as usize;
+        (blocks + per_group - 1) / per_group
+    }
+}
+
+/// A block group descriptor
+#[derive(Clone)]
+pub struct GroupDesc {
+    pub block_bitmap: u32,
+    pub inode_bitmap: u32,
+    pub inode_table: u32,
+    pub free_blocks_count: u16,
+    pub free_inodes_count: u16,
+    pub used_dirs_count: u16,
+}
+
+impl GroupDesc {
+    pub fn parse(raw: &[u8]) -> Self {
+        GroupDesc {
+            block_bitmap: read_u32(raw, 0),
+            inode_bitmap: read_u32(raw, 4),
+            inode_table: read_u32(raw, 8),
+            free_blocks_count: read_u16(raw, 12),
+            free_inodes_count: read_u16(raw, 14),
+            used_dirs_count: read_u16(raw, 16),
+        }
+    }
+
+    pub fn write(&self, raw: &mut [u8]) {
+        write_u32(raw, 0, self.block_bitmap);
+        write_u32(raw, 4, self.inode_bitmap);
+        write_u32(raw, 8, self.inode_table);
+        write_u16(raw, 12, self.free_blocks_count);
+        write_u16(raw, 14, self.free_inodes_count);
+        write_u16(raw, 16, self.used_dirs_count);
+    }
+}
+
+/// An inode, only the fields in use are parsed
+pub struct DiskINode {
+    pub mode: u16,
+    pub uid: u32,
+    pub size: u64,
+    pub atime: u32,
+    pub ctime: u32,
+    pub mtime: u32,
+    pub dtime: u32,
+    pub gid: u32,
+    pub links_count: u16,
+    /// Number of 512B sectors in use, including indirect blocks
+    pub blocks: u32,
+    pub block: [u32; N_BLOCKS],
+    /// The inode as read, written back with the fields above updated
+    raw: Vec<u8>,
+}
+
+impl DiskINode {
+    pub fn parse(raw: Vec<u8>) -> Self {
+        let mode = read_u16(&raw, 0);
+        let mut block = [0; N_BLOCKS];
+        for (i, b) in block.iter_mut().enumerate() {
+            *b = read_u32(&raw, 40 + i * 4);
+        }
+        let mut size = read_u32(&raw, 4) as u64;
+        if mode & S_IFMT == S_IFREG {
+            size |= (read_u32(&raw, 108) as u64) << 32;
+        }
+        DiskINode {
+            mode,
+            uid: read_u16(&raw, 2) as u32 | (read_u16(&raw, 120) as u32) << 16,
+            size,
+            atime: read_u32(&raw, 8),
+            ctime: read_u32(&raw, 12),
+            mtime: read_u32(&raw, 16),
+            dtime: read_u32(&raw, 20),
+            gid: read_u16(&raw, 24) as u32 | (read_u16(&raw, 122) as u32) << 16,
+            links_count: read_u16(&raw, 26),
+            blocks: read_u32(&raw, 28),
+            block,
+            raw,
+        }
+    }
+
+    /// A cleared inode of `type_`, `size` bytes on disk
+    pub fn new(type_: FileType, mode: u16, size: usize) -> Self {
+        let mut raw = Vec::new();
+        raw.resize(size, 0);
+        let mut inode = DiskINode::parse(raw);
+        inode.mode = type_to_mode(type_) | (mode & 0o7777);
+        inode
+    }
+
+    pub fn to_bytes(&mut self) -> &[u8] {
+        let raw = &mut self.raw;
+        write_u16(raw, 0, self.mode);
+        write_u16(raw, 2, self.uid as u16);
+        write_u32(raw, 4, self.size as u32);
+        write_u32(raw, 8, self.atime);
+        write_u32(raw, 12, self.ctime);
+        write_u32(raw, 16, self.mtime);
+        write_u32(raw, 20, self.dtime);
+        write_u16(raw, 24, self.gid as u16);
+        write_u16(raw, 26, self.links_count);
+        write_u32(raw, 28, self.blocks);
+        for (i, &b) in self.block.iter().enumerate() {
+            write_u32(raw, 40 + i * 4, b);
+        }
+        if self.mode & S_IFMT == S_IFREG {
+            write_u32(raw, 108, (self.size >> 32) as u32);
+        }
+        write_u16(raw, 120, (self.uid >> 16) as u16);
+        write_u16(raw, 122, (self.gid >> 16) as u16);
+        &self.raw
+    }
+
+    pub fn type_(&self) -> FileType {
+        match self.mode & S_IFMT {
+            S_IFSOCK => FileType::Socket,
+            S_IFLNK => FileType::SymLink,
+            S_IFBLK => FileType::BlockDevice,
+            S_IFDIR => FileType::Dir,
+            S_IFCHR => FileType::CharDevice,
+            S_IFIFO => FileType::NamedPipe,
+            _ => FileType::File,
+        }
+    }
+
+    /// Whether the target of the symbolic link is kept in the block pointers
+    pub fn is_fast_symlink(&self) -> bool {
+        self.type_() == FileType::SymLink && self.blocks == 0
+    }
+
+    /// The block pointers as bytes, holding the target of a fast symbolic link
+    pub fn inline_data(&self) -> [u8; FAST_SYMLINK_SIZE] {
+        let mut data = [0; FAST_SYMLINK_SIZE];
+        for (i, &b) in self.block.iter().enumerate() {
+            write_u32(&mut data, i * 4, b);
+        }
+        data
+    }
+
+    pub fn set_inline_data(&mut self, data: &[u8; FAST_SYMLINK_SIZE]) {
+        for (i, b) in self.block.iter_mut().enumerate() {
+            *b = read_u32(data, i * 4);
+        }
+    }
+}
+
+fn type_to_mode(type_: FileType) -> u16 {
+    match type_ {
+        FileType::File => S_IFREG,
+        FileType::Dir => S_IFDIR,
+        FileType::SymLink => S_IFLNK,
+        FileType::CharDevice => S_IFCHR,
+        FileType::BlockDevice => S_IFBLK,
+        FileType::NamedPipe => S_IFIFO,
+        FileType::Socket => S_IFSOCK,
+    }
+}
+
+/// A parsed directory entry, `offset` bytes into the directory
+pub struct DirEntry {
+    pub offset: usize,
+    pub inode: u32,
+    pub rec_len: usize,
+    pub name: String,
+}
+
+/// Parse the entries of a directory, including free ones,
+/// or fail with the offset of a broken one
+pub fn parse_dir_entries(data: &[u8]) -> Result<Vec<DirEntry>, usize> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset + 8 <= data.len() {
+        let rec_len = read_u16(data, offset + 4) as usize;
+        let name_len = data[offset + 6] as usize;
+        if rec_len < 8 || offset + rec_len > data.len() || 8 + name_len > rec_len {
+            return Err(offset);
+        }
+        let name = &data[offset + 8..offset + 8 + name_len];
+        entries.push(DirEntry {
+            offset,
+            inode: read_u32(data, offset),
+            rec_len,
+            name: String::from_utf8_lossy(name).into_owned(),
+        });
+        offset += rec_len;
+    }
+    Ok(entries)
+}
+
+/// The file type recorded in directory entries
+pub fn type_to_dirent(type_: FileType) -> u8 {
+    match type_ {
+        FileType::File => 1,
+        FileType::Dir => 2,
+        FileType::CharDevice => 3,
+        FileType::BlockDevice => 4,
+        FileType::NamedPipe => 5,
+        FileType::Socket => 6,
+        FileType::SymLink => 7,
+    }
+}
+
+/// Size of a directory entry with a name of `name_len` bytes
+pub fn dirent_size(name_len: usize) -> usize {
+    (8 + name_len + 3) & !3
+}
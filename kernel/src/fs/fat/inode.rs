@@ -0,0 +1,832 @@
+//! Files and directories of FAT
+
+use super::structs::*;
+use super::FatFileSystem;
+use crate::syscall::TimeSpec;
+use alloc::{
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::any::Any;
+use rcore_fs::vfs::*;
+use spin::RwLock;
+
+/// Directories are limited to 65536 entries
+const MAX_DIR_SIZE: usize = 65536 * DIRENT_SIZE;
+
+pub struct FatINode {
+    id: usize,
+    is_root: bool,
+    state: RwLock<NodeState>,
+    fs: Arc<FatFileSystem>,
+    self_ptr: RwLock<Weak<FatINode>>,
+}
+
+struct NodeState {
+    /// Position of the short entry on the device, `None` for the root or once removed
+    pos: Option<usize>,
+    entry: ShortEntry,
+    clusters: Vec<u32>,
+    /// The directory holding the entry, `None` for the root
+    parent: Option<Arc<FatINode>>,
+}
+
+/// A parsed directory entry, with its long name if any
+struct DirEntry {
+    /// Offset of the first long name entry, or the short entry without a long name
+    start: usize,
+    /// Offset of the short entry
+    offset: usize,
+    name: String,
+    entry: ShortEntry,
+}
+
+impl DirEntry {
+    fn is_dot(&self) -> bool {
+        self.name == "." || self.name == ".."
+    }
+}
+
+impl FatINode {
+    /// Open the entry at `pos`, in the directory `parent`
+    pub(super) fn new(
+        id: usize,
+        fs: Arc<FatFileSystem>,
+        pos: usize,
+        entry: ShortEntry,
+        parent: Arc<FatINode>,
+    ) -> Result<Self> {
+        let clusters = fs.read_chain(entry.first_cluster(fs.fat_type))?;
+        Ok(FatINode {
+            id,
+            is_root: false,
+            state: RwLock::new(NodeState {
+                pos: Some(pos),
+                entry,
+                clusters,
+                parent: Some(parent),
+            }),
+            fs,
+            self_ptr: RwLock::new(Weak::new()),
+        })
+    }
+
+    /// Open the root directory, which has no entry
+    pub(super) fn root(fs: Arc<FatFileSystem>) -> Result<Self> {
+        let mut entry = ShortEntry::new(&[b' '; 11], 0, ATTR_DIRECTORY);
+        let clusters = match fs.fat_type {
+            FatType::Fat32 => {
+                entry.set_first_cluster(fs.root_cluster);
+                fs.read_chain(fs.root_cluster)?
+            }
+            _ => Vec::new(),
+        };
+        Ok(FatINode {
+            id: 1,
+            is_root: true,
+            state: RwLock::new(NodeState {
+                pos: None,
+                entry,
+                clusters,
+                parent: None,
+            }),
+            fs,
+            self_ptr: RwLock::new(Weak::new()),
+        })
+    }
+
+    pub(super) fn set_self_ptr(inode: &Arc<FatINode>) {
+        *inode.self_ptr.write() = Arc::downgrade(inode);
+    }
+
+    fn arc(&self) -> Arc<FatINode> {
+        self.self_ptr.read().upgrade().unwrap()
+    }
+
+    /// The root directory of FAT12 and FAT16 is a fixed region before the clusters
+    fn is_fixed_root(&self) -> bool {
+        self.is_root && self.fs.fat_type != FatType::Fat32
+    }
+
+    /// The first cluster, as recorded in ".." entries, which use 0 for the root
+    fn dir_cluster(&self, state: &NodeState) -> u32 {
+        if self.is_root {
+            0
+        } else {
+            state.entry.first_cluster(self.fs.fat_type)
+        }
+    }
+
+    /// Bytes allocated to the file
+    fn capacity(&self, state: &NodeState) -> usize {
+        if self.is_fixed_root() {
+            self.fs.root_entries * DIRENT_SIZE
+        } else {
+            state.clusters.len() * self.fs.cluster_size
+        }
+    }
+
+    /// Offset on the device of the byte `offset` into the file, and bytes left in its cluster
+    fn device_offset(&self, state: &NodeState, offset: usize) -> (usize, usize) {
+        if self.is_fixed_root() {
+            return (self.fs.root_dir + offset, self.capacity(state) - offset);
+        }
+        let cluster_size = self.fs.cluster_size;
+        let cluster = state.clusters[offset / cluster_size];
+        let skip = offset % cluster_size;
+        (self.fs.cluster_offset(cluster) + skip, cluster_size - skip)
+    }
+
+    /// Read allocated bytes, regardless of the size of the file
+    fn read_raw(&self, state: &NodeState, offset: usize, buf: &mut [u8]) -> Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let (pos, left) = self.device_offset(state, offset + done);
+            let len = left.min(buf.len() - done);
+            self.fs.read(pos, &mut buf[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Write bytes, allocating clusters as needed
+    fn write_raw(&self, state: &mut NodeState, offset: usize, buf: &[u8]) -> Result<()> {
+        self.grow(state, offset + buf.len())?;
+        let mut done = 0;
+        while done < buf.len() {
+            let (pos, left) = self.device_offset(state, offset + done);
+            let len = left.min(buf.len() - done);
+            self.fs.write(pos, &buf[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Write zeros from `from` to `to`
+    fn zero_raw(&self, state: &mut NodeState, from: usize, to: usize) -> Result<()> {
+        let zeros = vec![0u8; self.fs.cluster_size];
+        let mut pos = from;
+        while pos < to {
+            let len = zeros.len().min(to - pos);
+            self.write_raw(state, pos, &zeros[..len])?;
+            pos += len;
+        }
+        Ok(())
+    }
+
+    /// Allocate clusters until `len` bytes fit
+    fn grow(&self, state: &mut NodeState, len: usize) -> Result<()> {
+        if self.is_fixed_root() {
+            return if len <= self.capacity(state) {
+                Ok(())
+            } else {
+                Err(FsError::NoDeviceSpace)
+            };
+        }
+        while self.capacity(state) < len {
+            let prev = state.clusters.last().cloned().unwrap_or(0);
+            let cluster = self.fs.alloc_cluster(prev)?;
+            if prev == 0 {
+                state.entry.set_first_cluster(cluster);
+            }
+            state.clusters.push(cluster);
+        }
+        Ok(())
+    }
+
+    /// Free the clusters after the first `keep`
+    fn shrink(&self, state: &mut NodeState, keep: usize) -> Result<()> {
+        if keep >= state.clusters.len() {
+            return Ok(());
+        }
+        if keep == 0 {
+            state.entry.set_first_cluster(0);
+        } else {
+            let end_mark = self.fs.fat_type.end_mark();
+            self.fs.write_fat(state.clusters[keep - 1], end_mark)?;
+        }
+        self.fs.free_chain(state.clusters[keep])?;
+        state.clusters.truncate(keep);
+        Ok(())
+    }
+
+    /// Write the entry back to the directory
+    fn write_entry(&self, state: &NodeState) -> Result<()> {
+        match state.pos {
+            Some(pos) => self.fs.write(pos, &state.entry.raw),
+            None => Ok(()),
+        }
+    }
+
+    fn size(&self, state: &NodeState) -> usize {
+        if state.entry.is_dir() {
+            self.capacity(state)
+        } else {
+            state.entry.size() as usize
+        }
+    }
+
+    /// Change the size of a regular file
+    fn truncate(&self, state: &mut NodeState, len: usize) -> Result<()> {
+        if len > MAX_FILE_SIZE {
+            return Err(FsError::InvalidParam);
+        }
+        let size = self.size(state);
+        let cluster_size = self.fs.cluster_size;
+        if len < size {
+            self.shrink(state, (len + cluster_size - 1) / cluster_size)?;
+            if len % cluster_size != 0 {
+                // zero the tail of the last cluster, which is read back if the file grows again
+                let end = (len / cluster_size + 1) * cluster_size;
+                self.zero_raw(state, len, end)?;
+            }
+        } else {
+            // FAT has no holes
+            self.zero_raw(state, size, len)?;
+        }
+        state.entry.set_size(len as u32);
+        Ok(())
+    }
+
+    /// Read the whole directory and parse its entries, including "." and ".."
+    fn dir_entries(&self, state: &NodeState) -> Result<(Vec<u8>, Vec<DirEntry>)> {
+        let mut data = vec![0u8; self.capacity(state)];
+        self.read_raw(state, 0, &mut data)?;
+        let mut entries = Vec::new();
+        // the long name being collected, its first entry, checksum and the next order expected
+        let mut lfn: Vec<u16> = Vec::new();
+        let mut lfn_start = 0;
+        let mut lfn_sum = 0;
+        let mut lfn_next = 0;
+        let mut chars = [0u16; LFN_CHARS];
+        for offset in (0..data.len()).step_by(DIRENT_SIZE) {
+            let raw = &data[offset..offset + DIRENT_SIZE];
+            match raw[0] {
+                ENTRY_END => break,
+                ENTRY_FREE => {
+                    lfn_next = 0;
+                    continue;
+                }
+                _ => {}
+            }
+            let attr = raw[11];
+            if attr & 0x3f == ATTR_LONG_NAME {
+                let (order, checksum) = parse_lfn(raw, &mut chars);
+                let index = (order & 0x1f) as usize;
+                if order & LFN_LAST != 0 && index != 0 && index <= 20 {
+                    lfn = vec![0xffff; index * LFN_CHARS];
+                    lfn_start = offset;
+                    lfn_sum = checksum;
+                } else if index == 0 || index != lfn_next || checksum != lfn_sum {
+                    // a broken long name, the short name is used
+                    lfn_next = 0;
+                    continue;
+                }
+                lfn[(index - 1) * LFN_CHARS..index * LFN_CHARS].copy_from_slice(&chars);
+                lfn_next = index - 1;
+                continue;
+            }
+            if attr & ATTR_VOLUME_ID != 0 {
+                lfn_next = 0;
+                continue;
+            }
+            let entry = ShortEntry::parse(raw);
+            let has_lfn =
+                !lfn.is_empty() && lfn_next == 0 && lfn_sum == lfn_checksum(entry.short_name());
+            let (start, name) = if has_lfn {
+                let len = lfn.iter().position(|&c| c == 0 || c == 0xffff);
+                let name =
+                    core::char::decode_utf16(lfn[..len.unwrap_or(lfn.len())].iter().cloned())
+                        .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+                        .collect();
+                (lfn_start, name)
+            } else {
+                (offset, entry.name())
+            };
+            entries.push(DirEntry {
+                start,
+                offset,
+                name,
+                entry,
+            });
+            lfn.clear();
+            lfn_next = 0;
+        }
+        Ok((data, entries))
+    }
+
+    /// Find the entry `name`, ignoring case
+    fn find_entry(&self, state: &NodeState, name: &str) -> Result<Option<DirEntry>> {
+        let (_, entries) = self.dir_entries(state)?;
+        Ok(entries
+            .into_iter()
+            .find(|entry| !entry.is_dot() && same_name(&entry.name, name)))
+    }
+
+    /// Open the file of `entry` in this directory
+    fn open_entry(&self, state: &NodeState, entry: &DirEntry) -> Result<Arc<FatINode>> {
+        let (pos, _) = self.device_offset(state, entry.offset);
+        let parent = self.arc();
+        self.fs.get_inode(pos, |id, fs| {
+            FatINode::new(id, fs, pos, entry.entry.clone(), parent)
+        })
+    }
+
+    /// Write `entries` into free slots in a row, returning the offset of the last one
+    fn add_entries(&self, state: &mut NodeState, entries: &[[u8; DIRENT_SIZE]]) -> Result<usize> {
+        let needed = entries.len() * DIRENT_SIZE;
+        let (data, _) = self.dir_entries(state)?;
+        let mut start = 0;
+        let mut end = data.len();
+        for offset in (0..data.len()).step_by(DIRENT_SIZE) {
+            match data[offset] {
+                ENTRY_END => {
+                    end = offset;
+                    break;
+                }
+                ENTRY_FREE => {
+                    if offset + DIRENT_SIZE - start >= needed {
+                        break;
+                    }
+                }
+                _ => start = offset + DIRENT_SIZE,
+            }
+        }
+        if start + needed > MAX_DIR_SIZE {
+            return Err(FsError::NoDeviceSpace);
+        }
+        let mut buf = Vec::with_capacity(needed);
+        for entry in entries.iter() {
+            buf.extend_from_slice(entry);
+        }
+        self.write_raw(state, start, &buf)?;
+        let after = start + needed;
+        if after > end && after < data.len() && data[after] != ENTRY_END {
+            // entries after the end are free but may not be zeroed, so keep the end marked
+            self.write_raw(state, after, &[ENTRY_END])?;
+        }
+        Ok(after - DIRENT_SIZE)
+    }
+
+    /// Mark the entries of `entry` free
+    fn remove_entry(&self, state: &NodeState, entry: &DirEntry) -> Result<()> {
+        for offset in (entry.start..=entry.offset).step_by(DIRENT_SIZE) {
+            let (pos, _) = self.device_offset(state, offset);
+            self.fs.write(pos, &[ENTRY_FREE])?;
+        }
+        Ok(())
+    }
+
+    /// The entries naming `name` in this directory, for a file with `entry`,
+    /// whose short name and case flags are replaced
+    fn name_entries(
+        &self,
+        state: &NodeState,
+        name: &str,
+        entry: &mut ShortEntry,
+    ) -> Result<Vec<[u8; DIRENT_SIZE]>> {
+        let (_, entries) = self.dir_entries(state)?;
+        let taken = |short: &[u8; 11]| entries.iter().any(|e| e.entry.short_name() == &short[..]);
+        let mut result = Vec::new();
+        match exact_short_name(name) {
+            Some((short, ntres)) if !taken(&short) => {
+                entry.set_short_name(&short, ntres);
+            }
+            _ => {
+                let short = generate_short_name(name, taken).ok_or(FsError::NoDeviceSpace)?;
+                entry.set_short_name(&short, 0);
+                let name: Vec<u16> = name.encode_utf16().collect();
+                result = lfn_entries(&name, lfn_checksum(&short));
+            }
+        }
+        result.push(entry.raw);
+        Ok(result)
+    }
+
+    fn is_empty_dir(&self, state: &NodeState) -> Result<bool> {
+        let (_, entries) = self.dir_entries(state)?;
+        Ok(entries.iter().all(|entry| entry.is_dot()))
+    }
+}
+
+/// File names are compared ignoring case
+fn same_name(a: &str, b: &str) -> bool {
+    if a.is_ascii() && b.is_ascii() {
+        a.eq_ignore_ascii_case(b)
+    } else {
+        a.to_lowercase() == b.to_lowercase()
+    }
+}
+
+/// Trailing dots are dropped, like other systems do
+fn check_name(name: &str) -> Result<&str> {
+    let name = name.trim_end_matches('.');
+    let invalid = |c: char| c < ' ' || "\"*/:<>?\\|".contains(c);
+    if name.is_empty() || name.encode_utf16().count() > MAX_NAME_LEN || name.contains(invalid) {
+        return Err(FsError::InvalidParam);
+    }
+    Ok(name)
+}
+
+fn check_dir(inode: &FatINode, state: &NodeState) -> Result<()> {
+    if !state.entry.is_dir() {
+        return Err(FsError::NotDir);
+    }
+    if state.pos.is_none() && !inode.is_root {
+        return Err(FsError::DirRemoved);
+    }
+    Ok(())
+}
+
+fn downcast(inode: &Arc<dyn INode>) -> Result<&FatINode> {
+    inode
+        .as_any_ref()
+        .downcast_ref::<FatINode>()
+        .ok_or(FsError::NotSameFs)
+}
+
+impl INode for FatINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let state = self.state.read();
+        if state.entry.is_dir() {
+            return Err(FsError::IsDir);
+        }
+        let size = self.size(&state);
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min(size - offset);
+        self.read_raw(&state, offset, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let mut state = self.state.write();
+        if state.entry.is_dir() {
+            return Err(FsError::IsDir);
+        }
+        let end = offset + buf.len();
+        if end > MAX_FILE_SIZE {
+            return Err(FsError::InvalidParam);
+        }
+        let size = self.size(&state);
+        if offset > size {
+            self.zero_raw(&mut state, size, offset)?;
+        }
+        self.write_raw(&mut state, offset, buf)?;
+        if end > size {
+            state.entry.set_size(end as u32);
+        }
+        self.write_entry(&state)?;
+        Ok(buf.len())
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: true,
+            write: true,
+            error: false,
+        })
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        let state = self.state.read();
+        let entry = &state.entry;
+        let time = |sec: i64| Timespec { sec, nsec: 0 };
+        let (type_, nlinks) = if entry.is_dir() {
+            // a directory is linked from its parent, its "." and the ".." of subdirectories
+            let (_, entries) = self.dir_entries(&state)?;
+            let subdirs = entries
+                .iter()
+                .filter(|e| !e.is_dot() && e.entry.is_dir())
+                .count();
+            (FileType::Dir, 2 + subdirs)
+        } else {
+            (FileType::File, 1)
+        };
+        // there are no permissions, only a read-only attribute
+        let mode = if entry.attr() & ATTR_READ_ONLY != 0 {
+            0o555
+        } else {
+            0o755
+        };
+        let allocated = self.capacity(&state);
+        Ok(Metadata {
+            dev: self.fs.dev,
+            inode: self.id,
+            size: self.size(&state),
+            blk_size: self.fs.cluster_size,
+            blocks: allocated / 512,
+            atime: time(entry.atime()),
+            mtime: time(entry.mtime()),
+            ctime: time(entry.ctime()),
+            type_,
+            mode,
+            nlinks,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+        })
+    }
+
+    /// Owners can't be kept, and are ignored
+    fn set_metadata(&self, metadata: &Metadata) -> Result<()> {
+        let mut state = self.state.write();
+        let entry = &mut state.entry;
+        entry.set_atime(metadata.atime.sec);
+        entry.set_mtime(metadata.mtime.sec);
+        entry.set_ctime(metadata.ctime.sec);
+        let attr = entry.attr() & !ATTR_READ_ONLY;
+        if metadata.mode & 0o200 == 0 {
+            entry.set_attr(attr | ATTR_READ_ONLY);
+        } else {
+            entry.set_attr(attr);
+        }
+        self.write_entry(&state)
+    }
+
+    fn sync_all(&self) -> Result<()> {
+        self.fs.sync()
+    }
+
+    fn sync_data(&self) -> Result<()> {
+        self.fs.sync()
+    }
+
+    fn resize(&self, len: usize) -> Result<()> {
+        let mut state = self.state.write();
+        if state.entry.is_dir() {
+            return Err(FsError::NotFile);
+        }
+        self.truncate(&mut state, len)?;
+        self.write_entry(&state)
+    }
+
+    fn create(&self, name: &str, type_: FileType, mode: u32) -> Result<Arc<dyn INode>> {
+        let name = check_name(name)?;
+        let attr = match type_ {
+            FileType::File => ATTR_ARCHIVE,
+            FileType::Dir => ATTR_DIRECTORY,
+            _ => return Err(FsError::NotSupported),
+        };
+        let mut state = self.state.write();
+        check_dir(self, &state)?;
+        if self.find_entry(&state, name)?.is_some() {
+            return Err(FsError::EntryExist);
+        }
+        let mut entry = ShortEntry::new(&[b' '; 11], 0, attr);
+        if mode & 0o200 == 0 {
+            entry.set_attr(attr | ATTR_READ_ONLY);
+        }
+        let now = TimeSpec::get_epoch().sec as i64;
+        entry.set_ctime(now);
+        entry.set_mtime(now);
+        entry.set_atime(now);
+
+        let mut cluster = 0;
+        if type_ == FileType::Dir {
+            cluster = self.fs.alloc_cluster(0)?;
+            let mut dot = entry.clone();
+            dot.set_short_name(b".          ", 0);
+            dot.set_first_cluster(cluster);
+            let mut dotdot = entry.clone();
+            dotdot.set_short_name(b"..         ", 0);
+            dotdot.set_first_cluster(self.dir_cluster(&state));
+            let offset = self.fs.cluster_offset(cluster);
+            let written = self
+                .fs
+                .write(offset, &dot.raw)
+                .and_then(|_| self.fs.write(offset + DIRENT_SIZE, &dotdot.raw));
+            if let Err(e) = written {
+                self.fs.free_chain(cluster)?;
+                return Err(e);
+            }
+            entry.set_first_cluster(cluster);
+        }
+        let added = self
+            .name_entries(&state, name, &mut entry)
+            .and_then(|entries| self.add_entries(&mut state, &entries));
+        let offset = match added {
+            Ok(offset) => offset,
+            Err(e) => {
+                if cluster != 0 {
+                    self.fs.free_chain(cluster)?;
+                }
+                return Err(e);
+            }
+        };
+        let entry = DirEntry {
+            start: offset,
+            offset,
+            name: String::from(name),
+            entry,
+        };
+        Ok(self.open_entry(&state, &entry)?)
+    }
+
+    fn link(&self, _name: &str, _other: &Arc<dyn INode>) -> Result<()> {
+        // a file is its directory entry, so it can't have more than one
+        Err(FsError::NotSupported)
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        if name == "." || name == ".." {
+            return Err(FsError::IsDir);
+        }
+        let state = self.state.write();
+        check_dir(self, &state)?;
+        let entry = self
+            .find_entry(&state, name.trim_end_matches('.'))?
+            .ok_or(FsError::EntryNotFound)?;
+        let (pos, _) = self.device_offset(&state, entry.offset);
+        let child = self.open_entry(&state, &entry)?;
+        let mut child_state = child.state.write();
+        if child_state.entry.is_dir() && !child.is_empty_dir(&child_state)? {
+            return Err(FsError::DirNotEmpty);
+        }
+        self.remove_entry(&state, &entry)?;
+        // the clusters are freed once the file is closed
+        child_state.pos = None;
+        self.fs.inodes.write().remove(&pos);
+        Ok(())
+    }
+
+    fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> Result<()> {
+        let new_name = check_name(new_name)?;
+        if old_name == "." || old_name == ".." {
+            return Err(FsError::IsDir);
+        }
+        let target = downcast(target)?;
+        if !Arc::ptr_eq(&self.fs, &target.fs) {
+            return Err(FsError::NotSameFs);
+        }
+        let old_name = old_name.trim_end_matches('.');
+        let child = {
+            let state = self.state.read();
+            check_dir(self, &state)?;
+            let entry = self
+                .find_entry(&state, old_name)?
+                .ok_or(FsError::EntryNotFound)?;
+            self.open_entry(&state, &entry)?
+        };
+        let child_is_dir = child.state.read().entry.is_dir();
+        if child_is_dir {
+            // a directory can't be moved into itself
+            let mut dir = target.arc();
+            loop {
+                if Arc::ptr_eq(&dir, &child) {
+                    return Err(FsError::InvalidParam);
+                }
+                let parent = dir.state.read().parent.clone();
+                match parent {
+                    Some(parent) => dir = parent,
+                    None => break,
+                }
+            }
+        }
+        let existing = {
+            let state = target.state.read();
+            check_dir(target, &state)?;
+            match target.find_entry(&state, new_name)? {
+                Some(entry) => Some((target.open_entry(&state, &entry)?, entry.name)),
+                None => None,
+            }
+        };
+        if let Some((existing, existing_name)) = existing {
+            if Arc::ptr_eq(&existing, &child) {
+                // the same entry, renamed if only the case of the name changes
+                if existing_name == new_name {
+                    return Ok(());
+                }
+            } else {
+                let existing_is_dir = existing.state.read().entry.is_dir();
+                match (child_is_dir, existing_is_dir) {
+                    (true, false) => return Err(FsError::NotDir),
+                    (false, true) => return Err(FsError::IsDir),
+                    _ => {}
+                }
+                drop(existing);
+                target.unlink(&existing_name)?;
+            }
+        }
+
+        // lock in the order of inode numbers
+        let same_dir = self.id == target.id;
+        let (mut state, mut target_state) = if same_dir {
+            (self.state.write(), None)
+        } else if self.id < target.id {
+            let state = self.state.write();
+            (state, Some(target.state.write()))
+        } else {
+            let target_state = target.state.write();
+            (self.state.write(), Some(target_state))
+        };
+        let mut child_state = child.state.write();
+        let old_pos = child_state.pos.ok_or(FsError::EntryNotFound)?;
+        let old_entry = self
+            .dir_entries(&state)?
+            .1
+            .into_iter()
+            .find(|entry| self.device_offset(&state, entry.offset).0 == old_pos)
+            .ok_or(FsError::EntryNotFound)?;
+
+        let mut entry = child_state.entry.clone();
+        let (dir, dir_state) = match target_state {
+            Some(ref mut target_state) => (target, &mut **target_state),
+            None => (self, &mut *state),
+        };
+        let entries = dir.name_entries(dir_state, new_name, &mut entry)?;
+        let offset = dir.add_entries(dir_state, &entries)?;
+        let (new_pos, _) = dir.device_offset(dir_state, offset);
+        let target_cluster = dir.dir_cluster(dir_state);
+        self.remove_entry(&state, &old_entry)?;
+        self.fs.move_inode(old_pos, new_pos);
+        child_state.pos = Some(new_pos);
+        child_state.entry = entry;
+        if !same_dir {
+            child_state.parent = Some(target.arc());
+            if child_is_dir {
+                // point ".." to the new parent
+                let (pos, _) = child.device_offset(&child_state, DIRENT_SIZE);
+                let mut raw = [0u8; DIRENT_SIZE];
+                self.fs.read(pos, &mut raw)?;
+                let mut dotdot = ShortEntry::parse(&raw);
+                dotdot.set_first_cluster(target_cluster);
+                self.fs.write(pos, &dotdot.raw)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
+        let state = self.state.read();
+        check_dir(self, &state)?;
+        match name {
+            "." => return Ok(self.arc()),
+            ".." => {
+                return Ok(match state.parent {
+                    Some(ref parent) => parent.clone(),
+                    None => self.arc(),
+                })
+            }
+            _ => {}
+        }
+        let entry = self
+            .find_entry(&state, name.trim_end_matches('.'))?
+            .ok_or(FsError::EntryNotFound)?;
+        Ok(self.open_entry(&state, &entry)?)
+    }
+
+    fn get_entry(&self, id: usize) -> Result<String> {
+        let state = self.state.read();
+        if !state.entry.is_dir() {
+            return Err(FsError::NotDir);
+        }
+        // the root directory has no "." and ".." on disk
+        let id = if self.is_root {
+            match id {
+                0 => return Ok(String::from(".")),
+                1 => return Ok(String::from("..")),
+                _ => id - 2,
+            }
+        } else {
+            id
+        };
+        let (_, entries) = self.dir_entries(&state)?;
+        entries
+            .into_iter()
+            .nth(id)
+            .map(|entry| entry.name)
+            .ok_or(FsError::EntryNotFound)
+    }
+
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        self.fs.clone()
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Drop for FatINode {
+    /// Free the clusters of a removed file once it's no longer open
+    fn drop(&mut self) {
+        let state = self.state.write();
+        let pos = match (state.pos, self.is_root) {
+            (_, true) => 0,
+            (Some(pos), false) => pos,
+            (None, false) => {
+                if let Some(&first) = state.clusters.first() {
+                    if let Err(e) = self.fs.free_chain(first) {
+                        warn!("fat: failed to free removed file {}: {:?}", self.id, e);
+                    }
+                }
+                return;
+            }
+        };
+        drop(state);
+        self.fs.forget_inode(pos);
+    }
+}
@@ -0,0 +1,413 @@
+//! FAT12, FAT16 and FAT32 file systems, with long file names
+//!
+//! FAT has no inodes, a file is its directory entry. Entries are opened as `FatINode`s
+//! kept by the position of the entry on the device, so each has one in-memory copy,
+//! with inode numbers handed out as they are opened. Like ext2, everything is written
+//! through to the device as soon as it changes.
+
+mod inode;
+mod structs;
+
+pub use self::inode::FatINode;
+
+use self::structs::*;
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rcore_fs::dev::Device;
+use rcore_fs::vfs::*;
+use spin::{Mutex, RwLock};
+
+/// The first data cluster, clusters 0 and 1 only have reserved FAT entries
+const FIRST_CLUSTER: u32 = 2;
+
+/// Free cluster counters, kept in the FSInfo sector on FAT32
+struct AllocState {
+    free_count: u32,
+    /// Where the search for a free cluster starts
+    next_free: u32,
+}
+
+pub struct FatFileSystem {
+    device: Arc<dyn Device>,
+    dev: usize,
+    fat_type: FatType,
+    cluster_size: usize,
+    /// Clusters from 2 to `cluster_count + 1` hold data
+    cluster_count: u32,
+    /// Offset of each FAT in use
+    fats: Vec<usize>,
+    /// Offset of the fixed root directory of FAT12 and FAT16
+    root_dir: usize,
+    root_entries: usize,
+    /// First cluster of the root directory of FAT32
+    root_cluster: u32,
+    data: usize,
+    /// Offset of the FSInfo sector of FAT32
+    fs_info: Option<usize>,
+    alloc: Mutex<AllocState>,
+    /// Opened entries by their position on the device, the root at 0
+    inodes: RwLock<BTreeMap<usize, Weak<FatINode>>>,
+    next_ino: AtomicUsize,
+    self_ptr: RwLock<Weak<FatFileSystem>>,
+}
+
+impl FatFileSystem {
+    /// Load a FAT file system from `device`
+    pub fn open(device: Arc<dyn Device>) -> Result<Arc<Self>> {
+        let mut raw = vec![0; 512];
+        read_device(&*device, 0, &mut raw)?;
+        let boot = BootSector::parse(&raw).ok_or(FsError::WrongFs)?;
+        let fat_type = boot.fat_type();
+        if (fat_type == FatType::Fat32) != (boot.root_entries == 0) {
+            warn!("fat: root directory doesn't match {:?}", fat_type);
+            return Err(FsError::WrongFs);
+        }
+        let sector = boot.bytes_per_sector as usize;
+        let cluster_size = sector * boot.sectors_per_cluster as usize;
+        let fat_size = boot.fat_sectors as usize * sector;
+        let fat_start = boot.reserved_sectors as usize * sector;
+        let fats = if fat_type == FatType::Fat32 && boot.ext_flags & 0x80 != 0 {
+            // mirroring is off, only the active FAT is in use
+            let active = (boot.ext_flags & 0xf) as usize;
+            vec![fat_start + active * fat_size]
+        } else {
+            (0..boot.num_fats as usize)
+                .map(|i| fat_start + i * fat_size)
+                .collect()
+        };
+        let root_dir = fat_start + boot.num_fats as usize * fat_size;
+        let cluster_count = boot.cluster_count();
+
+        let mut fs_info = None;
+        let mut hints = None;
+        if fat_type == FatType::Fat32 && boot.fs_info != 0 && boot.fs_info != 0xffff {
+            let offset = boot.fs_info as usize * sector;
+            read_device(&*device, offset, &mut raw)?;
+            if let Some(info) = FsInfoSector::parse(&raw) {
+                fs_info = Some(offset);
+                hints = Some(info);
+            }
+        }
+
+        let fs = FatFileSystem {
+            device,
+            dev: super::alloc_dev(),
+            fat_type,
+            cluster_size,
+            cluster_count,
+            fats,
+            root_dir,
+            root_entries: boot.root_entries as usize,
+            root_cluster: boot.root_cluster,
+            data: boot.first_data_sector() as usize * sector,
+            fs_info,
+            alloc: Mutex::new(AllocState {
+                free_count: 0,
+                next_free: FIRST_CLUSTER,
+            }),
+            inodes: RwLock::new(BTreeMap::new()),
+            next_ino: AtomicUsize::new(2),
+            self_ptr: RwLock::new(Weak::new()),
+        };
+        let free_count = match hints {
+            Some(ref info) if info.free_count <= cluster_count => info.free_count,
+            _ => fs.count_free()?,
+        };
+        let next_free = match hints {
+            Some(ref info) if fs.is_data_cluster(info.next_free) => info.next_free,
+            _ => FIRST_CLUSTER,
+        };
+        *fs.alloc.lock() = AllocState {
+            free_count,
+            next_free,
+        };
+        info!(
+            "fat: {:?}, {} clusters of {} bytes, {} free",
+            fat_type, cluster_count, cluster_size, free_count
+        );
+
+        let fs = Arc::new(fs);
+        *fs.self_ptr.write() = Arc::downgrade(&fs);
+        Ok(fs)
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        read_device(&*self.device, offset, buf)
+    }
+
+    fn write(&self, offset: usize, buf: &[u8]) -> Result<()> {
+        match self.device.write_at(offset, buf) {
+            Ok(len) if len == buf.len() => Ok(()),
+            _ => Err(FsError::DeviceError),
+        }
+    }
+
+    fn is_data_cluster(&self, cluster: u32) -> bool {
+        cluster >= FIRST_CLUSTER && cluster < FIRST_CLUSTER + self.cluster_count
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> usize {
+        self.data + (cluster - FIRST_CLUSTER) as usize * self.cluster_size
+    }
+
+    /// Offset of the FAT entry of `cluster` in a FAT
+    fn entry_offset(&self, cluster: u32) -> usize {
+        let cluster = cluster as usize;
+        match self.fat_type {
+            FatType::Fat12 => cluster + cluster / 2,
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+        }
+    }
+
+    /// The FAT entry of `cluster`, the next cluster of its chain
+    fn read_fat(&self, cluster: u32) -> Result<u32> {
+        let offset = self.fats[0] + self.entry_offset(cluster);
+        match self.fat_type {
+            FatType::Fat12 => {
+                let mut buf = [0u8; 2];
+                self.read(offset, &mut buf)?;
+                let value = u16::from_le_bytes(buf) as u32;
+                Ok(if cluster % 2 == 0 {
+                    value & 0xfff
+                } else {
+                    value >> 4
+                })
+            }
+            FatType::Fat16 => {
+                let mut buf = [0u8; 2];
+                self.read(offset, &mut buf)?;
+                Ok(u16::from_le_bytes(buf) as u32)
+            }
+            FatType::Fat32 => {
+                let mut buf = [0u8; 4];
+                self.read(offset, &mut buf)?;
+                Ok(u32::from_le_bytes(buf) & 0x0fff_ffff)
+            }
+        }
+    }
+
+    /// Set the FAT entry of `cluster` in every FAT
+    fn write_fat(&self, cluster: u32, value: u32) -> Result<()> {
+        let entry = self.entry_offset(cluster);
+        let mut buf = [0u8; 4];
+        let len = match self.fat_type {
+            FatType::Fat32 => 4,
+            _ => 2,
+        };
+        self.read(self.fats[0] + entry, &mut buf[..len])?;
+        match self.fat_type {
+            FatType::Fat12 => {
+                // entries share the byte in the middle
+                let old = read_u16(&buf, 0);
+                let new = if cluster % 2 == 0 {
+                    (old & 0xf000) | value as u16
+                } else {
+                    (old & 0x000f) | (value as u16) << 4
+                };
+                write_u16(&mut buf, 0, new);
+            }
+            FatType::Fat16 => write_u16(&mut buf, 0, value as u16),
+            FatType::Fat32 => {
+                // the high 4 bits are reserved and kept
+                let old = read_u32(&buf, 0);
+                write_u32(&mut buf, 0, (old & 0xf000_0000) | value);
+            }
+        }
+        for &fat in self.fats.iter() {
+            self.write(fat + entry, &buf[..len])?;
+        }
+        Ok(())
+    }
+
+    /// Count the free clusters by reading the whole FAT
+    fn count_free(&self) -> Result<u32> {
+        let mut free = 0;
+        let mut cluster = FIRST_CLUSTER;
+        let end = FIRST_CLUSTER + self.cluster_count;
+        let mut buf = vec![0u8; 1024 * 4 + 2];
+        while cluster < end {
+            let count = (end - cluster).min(1024);
+            let start = self.entry_offset(cluster);
+            let len = self.entry_offset(cluster + count) - start + 2;
+            self.read(self.fats[0] + start, &mut buf[..len])?;
+            for i in 0..count {
+                let c = cluster + i;
+                let offset = self.entry_offset(c) - start;
+                let value = match self.fat_type {
+                    FatType::Fat12 if c % 2 == 0 => read_u16(&buf, offset) as u32 & 0xfff,
+                    FatType::Fat12 => read_u16(&buf, offset) as u32 >> 4,
+                    FatType::Fat16 => read_u16(&buf, offset) as u32,
+                    FatType::Fat32 => read_u32(&buf, offset) & 0x0fff_ffff,
+                };
+                if value == 0 {
+                    free += 1;
+                }
+            }
+            cluster += count;
+        }
+        Ok(free)
+    }
+
+    fn write_fs_info(&self, state: &AllocState) -> Result<()> {
+        match self.fs_info {
+            Some(offset) => {
+                let info = FsInfoSector {
+                    free_count: state.free_count,
+                    next_free: state.next_free,
+                };
+                self.write(offset + 488, &info.counters())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Allocate a zeroed cluster at the end of the chain ending with `prev`, 0 for a new chain
+    fn alloc_cluster(&self, prev: u32) -> Result<u32> {
+        let mut state = self.alloc.lock();
+        if state.free_count == 0 {
+            return Err(FsError::NoDeviceSpace);
+        }
+        let start = state.next_free - FIRST_CLUSTER;
+        let mut found = None;
+        for i in 0..self.cluster_count {
+            let cluster = FIRST_CLUSTER + (start + i) % self.cluster_count;
+            if self.read_fat(cluster)? == 0 {
+                found = Some(cluster);
+                break;
+            }
+        }
+        let cluster = match found {
+            Some(cluster) => cluster,
+            None => {
+                warn!("fat: free cluster count is wrong");
+                state.free_count = 0;
+                return Err(FsError::NoDeviceSpace);
+            }
+        };
+        self.write_fat(cluster, self.fat_type.end_mark())?;
+        if prev != 0 {
+            self.write_fat(prev, cluster)?;
+        }
+        state.free_count -= 1;
+        state.next_free = match cluster + 1 {
+            next if self.is_data_cluster(next) => next,
+            _ => FIRST_CLUSTER,
+        };
+        self.write_fs_info(&state)?;
+        drop(state);
+
+        let zeros = vec![0u8; self.cluster_size];
+        self.write(self.cluster_offset(cluster), &zeros)?;
+        Ok(cluster)
+    }
+
+    /// Free the chain starting from `cluster`
+    fn free_chain(&self, mut cluster: u32) -> Result<()> {
+        let mut state = self.alloc.lock();
+        let mut count = 0;
+        while self.is_data_cluster(cluster) && count < self.cluster_count {
+            let next = self.read_fat(cluster)?;
+            self.write_fat(cluster, 0)?;
+            state.free_count += 1;
+            count += 1;
+            cluster = next;
+        }
+        self.write_fs_info(&state)
+    }
+
+    /// The clusters of the chain starting from `cluster`
+    fn read_chain(&self, mut cluster: u32) -> Result<Vec<u32>> {
+        let mut chain = Vec::new();
+        while self.is_data_cluster(cluster) {
+            if chain.len() as u32 >= self.cluster_count {
+                warn!("fat: cluster chain loops");
+                return Err(FsError::DeviceError);
+            }
+            chain.push(cluster);
+            cluster = self.read_fat(cluster)?;
+        }
+        if cluster != 0 && cluster < self.fat_type.end_of_chain() {
+            warn!("fat: cluster chain ends with {:#x}", cluster);
+            return Err(FsError::DeviceError);
+        }
+        Ok(chain)
+    }
+
+    /// Get the opened entry at `pos`, or open it with `open`
+    fn get_inode(
+        &self,
+        pos: usize,
+        open: impl FnOnce(usize, Arc<FatFileSystem>) -> Result<FatINode>,
+    ) -> Result<Arc<FatINode>> {
+        if let Some(inode) = self.inodes.read().get(&pos).and_then(|weak| weak.upgrade()) {
+            return Ok(inode);
+        }
+        let fs = self.self_ptr.read().upgrade().unwrap();
+        let id = self.next_ino.fetch_add(1, Ordering::Relaxed);
+        let inode = Arc::new(open(id, fs)?);
+        FatINode::set_self_ptr(&inode);
+        let mut inodes = self.inodes.write();
+        // opened by someone else meanwhile
+        if let Some(other) = inodes.get(&pos).and_then(|weak| weak.upgrade()) {
+            return Ok(other);
+        }
+        inodes.insert(pos, Arc::downgrade(&inode));
+        Ok(inode)
+    }
+
+    /// The opened entry at `pos` moved to `new_pos`
+    fn move_inode(&self, pos: usize, new_pos: usize) {
+        let mut inodes = self.inodes.write();
+        if let Some(weak) = inodes.remove(&pos) {
+            inodes.insert(new_pos, weak);
+        }
+    }
+
+    /// Forget the entry at `pos` when its last reference is dropped
+    fn forget_inode(&self, pos: usize) {
+        let mut inodes = self.inodes.write();
+        if inodes
+            .get(&pos)
+            .map_or(false, |weak| weak.strong_count() == 0)
+        {
+            inodes.remove(&pos);
+        }
+    }
+}
+
+impl FileSystem for FatFileSystem {
+    fn sync(&self) -> Result<()> {
+        self.device.sync().map_err(|_| FsError::DeviceError)
+    }
+
+    fn root_inode(&self) -> Arc<dyn INode> {
+        self.get_inode(0, |_, fs| FatINode::root(fs))
+            .expect("failed to read the root directory")
+    }
+
+    fn info(&self) -> FsInfo {
+        let state = self.alloc.lock();
+        FsInfo {
+            bsize: self.cluster_size,
+            frsize: self.cluster_size,
+            blocks: self.cluster_count as usize,
+            bfree: state.free_count as usize,
+            bavail: state.free_count as usize,
+            files: 0,
+            ffree: 0,
+            namemax: MAX_NAME_LEN,
+        }
+    }
+}
+
+fn read_device(device: &dyn Device, offset: usize, buf: &mut [u8]) -> Result<()> {
+    match device.read_at(offset, buf) {
+        Ok(len) if len == buf.len() => Ok(()),
+        _ => Err(FsError::DeviceError),
+    }
+}
@@ -0,0 +1,500 @@
+//! On-disk structures of FAT, all little endian
+
+use alloc::{string::String, vec::Vec};
+
+pub const DIRENT_SIZE: usize = 32;
+/// Characters of a long name kept in each long name entry
+pub const LFN_CHARS: usize = 13;
+/// Maximum length of a long name in UTF-16 units
+pub const MAX_NAME_LEN: usize = 255;
+/// File sizes are kept in 32 bits
+pub const MAX_FILE_SIZE: usize = 0xffff_ffff;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+pub const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+/// First byte of the name of a free entry
+pub const ENTRY_FREE: u8 = 0xe5;
+/// First byte of the name of the end of a directory, all entries after it are free
+pub const ENTRY_END: u8 = 0;
+/// Set in the order of the last long name entry of a name, which comes first on disk
+pub const LFN_LAST: u8 = 0x40;
+
+/// The base of the short name is displayed in lower case
+const NTRES_LOWER_BASE: u8 = 0x08;
+/// The extension of the short name is displayed in lower case
+const NTRES_LOWER_EXT: u8 = 0x10;
+
+const FSINFO_LEAD_SIG: u32 = 0x4161_5252;
+const FSINFO_STRUC_SIG: u32 = 0x6141_7272;
+
+pub fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+pub fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+pub fn write_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+pub fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// The type is decided by the number of clusters only
+    fn from_clusters(clusters: u32) -> Self {
+        if clusters < 4085 {
+            FatType::Fat12
+        } else if clusters < 65525 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        }
+    }
+
+    /// Entries from this value on mark the end of a chain
+    pub fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xff8,
+            FatType::Fat16 => 0xfff8,
+            FatType::Fat32 => 0x0fff_fff8,
+        }
+    }
+
+    /// The value written to end a chain
+    pub fn end_mark(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xfff,
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => 0x0fff_ffff,
+        }
+    }
+}
+
+/// The BIOS parameter block in the boot sector
+pub struct BootSector {
+    pub bytes_per_sector: u16,
+    pub sectors_per_cluster: u8,
+    pub reserved_sectors: u16,
+    pub num_fats: u8,
+    /// Entries of the fixed root directory, 0 on FAT32
+    pub root_entries: u16,
+    pub total_sectors: u32,
+    pub fat_sectors: u32,
+    /// FAT32 only, the active FAT in the low bits, and bit 7 set if only it's in use
+    pub ext_flags: u16,
+    /// FAT32 only, the first cluster of the root directory
+    pub root_cluster: u32,
+    /// FAT32 only, the sector of the FSInfo structure
+    pub fs_info: u16,
+}
+
+impl BootSector {
+    pub fn parse(raw: &[u8]) -> Option<Self> {
+        if raw[510] != 0x55 || raw[511] != 0xaa {
+            return None;
+        }
+        let total_sectors = match read_u16(raw, 19) {
+            0 => read_u32(raw, 32),
+            n => n as u32,
+        };
+        let fat_sectors = match read_u16(raw, 22) {
+            0 => read_u32(raw, 36),
+            n => n as u32,
+        };
+        let boot = BootSector {
+            bytes_per_sector: read_u16(raw, 11),
+            sectors_per_cluster: raw[13],
+            reserved_sectors: read_u16(raw, 14),
+            num_fats: raw[16],
+            root_entries: read_u16(raw, 17),
+            total_sectors,
+            fat_sectors,
+            ext_flags: read_u16(raw, 40),
+            root_cluster: read_u32(raw, 44),
+            fs_info: read_u16(raw, 48),
+        };
+        let valid = boot.bytes_per_sector.is_power_of_two()
+            && boot.bytes_per_sector >= 512
+            && boot.bytes_per_sector <= 4096
+            && boot.sectors_per_cluster.is_power_of_two()
+            && boot.reserved_sectors != 0
+            && boot.num_fats != 0
+            && boot.fat_sectors != 0
+            && boot.first_data_sector() < boot.total_sectors;
+        if valid {
+            Some(boot)
+        } else {
+            None
+        }
+    }
+
+    pub fn root_dir_sectors(&self) -> u32 {
+        let bytes = self.root_entries as u32 * DIRENT_SIZE as u32;
+        let sector = self.bytes_per_sector as u32;
+        (bytes + sector - 1) / sector
+    }
+
+    pub fn first_data_sector(&self) -> u32 {
+        self.reserved_sectors as u32
+            + self.num_fats as u32 * self.fat_sectors
+            + self.root_dir_sectors()
+    }
+
+    pub fn cluster_count(&self) -> u32 {
+        (self.total_sectors - self.first_data_sector()) / self.sectors_per_cluster as u32
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        FatType::from_clusters(self.cluster_count())
+    }
+}
+
+/// The FSInfo sector of FAT32, hints of free clusters
+pub struct FsInfoSector {
+    pub free_count: u32,
+    pub next_free: u32,
+}
+
+impl FsInfoSector {
+    pub fn parse(raw: &[u8]) -> Option<Self> {
+        if read_u32(raw, 0) != FSINFO_LEAD_SIG || read_u32(raw, 484) != FSINFO_STRUC_SIG {
+            return None;
+        }
+        Some(FsInfoSector {
+            free_count: read_u32(raw, 488),
+            next_free: read_u32(raw, 492),
+        })
+    }
+
+    /// The bytes of the counters, to be written 488 bytes into the sector
+    pub fn counters(&self) -> [u8; 8] {
+        let mut raw = [0u8; 8];
+        write_u32(&mut raw, 0, self.free_count);
+        write_u32(&mut raw, 4, self.next_free);
+        raw
+    }
+}
+
+/// A short name directory entry, holding everything about the file but the long name
+#[derive(Clone)]
+pub struct ShortEntry {
+    pub raw: [u8; DIRENT_SIZE],
+}
+
+impl ShortEntry {
+    pub fn parse(raw: &[u8]) -> Self {
+        let mut entry = ShortEntry {
+            raw: [0; DIRENT_SIZE],
+        };
+        entry.raw.copy_from_slice(&raw[..DIRENT_SIZE]);
+        entry
+    }
+
+    /// A new entry named `name`, an 11 byte short name, and the case flags
+    pub fn new(name: &[u8; 11], ntres: u8, attr: u8) -> Self {
+        let mut entry = ShortEntry {
+            raw: [0; DIRENT_SIZE],
+        };
+        entry.raw[..11].copy_from_slice(name);
+        entry.raw[11] = attr;
+        entry.raw[12] = ntres;
+        entry
+    }
+
+    pub fn short_name(&self) -> &[u8] {
+        &self.raw[..11]
+    }
+
+    pub fn set_short_name(&mut self, name: &[u8; 11], ntres: u8) {
+        self.raw[..11].copy_from_slice(name);
+        self.raw[12] = ntres;
+    }
+
+    pub fn attr(&self) -> u8 {
+        self.raw[11]
+    }
+
+    pub fn set_attr(&mut self, attr: u8) {
+        self.raw[11] = attr;
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attr() & ATTR_DIRECTORY != 0
+    }
+
+    /// The high half of the cluster is only used by FAT32
+    pub fn first_cluster(&self, fat_type: FatType) -> u32 {
+        let low = read_u16(&self.raw, 26) as u32;
+        match fat_type {
+            FatType::Fat32 => low | (read_u16(&self.raw, 20) as u32) << 16,
+            _ => low,
+        }
+    }
+
+    pub fn set_first_cluster(&mut self, cluster: u32) {
+        write_u16(&mut self.raw, 20, (cluster >> 16) as u16);
+        write_u16(&mut self.raw, 26, cluster as u16);
+    }
+
+    pub fn size(&self) -> u32 {
+        read_u32(&self.raw, 28)
+    }
+
+    pub fn set_size(&mut self, size: u32) {
+        write_u32(&mut self.raw, 28, size);
+    }
+
+    pub fn ctime(&self) -> i64 {
+        from_fat_time(read_u16(&self.raw, 16), read_u16(&self.raw, 14))
+    }
+
+    pub fn mtime(&self) -> i64 {
+        from_fat_time(read_u16(&self.raw, 24), read_u16(&self.raw, 22))
+    }
+
+    /// Only the date of the last access is kept
+    pub fn atime(&self) -> i64 {
+        from_fat_time(read_u16(&self.raw, 18), 0)
+    }
+
+    pub fn set_ctime(&mut self, time: i64) {
+        let (date, time) = to_fat_time(time);
+        self.raw[13] = 0;
+        write_u16(&mut self.raw, 14, time);
+        write_u16(&mut self.raw, 16, date);
+    }
+
+    pub fn set_mtime(&mut self, time: i64) {
+        let (date, time) = to_fat_time(time);
+        write_u16(&mut self.raw, 22, time);
+        write_u16(&mut self.raw, 24, date);
+    }
+
+    pub fn set_atime(&mut self, time: i64) {
+        write_u16(&mut self.raw, 18, to_fat_time(time).0);
+    }
+
+    /// The name of the entry from its short name
+    pub fn name(&self) -> String {
+        let raw = &self.raw;
+        let ntres = raw[12];
+        let mut name = String::new();
+        for (i, &c) in raw[..8].iter().enumerate() {
+            // 0xe5 is a valid first byte, stored as 0x05 as it marks free entries
+            let c = if i == 0 && c == 0x05 { ENTRY_FREE } else { c };
+            name.push(short_char(c, ntres & NTRES_LOWER_BASE != 0));
+        }
+        let len = name.trim_end_matches(' ').len();
+        name.truncate(len);
+        let ext: String = raw[8..11]
+            .iter()
+            .map(|&c| short_char(c, ntres & NTRES_LOWER_EXT != 0))
+            .collect();
+        let ext = ext.trim_end_matches(' ');
+        if !ext.is_empty() {
+            name.push('.');
+            name += ext;
+        }
+        name
+    }
+}
+
+/// Characters of short names beyond ASCII are in an unknown code page, shown as Latin-1
+fn short_char(c: u8, lower: bool) -> char {
+    let c = if lower { c.to_ascii_lowercase() } else { c };
+    c as char
+}
+
+/// The checksum of a short name, kept in the long name entries belonging to it
+pub fn lfn_checksum(short_name: &[u8]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &c| {
+        (sum >> 1).wrapping_add(sum << 7).wrapping_add(c)
+    })
+}
+
+/// Offsets of the name characters in a long name entry
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// Take the part of a long name in `raw`, returning its order and checksum
+pub fn parse_lfn(raw: &[u8], chars: &mut [u16; LFN_CHARS]) -> (u8, u8) {
+    for (c, &offset) in chars.iter_mut().zip(LFN_OFFSETS.iter()) {
+        *c = read_u16(raw, offset);
+    }
+    (raw[0], raw[13])
+}
+
+/// Long name entries of `name` for the short name with `checksum`, in the order on disk
+pub fn lfn_entries(name: &[u16], checksum: u8) -> Vec<[u8; DIRENT_SIZE]> {
+    let count = (name.len() + LFN_CHARS - 1) / LFN_CHARS;
+    let mut entries = Vec::new();
+    for i in (0..count).rev() {
+        let mut raw = [0u8; DIRENT_SIZE];
+        raw[0] = (i + 1) as u8 | if i + 1 == count { LFN_LAST } else { 0 };
+        raw[11] = ATTR_LONG_NAME;
+        raw[13] = checksum;
+        for (j, &offset) in LFN_OFFSETS.iter().enumerate() {
+            // terminated by a 0 if there's room, padded with 0xffff
+            let c = match i * LFN_CHARS + j {
+                k if k < name.len() => name[k],
+                k if k == name.len() => 0,
+                _ => 0xffff,
+            };
+            write_u16(&mut raw, offset, c);
+        }
+        entries.push(raw);
+    }
+    entries
+}
+
+/// Characters allowed in short names besides letters and digits
+fn short_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "$%'-_@~`!(){}^#&".contains(c)
+}
+
+/// Split `name` into the base and the extension, the extension after the last dot
+fn split_ext(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(0) | None => (name, ""),
+        Some(i) => (&name[..i], &name[i + 1..]),
+    }
+}
+
+/// The short name of `name` if it's a valid one in a single case per part,
+/// with the case flags recording lower case parts
+pub fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = split_ext(name);
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 {
+        return None;
+    }
+    if !base.chars().chain(ext.chars()).all(short_name_char) {
+        return None;
+    }
+    let mut ntres = 0;
+    for (part, flag) in [(base, NTRES_LOWER_BASE), (ext, NTRES_LOWER_EXT)].iter() {
+        let lower = part.chars().any(|c| c.is_ascii_lowercase());
+        let upper = part.chars().any(|c| c.is_ascii_uppercase());
+        match (lower, upper) {
+            (true, true) => return None,
+            (true, false) => ntres |= flag,
+            _ => {}
+        }
+    }
+    let mut short = [b' '; 11];
+    for (i, c) in base.bytes().enumerate() {
+        short[i] = c.to_ascii_uppercase();
+    }
+    for (i, c) in ext.bytes().enumerate() {
+        short[8 + i] = c.to_ascii_uppercase();
+    }
+    if short[0] == ENTRY_FREE {
+        short[0] = 0x05;
+    }
+    Some((short, ntres))
+}
+
+/// A short name for the long name `name` in the form `BASE~N.EXT`,
+/// with the lowest `N` for which `taken` returns false
+pub fn generate_short_name(name: &str, taken: impl Fn(&[u8; 11]) -> bool) -> Option<[u8; 11]> {
+    let (base, ext) = split_ext(name.trim_start_matches('.'));
+    let convert = |part: &str, len: usize| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| {
+                if short_name_char(c) {
+                    c.to_ascii_uppercase() as u8
+                } else {
+                    b'_'
+                }
+            })
+            .take(len)
+            .collect()
+    };
+    let mut base = convert(base, 8);
+    if base.is_empty() {
+        base.push(b'_');
+    }
+    let ext = convert(ext, 3);
+    for n in 1..1_000_000usize {
+        let tail = format!("~{}", n);
+        let keep = base.len().min(8 - tail.len());
+        let mut short = [b' '; 11];
+        short[..keep].copy_from_slice(&base[..keep]);
+        short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        short[8..8 + ext.len()].copy_from_slice(&ext);
+        if !taken(&short) {
+            return Some(short);
+        }
+    }
+    None
+}
+
+/// Seconds since the epoch of a FAT date and time, which are local time, taken as UTC
+fn from_fat_time(date: u16, time: u16) -> i64 {
+    if date == 0 {
+        return 0;
+    }
+    let year = 1980 + (date >> 9) as i64;
+    let month = ((date >> 5) & 0xf).max(1) as i64;
+    let day = (date & 0x1f).max(1) as i64;
+    let hour = (time >> 11) as i64;
+    let min = ((time >> 5) & 0x3f) as i64;
+    let sec = ((time & 0x1f) * 2) as i64;
+    days_from_civil(year, month, day) * 86400 + hour * 3600 + min * 60 + sec
+}
+
+/// The FAT date and time of seconds since the epoch, clamped to the years FAT can hold
+fn to_fat_time(time: i64) -> (u16, u16) {
+    let min_time = days_from_civil(1980, 1, 1) * 86400;
+    let max_time = days_from_civil(2108, 1, 1) * 86400 - 2;
+    let time = time.max(min_time).min(max_time);
+    let (year, month, day) = civil_from_days(time.div_euclid(86400));
+    let secs = time.rem_euclid(86400);
+    let date = ((year - 1980) << 9 | month << 5 | day) as u16;
+    let time = ((secs / 3600) << 11 | (secs / 60 % 60) << 5 | (secs % 60) / 2) as u16;
+    (date, time)
+}
+
+/// Days since the epoch of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = (month + 9) % 12;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The date of days since the epoch
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
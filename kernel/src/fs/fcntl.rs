@@ -10,11 +10,21 @@ pub const F_GETLK: usize = 5; /* Get record locking info.  */
 pub const F_SETLK: usize = 6; /* Set record locking info (non-blocking).  */
 pub const F_SETLKW: usize = 7; /* Set record locking info (blocking).  */
 
+/* For F_[GET|SET]LK */
+pub const F_RDLCK: usize = 0;
+pub const F_WRLCK: usize = 1;
+pub const F_UNLCK: usize = 2;
+
 const F_LINUX_SPECIFIC_BASE: usize = 1024;
 
 pub const FD_CLOEXEC: usize = 1;
 pub const F_DUPFD_CLOEXEC: usize = F_LINUX_SPECIFIC_BASE + 6;
 
+/* Open file description locks, owned by the open file instead of the process */
+pub const F_OFD_GETLK: usize = 36;
+pub const F_OFD_SETLK: usize = 37;
+pub const F_OFD_SETLKW: usize = 38;
+
 pub const O_NONBLOCK: usize = 0o4000;
 pub const O_APPEND: usize = 0o2000;
 pub const O_CLOEXEC: usize = 0o2000000; /* set close_on_exec */
@@ -1,14 +1,18 @@
 //! File handle for process
 
+use super::inotify::{self, InotifyMask};
+use super::lock::{self, LockOwner};
+use super::page_cache::{self, CachedINode};
 use crate::memory::GlobalFrameAlloc;
-use crate::process::{current_thread, INodeForMap};
-use crate::syscall::{MmapProt, SysResult, TimeSpec};
+use crate::process::current_thread;
+use crate::syscall::{MmapFlags, MmapProt, SysResult, TimeSpec};
 use alloc::{string::String, sync::Arc};
 use core::fmt;
 
 use rcore_fs::vfs::FsError::{Interrupted, NotSupported};
 use rcore_fs::vfs::{FileType, FsError, INode, MMapArea, Metadata, PollStatus, Result};
-use rcore_memory::memory_set::handler::File;
+use rcore_memory::memory_set::handler::{File, SharedFile};
+use rcore_memory::PAGE_SIZE;
 
 use crate::fs::fcntl::{O_APPEND, O_NONBLOCK};
 use crate::sync::SpinLock as Mutex;
@@ -16,28 +20,31 @@ use crate::syscall::SysError::{EAGAIN, ESPIPE};
 use bitflags::_core::cell::Cell;
 use spin::RwLock;
 
-enum Flock {
-    None = 0,
-    Shared = 1,
-    Exclusive = 2,
-}
-
 struct OpenFileDescription {
     offset: u64,
     options: OpenOptions,
-    flock: Flock,
+    /// Directory and name the file was opened at, told about changes of the file
+    parent: Option<(Arc<dyn INode>, String)>,
+    /// Cached pages of the file, kept while it is open
+    pages: Option<Arc<page_cache::CachedPages>>,
 }
 
 impl OpenFileDescription {
-    fn create(options: OpenOptions) -> Arc<RwLock<Self>> {
+    fn create(inode: &Arc<dyn INode>, options: OpenOptions) -> Arc<RwLock<Self>> {
         Arc::new(RwLock::new(OpenFileDescription {
             offset: 0,
             options,
-            flock: Flock::None,
+            parent: None,
+            pages: page_cache::file_pages(inode).ok().flatten(),
         }))
     }
 }
 
+/// Create the slab cache of open file descriptions
+pub fn create_description_cache() {
+    crate::slab::create_arc_cache::<RwLock<OpenFileDescription>>("file_description");
+}
+
 #[derive(Clone)]
 pub struct FileHandle {
     inode: Arc<dyn INode>,
@@ -72,8 +79,8 @@ impl FileHandle {
         fd_cloexec: bool,
     ) -> Self {
         return FileHandle {
+            description: OpenFileDescription::create(&inode, options),
             inode,
-            description: OpenFileDescription::create(options),
             path,
             pipe,
             fd_cloexec,
@@ -98,6 +105,31 @@ impl FileHandle {
         // options.append = (arg & O_APPEND) != 0;
     }
 
+    /// Record the file was opened as `name` in `dir`, for watches of the directory
+    pub fn set_parent(&self, dir: Arc<dyn INode>, name: &str) {
+        self.description.write().parent = Some((dir, String::from(name)));
+    }
+
+    /// Report `mask` happened to the file, to the watches of it and its directory
+    pub fn notify(&self, mask: InotifyMask) {
+        if self.pipe || !inotify::watching() {
+            return;
+        }
+        inotify::notify(&self.inode, mask);
+        if let Some((dir, name)) = &self.description.read().parent {
+            inotify::notify_entry(dir, name, &self.inode, mask, 0);
+        }
+    }
+
+    pub fn options(&self) -> OpenOptions {
+        self.description.read().options
+    }
+
+    /// The owner of `flock` and OFD locks taken through the open file description
+    pub fn lock_owner(&self) -> LockOwner {
+        LockOwner::File(&*self.description as *const _ as usize)
+    }
+
     // pub fn get_options(&self) -> usize {
     // let options = self.description.read().options;
     // let mut ret = 0 as usize;
@@ -118,7 +150,7 @@ impl FileHandle {
         if !self.description.read().options.nonblock {
             // block
             loop {
-                match self.inode.read_at(offset, buf) {
+                match page_cache::read_at(&self.inode, offset, buf) {
                     Ok(read_len) => {
                         return Ok(read_len);
                     }
@@ -131,7 +163,7 @@ impl FileHandle {
                 }
             }
         } else {
-            let len = self.inode.read_at(offset, buf)?;
+            let len = page_cache::read_at(&self.inode, offset, buf)?;
             Ok(len)
         }
     }
@@ -152,8 +184,11 @@ impl FileHandle {
         if !self.description.read().options.write {
             return Err(FsError::InvalidParam); // TODO: => EBADF
         }
-        let len = self.inode.write_at(offset, buf)?;
+        let len = page_cache::write_at(&self.inode, offset, buf)?;
         TimeSpec::update(&self.inode);
+        if len > 0 {
+            self.notify(InotifyMask::MODIFY);
+        }
         Ok(len)
     }
 
@@ -171,15 +206,18 @@ impl FileHandle {
         if !self.description.read().options.write {
             return Err(FsError::InvalidParam); // TODO: => EBADF
         }
-        self.inode.resize(len as usize)?;
+        page_cache::resize(&self.inode, len as usize)?;
+        self.notify(InotifyMask::MODIFY);
         Ok(())
     }
 
     pub fn sync_all(&mut self) -> Result<()> {
+        page_cache::sync(&self.inode)?;
         self.inode.sync_all()
     }
 
     pub fn sync_data(&mut self) -> Result<()> {
+        page_cache::sync(&self.inode)?;
         self.inode.sync_data()
     }
 
@@ -187,10 +225,6 @@ impl FileHandle {
         self.inode.metadata()
     }
 
-    pub fn lookup_follow(&self, path: &str, max_follow: usize) -> Result<Arc<dyn INode>> {
-        self.inode.lookup_follow(path, max_follow)
-    }
-
     pub fn read_entry(&mut self) -> Result<String> {
         let mut description = self.description.write();
         if !description.options.read {
@@ -230,18 +264,41 @@ impl FileHandle {
         match self.inode.metadata()?.type_ {
             FileType::File => {
                 let prot = MmapProt::from_bits_truncate(area.prot);
+                let flags = MmapFlags::from_bits_truncate(area.flags);
                 let thread = current_thread().unwrap();
+                if flags.contains(MmapFlags::SHARED) {
+                    if area.offset % PAGE_SIZE != 0 {
+                        return Err(FsError::InvalidParam);
+                    }
+                    let writable = self.description.read().options.write
+                        && !super::mount::read_only(&self.inode);
+                    let pages = page_cache::file_pages(&self.inode)?.ok_or(NotSupported)?;
+                    let mut vm = thread.vm.lock();
+                    vm.push(
+                        area.start_vaddr,
+                        area.end_vaddr,
+                        prot.to_attr(),
+                        SharedFile::new(pages, area.start_vaddr, area.offset),
+                        "mmap_file_shared",
+                    );
+                    // nor can `mprotect` make it writable later
+                    if !writable {
+                        vm.forbid_write(area.start_vaddr, area.end_vaddr)
+                            .map_err(|_| FsError::InvalidParam)?;
+                    }
+                    return Ok(());
+                }
                 thread.vm.lock().push(
                     area.start_vaddr,
                     area.end_vaddr,
                     prot.to_attr(),
-                    File {
-                        file: INodeForMap(self.inode.clone()),
-                        mem_start: area.start_vaddr,
-                        file_start: area.offset,
-                        file_end: area.offset + area.end_vaddr - area.start_vaddr,
-                        allocator: GlobalFrameAlloc,
-                    },
+                    File::new(
+                        CachedINode(self.inode.clone()),
+                        area.start_vaddr,
+                        area.offset,
+                        area.offset + area.end_vaddr - area.start_vaddr,
+                        GlobalFrameAlloc,
+                    ),
                     "mmap_file",
                 );
                 Ok(())
@@ -256,6 +313,23 @@ impl FileHandle {
     }
 }
 
+impl Drop for FileHandle {
+    fn drop(&mut self) {
+        // the open file description is closed with its last handle
+        if Arc::strong_count(&self.description) == 1 {
+            let mask = match self.description.read().options.write {
+                true => InotifyMask::CLOSE_WRITE,
+                false => InotifyMask::CLOSE_NOWRITE,
+            };
+            self.notify(mask);
+            lock::release(&self.inode, self.lock_owner());
+            // an unlinked file is gone once closed
+            self.description.write().pages = None;
+            page_cache::forget(&self.inode);
+        }
+    }
+}
+
 impl fmt::Debug for FileHandle {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let description = self.description.read();
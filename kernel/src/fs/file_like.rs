@@ -3,6 +3,7 @@ use core::fmt;
 use super::ioctl::*;
 use super::FileHandle;
 use crate::fs::epoll::EpollInstance;
+use crate::fs::inotify::Inotify;
 use crate::net::Socket;
 use crate::syscall::{SysError, SysResult};
 use alloc::boxed::Box;
@@ -14,6 +15,7 @@ pub enum FileLike {
     File(FileHandle),
     Socket(Box<dyn Socket>),
     EpollInstance(EpollInstance),
+    Inotify(Inotify),
 }
 
 impl FileLike {
@@ -23,6 +25,7 @@ impl FileLike {
             File(file) => File(file.dup(fd_cloexec)),
             Socket(s) => Socket(s.clone()),
             EpollInstance(e) => EpollInstance(e.clone()),
+            Inotify(i) => Inotify(i.dup(fd_cloexec)),
         }
     }
 
@@ -33,6 +36,7 @@ impl FileLike {
             FileLike::EpollInstance(_) => {
                 return Err(SysError::ENOSYS);
             }
+            FileLike::Inotify(inotify) => inotify.read(buf).await?,
         };
         Ok(len)
     }
@@ -40,7 +44,7 @@ impl FileLike {
         let len = match self {
             FileLike::File(file) => file.write(buf)?,
             FileLike::Socket(socket) => socket.write(buf, None)?,
-            FileLike::EpollInstance(_) => {
+            FileLike::EpollInstance(_) | FileLike::Inotify(_) => {
                 return Err(SysError::ENOSYS);
             }
         };
@@ -50,7 +54,7 @@ impl FileLike {
         match self {
             FileLike::File(file) => file.io_control(request as u32, arg1).map_err(Into::into),
             FileLike::Socket(socket) => socket.ioctl(request, arg1, arg2, arg3),
-            FileLike::EpollInstance(_) => {
+            FileLike::EpollInstance(_) | FileLike::Inotify(_) => {
                 return Err(SysError::ENOSYS);
             }
         }
@@ -72,6 +76,7 @@ impl FileLike {
             FileLike::EpollInstance(_) => {
                 return Err(SysError::ENOSYS);
             }
+            FileLike::Inotify(inotify) => inotify.poll(),
         };
         Ok(status)
     }
@@ -85,6 +90,7 @@ impl FileLike {
             FileLike::EpollInstance(_) => {
                 return Err(SysError::ENOSYS);
             }
+            FileLike::Inotify(inotify) => inotify.async_poll().await,
         };
         Ok(status)
     }
@@ -96,6 +102,7 @@ impl fmt::Debug for FileLike {
             FileLike::File(file) => write!(f, "File({:?})", file),
             FileLike::Socket(socket) => write!(f, "Socket({:?})", socket),
             FileLike::EpollInstance(_) => write!(f, "EpollInstance()"),
+            FileLike::Inotify(_) => write!(f, "Inotify()"),
         }
     }
 }
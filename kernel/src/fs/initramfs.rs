@@ -0,0 +1,287 @@
+//! Initial RAM file system, unpacked from `newc` cpio archives into a RamFS root
+//!
+//! An archive is linked to the kernel with the `link_initramfs` feature, or loaded by the
+//! bootloader, and may be compressed with gzip. Like Linux, the linked one is unpacked first,
+//! so the bootloader's one can replace its files.
+
+use crate::memory::phys_to_virt;
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use compression::prelude::*;
+use core::ops::Range;
+use core::slice;
+use core::str;
+use core::sync::atomic::{AtomicBool, Ordering};
+use rcore_fs::vfs::*;
+use rcore_fs_ramfs::RamFS;
+use spin::Mutex;
+
+// Hard link the initramfs
+#[cfg(feature = "link_initramfs")]
+global_asm!(concat!(
+    r#"
+	.section .data.initramfs
+	.global _initramfs_start
+	.global _initramfs_end
+_initramfs_start:
+    .incbin ""#,
+    env!("INITRAMFS"),
+    r#""
+_initramfs_end:
+"#
+));
+
+const HEADER_SIZE: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+const S_IFMT: usize = 0o170000;
+const S_IFDIR: usize = 0o040000;
+const S_IFREG: usize = 0o100000;
+const S_IFLNK: usize = 0o120000;
+
+/// Physical memory of the archive loaded by the bootloader,
+/// kept from the frame allocator until it's unpacked
+static BOOT_IMAGE: Mutex<Option<Range<usize>>> = Mutex::new(None);
+
+/// Whether the root file system is the initramfs
+static LOADED: AtomicBool = AtomicBool::new(false);
+
+/// Record the archive loaded by the bootloader at physical memory `paddr`
+pub fn set_boot_image(paddr: Range<usize>) {
+    *BOOT_IMAGE.lock() = Some(paddr);
+}
+
+/// Whether the root file system is the initramfs
+pub fn loaded() -> bool {
+    LOADED.load(Ordering::Relaxed)
+}
+
+/// A RamFS with the archives unpacked, `None` if there are none
+pub fn load() -> Option<Arc<RamFS>> {
+    let linked = linked_image();
+    let boot = BOOT_IMAGE.lock().take();
+    if linked.is_none() && boot.is_none() {
+        return None;
+    }
+    let ramfs = RamFS::new();
+    let root = ramfs.root_inode();
+    if let Some(image) = linked {
+        info!("initramfs linked to kernel, {} bytes", image.len());
+        unpack(&root, image);
+    }
+    if let Some(paddr) = boot {
+        info!(
+            "initramfs loaded by the bootloader, from {:#x} to {:#x}",
+            paddr.start, paddr.end
+        );
+        let image =
+            unsafe { slice::from_raw_parts(phys_to_virt(paddr.start) as *const u8, paddr.len()) };
+        unpack(&root, image);
+        crate::memory::release_boot_memory(paddr);
+    }
+    LOADED.store(true, Ordering::Relaxed);
+    Some(ramfs)
+}
+
+#[cfg(feature = "link_initramfs")]
+fn linked_image() -> Option<&'static [u8]> {
+    extern "C" {
+        fn _initramfs_start();
+        fn _initramfs_end();
+    }
+    let start = _initramfs_start as usize;
+    let end = _initramfs_end as usize;
+    Some(unsafe { slice::from_raw_parts(start as *const u8, end - start) })
+}
+
+#[cfg(not(feature = "link_initramfs"))]
+fn linked_image() -> Option<&'static [u8]> {
+    None
+}
+
+fn unpack(root: &Arc<dyn INode>, image: &[u8]) {
+    if let Err(e) = unpack_archives(root, image) {
+        warn!("initramfs: broken archive: {:?}", e);
+    }
+}
+
+/// Unpack concatenated archives, which may be padded with zeros in between
+fn unpack_archives(root: &Arc<dyn INode>, mut data: &[u8]) -> Result<()> {
+    loop {
+        while let Some((&0, rest)) = data.split_first() {
+            data = rest;
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+        // the end of a gzip stream isn't known before decoding it, so it takes the rest
+        if data.starts_with(&GZIP_MAGIC) {
+            let data = data
+                .to_vec()
+                .decode(&mut GZipDecoder::new())
+                .collect::<core::result::Result<Vec<_>, _>>()
+                .map_err(|_| FsError::InvalidParam)?;
+            return unpack_archives(root, &data);
+        }
+        data = unpack_archive(root, data)?;
+    }
+}
+
+/// Unpack the archive at the start of `data`, returning what follows it
+fn unpack_archive<'a>(root: &Arc<dyn INode>, data: &'a [u8]) -> Result<&'a [u8]> {
+    // the first of hard links to each file
+    let mut links = BTreeMap::new();
+    let mut offset = 0;
+    loop {
+        let header = Header::parse(&data[offset..])?;
+        let name_start = offset + HEADER_SIZE;
+        let content_start = align4(name_start + header.namesize);
+        let content_end = content_start + header.filesize;
+        if header.namesize == 0 || content_end > data.len() {
+            return Err(FsError::InvalidParam);
+        }
+        let name = str::from_utf8(&data[name_start..name_start + header.namesize - 1])
+            .map_err(|_| FsError::InvalidParam)?;
+        let content = &data[content_start..content_end];
+        offset = align4(content_end).min(data.len());
+        if name == TRAILER {
+            return Ok(&data[offset..]);
+        }
+        if let Err(e) = create(root, name, &header, content, &mut links) {
+            warn!("initramfs: failed to unpack {}: {:?}", name, e);
+        }
+    }
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// Header of an entry in the `newc` format, in hexadecimal text
+struct Header {
+    ino: usize,
+    mode: usize,
+    uid: usize,
+    gid: usize,
+    nlink: usize,
+    mtime: usize,
+    filesize: usize,
+    dev_major: usize,
+    dev_minor: usize,
+    namesize: usize,
+}
+
+impl Header {
+    fn parse(buf: &[u8]) -> Result<Self> {
+        // "070702" has checksums of the contents, which are left unchecked
+        if buf.len() < HEADER_SIZE || !(buf.starts_with(b"070701") || buf.starts_with(b"070702")) {
+            return Err(FsError::InvalidParam);
+        }
+        let field = |index: usize| {
+            let start = 6 + index * 8;
+            str::from_utf8(&buf[start..start + 8])
+                .ok()
+                .and_then(|s| usize::from_str_radix(s, 16).ok())
+                .ok_or(FsError::InvalidParam)
+        };
+        Ok(Header {
+            ino: field(0)?,
+            mode: field(1)?,
+            uid: field(2)?,
+            gid: field(3)?,
+            nlink: field(4)?,
+            mtime: field(5)?,
+            filesize: field(6)?,
+            dev_major: field(7)?,
+            dev_minor: field(8)?,
+            namesize: field(11)?,
+        })
+    }
+}
+
+/// Create the entry `name` under `root`, replacing what's there unless both are directories
+fn create(
+    root: &Arc<dyn INode>,
+    name: &str,
+    header: &Header,
+    content: &[u8],
+    links: &mut BTreeMap<(usize, usize, usize), Arc<dyn INode>>,
+) -> Result<()> {
+    let name = name.trim_start_matches("./").trim_start_matches('/');
+    if name.is_empty() || name == "." {
+        return set_metadata(root, header);
+    }
+    let type_ = match header.mode & S_IFMT {
+        S_IFDIR => FileType::Dir,
+        S_IFREG => FileType::File,
+        S_IFLNK => FileType::SymLink,
+        _ => {
+            // device files are provided by DevFS at /dev
+            info!("initramfs: skip special file {}", name);
+            return Ok(());
+        }
+    };
+    let (dir, file_name) = match name.rfind('/') {
+        Some(pos) => (make_dirs(root, &name[..pos])?, &name[pos + 1..]),
+        None => (root.clone(), name),
+    };
+    if let Ok(old) = dir.find(file_name) {
+        if type_ == FileType::Dir && old.metadata()?.type_ == FileType::Dir {
+            return set_metadata(&old, header);
+        }
+        dir.unlink(file_name)?;
+    }
+    let mode = (header.mode & 0o7777) as u32;
+    let inode = if type_ == FileType::File && header.nlink > 1 {
+        // hard links share the inode number, and the contents come with the last one
+        let key = (header.dev_major, header.dev_minor, header.ino);
+        match links.get(&key) {
+            Some(first) => {
+                dir.link(file_name, first)?;
+                first.clone()
+            }
+            None => {
+                let inode = dir.create(file_name, type_, mode)?;
+                links.insert(key, inode.clone());
+                inode
+            }
+        }
+    } else {
+        dir.create(file_name, type_, mode)?
+    };
+    if !content.is_empty() && inode.write_at(0, content)? != content.len() {
+        return Err(FsError::NoDeviceSpace);
+    }
+    set_metadata(&inode, header)
+}
+
+/// Find the directory `path` under `root`, creating missing ones
+fn make_dirs(root: &Arc<dyn INode>, path: &str) -> Result<Arc<dyn INode>> {
+    let mut dir = root.clone();
+    for name in path.split('/').filter(|&s| s != "" && s != ".") {
+        dir = match dir.find(name) {
+            Ok(inode) => inode,
+            Err(FsError::EntryNotFound) => dir.create(name, FileType::Dir, 0o755)?,
+            Err(e) => return Err(e),
+        };
+    }
+    Ok(dir)
+}
+
+fn set_metadata(inode: &Arc<dyn INode>, header: &Header) -> Result<()> {
+    let mut metadata = inode.metadata()?;
+    let time = Timespec {
+        sec: header.mtime as i64,
+        nsec: 0,
+    };
+    metadata.mode = (header.mode & 0o7777) as u16;
+    metadata.uid = header.uid;
+    metadata.gid = header.gid;
+    metadata.atime = time;
+    metadata.mtime = time;
+    metadata.ctime = time;
+    match inode.set_metadata(&metadata) {
+        Err(FsError::NotSupported) => Ok(()),
+        result => result,
+    }
+}
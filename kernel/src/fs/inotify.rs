@@ -0,0 +1,513 @@
+//! inotify, file change notification
+//!
+//! The syscalls report what they do to files with `notify` and `notify_entry`, which queue
+//! events to the instances watching them. Watches are keyed by the `(dev, inode)` of files,
+//! like the page cache, so they don't depend on the path or the `INode` used to find them.
+
+use crate::process::{current_thread, Process, Thread};
+use crate::sync::{Event, EventBus, SpinNoIrqLock as Mutex};
+use crate::syscall::{SysError, SysResult};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::{String, ToString};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use core::future::Future;
+use core::mem::size_of;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::task::{Context, Poll};
+use rcore_fs::vfs::{FileType, INode, PollStatus};
+use spin::RwLock;
+
+use super::FileLike;
+
+bitflags! {
+    pub struct InotifyMask: u32 {
+        /// File was accessed
+        const ACCESS = 0x1;
+        /// File was modified
+        const MODIFY = 0x2;
+        /// Metadata changed
+        const ATTRIB = 0x4;
+        /// Writable file was closed
+        const CLOSE_WRITE = 0x8;
+        /// Unwritable file was closed
+        const CLOSE_NOWRITE = 0x10;
+        /// File was opened
+        const OPEN = 0x20;
+        /// File was moved from the watched directory
+        const MOVED_FROM = 0x40;
+        /// File was moved to the watched directory
+        const MOVED_TO = 0x80;
+        /// File was created in the watched directory
+        const CREATE = 0x100;
+        /// File was deleted from the watched directory
+        const DELETE = 0x200;
+        /// Watched file was deleted
+        const DELETE_SELF = 0x400;
+        /// Watched file was moved
+        const MOVE_SELF = 0x800;
+        /// File system of the watched file was unmounted
+        const UNMOUNT = 0x2000;
+        /// Event queue overflowed
+        const Q_OVERFLOW = 0x4000;
+        /// Watch was removed
+        const IGNORED = 0x8000;
+        /// Only watch the path if it's a directory
+        const ONLYDIR = 0x0100_0000;
+        /// Don't follow a symbolic link at the end of the path
+        const DONT_FOLLOW = 0x0200_0000;
+        /// Ignore events of unlinked children
+        const EXCL_UNLINK = 0x0400_0000;
+        /// Fail if the path is already watched
+        const MASK_CREATE = 0x1000_0000;
+        /// Add to the mask of an existing watch instead of replacing it
+        const MASK_ADD = 0x2000_0000;
+        /// Subject of the event is a directory
+        const ISDIR = 0x4000_0000;
+        /// Only report one event, then remove the watch
+        const ONESHOT = 0x8000_0000;
+
+        const ALL_EVENTS = 0xfff;
+    }
+}
+
+/// Events queued to an instance before it reports an overflow, as Linux by default
+const MAX_QUEUED_EVENTS: usize = 16384;
+
+/// `(dev, inode)` of a watched file
+type Key = (usize, usize);
+
+lazy_static! {
+    /// Instances watching each file, with their watch descriptors
+    static ref WATCHES: RwLock<BTreeMap<Key, Vec<(Weak<Mutex<InotifyData>>, i32)>>> =
+        RwLock::new(BTreeMap::new());
+}
+
+/// Cookie relating the two events of a rename
+static NEXT_COOKIE: AtomicU32 = AtomicU32::new(1);
+
+/// Whether any file is watched, to skip preparing events nobody reads
+pub fn watching() -> bool {
+    !WATCHES.read().is_empty()
+}
+
+/// A new cookie for the `MOVED_FROM` and `MOVED_TO` events of a rename
+pub fn next_cookie() -> u32 {
+    NEXT_COOKIE.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Report `mask` happened to `inode`, to the watches of itself
+pub fn notify(inode: &Arc<dyn INode>, mask: InotifyMask) {
+    if !watching() {
+        return;
+    }
+    if let Some((key, is_dir)) = key_of(inode) {
+        send(key, with_isdir(mask, is_dir), 0, "");
+    }
+}
+
+/// Report `mask` happened to `inode` as the entry `name` of `dir`, to the watches of `dir`
+pub fn notify_entry(
+    dir: &Arc<dyn INode>,
+    name: &str,
+    inode: &Arc<dyn INode>,
+    mask: InotifyMask,
+    cookie: u32,
+) {
+    if !watching() || name.is_empty() {
+        return;
+    }
+    if let (Some((key, _)), Some((_, is_dir))) = (key_of(dir), key_of(inode)) {
+        send(key, with_isdir(mask, is_dir), cookie, name);
+    }
+}
+
+/// Report `inode` lost a link, which deletes it with the last one.
+/// Watches of a deleted file are removed.
+pub fn notify_unlinked(inode: &Arc<dyn INode>) {
+    if !watching() {
+        return;
+    }
+    let metadata = match inode.metadata() {
+        Ok(metadata) => metadata,
+        Err(_) => return,
+    };
+    if metadata.type_ != FileType::Dir && metadata.nlinks > 0 {
+        notify(inode, InotifyMask::ATTRIB);
+        return;
+    }
+    let key = (metadata.dev, metadata.inode);
+    let is_dir = metadata.type_ == FileType::Dir;
+    send(key, with_isdir(InotifyMask::DELETE_SELF, is_dir), 0, "");
+    let watches = WATCHES.write().remove(&key).unwrap_or_default();
+    for (data, wd) in watches {
+        if let Some(data) = data.upgrade() {
+            let mut data = data.lock();
+            if data.watches.remove(&wd).is_some() {
+                data.push(wd, InotifyMask::IGNORED, 0, "");
+            }
+        }
+    }
+}
+
+fn key_of(inode: &Arc<dyn INode>) -> Option<(Key, bool)> {
+    let metadata = inode.metadata().ok()?;
+    Some((
+        (metadata.dev, metadata.inode),
+        metadata.type_ == FileType::Dir,
+    ))
+}
+
+fn with_isdir(mask: InotifyMask, is_dir: bool) -> InotifyMask {
+    if is_dir {
+        mask | InotifyMask::ISDIR
+    } else {
+        mask
+    }
+}
+
+/// Queue the event to the watches of `key`
+fn send(key: Key, mask: InotifyMask, cookie: u32, name: &str) {
+    // the registry is unlocked before the instances, which remove their watches when dropped
+    let targets: Vec<_> = match WATCHES.read().get(&key) {
+        Some(watches) => watches
+            .iter()
+            .filter_map(|(data, wd)| data.upgrade().map(|data| (data, *wd)))
+            .collect(),
+        None => return,
+    };
+    for (data, wd) in targets {
+        let removed = data.lock().report(wd, mask, cookie, name);
+        if let Some(key) = removed {
+            unregister(key, &data, wd);
+        }
+    }
+}
+
+fn register(key: Key, data: &Arc<Mutex<InotifyData>>, wd: i32) {
+    WATCHES
+        .write()
+        .entry(key)
+        .or_insert_with(Vec::new)
+        .push((Arc::downgrade(data), wd));
+}
+
+fn unregister(key: Key, data: &Arc<Mutex<InotifyData>>, wd: i32) {
+    let data = Arc::downgrade(data);
+    let mut watches = WATCHES.write();
+    if let Some(list) = watches.get_mut(&key) {
+        list.retain(|(d, w)| !(*w == wd && Weak::ptr_eq(d, &data)));
+        if list.is_empty() {
+            watches.remove(&key);
+        }
+    }
+}
+
+struct Watch {
+    key: Key,
+    mask: InotifyMask,
+}
+
+struct InotifyEvent {
+    wd: i32,
+    mask: InotifyMask,
+    cookie: u32,
+    name: String,
+}
+
+/// Header of an event read from an instance, followed by `len` bytes of name
+#[repr(C)]
+struct InotifyEventHeader {
+    wd: i32,
+    mask: u32,
+    cookie: u32,
+    len: u32,
+}
+
+impl InotifyEvent {
+    /// Length of the name, NUL padded to align the next event
+    fn name_len(&self) -> usize {
+        if self.name.is_empty() {
+            return 0;
+        }
+        let align = size_of::<InotifyEventHeader>();
+        (self.name.len() + 1 + align - 1) / align * align
+    }
+
+    fn size(&self) -> usize {
+        size_of::<InotifyEventHeader>() + self.name_len()
+    }
+
+    fn write_to(&self, buf: &mut [u8]) {
+        let header = InotifyEventHeader {
+            wd: self.wd,
+            mask: self.mask.bits(),
+            cookie: self.cookie,
+            len: self.name_len() as u32,
+        };
+        let header_size = size_of::<InotifyEventHeader>();
+        unsafe {
+            (buf.as_mut_ptr() as *mut InotifyEventHeader).write_unaligned(header);
+        }
+        let name = &mut buf[header_size..self.size()];
+        for byte in name.iter_mut() {
+            *byte = 0;
+        }
+        name[..self.name.len()].copy_from_slice(self.name.as_bytes());
+    }
+}
+
+struct InotifyData {
+    events: VecDeque<InotifyEvent>,
+    watches: BTreeMap<i32, Watch>,
+    next_wd: i32,
+    eventbus: EventBus,
+}
+
+impl InotifyData {
+    /// Queue the event if the watch `wd` asks for it.
+    /// Returns the file of a oneshot watch removed by it.
+    fn report(&mut self, wd: i32, mask: InotifyMask, cookie: u32, name: &str) -> Option<Key> {
+        let watch = self.watches.get(&wd)?;
+        if !watch.mask.intersects(mask & InotifyMask::ALL_EVENTS) {
+            return None;
+        }
+        let oneshot = watch.mask.contains(InotifyMask::ONESHOT);
+        let key = watch.key;
+        self.push(wd, mask, cookie, name);
+        if !oneshot {
+            return None;
+        }
+        self.watches.remove(&wd);
+        self.push(wd, InotifyMask::IGNORED, 0, "");
+        Some(key)
+    }
+
+    fn push(&mut self, wd: i32, mask: InotifyMask, cookie: u32, name: &str) {
+        // identical events in a row are merged, as Linux does
+        if let Some(last) = self.events.back() {
+            if last.wd == wd && last.mask == mask && last.cookie == cookie && last.name == name {
+                return;
+            }
+        }
+        let event = if self.events.len() < MAX_QUEUED_EVENTS {
+            InotifyEvent {
+                wd,
+                mask,
+                cookie,
+                name: name.to_string(),
+            }
+        } else if self.events.len() == MAX_QUEUED_EVENTS {
+            InotifyEvent {
+                wd: -1,
+                mask: InotifyMask::Q_OVERFLOW,
+                cookie: 0,
+                name: String::new(),
+            }
+        } else {
+            return;
+        };
+        self.events.push_back(event);
+        self.eventbus.set(Event::READABLE);
+    }
+
+    /// Read whole events into `buf`
+    fn read(&mut self, buf: &mut [u8]) -> SysResult {
+        let mut len = 0;
+        while let Some(event) = self.events.front() {
+            let size = event.size();
+            if len + size > buf.len() {
+                break;
+            }
+            event.write_to(&mut buf[len..len + size]);
+            len += size;
+            self.events.pop_front();
+        }
+        if self.events.is_empty() {
+            self.eventbus.clear(Event::READABLE);
+        }
+        if len == 0 {
+            // too small for the first event
+            return Err(SysError::EINVAL);
+        }
+        Ok(len)
+    }
+}
+
+impl Drop for InotifyData {
+    fn drop(&mut self) {
+        let mut watches = WATCHES.write();
+        for watch in self.watches.values() {
+            if let Some(list) = watches.get_mut(&watch.key) {
+                list.retain(|(data, _)| data.strong_count() > 0);
+                if list.is_empty() {
+                    watches.remove(&watch.key);
+                }
+            }
+        }
+    }
+}
+
+/// An inotify instance, of which the file descriptor reads the events
+#[derive(Clone)]
+pub struct Inotify {
+    data: Arc<Mutex<InotifyData>>,
+    pub nonblock: bool,
+    pub fd_cloexec: bool,
+}
+
+impl Inotify {
+    pub fn new(nonblock: bool, fd_cloexec: bool) -> Self {
+        Inotify {
+            data: Arc::new(Mutex::new(InotifyData {
+                events: VecDeque::new(),
+                watches: BTreeMap::new(),
+                next_wd: 1,
+                eventbus: EventBus::default(),
+            })),
+            nonblock,
+            fd_cloexec,
+        }
+    }
+
+    pub fn dup(&self, fd_cloexec: bool) -> Self {
+        Inotify {
+            data: self.data.clone(),
+            nonblock: self.nonblock,
+            fd_cloexec,
+        }
+    }
+
+    /// Watch `inode` for the events in `mask`, returning the watch descriptor
+    pub fn add_watch(&self, inode: &Arc<dyn INode>, mask: InotifyMask) -> Result<i32, SysError> {
+        if !mask.intersects(InotifyMask::ALL_EVENTS)
+            || mask.contains(InotifyMask::MASK_ADD | InotifyMask::MASK_CREATE)
+        {
+            return Err(SysError::EINVAL);
+        }
+        let metadata = inode.metadata()?;
+        if mask.contains(InotifyMask::ONLYDIR) && metadata.type_ != FileType::Dir {
+            return Err(SysError::ENOTDIR);
+        }
+        let key = (metadata.dev, metadata.inode);
+        let events = mask & (InotifyMask::ALL_EVENTS | InotifyMask::ONESHOT);
+        let mut data = self.data.lock();
+        if let Some((&wd, watch)) = data.watches.iter_mut().find(|(_, w)| w.key == key) {
+            if mask.contains(InotifyMask::MASK_CREATE) {
+                return Err(SysError::EEXIST);
+            }
+            if mask.contains(InotifyMask::MASK_ADD) {
+                watch.mask |= events;
+            } else {
+                watch.mask = events;
+            }
+            return Ok(wd);
+        }
+        let wd = data.next_wd;
+        data.next_wd += 1;
+        data.watches.insert(wd, Watch { key, mask: events });
+        register(key, &self.data, wd);
+        Ok(wd)
+    }
+
+    /// Remove the watch `wd`, which reports `IGNORED`
+    pub fn rm_watch(&self, wd: i32) -> Result<(), SysError> {
+        let mut data = self.data.lock();
+        let watch = data.watches.remove(&wd).ok_or(SysError::EINVAL)?;
+        unregister(watch.key, &self.data, wd);
+        data.push(wd, InotifyMask::IGNORED, 0, "");
+        Ok(())
+    }
+
+    pub async fn read(&self, buf: &mut [u8]) -> SysResult {
+        let thread = current_thread().unwrap();
+        loop {
+            {
+                let mut data = self.data.lock();
+                if !data.events.is_empty() {
+                    return data.read(buf);
+                }
+                if self.nonblock {
+                    return Err(SysError::EAGAIN);
+                }
+            }
+            if thread.has_signal_to_handle() {
+                return Err(SysError::EINTR);
+            }
+            self.wait(Some(&thread)).await;
+        }
+    }
+
+    pub fn poll(&self) -> PollStatus {
+        PollStatus {
+            read: !self.data.lock().events.is_empty(),
+            write: false,
+            error: false,
+        }
+    }
+
+    pub fn async_poll<'a>(&'a self) -> impl Future<Output = PollStatus> + 'a {
+        self.wait(None)
+    }
+
+    /// Wait for events, or for a signal to `thread` too if given
+    fn wait<'a>(
+        &'a self,
+        thread: Option<&'a Arc<Thread>>,
+    ) -> impl Future<Output = PollStatus> + 'a {
+        #[must_use = "future does nothing unless polled/`await`-ed"]
+        struct InotifyFuture<'a> {
+            inotify: &'a Inotify,
+            thread: Option<&'a Arc<Thread>>,
+        }
+
+        impl<'a> Future for InotifyFuture<'a> {
+            type Output = PollStatus;
+
+            fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                if let Some(thread) = self.thread {
+                    if thread.has_signal_to_handle() {
+                        return Poll::Ready(self.inotify.poll());
+                    }
+                    let waker = cx.waker().clone();
+                    thread.proc.lock().eventbus.lock().subscribe(Box::new({
+                        move |_| {
+                            waker.wake_by_ref();
+                            true
+                        }
+                    }));
+                }
+                let mut data = self.inotify.data.lock();
+                if !data.events.is_empty() {
+                    drop(data);
+                    return Poll::Ready(self.inotify.poll());
+                }
+                let waker = cx.waker().clone();
+                data.eventbus.subscribe(Box::new({
+                    move |_| {
+                        waker.wake_by_ref();
+                        true
+                    }
+                }));
+                Poll::Pending
+            }
+        }
+
+        InotifyFuture {
+            inotify: self,
+            thread,
+        }
+    }
+}
+
+impl Process {
+    pub fn get_inotify(&self, fd: usize) -> Result<&Inotify, SysError> {
+        match self.files.get(&fd).ok_or(SysError::EBADF)? {
+            FileLike::Inotify(inotify) => Ok(inotify),
+            _ => Err(SysError::EINVAL),
+        }
+    }
+}
@@ -0,0 +1,335 @@
+//! Advisory file locks of `flock` and `fcntl`
+//!
+//! Each file has whole-file locks taken by `flock`, and byte-range record locks.
+//! Locks of `flock` and open file description (OFD) record locks belong to the open file
+//! description, and are released when it's closed. POSIX record locks belong to the process,
+//! and are released when it closes any descriptor of the file, or exits.
+//! The two kinds of locks don't conflict with each other, like Linux.
+
+use crate::sync::SpinNoIrqLock as Mutex;
+use crate::syscall::SysError;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::task::Waker;
+use rcore_fs::vfs::INode;
+
+/// Processes followed through the locks they wait for, before giving up finding a deadlock
+const MAX_DEADLOCK_DEPTH: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockOwner {
+    /// A process by its pid, for POSIX record locks
+    Process(usize),
+    /// An open file description by its address, for `flock` and OFD locks
+    File(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockType {
+    Shared,
+    Exclusive,
+}
+
+/// A lock of the bytes from `start` to before `end`
+#[derive(Debug, Clone, Copy)]
+pub struct FileLock {
+    pub owner: LockOwner,
+    pub type_: LockType,
+    pub start: u64,
+    /// `u64::max_value()` for a lock up to the end of the file, however it grows
+    pub end: u64,
+}
+
+impl FileLock {
+    fn conflicts(&self, other: &FileLock) -> bool {
+        self.owner != other.owner
+            && self.start < other.end
+            && other.start < self.end
+            && (self.type_ == LockType::Exclusive || other.type_ == LockType::Exclusive)
+    }
+}
+
+#[derive(Default)]
+struct FileLocks {
+    /// Whole-file locks of `flock`, by open file description
+    flocks: Vec<(LockOwner, LockType)>,
+    /// Record locks, not overlapping others of the same owner
+    records: Vec<FileLock>,
+    /// Tasks waiting for a lock to be released
+    waiters: Vec<Waker>,
+}
+
+impl FileLocks {
+    fn is_empty(&self) -> bool {
+        self.flocks.is_empty() && self.records.is_empty() && self.waiters.is_empty()
+    }
+
+    fn wake_all(&mut self) {
+        for waker in self.waiters.drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Remove the record locks of `owner` in `start..end`, returning whether any was there
+    fn remove_range(&mut self, owner: LockOwner, start: u64, end: u64) -> bool {
+        let mut changed = false;
+        let mut split = Vec::new();
+        self.records.retain(|lock| {
+            if lock.owner != owner || lock.end <= start || end <= lock.start {
+                return true;
+            }
+            changed = true;
+            if lock.start < start {
+                split.push(FileLock {
+                    end: start,
+                    ..*lock
+                });
+            }
+            if lock.end > end {
+                split.push(FileLock {
+                    start: end,
+                    ..*lock
+                });
+            }
+            false
+        });
+        self.records.extend(split);
+        changed
+    }
+
+    /// Add `lock`, merged with the locks of the same type it touches
+    fn insert(&mut self, mut lock: FileLock) {
+        self.remove_range(lock.owner, lock.start, lock.end);
+        self.records.retain(|other| {
+            if other.owner != lock.owner || other.type_ != lock.type_ {
+                return true;
+            }
+            if other.end == lock.start {
+                lock.start = other.start;
+                return false;
+            }
+            if other.start == lock.end {
+                lock.end = other.end;
+                return false;
+            }
+            true
+        });
+        self.records.push(lock);
+    }
+}
+
+type Key = (usize, usize);
+
+#[derive(Default)]
+struct LockTable {
+    files: BTreeMap<Key, FileLocks>,
+    /// The process each waiting process waits for, to find deadlocks
+    waiting: BTreeMap<usize, usize>,
+}
+
+impl LockTable {
+    /// Whether `pid` waiting for `holder` would make them wait for each other forever
+    fn deadlocks(&self, pid: usize, holder: usize) -> bool {
+        let mut holder = holder;
+        for _ in 0..MAX_DEADLOCK_DEPTH {
+            if holder == pid {
+                return true;
+            }
+            holder = match self.waiting.get(&holder) {
+                Some(&next) => next,
+                None => return false,
+            };
+        }
+        false
+    }
+
+    fn stop_waiting(&mut self, owner: LockOwner) {
+        if let LockOwner::Process(pid) = owner {
+            self.waiting.remove(&pid);
+        }
+    }
+
+    /// Wait for a lock of the file to be released, if `waker` is given
+    fn wait(&mut self, key: Key, waker: Option<&Waker>) -> Result<(), SysError> {
+        match waker {
+            Some(waker) => self
+                .files
+                .entry(key)
+                .or_default()
+                .waiters
+                .push(waker.clone()),
+            None => self.tidy(key),
+        }
+        Err(SysError::EAGAIN)
+    }
+
+    /// Drop the entry of a file left without locks
+    fn tidy(&mut self, key: Key) {
+        if self.files.get(&key).map_or(false, |locks| locks.is_empty()) {
+            self.files.remove(&key);
+        }
+    }
+}
+
+lazy_static! {
+    static ref LOCKS: Mutex<LockTable> = Mutex::new(LockTable::default());
+}
+
+fn key_of(inode: &Arc<dyn INode>) -> Result<Key, SysError> {
+    let metadata = inode.metadata()?;
+    Ok((metadata.dev, metadata.inode))
+}
+
+/// Take the `flock` lock of `type_` on `inode` for the open file description `owner`,
+/// replacing the one it has, or release it with `None`.
+///
+/// If another description holds a conflicting lock, fails with `EAGAIN`,
+/// after registering `waker` to be woken when a lock of the file is released.
+pub fn flock(
+    inode: &Arc<dyn INode>,
+    owner: LockOwner,
+    type_: Option<LockType>,
+    waker: Option<&Waker>,
+) -> Result<(), SysError> {
+    let key = key_of(inode)?;
+    let mut table = LOCKS.lock();
+    let locks = table.files.entry(key).or_default();
+    // like Linux, converting a lock isn't atomic, so others waiting can take it meanwhile
+    if let Some(i) = locks.flocks.iter().position(|&(other, _)| other == owner) {
+        let (_, old) = locks.flocks.remove(i);
+        if type_ == Some(old) {
+            locks.flocks.push((owner, old));
+            return Ok(());
+        }
+        locks.wake_all();
+    }
+    let type_ = match type_ {
+        Some(type_) => type_,
+        None => {
+            table.tidy(key);
+            return Ok(());
+        }
+    };
+    let conflict = locks
+        .flocks
+        .iter()
+        .any(|&(_, other)| type_ == LockType::Exclusive || other == LockType::Exclusive);
+    if conflict {
+        return table.wait(key, waker);
+    }
+    locks.flocks.push((owner, type_));
+    Ok(())
+}
+
+/// Find a record lock on `inode` that conflicts with `lock`
+pub fn test(inode: &Arc<dyn INode>, lock: &FileLock) -> Result<Option<FileLock>, SysError> {
+    let key = key_of(inode)?;
+    let table = LOCKS.lock();
+    let found = table
+        .files
+        .get(&key)
+        .and_then(|locks| locks.records.iter().find(|other| other.conflicts(lock)));
+    Ok(found.cloned())
+}
+
+/// Take the record lock `lock` on `inode`, replacing locks of its owner in the range.
+///
+/// If another owner holds a conflicting lock, fails with `EAGAIN`,
+/// after registering `waker` to be woken when a lock of the file is released.
+/// Fails with `EDEADLK` instead if the process would wait for itself.
+pub fn lock(inode: &Arc<dyn INode>, lock: FileLock, waker: Option<&Waker>) -> Result<(), SysError> {
+    let key = key_of(inode)?;
+    let mut table = LOCKS.lock();
+    let locks = table.files.entry(key).or_default();
+    let blocker = locks
+        .records
+        .iter()
+        .find(|other| other.conflicts(&lock))
+        .map(|other| other.owner);
+    if let Some(blocker) = blocker {
+        if let (LockOwner::Process(pid), LockOwner::Process(holder), Some(_)) =
+            (lock.owner, blocker, waker)
+        {
+            if table.deadlocks(pid, holder) {
+                table.waiting.remove(&pid);
+                return Err(SysError::EDEADLK);
+            }
+            table.waiting.insert(pid, holder);
+        }
+        return table.wait(key, waker);
+    }
+    // a shared lock replacing an exclusive one lets others in
+    if lock.type_ == LockType::Shared {
+        locks.wake_all();
+    }
+    locks.insert(lock);
+    table.stop_waiting(lock.owner);
+    Ok(())
+}
+
+/// Release the record locks of `owner` on `inode` in `start..end`
+pub fn unlock(
+    inode: &Arc<dyn INode>,
+    owner: LockOwner,
+    start: u64,
+    end: u64,
+) -> Result<(), SysError> {
+    let key = key_of(inode)?;
+    let mut table = LOCKS.lock();
+    if let Some(locks) = table.files.get_mut(&key) {
+        if locks.remove_range(owner, start, end) {
+            locks.wake_all();
+        }
+    }
+    table.tidy(key);
+    Ok(())
+}
+
+/// Stop waiting for a lock, when `owner` gives up
+pub fn cancel(owner: LockOwner) {
+    LOCKS.lock().stop_waiting(owner);
+}
+
+/// Release all locks of `owner` on `inode`
+pub fn release(inode: &Arc<dyn INode>, owner: LockOwner) {
+    // most files are never locked, so don't bother finding the key
+    if LOCKS.lock().files.is_empty() {
+        return;
+    }
+    let key = match key_of(inode) {
+        Ok(key) => key,
+        Err(_) => return,
+    };
+    let mut table = LOCKS.lock();
+    if let Some(locks) = table.files.get_mut(&key) {
+        let count = locks.flocks.len() + locks.records.len();
+        locks.flocks.retain(|&(other, _)| other != owner);
+        locks.records.retain(|lock| lock.owner != owner);
+        if locks.flocks.len() + locks.records.len() != count {
+            locks.wake_all();
+        }
+    }
+    table.tidy(key);
+}
+
+/// Release all record locks of the process `pid`, when it exits
+pub fn release_process(pid: usize) {
+    let owner = LockOwner::Process(pid);
+    let mut table = LOCKS.lock();
+    table.waiting.remove(&pid);
+    let mut empty = Vec::new();
+    for (key, locks) in table.files.iter_mut() {
+        let count = locks.records.len();
+        locks.records.retain(|lock| lock.owner != owner);
+        if locks.records.len() != count {
+            locks.wake_all();
+        }
+        if locks.is_empty() {
+            empty.push(*key);
+        }
+    }
+    for key in empty {
+        table.files.remove(&key);
+    }
+}
@@ -1,30 +1,44 @@
-use alloc::{sync::Arc, vec::Vec};
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-use rcore_fs::{dev::block_cache::BlockCache, vfs::*};
+use rcore_fs::vfs::*;
 use rcore_fs_devfs::{
     special::{NullINode, ZeroINode},
     DevFS,
 };
-use rcore_fs_mountfs::MountFS;
 use rcore_fs_ramfs::RamFS;
-use rcore_fs_sfs::{INodeImpl, SimpleFileSystem};
+#[cfg(feature = "link_user")]
+use rcore_fs_sfs::SimpleFileSystem;
+use spin::RwLock;
 
 use self::devfs::{Fbdev, RandomINode};
+use self::numbered::NumberedFS;
+use self::overlay::OverlayFS;
 
 pub use self::devfs::{Serial, ShmINode, TTY};
 pub use self::file::*;
 pub use self::file_like::*;
 pub use self::pipe::Pipe;
 pub use self::pseudo::*;
-use crate::drivers::{BlockDriver, BlockDriverWrapper};
 
 mod devfs;
 mod device;
 pub mod epoll;
+pub mod ext2;
+pub mod fat;
 pub mod fcntl;
 mod file;
 mod file_like;
+pub mod initramfs;
+pub mod inotify;
+pub mod lock;
 pub mod ioctl;
+pub mod mount;
+mod numbered;
+pub mod overlay;
+pub mod page_cache;
+mod partition;
+pub mod path;
 mod pipe;
 mod pseudo;
 
@@ -44,36 +58,8 @@ _user_img_end:
 ));
 
 lazy_static! {
-    /// The root of file system
-    pub static ref ROOT_INODE: Arc<dyn INode> = {
-        #[cfg(not(feature = "link_user"))]
-        let device = {
-            let driver = BlockDriverWrapper(
-                crate::drivers::BLK_DRIVERS
-                    .read().iter()
-                    .next().expect("Block device not found")
-                    .clone()
-            );
-            // enable block cache
-            Arc::new(BlockCache::new(driver, 0x100))
-            // Arc::new(driver)
-        };
-        #[cfg(feature = "link_user")]
-        let device = {
-            extern {
-                fn _user_img_start();
-                fn _user_img_end();
-            }
-            info!("SFS linked to kernel, from {:08x} to {:08x}", _user_img_start as usize, _user_img_end as usize);
-            Arc::new(unsafe { device::MemBuf::new(_user_img_start, _user_img_end) })
-        };
-
-        // use SFS as rootfs
-        let sfs = SimpleFileSystem::open(device).expect("failed to open SFS");
-        let rootfs = MountFS::new(sfs);
-        let root = rootfs.root_inode();
-
-        // create DevFS
+    /// The device file system, mounted at /dev
+    pub static ref DEVFS: Arc<NumberedFS> = {
         let devfs = DevFS::new();
         devfs.add("null", Arc::new(NullINode::default())).expect("failed to mknod /dev/null");
         devfs.add("zero", Arc::new(ZeroINode::default())).expect("failed to mknod /dev/zero");
@@ -86,33 +72,133 @@ lazy_static! {
             devfs.add(&format!("ttyS{}", i), Arc::new(serial)).expect("failed to add a serial");
         }
 
-
         #[cfg(feature = "hypervisor")]
         devfs.add("rvm", Arc::new(crate::rvm::RvmINode::new())).expect("failed to mknod /dev/rvm");
 
-        // mount DevFS at /dev
-        let dev = root.find(true, "dev").unwrap_or_else(|_| {
-            root.create("dev", FileType::Dir, 0o666).expect("failed to mkdir /dev")
-        });
-        let devfs = dev.mount(devfs).expect("failed to mount DevFS");
-
-        // mount RamFS at /dev/shm
-        let shm = devfs.root_inode().find(true, "shm").expect("cannot find shm");
-        let shmfs = RamFS::new();
-        shm.mount(shmfs).expect("failed to mount /dev/shm");
-
-        // mount RamFS at /tmp
-        let ramfs = RamFS::new();
-        let tmp = root.find(true, "tmp").unwrap_or_else(|_| {
-            root.create("tmp", FileType::Dir, 0o666).expect("failed to mkdir /tmp")
-        });
-        tmp.mount(ramfs).expect("failed to mount RamFS");
-
-        root
+        NumberedFS::new(devfs)
+    };
+
+    /// The root of file system, changed by `pivot_root`
+    static ref ROOT_INODE: RwLock<Arc<dyn INode>> = RwLock::new(open_root());
+}
+
+/// The root of file system
+pub fn root_inode() -> Arc<dyn INode> {
+    ROOT_INODE.read().clone()
+}
+
+/// Make the mount whose root is `new_root` the root file system,
+/// and move the old one to the absolute path `put_old`.
+/// Busy while a directory of the old tree is in `open_files`.
+pub fn pivot_root(
+    new_root: &Arc<dyn INode>,
+    put_old: &str,
+    open_files: &[Arc<dyn INode>],
+) -> Result<mount::Pivot> {
+    let (rootfs, pivot) = mount::pivot_root(new_root, put_old, open_files)?;
+    *ROOT_INODE.write() = rootfs.root_inode();
+    Ok(pivot)
+}
+
+/// Open the root file system, and mount the usual ones on it
+fn open_root() -> Arc<dyn INode> {
+    // boot from the initramfs if there is one, which can `pivot_root` to a disk later
+    let rootfs = match initramfs::load() {
+        Some(ramfs) => mount::mount_root(NumberedFS::new(ramfs), "rootfs", "rootfs"),
+        None => {
+            let (fs, source, fstype) = open_disk_root();
+            // keep the disk untouched with `overlayroot=tmpfs`, writing to memory instead
+            if boot_option("overlayroot=").as_deref() == Some("tmpfs") {
+                info!("root file system: tmpfs over {}", source);
+                let upper = NumberedFS::new(RamFS::new()).root_inode();
+                let fs = OverlayFS::new(fs.root_inode(), upper)
+                    .expect("failed to create the overlay");
+                mount::mount_root(fs, "overlay", "overlay")
+            } else {
+                mount::mount_root(fs, &source, &fstype)
+            }
+        }
     };
+    let root = rootfs.root_inode();
+
+    // mount DevFS at /dev
+    let dev = root.find(true, "dev").unwrap_or_else(|_| {
+        root.create("dev", FileType::Dir, 0o666)
+            .expect("failed to mkdir /dev")
+    });
+    let dev: Arc<dyn INode> = dev;
+    let devfs = mount::mount(&dev, "/dev", "devfs", "devfs", DEVFS.clone(), false)
+        .expect("failed to mount DevFS");
+
+    // mount RamFS at /dev/shm
+    let shm: Arc<dyn INode> = devfs
+        .root_inode()
+        .find(true, "shm")
+        .expect("cannot find shm");
+    mount::mount(&shm, "/dev/shm", "tmpfs", "tmpfs", NumberedFS::new(RamFS::new()), false)
+        .expect("failed to mount /dev/shm");
+
+    // mount RamFS at /tmp
+    let tmp: Arc<dyn INode> = root.find(true, "tmp").unwrap_or_else(|_| {
+        root.create("tmp", FileType::Dir, 0o666)
+            .expect("failed to mkdir /tmp")
+    });
+    mount::mount(&tmp, "/tmp", "tmpfs", "tmpfs", NumberedFS::new(RamFS::new()), false)
+        .expect("failed to mount RamFS");
+
+    root
+}
+
+/// Open the root file system on a disk,
+/// selected with `root=/dev/sdX` or a partition like `root=/dev/sdX1`, and `rootfstype=`
+#[cfg(not(feature = "link_user"))]
+fn open_disk_root() -> (Arc<dyn FileSystem>, String, String) {
+    let source = boot_option("root=").unwrap_or_else(|| String::from("/dev/sda"));
+    let fstype = boot_option("rootfstype=").unwrap_or_else(|| String::from("sfs"));
+    info!("root file system: {} on {}", fstype, source);
+    let fs = mount::create_fs(&fstype, &source, "")
+        .expect("unknown root file system type")
+        .expect("failed to open the root file system");
+    (fs, source, fstype)
+}
+
+/// Open the SFS image linked to the kernel as the root file system
+#[cfg(feature = "link_user")]
+fn open_disk_root() -> (Arc<dyn FileSystem>, String, String) {
+    extern "C" {
+        fn _user_img_start();
+        fn _user_img_end();
+    }
+    info!(
+        "SFS linked to kernel, from {:08x} to {:08x}",
+        _user_img_start as usize, _user_img_end as usize
+    );
+    let device = Arc::new(unsafe { device::MemBuf::new(_user_img_start, _user_img_end) });
+
+    // use SFS as rootfs
+    let sfs = SimpleFileSystem::open(device).expect("failed to open SFS");
+    (NumberedFS::new(sfs), String::from("rootfs"), String::from("sfs"))
+}
+
+/// Get the value of `key` in the kernel command line, with `key` ending in '='
+fn boot_option(key: &str) -> Option<String> {
+    let cmdline = crate::drivers::CMDLINE.read();
+    cmdline
+        .split_whitespace()
+        .find(|arg| arg.starts_with(key))
+        .map(|arg| String::from(&arg[key.len()..]))
+}
+
+/// The next device number of a file system, above those of the device files
+static NEXT_DEV: AtomicUsize = AtomicUsize::new(0x800);
+
+/// Allocate a device number for a new file system, as files are told apart by (dev, inode)
+pub fn alloc_dev() -> usize {
+    NEXT_DEV.fetch_add(1, Ordering::Relaxed)
 }
 
-pub const FOLLOW_MAX_DEPTH: usize = 3;
+/// Symbolic links followed in resolving a path before giving up, as Linux
+pub const FOLLOW_MAX_DEPTH: usize = 40;
 
 pub trait INodeExt {
     fn read_as_vec(&self) -> Result<Vec<u8>>;
@@ -0,0 +1,495 @@
+//! File system types and the mount table
+//!
+//! File systems are created by name through the registry and attached with `MountFS`.
+//! `MountFS` can't remove a mount point, so each mount is attached through a `MountSlot`,
+//! which shows the covered directory again after umount.
+
+use super::{
+    ext2::Ext2FileSystem, fat::FatFileSystem, numbered::NumberedFS, overlay::OverlayFS,
+    partition::Partition, path, DEVFS,
+};
+use crate::drivers::{BlockDriverWrapper, BLK_DRIVERS};
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use rcore_fs::dev::{block_cache::BlockCache, Device};
+use rcore_fs::vfs::*;
+use rcore_fs_mountfs::{MNode, MountFS};
+use rcore_fs_ramfs::RamFS;
+use rcore_fs_sfs::SimpleFileSystem;
+use spin::RwLock;
+
+/// Create a file system from `source` with mount `options`
+pub type FsCreator = fn(source: &str, options: &str) -> Result<Arc<dyn FileSystem>>;
+
+lazy_static! {
+    /// File system types by name
+    static ref FS_TYPES: RwLock<BTreeMap<&'static str, FsCreator>> = {
+        let mut types: BTreeMap<&'static str, FsCreator> = BTreeMap::new();
+        types.insert("sfs", create_sfs);
+        types.insert("ext2", create_ext2);
+        types.insert("vfat", create_vfat);
+        types.insert("overlay", create_overlay);
+        types.insert("ramfs", create_ramfs);
+        types.insert("tmpfs", create_ramfs);
+        types.insert("devfs", create_devfs);
+        RwLock::new(types)
+    };
+    /// Mounted file systems, in the order they were mounted
+    static ref MOUNTS: RwLock<Vec<Mount>> = RwLock::new(Vec::new());
+}
+
+/// Add a file system type, replacing the old one of the same name
+pub fn register_fs_type(name: &'static str, create: FsCreator) {
+    FS_TYPES.write().insert(name, create);
+}
+
+/// Create a file system of type `fstype`, `None` if the type is unknown
+pub fn create_fs(fstype: &str, source: &str, options: &str) -> Option<Result<Arc<dyn FileSystem>>> {
+    let create = *FS_TYPES.read().get(fstype)?;
+    Some(create(source, options))
+}
+
+/// Open the block device named by `source`, like `/dev/sdb` for the second disk,
+/// or `/dev/sdb1` for its first partition
+fn block_device(source: &str) -> Result<Arc<dyn Device>> {
+    let name = source.trim_start_matches("/dev/");
+    if name.len() < 3 || !(name.starts_with("sd") || name.starts_with("vd")) {
+        return Err(FsError::NoDevice);
+    }
+    let index = match name.as_bytes()[2] {
+        c @ b'a'..=b'z' => (c - b'a') as usize,
+        _ => return Err(FsError::NoDevice),
+    };
+    let driver = BLK_DRIVERS
+        .read()
+        .get(index)
+        .cloned()
+        .ok_or(FsError::NoDevice)?;
+    if name.len() == 3 {
+        return Ok(Arc::new(BlockCache::new(BlockDriverWrapper(driver), 0x100)));
+    }
+    let partition = name[3..].parse().map_err(|_| FsError::NoDevice)?;
+    let partition = Partition::open(driver, partition).ok_or(FsError::NoDevice)?;
+    Ok(Arc::new(BlockCache::new(partition, 0x100)))
+}
+
+fn create_sfs(source: &str, _options: &str) -> Result<Arc<dyn FileSystem>> {
+    let device = block_device(source)?;
+    let sfs = SimpleFileSystem::open(device).map_err(|_| FsError::WrongFs)?;
+    Ok(NumberedFS::new(sfs))
+}
+
+fn create_ext2(source: &str, _options: &str) -> Result<Arc<dyn FileSystem>> {
+    let device = block_device(source)?;
+    Ok(Ext2FileSystem::open(device)?)
+}
+
+fn create_vfat(source: &str, _options: &str) -> Result<Arc<dyn FileSystem>> {
+    let device = block_device(source)?;
+    Ok(FatFileSystem::open(device)?)
+}
+
+/// Merge the directories at the absolute paths `upperdir=` over `lowerdir=` in `options`.
+/// There is only one lower directory, and `workdir=` is unused.
+fn create_overlay(_source: &str, options: &str) -> Result<Arc<dyn FileSystem>> {
+    let dir = |key: &str| {
+        let dir_path = options
+            .split(',')
+            .find(|option| option.starts_with(key))
+            .map(|option| &option[key.len()..])
+            .ok_or(FsError::InvalidParam)?;
+        if dir_path.contains(':') {
+            return Err(FsError::NotSupported);
+        }
+        path::lookup(&super::root_inode(), dir_path)
+    };
+    Ok(OverlayFS::new(dir("lowerdir=")?, dir("upperdir=")?)?)
+}
+
+fn create_ramfs(_source: &str, _options: &str) -> Result<Arc<dyn FileSystem>> {
+    Ok(NumberedFS::new(RamFS::new()))
+}
+
+fn create_devfs(_source: &str, _options: &str) -> Result<Arc<dyn FileSystem>> {
+    Ok(DEVFS.clone())
+}
+
+/// The file system, or a directory of it for bind mounts, attached at a mount point
+pub struct MountSlot {
+    /// The mounted file system and its root, `None` after umount
+    mounted: RwLock<Option<(Arc<dyn FileSystem>, Arc<dyn INode>)>>,
+    /// The directory covered by the mount
+    covered: Arc<dyn INode>,
+}
+
+impl FileSystem for MountSlot {
+    fn sync(&self) -> Result<()> {
+        match &*self.mounted.read() {
+            Some((fs, _)) => fs.sync(),
+            None => Ok(()),
+        }
+    }
+
+    fn root_inode(&self) -> Arc<dyn INode> {
+        match &*self.mounted.read() {
+            Some((_, root)) => root.clone(),
+            None => self.covered.clone(),
+        }
+    }
+
+    fn info(&self) -> FsInfo {
+        match &*self.mounted.read() {
+            Some((fs, _)) => fs.info(),
+            None => self.covered.fs().info(),
+        }
+    }
+}
+
+/// An entry of the mount table
+pub struct Mount {
+    pub source: String,
+    pub target: String,
+    pub fstype: String,
+    pub readonly: bool,
+    /// The file system as seen through the mount point
+    pub fs: Arc<MountFS>,
+    /// What the file system is attached through, emptied on umount
+    slot: Arc<MountSlot>,
+}
+
+/// Whether `fs` is the file system `inode` belongs to
+fn owns(fs: &Arc<MountFS>, inode: &Arc<dyn INode>) -> bool {
+    // only nodes of `MountFS` belong to a mount
+    if !inode.as_any_ref().is::<MNode>() {
+        return false;
+    }
+    let owner = inode.fs();
+    &*owner as *const dyn FileSystem as *const u8 == &**fs as *const MountFS as *const u8
+}
+
+/// Make `fs` the root file system, the first in the mount table, which can't be unmounted
+pub fn mount_root(fs: Arc<dyn FileSystem>, source: &str, fstype: &str) -> Arc<MountFS> {
+    let root = fs.root_inode();
+    let slot = Arc::new(MountSlot {
+        mounted: RwLock::new(Some((fs, root.clone()))),
+        covered: root,
+    });
+    let rootfs = MountFS::new(slot.clone());
+    MOUNTS.write().push(Mount {
+        source: String::from(source),
+        target: String::from("/"),
+        fstype: String::from(fstype),
+        readonly: false,
+        fs: rootfs.clone(),
+        slot,
+    });
+    rootfs
+}
+
+/// Mount `fs` at the directory `target_inode`, found at the absolute path `target`
+pub fn mount(
+    target_inode: &Arc<dyn INode>,
+    target: &str,
+    source: &str,
+    fstype: &str,
+    fs: Arc<dyn FileSystem>,
+    readonly: bool,
+) -> Result<Arc<MountFS>> {
+    let root = fs.root_inode();
+    attach(target_inode, target, source, fstype, fs, root, readonly)
+}
+
+/// Mount the directory `source_inode` at the directory `target_inode` as well
+pub fn bind(
+    target_inode: &Arc<dyn INode>,
+    target: &str,
+    source_inode: &Arc<dyn INode>,
+    source: &str,
+    readonly: bool,
+) -> Result<Arc<MountFS>> {
+    if source_inode.metadata()?.type_ != FileType::Dir {
+        return Err(FsError::NotDir);
+    }
+    let fstype = MOUNTS
+        .read()
+        .iter()
+        .rev()
+        .find(|m| owns(&m.fs, source_inode))
+        .map_or(String::from("none"), |m| m.fstype.clone());
+    let fs = source_inode.fs();
+    attach(
+        target_inode,
+        target,
+        source,
+        &fstype,
+        fs,
+        source_inode.clone(),
+        readonly,
+    )
+}
+
+fn attach(
+    target_inode: &Arc<dyn INode>,
+    target: &str,
+    source: &str,
+    fstype: &str,
+    fs: Arc<dyn FileSystem>,
+    root: Arc<dyn INode>,
+    readonly: bool,
+) -> Result<Arc<MountFS>> {
+    let (mounted, slot) = attach_slot(target_inode, Some((fs, root)))?;
+    MOUNTS.write().push(Mount {
+        source: String::from(source),
+        target: String::from(target),
+        fstype: String::from(fstype),
+        readonly,
+        fs: mounted.clone(),
+        slot,
+    });
+    Ok(mounted)
+}
+
+/// Attach a new slot holding `mounted` at the directory `target_inode`
+fn attach_slot(
+    target_inode: &Arc<dyn INode>,
+    mounted: Option<(Arc<dyn FileSystem>, Arc<dyn INode>)>,
+) -> Result<(Arc<MountFS>, Arc<MountSlot>)> {
+    let mountpoint = target_inode
+        .as_any_ref()
+        .downcast_ref::<MNode>()
+        .ok_or(FsError::NotSupported)?;
+    if target_inode.metadata()?.type_ != FileType::Dir {
+        return Err(FsError::NotDir);
+    }
+    let slot = Arc::new(MountSlot {
+        mounted: RwLock::new(mounted),
+        covered: target_inode.clone(),
+    });
+    let mounted = mountpoint.mount(slot.clone())?;
+    Ok((mounted, slot))
+}
+
+/// Change the flags of the mount whose root is `target_inode`
+pub fn remount(target_inode: &Arc<dyn INode>, readonly: bool) -> Result<()> {
+    let mut mounts = MOUNTS.write();
+    let mount = mounts
+        .iter_mut()
+        .rev()
+        .find(|m| owns(&m.fs, target_inode))
+        .ok_or(FsError::InvalidParam)?;
+    if is_root(mount, target_inode)? {
+        mount.readonly = readonly;
+        Ok(())
+    } else {
+        Err(FsError::InvalidParam)
+    }
+}
+
+/// Whether `inode` is the root of `mount`
+fn is_root(mount: &Mount, inode: &Arc<dyn INode>) -> Result<bool> {
+    if !owns(&mount.fs, inode) {
+        return Ok(false);
+    }
+    let root = mount.fs.root_inode().metadata()?;
+    let metadata = inode.metadata()?;
+    Ok(root.dev == metadata.dev && root.inode == metadata.inode)
+}
+
+/// Unmount the file system whose root is `target_inode`.
+/// It's busy while files of it are in `open_files`, a directory in `cwds` is under it,
+/// or other file systems are mounted on it, unless `detach` is set,
+/// which only hides it from new lookups.
+pub fn umount(
+    target_inode: Arc<dyn INode>,
+    detach: bool,
+    open_files: &[Arc<dyn INode>],
+    cwds: &[String],
+) -> Result<()> {
+    let mut mounts = MOUNTS.write();
+    let index = mounts
+        .iter()
+        .rposition(|m| owns(&m.fs, &target_inode))
+        .ok_or(FsError::InvalidParam)?;
+    if !is_root(&mounts[index], &target_inode)? {
+        return Err(FsError::InvalidParam);
+    }
+    drop(target_inode);
+    if index == 0 {
+        return Err(FsError::Busy);
+    }
+    let mount = &mounts[index];
+    if !detach {
+        let busy = open_files.iter().any(|inode| owns(&mount.fs, inode))
+            || cwds
+                .iter()
+                .any(|cwd| relative_path(cwd, &mount.target).is_some())
+            || mounts[index + 1..]
+                .iter()
+                .any(|m| owns(&mount.fs, &m.slot.covered));
+        if busy {
+            return Err(FsError::Busy);
+        }
+        // fails without releasing anything if a file of it is still mapped
+        if let Some((mounted, _)) = &*mount.slot.mounted.read() {
+            super::page_cache::release_fs(mounted)?;
+        }
+    }
+    mount.fs.sync()?;
+    *mount.slot.mounted.write() = None;
+    mounts.remove(index);
+    Ok(())
+}
+
+/// Whether the mount `inode` belongs to is read-only
+pub fn read_only(inode: &Arc<dyn INode>) -> bool {
+    MOUNTS
+        .read()
+        .iter()
+        .rev()
+        .find(|m| owns(&m.fs, inode))
+        .map_or(false, |m| m.readonly)
+}
+
+/// Usage and flags of a mounted file system
+pub struct MountInfo {
+    pub fstype: String,
+    pub readonly: bool,
+    pub info: FsInfo,
+}
+
+/// Usage and flags of the mount `inode` belongs to, `None` if it doesn't belong to one
+pub fn mount_info(inode: &Arc<dyn INode>) -> Option<MountInfo> {
+    MOUNTS
+        .read()
+        .iter()
+        .rev()
+        .find(|m| owns(&m.fs, inode))
+        .map(|m| MountInfo {
+            fstype: m.fstype.clone(),
+            readonly: m.readonly,
+            info: m.fs.info(),
+        })
+}
+
+/// The mount table in the format of /proc/mounts
+pub fn mounts() -> String {
+    let mut s = String::new();
+    for m in MOUNTS.read().iter() {
+        let flags = if m.readonly { "ro" } else { "rw" };
+        s += &format!("{} {} {} {} 0 0\n", m.source, m.target, m.fstype, flags);
+    }
+    s
+}
+
+/// Where the absolute paths of the old tree are after `pivot_root`
+pub struct Pivot {
+    /// Where the new root was mounted
+    prefix: String,
+    /// Where the old root is put in the new tree
+    old_root: String,
+}
+
+impl Pivot {
+    /// The absolute path in the new tree of `path` in the old one
+    pub fn path(&self, path: &str) -> String {
+        match (relative_path(path, &self.prefix), path) {
+            (Some(path), _) => String::from(path),
+            (None, "/") => self.old_root.clone(),
+            (None, path) => self.old_root.clone() + path,
+        }
+    }
+}
+
+/// Make the mount whose root is `new_root` the root file system, and move the old root to
+/// the directory at the absolute path `put_old` under `new_root`.
+/// Mounts under `new_root` move along with it, the others stay under the old root.
+/// The mount tree is built again, since `MountFS` resolves absolute paths from the top,
+/// and the old tree is left empty. Files in `open_files` keep working, but directories
+/// can't be looked up from any more, so it's busy while one of them is open.
+/// Current directories, kept as paths, should be moved with the returned `Pivot`.
+pub fn pivot_root(
+    new_root: &Arc<dyn INode>,
+    put_old: &str,
+    open_files: &[Arc<dyn INode>],
+) -> Result<(Arc<MountFS>, Pivot)> {
+    let mut mounts = MOUNTS.write();
+    let index = mounts
+        .iter()
+        .rposition(|m| owns(&m.fs, new_root))
+        .ok_or(FsError::InvalidParam)?;
+    if index == 0 || !is_root(&mounts[index], new_root)? {
+        return Err(FsError::InvalidParam);
+    }
+    let pivot = Pivot {
+        prefix: mounts[index].target.clone(),
+        old_root: match relative_path(put_old, &mounts[index].target) {
+            Some("/") | None => return Err(FsError::InvalidParam),
+            Some(path) => String::from(path),
+        },
+    };
+    for inode in open_files {
+        if mounts.iter().any(|m| owns(&m.fs, inode)) && inode.metadata()?.type_ == FileType::Dir {
+            return Err(FsError::Busy);
+        }
+    }
+
+    // targets in the new tree, parents first: mounts made on the new root come before the
+    // old root, which may be put under them
+    let mut targets = Vec::new();
+    for (i, m) in mounts.iter().enumerate().skip(index + 1) {
+        if let Some(path) = relative_path(&m.target, &pivot.prefix) {
+            targets.push((i, String::from(path)));
+        }
+    }
+    for (i, m) in mounts.iter().enumerate() {
+        if i != index && targets.iter().all(|&(j, _)| i != j) {
+            let path = match m.target.as_str() {
+                "/" => pivot.old_root.clone(),
+                target => pivot.old_root.clone() + target,
+            };
+            targets.push((i, path));
+        }
+    }
+
+    let slot = Arc::new(MountSlot {
+        mounted: RwLock::new(mounts[index].slot.mounted.read().clone()),
+        covered: new_root.clone(),
+    });
+    let rootfs = MountFS::new(slot.clone());
+    let root: Arc<dyn INode> = rootfs.root_inode();
+    let mut rebuilt = vec![(index, String::from("/"), rootfs.clone(), slot)];
+    for (i, target) in targets {
+        let dir = root.lookup(&target)?;
+        let mounted = mounts[i].slot.mounted.read().clone();
+        let (fs, slot) = attach_slot(&dir, mounted)?;
+        rebuilt.push((i, target, fs, slot));
+    }
+
+    // leave the old tree empty
+    for m in mounts.iter() {
+        *m.slot.mounted.write() = None;
+    }
+    let mut old: Vec<Option<Mount>> = mounts.drain(..).map(Some).collect();
+    for (i, target, fs, slot) in rebuilt {
+        let m = old[i].take().unwrap();
+        mounts.push(Mount {
+            target,
+            fs,
+            slot,
+            ..m
+        });
+    }
+    Ok((rootfs, pivot))
+}
+
+/// The part of the absolute path `path` after the directory `dir`, if it's under `dir`
+fn relative_path<'a>(path: &'a str, dir: &str) -> Option<&'a str> {
+    if dir == "/" {
+        Some(path)
+    } else if path == dir {
+        Some("/")
+    } else if path.starts_with(dir) && path.as_bytes()[dir.len()] == b'/' {
+        Some(&path[dir.len()..])
+    } else {
+        None
+    }
+}
@@ -0,0 +1,156 @@
+//! Device numbers for file systems that don't have their own
+//!
+//! Files are told apart by (dev, inode) in inotify, locks and path resolution,
+//! but SFS, RamFS and DevFS give every instance the same device number.
+//! `NumberedFS` wraps such a file system, passing everything through but the device number.
+
+use alloc::{
+    boxed::Box,
+    string::String,
+    sync::{Arc, Weak},
+};
+use core::any::Any;
+use core::future::Future;
+use core::pin::Pin;
+use rcore_fs::vfs::*;
+use spin::RwLock;
+
+pub struct NumberedFS {
+    inner: Arc<dyn FileSystem>,
+    dev: usize,
+    self_ptr: RwLock<Weak<NumberedFS>>,
+}
+
+impl NumberedFS {
+    /// Give `inner` a device number of its own
+    pub fn new(inner: Arc<dyn FileSystem>) -> Arc<Self> {
+        let fs = Arc::new(NumberedFS {
+            inner,
+            dev: super::alloc_dev(),
+            self_ptr: RwLock::new(Weak::new()),
+        });
+        *fs.self_ptr.write() = Arc::downgrade(&fs);
+        fs
+    }
+
+    fn arc(&self) -> Arc<Self> {
+        self.self_ptr.read().upgrade().unwrap()
+    }
+
+    fn wrap(&self, inode: Arc<dyn INode>) -> Arc<dyn INode> {
+        Arc::new(NumberedINode {
+            inode,
+            fs: self.arc(),
+        })
+    }
+}
+
+impl FileSystem for NumberedFS {
+    fn sync(&self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    fn root_inode(&self) -> Arc<dyn INode> {
+        self.wrap(self.inner.root_inode())
+    }
+
+    fn info(&self) -> FsInfo {
+        self.inner.info()
+    }
+}
+
+pub struct NumberedINode {
+    inode: Arc<dyn INode>,
+    fs: Arc<NumberedFS>,
+}
+
+/// The inode wrapped by `other`, which must be of the same file system as `this`
+fn unwrap<'a>(this: &NumberedINode, other: &'a Arc<dyn INode>) -> Result<&'a Arc<dyn INode>> {
+    match other.as_any_ref().downcast_ref::<NumberedINode>() {
+        Some(other) if Arc::ptr_eq(&this.fs, &other.fs) => Ok(&other.inode),
+        _ => Err(FsError::NotSameFs),
+    }
+}
+
+impl INode for NumberedINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.inode.read_at(offset, buf)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.inode.write_at(offset, buf)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        self.inode.poll()
+    }
+
+    fn async_poll<'a>(
+        &'a self,
+    ) -> Pin<Box<dyn Future<Output = Result<PollStatus>> + Send + Sync + 'a>> {
+        self.inode.async_poll()
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        let mut metadata = self.inode.metadata()?;
+        metadata.dev = self.fs.dev;
+        Ok(metadata)
+    }
+
+    fn set_metadata(&self, metadata: &Metadata) -> Result<()> {
+        // the device number is never written
+        self.inode.set_metadata(metadata)
+    }
+
+    fn sync_all(&self) -> Result<()> {
+        self.inode.sync_all()
+    }
+
+    fn sync_data(&self) -> Result<()> {
+        self.inode.sync_data()
+    }
+
+    fn resize(&self, len: usize) -> Result<()> {
+        self.inode.resize(len)
+    }
+
+    fn create(&self, name: &str, type_: FileType, mode: u32) -> Result<Arc<dyn INode>> {
+        Ok(self.fs.wrap(self.inode.create(name, type_, mode)?))
+    }
+
+    fn link(&self, name: &str, other: &Arc<dyn INode>) -> Result<()> {
+        self.inode.link(name, unwrap(self, other)?)
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        self.inode.unlink(name)
+    }
+
+    fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> Result<()> {
+        self.inode.move_(old_name, unwrap(self, target)?, new_name)
+    }
+
+    fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
+        Ok(self.fs.wrap(self.inode.find(name)?))
+    }
+
+    fn get_entry(&self, id: usize) -> Result<String> {
+        self.inode.get_entry(id)
+    }
+
+    fn io_control(&self, cmd: u32, data: usize) -> Result<usize> {
+        self.inode.io_control(cmd, data)
+    }
+
+    fn mmap(&self, area: MMapArea) -> Result<()> {
+        self.inode.mmap(area)
+    }
+
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        self.fs.clone()
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
@@ -0,0 +1,483 @@
+//! Overlay file system, merging a writable upper tree over a read-only lower one
+//!
+//! Files are copied up to the upper tree, along with their parents, before they are changed.
+//! Lower entries are hidden by whiteout files named `.wh.<name>` in the upper directory,
+//! and a `.wh..wh..opq` file makes an upper directory hide the whole lower one, as in aufs.
+
+use alloc::{
+    collections::BTreeSet,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::any::Any;
+use rcore_fs::vfs::*;
+use spin::RwLock;
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE: &str = ".wh..wh..opq";
+
+pub struct OverlayFS {
+    lower: Arc<dyn INode>,
+    upper: Arc<dyn INode>,
+    dev: usize,
+    self_ptr: RwLock<Weak<OverlayFS>>,
+}
+
+impl OverlayFS {
+    /// Merge the directory `upper` over the directory `lower`, which is never written to
+    pub fn new(lower: Arc<dyn INode>, upper: Arc<dyn INode>) -> Result<Arc<Self>> {
+        if lower.metadata()?.type_ != FileType::Dir || upper.metadata()?.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        let fs = Arc::new(OverlayFS {
+            lower,
+            upper,
+            dev: super::alloc_dev(),
+            self_ptr: RwLock::new(Weak::new()),
+        });
+        *fs.self_ptr.write() = Arc::downgrade(&fs);
+        Ok(fs)
+    }
+
+    fn arc(&self) -> Arc<Self> {
+        self.self_ptr.read().upgrade().unwrap()
+    }
+}
+
+impl FileSystem for OverlayFS {
+    fn sync(&self) -> Result<()> {
+        self.upper.fs().sync()
+    }
+
+    fn root_inode(&self) -> Arc<dyn INode> {
+        let layers = Layers {
+            upper: Some(self.upper.clone()),
+            lower: Some(self.lower.clone()),
+        };
+        OverlayINode::new(self.arc(), None, layers)
+    }
+
+    fn info(&self) -> FsInfo {
+        self.upper.fs().info()
+    }
+}
+
+/// The inodes of a file in each tree
+#[derive(Clone)]
+struct Layers {
+    /// `None` until copied up
+    upper: Option<Arc<dyn INode>>,
+    /// `None` for new files, and directories hiding the lower ones
+    lower: Option<Arc<dyn INode>>,
+}
+
+pub struct OverlayINode {
+    fs: Arc<OverlayFS>,
+    /// The directory holding it and its name there, to copy it up, `None` for the root
+    parent: Option<(Arc<OverlayINode>, String)>,
+    layers: RwLock<Layers>,
+    self_ptr: RwLock<Weak<OverlayINode>>,
+}
+
+impl OverlayINode {
+    fn new(
+        fs: Arc<OverlayFS>,
+        parent: Option<(Arc<OverlayINode>, String)>,
+        layers: Layers,
+    ) -> Arc<Self> {
+        let inode = Arc::new(OverlayINode {
+            fs,
+            parent,
+            layers: RwLock::new(layers),
+            self_ptr: RwLock::new(Weak::new()),
+        });
+        *inode.self_ptr.write() = Arc::downgrade(&inode);
+        inode
+    }
+
+    fn arc(&self) -> Arc<Self> {
+        self.self_ptr.read().upgrade().unwrap()
+    }
+
+    /// The upper inode, if the file has been copied up, maybe through another node of it
+    fn upper(&self) -> Option<Arc<dyn INode>> {
+        if let Some(upper) = &self.layers.read().upper {
+            return Some(upper.clone());
+        }
+        let (parent, name) = self.parent.as_ref()?;
+        let upper = parent.upper()?.find(name).ok()?;
+        self.layers.write().upper = Some(upper.clone());
+        Some(upper)
+    }
+
+    fn lower(&self) -> Option<Arc<dyn INode>> {
+        self.layers.read().lower.clone()
+    }
+
+    /// The inode holding the file now
+    fn active(&self) -> Arc<dyn INode> {
+        self.upper().or_else(|| self.lower()).unwrap()
+    }
+
+    /// Copy the file to the upper tree, with its parents, before it's changed
+    fn copy_up(&self) -> Result<Arc<dyn INode>> {
+        if let Some(upper) = self.upper() {
+            return Ok(upper);
+        }
+        // the root is always in the upper tree
+        let (parent, name) = self.parent.as_ref().unwrap();
+        let dir = parent.copy_up()?;
+        let lower = self.lower().unwrap();
+        let metadata = lower.metadata()?;
+        let upper = match dir.create(name, metadata.type_, metadata.mode as u32) {
+            // copied up by another node of it
+            Err(FsError::EntryExist) => dir.find(name)?,
+            result => {
+                let upper = result?;
+                if metadata.type_ == FileType::File || metadata.type_ == FileType::SymLink {
+                    copy_data(&lower, &upper, metadata.size)?;
+                }
+                match upper.set_metadata(&metadata) {
+                    Ok(()) | Err(FsError::NotSupported) => {}
+                    Err(e) => return Err(e),
+                }
+                upper
+            }
+        };
+        self.layers.write().upper = Some(upper.clone());
+        Ok(upper)
+    }
+
+    fn check_dir(&self) -> Result<()> {
+        if self.active().metadata()?.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        Ok(())
+    }
+
+    /// Names in the merged directory, the upper ones first
+    fn entries(&self) -> Result<Vec<String>> {
+        self.check_dir()?;
+        let mut names = Vec::new();
+        let mut seen = BTreeSet::new();
+        let mut hidden = BTreeSet::new();
+        if let Some(upper) = self.upper() {
+            for name in list(&upper) {
+                if name.starts_with(WHITEOUT_PREFIX) {
+                    hidden.insert(String::from(&name[WHITEOUT_PREFIX.len()..]));
+                } else {
+                    seen.insert(name.clone());
+                    names.push(name);
+                }
+            }
+        }
+        if let Some(lower) = self.lower() {
+            for name in list(&lower) {
+                if !seen.contains(&name) && !hidden.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    fn find_child(&self, name: &str) -> Result<Arc<OverlayINode>> {
+        let inode = self.find(name)?;
+        let child = inode.as_any_ref().downcast_ref::<OverlayINode>().unwrap();
+        Ok(child.arc())
+    }
+
+    fn same_fs(&self, other: &Arc<dyn INode>) -> Result<Arc<OverlayINode>> {
+        let other = other
+            .as_any_ref()
+            .downcast_ref::<OverlayINode>()
+            .ok_or(FsError::NotSameFs)?;
+        if !Arc::ptr_eq(&self.fs, &other.fs) {
+            return Err(FsError::NotSameFs);
+        }
+        Ok(other.arc())
+    }
+}
+
+/// All names in the directory `dir`
+fn list(dir: &Arc<dyn INode>) -> Vec<String> {
+    (0..)
+        .map(|i| dir.get_entry(i))
+        .take_while(|r| r.is_ok())
+        .map(|r| r.unwrap())
+        .collect()
+}
+
+fn whiteout(name: &str) -> String {
+    String::from(WHITEOUT_PREFIX) + name
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.starts_with(WHITEOUT_PREFIX) {
+        return Err(FsError::InvalidParam);
+    }
+    Ok(())
+}
+
+/// The result of `find`, with `None` if the entry doesn't exist
+fn found(result: Result<Arc<dyn INode>>) -> Result<Option<Arc<dyn INode>>> {
+    match result {
+        Ok(inode) => Ok(Some(inode)),
+        Err(FsError::EntryNotFound) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn is_dir(inode: &Arc<dyn INode>) -> bool {
+    inode
+        .metadata()
+        .map_or(false, |metadata| metadata.type_ == FileType::Dir)
+}
+
+fn copy_data(from: &Arc<dyn INode>, to: &Arc<dyn INode>, size: usize) -> Result<()> {
+    let mut buf = [0u8; 0x1000];
+    let mut offset = 0;
+    while offset < size {
+        let len = from.read_at(offset, &mut buf)?;
+        if len == 0 {
+            break;
+        }
+        to.write_at(offset, &buf[..len])?;
+        offset += len;
+    }
+    Ok(())
+}
+
+impl INode for OverlayINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.active().read_at(offset, buf)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.copy_up()?.write_at(offset, buf)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        self.active().poll()
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        let upper = self.upper();
+        let lower = self.lower();
+        let mut metadata = match &upper {
+            Some(upper) => upper.metadata()?,
+            None => lower.as_ref().unwrap().metadata()?,
+        };
+        // numbers of lower files stay the same after copy-up, and don't clash with upper ones
+        metadata.inode = match (&lower, &upper) {
+            (Some(lower), Some(_)) => lower.metadata()?.inode * 2,
+            (Some(_), None) => metadata.inode * 2,
+            _ => metadata.inode * 2 + 1,
+        };
+        metadata.dev = self.fs.dev;
+        Ok(metadata)
+    }
+
+    fn set_metadata(&self, metadata: &Metadata) -> Result<()> {
+        self.copy_up()?.set_metadata(metadata)
+    }
+
+    fn sync_all(&self) -> Result<()> {
+        match self.upper() {
+            Some(upper) => upper.sync_all(),
+            None => Ok(()),
+        }
+    }
+
+    fn sync_data(&self) -> Result<()> {
+        match self.upper() {
+            Some(upper) => upper.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    fn resize(&self, len: usize) -> Result<()> {
+        self.copy_up()?.resize(len)
+    }
+
+    fn create(&self, name: &str, type_: FileType, mode: u32) -> Result<Arc<dyn INode>> {
+        check_name(name)?;
+        self.check_dir()?;
+        if found(self.find(name))?.is_some() {
+            return Err(FsError::EntryExist);
+        }
+        let dir = self.copy_up()?;
+        // a removed lower entry of the name is hidden by a whiteout
+        let hidden = found(dir.find(&whiteout(name)))?.is_some();
+        if hidden {
+            dir.unlink(&whiteout(name))?;
+        }
+        let upper = dir.create(name, type_, mode)?;
+        if hidden && type_ == FileType::Dir {
+            upper.create(OPAQUE, FileType::File, 0)?;
+        }
+        let layers = Layers {
+            upper: Some(upper),
+            lower: None,
+        };
+        Ok(OverlayINode::new(
+            self.fs.clone(),
+            Some((self.arc(), String::from(name))),
+            layers,
+        ))
+    }
+
+    fn link(&self, name: &str, other: &Arc<dyn INode>) -> Result<()> {
+        check_name(name)?;
+        let other = self.same_fs(other)?;
+        self.check_dir()?;
+        if found(self.find(name))?.is_some() {
+            return Err(FsError::EntryExist);
+        }
+        let target = other.copy_up()?;
+        let dir = self.copy_up()?;
+        if found(dir.find(&whiteout(name)))?.is_some() {
+            dir.unlink(&whiteout(name))?;
+        }
+        dir.link(name, &target)
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        if name == "." || name == ".." {
+            return Err(FsError::IsDir);
+        }
+        let child = self.find_child(name)?;
+        let upper = child.upper();
+        let lower = child.lower();
+        let child_is_dir = is_dir(&child.active());
+        if child_is_dir && child.entries()?.iter().any(|n| n != "." && n != "..") {
+            return Err(FsError::DirNotEmpty);
+        }
+        let dir = self.copy_up()?;
+        if let Some(upper) = upper {
+            if child_is_dir {
+                // whiteouts are left in a directory that looks empty
+                for name in list(&upper) {
+                    if name.starts_with(WHITEOUT_PREFIX) {
+                        upper.unlink(&name)?;
+                    }
+                }
+            }
+            dir.unlink(name)?;
+        }
+        if lower.is_some() {
+            dir.create(&whiteout(name), FileType::File, 0)?;
+        }
+        Ok(())
+    }
+
+    fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> Result<()> {
+        check_name(new_name)?;
+        if old_name == "." || old_name == ".." {
+            return Err(FsError::IsDir);
+        }
+        let target = self.same_fs(target)?;
+        let child = self.find_child(old_name)?;
+        let child_is_dir = is_dir(&child.active());
+        // like Linux without `redirect_dir`, callers copy merged directories instead
+        if child_is_dir && child.lower().is_some() {
+            return Err(FsError::NotSameFs);
+        }
+        target.check_dir()?;
+        if let Some(existing) = found(target.find(new_name))? {
+            if existing.metadata()?.inode == child.metadata()?.inode {
+                return Ok(());
+            }
+            match (child_is_dir, is_dir(&existing)) {
+                (true, false) => return Err(FsError::NotDir),
+                (false, true) => return Err(FsError::IsDir),
+                _ => {}
+            }
+            target.unlink(new_name)?;
+        }
+        let lower = child.lower();
+        let upper = child.copy_up()?;
+        let dir = self.copy_up()?;
+        let target_dir = target.copy_up()?;
+        if found(target_dir.find(&whiteout(new_name)))?.is_some() {
+            target_dir.unlink(&whiteout(new_name))?;
+            if child_is_dir && found(upper.find(OPAQUE))?.is_none() {
+                upper.create(OPAQUE, FileType::File, 0)?;
+            }
+        }
+        dir.move_(old_name, &target_dir, new_name)?;
+        if lower.is_some() {
+            dir.create(&whiteout(old_name), FileType::File, 0)?;
+        }
+        Ok(())
+    }
+
+    fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
+        self.check_dir()?;
+        match name {
+            "." => return Ok(self.arc()),
+            ".." => {
+                return Ok(match &self.parent {
+                    Some((parent, _)) => parent.clone(),
+                    None => self.arc(),
+                })
+            }
+            _ => check_name(name).map_err(|_| FsError::EntryNotFound)?,
+        }
+        let upper_dir = self.upper();
+        let upper = match &upper_dir {
+            Some(dir) => found(dir.find(name))?,
+            None => None,
+        };
+        let lower = match (self.lower(), &upper) {
+            (Some(dir), None) => {
+                let hidden = match &upper_dir {
+                    Some(upper_dir) => found(upper_dir.find(&whiteout(name)))?.is_some(),
+                    None => false,
+                };
+                if hidden {
+                    None
+                } else {
+                    found(dir.find(name))?
+                }
+            }
+            // directories are merged, unless the upper one is opaque
+            (Some(dir), Some(upper)) if is_dir(upper) && found(upper.find(OPAQUE))?.is_none() => {
+                found(dir.find(name))?.filter(is_dir)
+            }
+            _ => None,
+        };
+        if upper.is_none() && lower.is_none() {
+            return Err(FsError::EntryNotFound);
+        }
+        Ok(OverlayINode::new(
+            self.fs.clone(),
+            Some((self.arc(), String::from(name))),
+            Layers { upper, lower },
+        ))
+    }
+
+    fn get_entry(&self, id: usize) -> Result<String> {
+        self.entries()?
+            .into_iter()
+            .nth(id)
+            .ok_or(FsError::EntryNotFound)
+    }
+
+    fn io_control(&self, cmd: u32, data: usize) -> Result<usize> {
+        self.active().io_control(cmd, data)
+    }
+
+    fn mmap(&self, area: MMapArea) -> Result<()> {
+        self.active().mmap(area)
+    }
+
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        self.fs.clone()
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
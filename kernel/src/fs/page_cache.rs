@@ -0,0 +1,382 @@
+//! Page cache of regular files
+//!
+//! Pages of a file are kept in frames shared by buffered I/O, `MAP_SHARED` mappings
+//! and private mappings until they are written to.
+//! Written pages stay dirty in the cache until synced, or written back to be evicted
+//! under memory pressure.
+
+use super::Pseudo;
+use crate::memory::{phys_to_virt, GlobalFrameAlloc};
+use crate::process::INodeForMap;
+use crate::sync::SpinNoIrqLock as Mutex;
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::slice;
+use core::sync::atomic::{AtomicBool, Ordering};
+use rcore_fs::vfs::{FileSystem, FileType, FsError, INode, Metadata, Result};
+use rcore_fs_mountfs::MNode;
+use rcore_memory::memory_set::handler::{CachedPage, FilePages, Read};
+use rcore_memory::{PhysAddr, VMError, VMResult, PAGE_SIZE};
+
+pub type CachedPages = spin::Mutex<FilePages<INodeForMap, GlobalFrameAlloc>>;
+
+lazy_static! {
+    /// Cached pages of files, indexed by (file system, inode)
+    static ref PAGE_CACHE: Mutex<BTreeMap<(usize, usize), Arc<CachedPages>>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// Set when `shrink` finds only dirty pages to evict
+static PRESSURED: AtomicBool = AtomicBool::new(false);
+
+/// The inode of the file system itself behind the mounts `inode` is looked up through
+fn real_inode(inode: &Arc<dyn INode>) -> &Arc<dyn INode> {
+    let mut inode = inode;
+    while let Some(mnode) = inode.as_any_ref().downcast_ref::<MNode>() {
+        inode = &mnode.inode;
+    }
+    inode
+}
+
+/// The address of a file system, which tells it apart while it lives
+fn fs_id(fs: &Arc<dyn FileSystem>) -> usize {
+    &**fs as *const dyn FileSystem as *const u8 as usize
+}
+
+/// Files are told apart by their file system, since device numbers may repeat
+fn key_of(inode: &Arc<dyn INode>, metadata: &Metadata) -> (usize, usize) {
+    (fs_id(&real_inode(inode).fs()), metadata.inode)
+}
+
+/// Only regular files are cached, devices and pipes are read directly
+fn cacheable(inode: &Arc<dyn INode>, metadata: &Metadata) -> bool {
+    // pseudo files are generated when opened
+    metadata.type_ == FileType::File && !inode.as_any_ref().is::<Pseudo>()
+}
+
+/// Get the cached pages of `inode`, `None` if the file is not cacheable
+pub fn file_pages(inode: &Arc<dyn INode>) -> Result<Option<Arc<CachedPages>>> {
+    let metadata = inode.metadata()?;
+    if !cacheable(inode, &metadata) {
+        return Ok(None);
+    }
+    let key = key_of(inode, &metadata);
+    let mut table = PAGE_CACHE.lock();
+    let pages = table.entry(key).or_insert_with(|| {
+        Arc::new(spin::Mutex::new(FilePages::new(
+            INodeForMap(inode.clone()),
+            GlobalFrameAlloc,
+        )))
+    });
+    Ok(Some(pages.clone()))
+}
+
+fn page_data(page: &CachedPage) -> &'static mut [u8] {
+    unsafe { slice::from_raw_parts_mut(phys_to_virt(page.frame) as *mut u8, PAGE_SIZE) }
+}
+
+/// Get the page at page aligned `offset`, reading it from the file if not cached.
+/// `None` if no frame is left for it, the page is not cached if the file can't be read.
+fn load(
+    pages: &mut FilePages<INodeForMap, GlobalFrameAlloc>,
+    offset: usize,
+) -> Result<Option<&mut CachedPage>> {
+    let mut error = FsError::DeviceError;
+    let loaded = pages.load(offset, |file, frame| {
+        let data = unsafe { slice::from_raw_parts_mut(phys_to_virt(frame) as *mut u8, PAGE_SIZE) };
+        let len = file.0.read_at(offset, data).map_err(|err| {
+            error = err;
+            VMError::IOError
+        })?;
+        data[len..].iter_mut().for_each(|x| *x = 0);
+        Ok(len)
+    });
+    match loaded {
+        Ok(page) => Ok(Some(page)),
+        Err(VMError::NoMemory) => Ok(None),
+        Err(_) => Err(error),
+    }
+}
+
+/// Read from `inode` at `offset` through the page cache
+pub fn read_at(inode: &Arc<dyn INode>, offset: usize, buf: &mut [u8]) -> Result<usize> {
+    let pages = match file_pages(inode)? {
+        Some(pages) => pages,
+        None => return inode.read_at(offset, buf),
+    };
+    let size = inode.metadata()?.size;
+    if offset >= size {
+        return Ok(0);
+    }
+    let end = size.min(offset + buf.len());
+    let mut pos = offset;
+    while pos < end {
+        let page_offset = pos & !(PAGE_SIZE - 1);
+        let len = (page_offset + PAGE_SIZE).min(end) - pos;
+        let mut pages = pages.lock();
+        let dst = &mut buf[pos - offset..pos - offset + len];
+        match load(&mut pages, page_offset)? {
+            Some(page) => dst.copy_from_slice(&page_data(page)[pos - page_offset..][..len]),
+            None => {
+                // no frame to cache the page, read it directly with the cache locked,
+                // so it isn't loaded meanwhile
+                let read = inode.read_at(pos, dst)?;
+                if read < len {
+                    return Ok(pos - offset + read);
+                }
+            }
+        }
+        pos += len;
+    }
+    Ok(end - offset)
+}
+
+/// Write to `inode` at `offset` through the page cache, the data is written back later
+pub fn write_at(inode: &Arc<dyn INode>, offset: usize, buf: &[u8]) -> Result<usize> {
+    let pages = match file_pages(inode)? {
+        Some(pages) => pages,
+        None => return inode.write_at(offset, buf),
+    };
+    let end = offset + buf.len();
+    if end > inode.metadata()?.size {
+        // extend the file first, so readers see the new size
+        resize(inode, end)?;
+    }
+    let mut pos = offset;
+    while pos < end {
+        let page_offset = pos & !(PAGE_SIZE - 1);
+        let len = (page_offset + PAGE_SIZE).min(end) - pos;
+        let mut pages = pages.lock();
+        let src = &buf[pos - offset..pos - offset + len];
+        match load(&mut pages, page_offset)? {
+            Some(page) => {
+                page_data(page)[pos - page_offset..][..len].copy_from_slice(src);
+                page.size = page.size.max(pos - page_offset + len);
+                page.dirty = true;
+            }
+            None => {
+                // no frame to cache the page, write it directly with the cache locked,
+                // so it isn't loaded meanwhile
+                let written = inode.write_at(pos, src)?;
+                if written < len {
+                    return Ok(pos - offset + written);
+                }
+            }
+        }
+        pos += len;
+    }
+    Ok(buf.len())
+}
+
+/// Change the size of `inode`, dropping cached data beyond the end
+pub fn resize(inode: &Arc<dyn INode>, len: usize) -> Result<()> {
+    if let Some(pages) = file_pages(inode)? {
+        let mut pages = pages.lock();
+        for (&offset, page) in pages.iter_mut() {
+            let valid = len.saturating_sub(offset).min(PAGE_SIZE);
+            // data past the old size is zero, and so is the file after resizing
+            page_data(page)[page.size.min(valid)..]
+                .iter_mut()
+                .for_each(|x| *x = 0);
+            page.size = valid;
+        }
+        while let Some(offset) = pages
+            .iter()
+            .find(|(offset, page)| **offset >= len && page.mapped == 0)
+            .map(|(&offset, _)| offset)
+        {
+            pages.evict(offset);
+        }
+    }
+    inode.resize(len)
+}
+
+/// Write dirty pages back to the file
+fn write_back(pages: &mut FilePages<INodeForMap, GlobalFrameAlloc>) -> Result<()> {
+    let file = pages.file().0.clone();
+    for (&offset, page) in pages.iter_mut() {
+        if page.dirty {
+            file.write_at(offset, &page_data(page)[..page.size])?;
+            page.dirty = false;
+        }
+    }
+    Ok(())
+}
+
+/// Write cached data of `inode` back to the file
+pub fn sync(inode: &Arc<dyn INode>) -> Result<()> {
+    if let Some(pages) = file_pages(inode)? {
+        write_back(&mut pages.lock())?;
+    }
+    Ok(())
+}
+
+/// Write cached data of all files back
+pub fn sync_all() -> Result<()> {
+    let table = PAGE_CACHE.lock();
+    for pages in table.values() {
+        write_back(&mut pages.lock())?;
+    }
+    Ok(())
+}
+
+/// Write back and drop the cached files of `fs` before unmounting it.
+/// Fails with `Busy` if a file of it is still mapped.
+pub fn release_fs(fs: &Arc<dyn FileSystem>) -> Result<()> {
+    let mut table = PAGE_CACHE.lock();
+    let fs = fs_id(fs);
+    let keys: Vec<_> = table
+        .keys()
+        .filter(|&&(owner, _)| owner == fs)
+        .cloned()
+        .collect();
+    for key in keys.iter() {
+        let pages = &table[key];
+        if Arc::strong_count(pages) > 1 || pages.lock().iter().any(|(_, page)| page.mapped != 0) {
+            return Err(FsError::Busy);
+        }
+    }
+    for key in keys.iter() {
+        let pages = table.remove(key).unwrap();
+        let mut pages = pages.lock();
+        write_back(&mut pages)?;
+        while let Some(offset) = pages.iter().next().map(|(&offset, _)| offset) {
+            pages.evict(offset);
+        }
+    }
+    Ok(())
+}
+
+/// Drop the cached pages of `inode` once its last link is removed, without writing them back.
+/// Pages of a file still open, or mapped, are left until it is closed or unmapped, as the
+/// open file descriptions hold them.
+pub fn forget(inode: &Arc<dyn INode>) {
+    let metadata = match inode.metadata() {
+        Ok(metadata) => metadata,
+        Err(_) => return,
+    };
+    if metadata.nlinks != 0 || !cacheable(inode, &metadata) {
+        return;
+    }
+    let key = key_of(inode, &metadata);
+    let mut table = PAGE_CACHE.lock();
+    let unused = table.get(&key).map_or(false, |pages| {
+        Arc::strong_count(pages) == 1 && pages.lock().iter().all(|(_, page)| page.mapped == 0)
+    });
+    if unused {
+        let pages = table.remove(&key).unwrap();
+        let mut pages = pages.lock();
+        while let Some(offset) = pages.iter().next().map(|(&offset, _)| offset) {
+            pages.evict(offset);
+        }
+    }
+}
+
+/// The number of pages in the cache
+pub fn cached_pages() -> usize {
+    let table = PAGE_CACHE.lock();
+    table
+        .values()
+        .map(|pages| pages.lock().iter().count())
+        .sum()
+}
+
+/// Evict up to `count` clean unmapped pages to free their frames.
+/// Called when frames run out, so it neither waits for locks nor writes to files, whose
+/// locks the allocating code may hold. Dirty pages are left to `write_back_pressured`.
+/// Returns the number of pages evicted.
+pub fn shrink(count: usize) -> usize {
+    let mut table = match PAGE_CACHE.try_lock() {
+        Some(table) => table,
+        None => return 0,
+    };
+    let mut evicted = 0;
+    for pages in table.values() {
+        let mut pages = match pages.try_lock() {
+            Some(pages) => pages,
+            None => continue,
+        };
+        while evicted < count {
+            let offset = match pages
+                .iter()
+                .find(|(_, page)| page.mapped == 0 && !page.dirty)
+            {
+                Some((&offset, _)) => offset,
+                None => break,
+            };
+            pages.evict(offset);
+            evicted += 1;
+        }
+        if evicted < count && pages.iter().any(|(_, page)| page.mapped == 0 && page.dirty) {
+            PRESSURED.store(true, Ordering::Relaxed);
+        }
+    }
+    // forget files with nothing cached and no mappings
+    while let Some(key) = table
+        .iter()
+        .find(|(_, pages)| {
+            Arc::strong_count(pages) == 1
+                && pages
+                    .try_lock()
+                    .map_or(false, |pages| pages.iter().next().is_none())
+        })
+        .map(|(&key, _)| key)
+    {
+        table.remove(&key);
+    }
+    evicted
+}
+
+/// Write back the dirty pages `shrink` had to leave, so they can be evicted next time.
+/// Called on the way back to user space, where no locks are held.
+pub fn write_back_pressured() {
+    if !PRESSURED.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    let files: Vec<_> = PAGE_CACHE.lock().values().cloned().collect();
+    for pages in files.iter() {
+        // pages failed to write stay dirty in the cache
+        if let Err(err) = write_back(&mut pages.lock()) {
+            warn!("page cache: failed to write back: {:?}", err);
+        }
+    }
+}
+
+/// Reader of a file through the page cache, mapping cached frames in private file mappings
+#[derive(Clone)]
+pub struct CachedINode(pub Arc<dyn INode>);
+
+impl Read for CachedINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> VMResult<usize> {
+        read_at(&self.0, offset, buf).map_err(|err| {
+            warn!("page cache: failed to read at {:#x}: {:?}", offset, err);
+            VMError::IOError
+        })
+    }
+
+    fn get_page(&self, offset: usize) -> VMResult<Option<PhysAddr>> {
+        let pages = match file_pages(&self.0) {
+            Ok(Some(pages)) => pages,
+            _ => return Ok(None),
+        };
+        let mut pages = pages.lock();
+        match load(&mut pages, offset) {
+            Ok(Some(page)) => {
+                page.mapped += 1;
+                Ok(Some(page.frame))
+            }
+            Ok(None) => Err(VMError::NoMemory),
+            Err(err) => {
+                warn!("page cache: failed to read at {:#x}: {:?}", offset, err);
+                Err(VMError::IOError)
+            }
+        }
+    }
+
+    fn put_page(&self, offset: usize) {
+        if let Ok(Some(pages)) = file_pages(&self.0) {
+            if let Some(page) = pages.lock().get(offset) {
+                page.mapped -= 1;
+            }
+        }
+    }
+}
@@ -0,0 +1,120 @@
+//! Partitions of disks, found in an MBR or GPT partition table
+
+use crate::drivers::BlockDriver;
+use alloc::sync::Arc;
+use rcore_fs::dev::{self, BlockDevice, DevError};
+
+const SECTOR_SIZE: usize = 512;
+/// Offset of the partition entries in the MBR
+const MBR_ENTRIES: usize = 446;
+/// Type of the MBR entry covering a disk with a GPT
+const GPT_PROTECTIVE: u8 = 0xee;
+
+/// A range of sectors of a disk
+pub struct Partition {
+    disk: Arc<dyn BlockDriver>,
+    start: usize,
+    count: usize,
+}
+
+impl Partition {
+    /// The `index`th partition of `disk`, counting from 1
+    pub fn open(disk: Arc<dyn BlockDriver>, index: usize) -> Option<Self> {
+        let mut mbr = [0u8; SECTOR_SIZE];
+        if !disk.read_block(0, &mut mbr) || mbr[510] != 0x55 || mbr[511] != 0xaa {
+            return None;
+        }
+        let (start, count) = if mbr[MBR_ENTRIES + 4] == GPT_PROTECTIVE {
+            gpt_entry(&*disk, index)?
+        } else {
+            mbr_entry(&mbr, index)?
+        };
+        info!("partition {}: {} sectors from {}", index, count, start);
+        Some(Partition { disk, start, count })
+    }
+}
+
+/// Sectors of the `index`th primary partition
+fn mbr_entry(mbr: &[u8], index: usize) -> Option<(usize, usize)> {
+    if index == 0 || index > 4 {
+        return None;
+    }
+    let entry = &mbr[MBR_ENTRIES + (index - 1) * 16..];
+    let start = read_u32(entry, 8) as usize;
+    let count = read_u32(entry, 12) as usize;
+    if entry[4] == 0 || count == 0 {
+        return None;
+    }
+    Some((start, count))
+}
+
+/// Sectors of the `index`th partition of the GPT
+fn gpt_entry(disk: &dyn BlockDriver, index: usize) -> Option<(usize, usize)> {
+    let mut header = [0u8; SECTOR_SIZE];
+    if !disk.read_block(1, &mut header) || &header[..8] != b"EFI PART" {
+        return None;
+    }
+    let entries = read_u64(&header, 72) as usize;
+    let entry_count = read_u32(&header, 80) as usize;
+    let entry_size = read_u32(&header, 84) as usize;
+    // entries are a power of 2 of at least 128 bytes, so none crosses sectors
+    if index == 0 || index > entry_count || entry_size < 128 || SECTOR_SIZE % entry_size != 0 {
+        return None;
+    }
+    let offset = (index - 1) * entry_size;
+    let mut sector = [0u8; SECTOR_SIZE];
+    if !disk.read_block(entries + offset / SECTOR_SIZE, &mut sector) {
+        return None;
+    }
+    let entry = &sector[offset % SECTOR_SIZE..];
+    // unused entries have no type
+    if entry[..16].iter().all(|&b| b == 0) {
+        return None;
+    }
+    let first = read_u64(entry, 32) as usize;
+    let last = read_u64(entry, 40) as usize;
+    if last < first {
+        return None;
+    }
+    Some((first, last - first + 1))
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+impl BlockDevice for Partition {
+    const BLOCK_SIZE_LOG2: u8 = 9; // 512
+
+    fn read_at(&self, block_id: usize, buf: &mut [u8]) -> dev::Result<()> {
+        if block_id + buf.len() / SECTOR_SIZE > self.count {
+            return Err(DevError);
+        }
+        match self.disk.read_block(self.start + block_id, buf) {
+            true => Ok(()),
+            false => Err(DevError),
+        }
+    }
+
+    fn write_at(&self, block_id: usize, buf: &[u8]) -> dev::Result<()> {
+        if block_id + buf.len() / SECTOR_SIZE > self.count {
+            return Err(DevError);
+        }
+        match self.disk.write_block(self.start + block_id, buf) {
+            true => Ok(()),
+            false => Err(DevError),
+        }
+    }
+
+    fn sync(&self) -> dev::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+//! Path resolution, following symbolic links like POSIX
+//!
+//! Symbolic links in the middle of a path are always followed, and the last one only if
+//! asked. `..` goes back through the directories walked into, so it leaves a directory
+//! reached by a link to where the link points, not to where the link is.
+
+use super::{root_inode, FOLLOW_MAX_DEPTH};
+use alloc::{string::String, sync::Arc, vec::Vec};
+use bitflags::bitflags;
+use rcore_fs::vfs::{FileType, FsError, INode, Result};
+
+bitflags! {
+    /// Restrictions on resolving a path, the `RESOLVE_*` flags of `openat2`
+    pub struct ResolveFlags: u64 {
+        /// Don't cross mount points
+        const NO_XDEV = 0x01;
+        /// Don't follow links of `/proc`, like `/proc/self/fd/0`
+        const NO_MAGICLINKS = 0x02;
+        /// Don't follow any symbolic link
+        const NO_SYMLINKS = 0x04;
+        /// Fail on escaping the starting directory
+        const BENEATH = 0x08;
+        /// Take the starting directory as the root
+        const IN_ROOT = 0x10;
+        /// Only resolve from cached entries
+        const CACHED = 0x20;
+    }
+}
+
+/// Find the file at `path` from the directory `start`, following a symbolic link at the end
+pub fn lookup(start: &Arc<dyn INode>, path: &str) -> Result<Arc<dyn INode>> {
+    resolve(start, path, true, ResolveFlags::empty())
+}
+
+/// Find the file at `path` from the directory `start`.
+///
+/// Up to `FOLLOW_MAX_DEPTH` symbolic links are followed before failing with `SymLoop`.
+/// A link at the end is followed if `follow`, or if the path ends with `/`.
+/// Escaping the starting directory or crossing a mount point, when `resolve` forbids it,
+/// fails with `NotSameFs`.
+pub fn resolve(
+    start: &Arc<dyn INode>,
+    path: &str,
+    follow: bool,
+    resolve: ResolveFlags,
+) -> Result<Arc<dyn INode>> {
+    if path.is_empty() {
+        return Err(FsError::EntryNotFound);
+    }
+    if resolve.contains(ResolveFlags::CACHED) {
+        // there is no cache of entries, so the caller retries without it
+        return Err(FsError::Again);
+    }
+    let scoped = resolve.intersects(ResolveFlags::BENEATH | ResolveFlags::IN_ROOT);
+    // only taken for absolute paths and links
+    let root = || match resolve.contains(ResolveFlags::IN_ROOT) {
+        true => start.clone(),
+        false => root_inode(),
+    };
+    let start_dev = start.metadata()?.dev;
+    let must_dir = path.ends_with('/');
+
+    // components left to walk, the next one at the end
+    let mut rest = Vec::new();
+    push_components(&mut rest, path);
+    // directories walked into, the parent of `current` at the end
+    let mut parents: Vec<Arc<dyn INode>> = Vec::new();
+    let mut current = start.clone();
+    if path.starts_with('/') {
+        if resolve.contains(ResolveFlags::BENEATH) {
+            return Err(FsError::NotSameFs);
+        }
+        current = root();
+    }
+    let mut type_ = current.metadata()?.type_;
+    let mut links = 0;
+
+    while let Some(name) = rest.pop() {
+        if type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        if name == ".." {
+            current = match parents.pop() {
+                Some(parent) => parent,
+                // a scoped walk has nothing above its root
+                None if resolve.contains(ResolveFlags::BENEATH) => return Err(FsError::NotSameFs),
+                None if scoped => current,
+                None => current.find("..")?,
+            };
+            type_ = FileType::Dir;
+            continue;
+        }
+        let inode = current.find(&name)?;
+        let metadata = inode.metadata()?;
+        if resolve.contains(ResolveFlags::NO_XDEV) && metadata.dev != start_dev {
+            return Err(FsError::NotSameFs);
+        }
+        let last = rest.is_empty();
+        if metadata.type_ == FileType::SymLink && (!last || follow || must_dir) {
+            if resolve.contains(ResolveFlags::NO_SYMLINKS) {
+                return Err(FsError::SymLoop);
+            }
+            links += 1;
+            if links > FOLLOW_MAX_DEPTH {
+                return Err(FsError::SymLoop);
+            }
+            let target = read_link(&inode)?;
+            if target.is_empty() {
+                return Err(FsError::EntryNotFound);
+            }
+            if target.starts_with('/') {
+                if resolve.contains(ResolveFlags::BENEATH) {
+                    return Err(FsError::NotSameFs);
+                }
+                parents.clear();
+                current = root();
+            }
+            // the link is walked from the directory it's in
+            push_components(&mut rest, &target);
+            continue;
+        }
+        parents.push(current);
+        current = inode;
+        type_ = metadata.type_;
+    }
+    if must_dir && type_ != FileType::Dir {
+        return Err(FsError::NotDir);
+    }
+    Ok(current)
+}
+
+/// Push the components of `path` to walk before the ones in `rest`
+fn push_components(rest: &mut Vec<String>, path: &str) {
+    let components = path.split('/').filter(|&s| s != "" && s != ".");
+    let start = rest.len();
+    rest.extend(components.map(String::from));
+    rest[start..].reverse();
+}
+
+fn read_link(inode: &Arc<dyn INode>) -> Result<String> {
+    let size = inode.metadata()?.size;
+    let mut buf = vec![0; size];
+    let len = inode.read_at(0, &mut buf)?;
+    buf.truncate(len);
+    String::from_utf8(buf).map_err(|_| FsError::InvalidParam)
+}
@@ -0,0 +1,361 @@
+//! Heap sanitizer for debug builds, enabled by the `kasan` feature
+//!
+//! Every allocation of the global allocator is surrounded by redzones,
+//! and the state of each 8-byte granule of the heap is kept in shadow memory:
+//! accessible, in a redzone, or freed. Freed objects are poisoned and kept in
+//! a quarantine for a while before being really freed, so that accesses to them
+//! are caught instead of hitting a new object.
+//!
+//! The compiler doesn't instrument memory accesses, so the shadow is checked
+//! when an object is freed or leaves the quarantine, and by `check_read` and
+//! `check_write` called from unsafe code copying memory. Violations are reported
+//! with the backtraces of allocation and free.
+
+use crate::backtrace;
+use crate::consts::{KERNEL_HEAP_SIZE, MEMORY_OFFSET};
+use crate::memory::{initial_heap, phys_to_virt};
+use crate::sync::SpinNoIrqLock;
+use core::alloc::Layout;
+use core::mem::size_of;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rcore_memory::PAGE_SIZE;
+
+/// Bytes described by a shadow byte
+const GRANULE: usize = 8;
+/// Bytes of redzone after an object, and before it besides the metadata
+const REDZONE: usize = 32;
+/// Bytes of freed objects kept in the quarantine
+const QUARANTINE_SIZE: usize = 1 << 20;
+/// Frames of backtraces saved
+const TRACE_LEN: usize = 8;
+const MAGIC: usize = 0x6b61_7361;
+/// Bytes of shadow scanned for the object of a bad access
+const SCAN_LIMIT: usize = 4 << 20;
+
+/// Shadow values, or the number of accessible bytes of a partial granule
+const ACCESSIBLE: u8 = 0;
+const LEFT_REDZONE: u8 = 0xfa;
+const RIGHT_REDZONE: u8 = 0xfc;
+const FREED: u8 = 0xfd;
+
+/// Fill of redzones and freed objects, checked on free
+const REDZONE_BYTE: u8 = 0xcc;
+const FREED_BYTE: u8 = 0x6b;
+
+/// Metadata at the start of each block, followed by the left redzone and the object
+#[repr(C)]
+struct Meta {
+    magic: usize,
+    /// Size of the object
+    size: usize,
+    /// Layout of the whole block
+    block_size: usize,
+    block_align: usize,
+    freed: bool,
+    /// Next block in the quarantine
+    next: *mut Meta,
+    alloc_trace: [usize; TRACE_LEN],
+    free_trace: [usize; TRACE_LEN],
+}
+
+impl Meta {
+    fn object(&self) -> usize {
+        self as *const _ as usize + left_size(self.block_align)
+    }
+}
+
+/// Shadow of the frames, from `phys_to_virt(MEMORY_OFFSET)`
+static SHADOW: AtomicUsize = AtomicUsize::new(0);
+/// Bytes of memory covered by `SHADOW`
+static SHADOW_COVERED: AtomicUsize = AtomicUsize::new(0);
+/// Shadow of the initial heap, which is not in the frames on all architectures
+static mut HEAP_SHADOW: [u8; KERNEL_HEAP_SIZE / GRANULE] = [0; KERNEL_HEAP_SIZE / GRANULE];
+
+/// Freed blocks waiting to be really freed, oldest first
+struct Quarantine {
+    head: *mut Meta,
+    tail: *mut Meta,
+    bytes: usize,
+}
+
+unsafe impl Send for Quarantine {}
+
+static QUARANTINE: SpinNoIrqLock<Quarantine> = SpinNoIrqLock::new(Quarantine {
+    head: null_mut(),
+    tail: null_mut(),
+    bytes: 0,
+});
+
+/// Pages of shadow memory needed by `frames` frames
+pub fn shadow_pages(frames: usize) -> usize {
+    let size = frames * PAGE_SIZE / GRANULE;
+    (size + PAGE_SIZE - 1) / PAGE_SIZE
+}
+
+/// Set up the shadow memory of `frames` frames at `shadow`,
+/// of `shadow_pages(frames)` pages.
+pub unsafe fn init(shadow: usize, frames: usize) {
+    let size = shadow_pages(frames) * PAGE_SIZE;
+    core::slice::from_raw_parts_mut(shadow as *mut u8, size)
+        .iter_mut()
+        .for_each(|x| *x = ACCESSIBLE);
+    SHADOW_COVERED.store(frames * PAGE_SIZE, Ordering::Relaxed);
+    SHADOW.store(shadow, Ordering::Release);
+    info!("kasan: shadow memory of {} frames at {:#x}", frames, shadow);
+}
+
+/// The shadow byte of `addr`, if it is covered
+fn shadow(addr: usize) -> Option<&'static mut u8> {
+    let heap = initial_heap();
+    if heap.contains(&addr) {
+        return Some(unsafe { &mut HEAP_SHADOW[(addr - heap.start) / GRANULE] });
+    }
+    let shadow = SHADOW.load(Ordering::Acquire);
+    let start = phys_to_virt(MEMORY_OFFSET);
+    if shadow == 0 || addr < start || addr - start >= SHADOW_COVERED.load(Ordering::Relaxed) {
+        return None;
+    }
+    Some(unsafe { &mut *((shadow + (addr - start) / GRANULE) as *mut u8) })
+}
+
+/// Set the shadow of granules in `[start, end)` to `value`
+fn poison(start: usize, end: usize, value: u8) {
+    for addr in (start..end).step_by(GRANULE) {
+        if let Some(shadow) = shadow(addr) {
+            *shadow = value;
+        }
+    }
+}
+
+/// Mark `size` bytes at `addr` accessible
+fn unpoison(addr: usize, size: usize) {
+    poison(addr, addr + size / GRANULE * GRANULE, ACCESSIBLE);
+    if size % GRANULE != 0 {
+        poison(
+            addr + size / GRANULE * GRANULE,
+            addr + size,
+            (size % GRANULE) as u8,
+        );
+    }
+}
+
+/// Bytes before the object, aligned to `align`
+fn left_size(align: usize) -> usize {
+    let size = size_of::<Meta>() + REDZONE;
+    (size + align - 1) / align * align
+}
+
+/// Layout of the block holding an object of `layout`
+fn block_layout(layout: Layout) -> Layout {
+    let align = layout.align().max(2 * GRANULE);
+    let size = (layout.size() + GRANULE - 1) / GRANULE * GRANULE;
+    Layout::from_size_align(left_size(align) + size + REDZONE, align).unwrap()
+}
+
+/// Allocate an object of `layout` with redzones, from the block allocated by `inner`
+pub unsafe fn alloc(layout: Layout, inner: impl FnOnce(Layout) -> *mut u8) -> *mut u8 {
+    let block_layout = block_layout(layout);
+    let block = inner(block_layout);
+    if block.is_null() {
+        return block;
+    }
+    let meta = &mut *(block as *mut Meta);
+    *meta = Meta {
+        magic: MAGIC,
+        size: layout.size(),
+        block_size: block_layout.size(),
+        block_align: block_layout.align(),
+        freed: false,
+        next: null_mut(),
+        alloc_trace: [0; TRACE_LEN],
+        free_trace: [0; TRACE_LEN],
+    };
+    backtrace::capture(&mut meta.alloc_trace);
+    let block = block as usize;
+    let object = meta.object();
+    let end = block + block_layout.size();
+    fill(block + size_of::<Meta>(), object, REDZONE_BYTE);
+    fill(object + layout.size(), end, REDZONE_BYTE);
+    poison(block, object, LEFT_REDZONE);
+    unpoison(object, layout.size());
+    let granules_end = object + (layout.size() + GRANULE - 1) / GRANULE * GRANULE;
+    poison(granules_end, end, RIGHT_REDZONE);
+    object as *mut u8
+}
+
+/// Poison the object at `ptr` and put it in the quarantine.
+/// Blocks leaving the quarantine are freed by `inner`.
+pub unsafe fn dealloc(ptr: *mut u8, layout: Layout, mut inner: impl FnMut(*mut u8, Layout)) {
+    let object = ptr as usize;
+    let block = object - left_size(block_layout(layout).align());
+    let meta = &mut *(block as *mut Meta);
+    if meta.magic != MAGIC || meta.size != layout.size() {
+        report(
+            object,
+            layout.size(),
+            true,
+            "invalid-free or left out-of-bounds write",
+        );
+        return;
+    }
+    if meta.freed {
+        report(object, layout.size(), true, "double-free");
+        return;
+    }
+    if !filled(block + size_of::<Meta>(), object, REDZONE_BYTE)
+        || !filled(object + meta.size, block + meta.block_size, REDZONE_BYTE)
+    {
+        report(object, meta.size, true, "out-of-bounds write");
+    }
+    meta.freed = true;
+    backtrace::capture(&mut meta.free_trace);
+    fill(object, object + meta.size, FREED_BYTE);
+    poison(object, block + meta.block_size - REDZONE, FREED);
+
+    // take the oldest blocks out of the quarantine, free them without the lock
+    let mut evicted = null_mut::<Meta>();
+    {
+        let mut quarantine = QUARANTINE.lock();
+        match quarantine.tail.is_null() {
+            true => quarantine.head = meta,
+            false => (*quarantine.tail).next = meta,
+        }
+        quarantine.tail = meta;
+        quarantine.bytes += meta.block_size;
+        while quarantine.bytes > QUARANTINE_SIZE {
+            let oldest = quarantine.head;
+            quarantine.head = (*oldest).next;
+            if quarantine.head.is_null() {
+                quarantine.tail = null_mut();
+            }
+            quarantine.bytes -= (*oldest).block_size;
+            (*oldest).next = evicted;
+            evicted = oldest;
+        }
+    }
+    while !evicted.is_null() {
+        let meta = &mut *evicted;
+        evicted = meta.next;
+        let object = meta.object();
+        if !filled(object, object + meta.size, FREED_BYTE) {
+            report(object, meta.size, true, "use-after-free write");
+        }
+        let block = meta as *mut Meta as usize;
+        let block_layout = Layout::from_size_align_unchecked(meta.block_size, meta.block_align);
+        // the memory may be used by anything from now on
+        poison(block, block + meta.block_size, ACCESSIBLE);
+        meta.magic = 0;
+        inner(block as *mut u8, block_layout);
+    }
+}
+
+/// Check a read of `size` bytes at `addr` by code the compiler can't check
+pub fn check_read(addr: usize, size: usize) {
+    check(addr, size, false);
+}
+
+/// Check a write of `size` bytes at `addr` by code the compiler can't check
+pub fn check_write(addr: usize, size: usize) {
+    check(addr, size, true);
+}
+
+fn check(addr: usize, size: usize, write: bool) {
+    for byte in addr..addr + size {
+        let value = match shadow(byte) {
+            Some(shadow) => *shadow,
+            None => continue,
+        };
+        let ok = value == ACCESSIBLE || (value < GRANULE as u8 && byte % GRANULE < value as usize);
+        if !ok {
+            let kind = match value {
+                FREED => "use-after-free",
+                _ => "out-of-bounds",
+            };
+            report(byte, size - (byte - addr), write, kind);
+            return;
+        }
+    }
+}
+
+/// Find the metadata of the object which `addr` is in or next to, from the shadow
+fn find_meta(addr: usize) -> Option<&'static Meta> {
+    // the start of the object is after the left redzone
+    let start = addr / GRANULE * GRANULE;
+    let mut object = start;
+    match *shadow(object)? {
+        LEFT_REDZONE => {
+            while *shadow(object)? == LEFT_REDZONE {
+                object += GRANULE;
+            }
+        }
+        _ => {
+            while *shadow(object - GRANULE)? != LEFT_REDZONE {
+                object -= GRANULE;
+                if start - object > SCAN_LIMIT {
+                    return None;
+                }
+            }
+        }
+    }
+    let mut align = 2 * GRANULE;
+    while align <= PAGE_SIZE {
+        let meta = unsafe { &*((object - left_size(align)) as *const Meta) };
+        if meta.magic == MAGIC && meta.block_align == align && meta.object() == object {
+            return Some(meta);
+        }
+        align *= 2;
+    }
+    None
+}
+
+/// Report a bad access of `size` bytes at `addr`
+fn report(addr: usize, size: usize, write: bool, kind: &str) {
+    println!("==================================================================");
+    println!(
+        "BUG: KASAN: {} {} of size {} @ {:#x}",
+        kind,
+        if write { "write" } else { "read" },
+        size,
+        addr
+    );
+    if let Some(meta) = find_meta(addr) {
+        let object = meta.object();
+        println!(
+            "The buggy address is {} bytes from the object of {} bytes @ {:#x}",
+            addr as isize - object as isize,
+            meta.size,
+            object
+        );
+        println!("Allocated by:");
+        print_trace(&meta.alloc_trace);
+        if meta.freed {
+            println!("Freed by:");
+            print_trace(&meta.free_trace);
+        }
+    }
+    println!("Accessed by:");
+    backtrace::backtrace();
+    println!("==================================================================");
+}
+
+fn print_trace(trace: &[usize]) {
+    let len = trace.iter().position(|&pc| pc == 0).unwrap_or(trace.len());
+    backtrace::print(&trace[..len]);
+}
+
+fn fill(start: usize, end: usize, value: u8) {
+    unsafe {
+        core::slice::from_raw_parts_mut(start as *mut u8, end - start)
+            .iter_mut()
+            .for_each(|x| *x = value);
+    }
+}
+
+fn filled(start: usize, end: usize, value: u8) -> bool {
+    unsafe {
+        core::slice::from_raw_parts(start as *const u8, end - start)
+            .iter()
+            .all(|&x| x == value)
+    }
+}
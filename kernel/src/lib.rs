@@ -35,7 +35,7 @@ extern crate rlibc;
 #[macro_use]
 extern crate num_derive;
 
-pub use buddy_system_allocator::LockedHeapWithRescue;
+pub use buddy_system_allocator::LockedHeap;
 
 #[macro_use] // print!
 pub mod logging;
@@ -47,6 +47,8 @@ pub mod consts;
 pub mod drivers;
 pub mod fs;
 pub mod ipc;
+#[cfg(feature = "kasan")]
+pub mod kasan;
 pub mod lang;
 pub mod lkm;
 pub mod memory;
@@ -56,6 +58,8 @@ pub mod process;
 pub mod rvm;
 pub mod shell;
 pub mod signal;
+pub mod slab;
+pub mod swap;
 pub mod sync;
 pub mod syscall;
 pub mod trap;
@@ -87,8 +91,10 @@ pub fn kmain() -> ! {
 /// Global heap allocator
 ///
 /// Available after `memory::init()`.
+static HEAP_ALLOCATOR: LockedHeap = LockedHeap::empty();
+
+/// Global allocator, serving small objects from slabs and the rest from `HEAP_ALLOCATOR`
 ///
 /// It should be defined in memory mod, but in Rust `global_allocator` must be in root mod.
 #[global_allocator]
-static HEAP_ALLOCATOR: LockedHeapWithRescue =
-    LockedHeapWithRescue::new(crate::memory::enlarge_heap);
+static GLOBAL_ALLOCATOR: slab::KernelAllocator = slab::KernelAllocator;
@@ -2,7 +2,7 @@ use super::*;
 use crate::lkm::structs::LoadedModule;
 use alloc::string::String;
 use alloc::sync::Arc;
-use core::alloc::{GlobalAlloc, Layout};
+use core::alloc::Layout;
 use core::slice::from_raw_parts;
 
 pub fn get_module(this_module: usize) -> &'static mut LoadedModule {
@@ -55,16 +55,27 @@ pub extern "C" fn lkm_api_query_symbol(symbol: *const u8) -> usize {
 
 #[no_mangle]
 pub extern "C" fn lkm_api_kmalloc(size: usize) -> usize {
-    unsafe { crate::HEAP_ALLOCATOR.alloc(Layout::from_size_align(size, 8).unwrap()) as usize }
+    unsafe { alloc::alloc::alloc(Layout::from_size_align(size, 8).unwrap()) as usize }
 }
 
 #[no_mangle]
 pub extern "C" fn lkm_api_kfree(ptr: usize, size: usize) {
     unsafe {
-        crate::HEAP_ALLOCATOR.dealloc(ptr as *mut u8, Layout::from_size_align(size, 8).unwrap());
+        alloc::alloc::dealloc(ptr as *mut u8, Layout::from_size_align(size, 8).unwrap());
     }
 }
 
+/// Allocate `size` bytes of virtually contiguous memory, 0 if out of memory
+#[no_mangle]
+pub extern "C" fn lkm_api_vmalloc(size: usize) -> usize {
+    crate::lkm::kernelvm::vmalloc(size).unwrap_or(0)
+}
+
+#[no_mangle]
+pub extern "C" fn lkm_api_vfree(ptr: usize) {
+    crate::lkm::kernelvm::vfree(ptr);
+}
+
 #[no_mangle]
 pub extern "C" fn lkm_api_info(ptr: *const u8) {
     let text = unsafe { cstr_to_str(ptr, 1024) };
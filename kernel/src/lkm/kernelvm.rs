@@ -2,12 +2,14 @@
 use crate::arch::paging::PageTableImpl;
 use crate::memory::GlobalFrameAlloc;
 use crate::sync::SpinLock as Mutex;
+use alloc::collections::BTreeMap;
 use alloc::vec::*;
 use core::mem::ManuallyDrop;
 use core::ops::DerefMut;
 use lazy_static::lazy_static;
-use rcore_memory::memory_set::handler::{ByFrame, MemoryHandler};
+use rcore_memory::memory_set::handler::{ByFrame, FrameAllocator, MemoryHandler};
 use rcore_memory::memory_set::MemoryAttr;
+use rcore_memory::paging::PageTable;
 use rcore_memory::{Page, PAGE_SIZE};
 
 ///Allocated virtual memory space by pages. returns some vaddr.
@@ -22,31 +24,47 @@ pub trait MemorySpaceManager {
     }
 }
 
-/// The most simple strategy: no free and allocate ahead.
-/// TODO: A better allocation strategy required.
-pub struct LinearManager {
-    last_page: usize,
+/// First fit allocator of free ranges in KSEG2, merging ranges on free.
+pub struct FreeRangeManager {
+    /// Free ranges, start -> size
+    free: BTreeMap<usize, usize>,
 }
-use crate::arch::consts::KSEG2_START;
+use crate::arch::consts::{KSEG2_SIZE, KSEG2_START};
 
-impl MemorySpaceManager for LinearManager {
-    fn new() -> LinearManager {
-        LinearManager { last_page: 0 }
+impl MemorySpaceManager for FreeRangeManager {
+    fn new() -> FreeRangeManager {
+        let mut free = BTreeMap::new();
+        free.insert(KSEG2_START, KSEG2_SIZE);
+        FreeRangeManager { free }
     }
     fn alloc(&mut self, size: usize) -> Option<(usize, usize)> {
-        let required_pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
-
-        let current = self.last_page * PAGE_SIZE + KSEG2_START;
-        self.last_page += required_pages;
-        Some((current, required_pages * PAGE_SIZE))
+        let size = (size + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        let (&start, &free_size) = self.free.iter().find(|(_, &free)| free >= size)?;
+        self.free.remove(&start);
+        if free_size > size {
+            self.free.insert(start + size, free_size - size);
+        }
+        Some((start, size))
     }
 
-    fn free(&mut self, (_addr, _size): (usize, usize)) {
-        //Do nothing.
+    fn free(&mut self, (addr, size): (usize, usize)) {
+        let mut start = addr;
+        let mut end = addr + size;
+        // merge with the ranges before and after
+        if let Some((&prev, &prev_size)) = self.free.range(..addr).next_back() {
+            if prev + prev_size == start {
+                self.free.remove(&prev);
+                start = prev;
+            }
+        }
+        if let Some(next_size) = self.free.remove(&end) {
+            end += next_size;
+        }
+        self.free.insert(start, end - start);
     }
 }
 
-type VirtualMemorySpaceManager = LinearManager;
+type VirtualMemorySpaceManager = FreeRangeManager;
 type LockedVMM = Mutex<VirtualMemorySpaceManager>;
 lazy_static! {
     pub static ref KERNELVM_MANAGER: LockedVMM = Mutex::new(VirtualMemorySpaceManager::new());
@@ -81,15 +99,16 @@ impl VirtualSpace {
         self.size
     }
 
+    /// Map `[start_addr, end_addr)` to new frames, `None` if frames run out
     pub fn add_area(
         &mut self,
         start_addr: usize,
         end_addr: usize,
         attr: &MemoryAttr,
-    ) -> &VirtualArea {
-        let area = VirtualArea::new(start_addr, end_addr - start_addr, attr, self);
+    ) -> Option<&VirtualArea> {
+        let area = VirtualArea::new(start_addr, end_addr - start_addr, attr, self)?;
         self.areas.push(area);
-        self.areas.last().unwrap()
+        self.areas.last()
     }
 }
 
@@ -98,6 +117,7 @@ impl Drop for VirtualSpace {
         for v in self.areas.iter_mut() {
             v.unmap(self.allocator, &mut self.page_allocator);
         }
+        self.allocator.lock().free((self.start, self.size));
     }
 }
 
@@ -113,23 +133,35 @@ impl VirtualArea {
         size: usize,
         attr: &MemoryAttr,
         parent: &mut VirtualSpace,
-    ) -> VirtualArea {
+    ) -> Option<VirtualArea> {
         let aligned_start_addr = page_addr - page_addr % PAGE_SIZE;
         let mut aligned_end = page_addr + size + PAGE_SIZE - 1;
         aligned_end = aligned_end - aligned_end % PAGE_SIZE;
+        // allocate frames before taking the lock, allocating may shrink the page cache
+        let mut frames = Vec::new();
+        for _ in Page::range_of(aligned_start_addr, aligned_end) {
+            match GlobalFrameAlloc.alloc() {
+                Some(frame) => frames.push(frame),
+                None => {
+                    for frame in frames {
+                        GlobalFrameAlloc.dealloc(frame);
+                    }
+                    return None;
+                }
+            }
+        }
         let lock = parent.allocator.lock();
         let mut active_pt = lock.kernel_table();
-        for p in Page::range_of(aligned_start_addr, aligned_end) {
-            parent
-                .page_allocator
-                .map(active_pt.deref_mut(), p.start_address(), attr);
+        for (p, frame) in Page::range_of(aligned_start_addr, aligned_end).zip(frames) {
+            let entry = active_pt.map(p.start_address(), frame);
+            attr.apply(entry);
         }
 
-        VirtualArea {
+        Some(VirtualArea {
             start: aligned_start_addr,
             end: aligned_end,
             _attr: attr.clone(),
-        }
+        })
     }
     pub fn unmap(&mut self, allocator: &LockedVMM, parent: &mut ByFrame<GlobalFrameAlloc>) {
         let lock = allocator.lock();
@@ -139,3 +171,28 @@ impl VirtualArea {
         }
     }
 }
+
+lazy_static! {
+    /// Spaces allocated by `vmalloc`, indexed by the start address
+    static ref VMALLOC_SPACES: Mutex<BTreeMap<usize, VirtualSpace>> = Mutex::new(BTreeMap::new());
+}
+
+/// Allocate `size` bytes of kernel memory, virtually contiguous,
+/// mapped to frames page by page.
+pub fn vmalloc(size: usize) -> Option<usize> {
+    let mut space = VirtualSpace::new(&KERNELVM_MANAGER, size)?;
+    let start = space.start();
+    // the range is freed when `space` drops on failure
+    space.add_area(start, start + space.size(), &MemoryAttr::default())?;
+    VMALLOC_SPACES.lock().insert(start, space);
+    Some(start)
+}
+
+/// Free memory at `addr` allocated by `vmalloc`
+pub fn vfree(addr: usize) {
+    // unmapped and freed on drop
+    let space = VMALLOC_SPACES.lock().remove(&addr);
+    if space.is_none() {
+        warn!("vfree: {:#x} is not allocated by vmalloc", addr);
+    }
+}
@@ -47,6 +47,8 @@ fn neg(u: usize) -> usize {
 }
 unsafe fn write_to_addr(base: usize, offset: usize, val: usize) {
     let addr = base + offset;
+    #[cfg(feature = "kasan")]
+    crate::kasan::check_write(addr, core::mem::size_of::<usize>());
     *(addr as *mut usize) = val;
 }
 impl ModuleManager {
@@ -289,7 +291,12 @@ impl ModuleManager {
                         if flags.is_execute() {
                             attr = attr.execute();
                         }
-                        let _area_ref = vspace_ref.add_area(prog_start_addr, prog_end_addr, &attr);
+                        let _area_ref = vspace_ref
+                            .add_area(prog_start_addr, prog_end_addr, &attr)
+                            .ok_or_else(|| {
+                                error!("[LKM] valloc failed!");
+                                ENOMEM
+                            })?;
                         //self.vallocator.map_pages(prog_start_addr, prog_end_addr, &attr);
                         //No need to flush TLB.
                         let target = unsafe {
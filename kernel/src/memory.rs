@@ -1,34 +1,205 @@
 //! Define the FrameAllocator for physical memory
 
 use super::HEAP_ALLOCATOR;
-use crate::consts::{KERNEL_OFFSET, MEMORY_OFFSET, PHYSICAL_MEMORY_OFFSET};
+use crate::consts::{
+    KERNEL_OFFSET, KSEG2_SIZE, MAX_CPU_NUM, MEMORY_OFFSET, PHYSICAL_MEMORY_OFFSET,
+};
+use crate::lkm::kernelvm::{MemorySpaceManager, KERNELVM_MANAGER};
 use crate::process::current_thread;
 use crate::sync::SpinNoIrqLock;
-use bitmap_allocator::BitAlloc;
+use alloc::string::String;
+use alloc::vec::Vec;
 use buddy_system_allocator::Heap;
+use core::alloc::Layout;
 use core::mem;
 use core::mem::size_of;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
 use log::*;
+use rcore_memory::buddy::{BuddyAllocator, FrameInfo, MAX_ORDER};
 use rcore_memory::*;
 
 pub use crate::arch::paging::*;
 pub use rcore_memory::memory_set::{handler::*, MemoryArea, MemoryAttr};
 pub type MemorySet = rcore_memory::memory_set::MemorySet<PageTableImpl>;
 
-// x86_64 support up to 1T memory
-#[cfg(target_arch = "x86_64")]
-pub type FrameAlloc = bitmap_allocator::BitAlloc256M;
+/// Physical memory zones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// Frames below 4 GiB, for devices with 32-bit DMA
+    Dma32 = 0,
+    Normal = 1,
+}
+
+const ZONES: [Zone; 2] = [Zone::Dma32, Zone::Normal];
+
+/// Physical address where `Zone::Normal` starts
+const DMA32_LIMIT: u64 = 1 << 32;
+
+/// Frame allocator with a buddy allocator per zone, indexed by frame numbers from `MEMORY_OFFSET`
+pub struct FrameAlloc {
+    zones: [Option<BuddyAllocator>; 2],
+}
+
+impl FrameAlloc {
+    const fn new() -> Self {
+        FrameAlloc {
+            zones: [None, None],
+        }
+    }
+
+    /// Allocate a block of `2^order` frames in `zone`,
+    /// falling back to lower zones like the kernel does for `Zone::Normal`.
+    pub fn alloc(&mut self, order: usize, zone: Zone) -> Option<usize> {
+        let zone = zone as usize;
+        self.zones[..=zone]
+            .iter_mut()
+            .rev()
+            .flatten()
+            .find_map(|buddy| buddy.alloc(order))
+    }
+
+    /// Allocate `count` contiguous frames aligned to `2^align_log2` frames in `zone`
+    pub fn alloc_contiguous(
+        &mut self,
+        count: usize,
+        align_log2: usize,
+        zone: Zone,
+    ) -> Option<usize> {
+        let zone = zone as usize;
+        self.zones[..=zone]
+            .iter_mut()
+            .rev()
+            .flatten()
+            .find_map(|buddy| buddy.alloc_contiguous(count, align_log2))
+    }
+
+    /// Free the block of `2^order` frames at `frame`
+    pub fn dealloc(&mut self, frame: usize, order: usize) {
+        self.zones
+            .iter_mut()
+            .flatten()
+            .find(|buddy| buddy.contains(frame))
+            .expect("frame not managed")
+            .dealloc(frame, order);
+    }
+
+    /// Add the free frames in `frames` to the zones managing them
+    pub fn insert(&mut self, frames: Range<usize>) {
+        for buddy in self.zones.iter_mut().flatten() {
+            let mut managed = frames.clone().filter(|&frame| buddy.contains(frame));
+            if let Some(start) = managed.next() {
+                let end = managed.last().unwrap_or(start) + 1;
+                buddy.insert(start..end);
+            }
+        }
+    }
+
+    /// Get the buddy allocator of `zone`
+    pub fn zone(&self, zone: Zone) -> Option<&BuddyAllocator> {
+        self.zones[zone as usize].as_ref()
+    }
+}
 
-// RISCV, ARM, MIPS has 1G memory
-#[cfg(any(
-    target_arch = "riscv32",
-    target_arch = "riscv64",
-    target_arch = "aarch64",
-    target_arch = "mips"
-))]
-pub type FrameAlloc = bitmap_allocator::BitAlloc1M;
+pub static FRAME_ALLOCATOR: SpinNoIrqLock<FrameAlloc> = SpinNoIrqLock::new(FrameAlloc::new());
+
+/// Init the frame allocator with free regions of frame numbers from `MEMORY_OFFSET`,
+/// iterated by `regions`.
+/// State of the buddy allocators is stored at the start of a region large enough.
+pub fn init_frame_allocator<I: Iterator<Item = Range<usize>>>(regions: impl Fn() -> I) {
+    let dma32_end = (DMA32_LIMIT.saturating_sub(MEMORY_OFFSET as u64) / PAGE_SIZE as u64)
+        .min(usize::MAX as u64) as usize;
+    // frames spanned by each zone
+    let span = |zone: Zone| {
+        let (low, high) = match zone {
+            Zone::Dma32 => (0, dma32_end),
+            Zone::Normal => (dma32_end, usize::MAX),
+        };
+        let start = regions
+            .clone()
+            .map(|r| r.start.max(low))
+            .filter(|&f| f < high)
+            .min();
+        let end = regions
+            .clone()
+            .map(|r| r.end.min(high))
+            .filter(|&f| f > low)
+            .max();
+        match (start, end) {
+            (Some(start), Some(end)) if start < end => Some(start..end),
+            _ => None,
+        }
+    };
+    let info_pages = |span: &Range<usize>| {
+        let size = (span.end - span.start) * size_of::<FrameInfo>();
+        (size + PAGE_SIZE - 1) / PAGE_SIZE
+    };
+    let pages: usize = ZONES
+        .iter()
+        .filter_map(|&zone| span(zone))
+        .map(|s| info_pages(&s))
+        .sum();
+    // the shadow memory of all frames follows the frame infos
+    #[cfg(feature = "kasan")]
+    let frames = regions().map(|r| r.end).max().unwrap_or(0);
+    #[cfg(feature = "kasan")]
+    let (shadow_frame, pages) = (pages, pages + crate::kasan::shadow_pages(frames));
+    let reserved = regions
+        .clone()
+        .find(|r| r.end - r.start > pages)
+        .expect("no room for the frame allocator");
+    let reserved = reserved.start..reserved.start + pages;
+    #[cfg(feature = "kasan")]
+    unsafe {
+        let shadow = phys_to_virt((reserved.start + shadow_frame) * PAGE_SIZE + MEMORY_OFFSET);
+        crate::kasan::init(shadow, frames);
+    }
+
+    let mut ba = FRAME_ALLOCATOR.lock();
+    let mut info_frame = reserved.start;
+    for &zone in ZONES.iter() {
+        let span = match span(zone) {
+            Some(span) => span,
+            None => continue,
+        };
+        let len = span.end - span.start;
+        let info = unsafe {
+            let vaddr = phys_to_virt(info_frame * PAGE_SIZE + MEMORY_OFFSET);
+            core::slice::from_raw_parts_mut(vaddr as *mut FrameInfo, len)
+        };
+        info_frame += info_pages(&span);
+        let mut buddy = BuddyAllocator::new(span.start, info);
+        for region in regions() {
+            // skip frames holding the allocator state
+            let start = region.start.max(span.start);
+            let start = if region.contains(&reserved.start) {
+                reserved.end.max(start)
+            } else {
+                start
+            };
+            let end = region.end.min(span.end);
+            if start < end {
+                buddy.insert(start..end);
+            }
+        }
+        info!(
+            "frame allocator: zone {:?} has {} frames",
+            zone,
+            buddy.total()
+        );
+        ba.zones[zone as usize] = Some(buddy);
+    }
+    crate::slab::enable();
+}
 
-pub static FRAME_ALLOCATOR: SpinNoIrqLock<FrameAlloc> = SpinNoIrqLock::new(FrameAlloc::DEFAULT);
+/// Give physical memory `paddr`, kept from the frame allocator at boot, to it
+pub fn release_boot_memory(paddr: Range<usize>) {
+    let start = (paddr.start - MEMORY_OFFSET) / PAGE_SIZE;
+    let end = (paddr.end - MEMORY_OFFSET - 1) / PAGE_SIZE + 1;
+    FRAME_ALLOCATOR.lock().insert(start..end);
+    info!("frame allocator: {} frames released", end - start);
+}
 
 /// Convert physical address to virtual address
 #[inline]
@@ -60,33 +231,113 @@ pub const fn kernel_offset(vaddr: usize) -> usize {
     vaddr - KERNEL_OFFSET
 }
 
+/// Pages evicted from the page cache at a time when frames run out
+const SHRINK_PAGES: usize = 32;
+
+/// Frames kept in a per-CPU cache at most
+const FRAME_CACHE_HIGH: usize = 64;
+/// Frames moved between a per-CPU cache and the buddy allocator at a time
+const FRAME_CACHE_BATCH: usize = 16;
+
+/// Free frames cached by a CPU, so single frames rarely take the global lock
+struct FrameCache {
+    frames: [usize; FRAME_CACHE_HIGH],
+    count: usize,
+}
+
+const EMPTY_FRAME_CACHE: SpinNoIrqLock<FrameCache> = SpinNoIrqLock::new(FrameCache {
+    frames: [0; FRAME_CACHE_HIGH],
+    count: 0,
+});
+
+static FRAME_CACHES: [SpinNoIrqLock<FrameCache>; MAX_CPU_NUM] = [EMPTY_FRAME_CACHE; MAX_CPU_NUM];
+
+impl FrameCache {
+    fn alloc(&mut self) -> Option<usize> {
+        if self.count == 0 {
+            let mut ba = FRAME_ALLOCATOR.lock();
+            while self.count < FRAME_CACHE_BATCH {
+                match ba.alloc(0, Zone::Normal) {
+                    Some(frame) => self.frames[self.count] = frame,
+                    None => break,
+                }
+                self.count += 1;
+            }
+        }
+        if self.count == 0 {
+            return None;
+        }
+        self.count -= 1;
+        Some(self.frames[self.count])
+    }
+
+    fn dealloc(&mut self, frame: usize) {
+        if self.count == FRAME_CACHE_HIGH {
+            self.drain(FRAME_CACHE_BATCH);
+        }
+        self.frames[self.count] = frame;
+        self.count += 1;
+    }
+
+    /// Return up to `count` frames to the buddy allocator
+    fn drain(&mut self, count: usize) {
+        let mut ba = FRAME_ALLOCATOR.lock();
+        let count = count.min(self.count);
+        for &frame in self.frames[self.count - count..self.count].iter() {
+            ba.dealloc(frame, 0);
+        }
+        self.count -= count;
+    }
+}
+
+/// Return frames cached by all CPUs to the buddy allocator, so they can be merged
+fn drain_frame_caches() {
+    for cache in FRAME_CACHES.iter() {
+        let mut cache = cache.lock();
+        let count = cache.count;
+        cache.drain(count);
+    }
+}
+
+/// The number of frames cached by all CPUs
+fn cached_frames() -> usize {
+    FRAME_CACHES.iter().map(|cache| cache.lock().count).sum()
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct GlobalFrameAlloc;
 
 impl FrameAllocator for GlobalFrameAlloc {
     fn alloc(&self) -> Option<usize> {
         // get the real address of the alloc frame
-        let ret = FRAME_ALLOCATOR
-            .lock()
-            .alloc()
-            .map(|id| id * PAGE_SIZE + MEMORY_OFFSET);
+        let alloc = || {
+            FRAME_CACHES[crate::arch::cpu::id()]
+                .lock()
+                .alloc()
+                .map(|id| id * PAGE_SIZE + MEMORY_OFFSET)
+        };
+        let mut ret = alloc();
+        if ret.is_none() && crate::fs::page_cache::shrink(SHRINK_PAGES) != 0 {
+            // retry after evicting pages of the file cache
+            ret = alloc();
+        }
+        if ret.is_none() && crate::slab::reclaim() != 0 {
+            ret = alloc();
+        }
+        if ret.is_none() {
+            // other CPUs may still have frames
+            drain_frame_caches();
+            ret = alloc();
+        }
         trace!("Allocate frame: {:x?}", ret);
         ret
-        // TODO: try to swap out when alloc failed
     }
     fn alloc_contiguous(&self, size: usize, align_log2: usize) -> Option<PhysAddr> {
-        // get the real address of the alloc frame
-        let ret = FRAME_ALLOCATOR
-            .lock()
-            .alloc_contiguous(size, align_log2)
-            .map(|id| id * PAGE_SIZE + MEMORY_OFFSET);
-        trace!("Allocate frame: {:x?}", ret);
-        ret
-        // TODO: try to swap out when alloc failed
+        alloc_frame_contiguous_in(size, align_log2, Zone::Normal)
     }
     fn dealloc(&self, target: usize) {
         trace!("Deallocate frame: {:x}", target);
-        FRAME_ALLOCATOR
+        FRAME_CACHES[crate::arch::cpu::id()]
             .lock()
             .dealloc((target - MEMORY_OFFSET) / PAGE_SIZE);
     }
@@ -102,37 +353,199 @@ pub fn alloc_frame_contiguous(size: usize, align_log2: usize) -> Option<usize> {
     GlobalFrameAlloc.alloc_contiguous(size, align_log2)
 }
 
-pub struct KernelStack(usize);
+/// Allocate `size` contiguous frames in `zone`, e.g. `Zone::Dma32` for DMA buffers of
+/// devices with 32-bit addresses. Free them one by one with `dealloc_frame`.
+pub fn alloc_frame_contiguous_in(size: usize, align_log2: usize, zone: Zone) -> Option<usize> {
+    // get the real address of the alloc frame
+    let alloc = || {
+        FRAME_ALLOCATOR
+            .lock()
+            .alloc_contiguous(size, align_log2, zone)
+            .map(|id| id * PAGE_SIZE + MEMORY_OFFSET)
+    };
+    let mut ret = alloc();
+    if ret.is_none() {
+        // cached frames may be the missing buddies
+        drain_frame_caches();
+        crate::fs::page_cache::shrink(SHRINK_PAGES);
+        crate::slab::reclaim();
+        ret = alloc();
+    }
+    trace!("Allocate frame: {:x?}", ret);
+    ret
+}
+
+/// The number of frames in total and free ones
+pub fn frame_stats() -> (usize, usize) {
+    let (total, free) = {
+        let ba = FRAME_ALLOCATOR.lock();
+        ZONES
+            .iter()
+            .filter_map(|&zone| ba.zone(zone))
+            .fold((0, 0), |(total, free), buddy| {
+                (total + buddy.total(), free + buddy.free())
+            })
+    };
+    (total, free + cached_frames())
+}
+
+/// Statistics of physical memory in the format of `/proc/meminfo`
+pub fn meminfo() -> String {
+    let (total, free) = frame_stats();
+    let cached = crate::fs::page_cache::cached_pages();
+    let slab = crate::slab::slab_frames();
+    let kb = |frames: usize| frames * PAGE_SIZE / 1024;
+    let mut info = String::new();
+    info += &format!("MemTotal:       {:8} kB\n", kb(total));
+    info += &format!("MemFree:        {:8} kB\n", kb(free));
+    info += &format!("MemAvailable:   {:8} kB\n", kb(free + cached));
+    info += &format!("MemUsed:        {:8} kB\n", kb(total - free));
+    info += &format!("Cached:         {:8} kB\n", kb(cached));
+    info += &format!("Slab:           {:8} kB\n", kb(slab));
+    info
+}
+
+/// Free blocks of each order and fragmentation in each zone,
+/// in the format of `/proc/buddyinfo` with a column of permille unusable for huge pages.
+pub fn buddyinfo() -> String {
+    let huge_order = (HUGE_PAGE_SIZE / PAGE_SIZE).trailing_zeros() as usize;
+    // don't allocate with the lock held, enlarging the heap needs it
+    let mut stats = [None; 2];
+    {
+        let ba = FRAME_ALLOCATOR.lock();
+        for &zone in ZONES.iter() {
+            stats[zone as usize] = ba.zone(zone).map(|buddy| {
+                let mut blocks = [0; MAX_ORDER];
+                for (order, count) in blocks.iter_mut().enumerate() {
+                    *count = buddy.free_blocks(order);
+                }
+                (blocks, buddy.fragmentation(huge_order))
+            });
+        }
+    }
+    let mut info = String::new();
+    for &zone in ZONES.iter() {
+        if let Some((blocks, fragmentation)) = stats[zone as usize] {
+            info += &format!("Node 0, zone {:>8?}", zone);
+            for count in blocks.iter() {
+                info += &format!(" {:6}", count);
+            }
+            info += &format!(" {:6}\n", fragmentation);
+        }
+    }
+    info
+}
+
+/// Kernel stack of a thread, in a slot of the kernel stack region
+pub struct KernelStack {
+    slot: usize,
+}
 const KSTACK_SIZE: usize = 0x4000; //16KB
+/// Each slot has an unmapped guard page below the stack
+const KSTACK_SLOT_SIZE: usize = KSTACK_SIZE + PAGE_SIZE;
+
+/// Region in KSEG2 holding the kernel stacks
+struct KernelStackRegion {
+    start: usize,
+    count: usize,
+    /// The first slot never used, and slots freed
+    slots: SpinNoIrqLock<(usize, Vec<usize>)>,
+}
+
+lazy_static! {
+    static ref KSTACK_REGION: KernelStackRegion = {
+        // half of KSEG2, the rest is for modules and vmalloc
+        let size = KSEG2_SIZE / 2 / KSTACK_SLOT_SIZE * KSTACK_SLOT_SIZE;
+        let (start, size) = KERNELVM_MANAGER
+            .lock()
+            .alloc(size)
+            .expect("failed to reserve kernel stack region");
+        KernelStackRegion {
+            start,
+            count: size / KSTACK_SLOT_SIZE,
+            slots: SpinNoIrqLock::new((0, Vec::new())),
+        }
+    };
+}
 
 impl KernelStack {
-    pub fn new() -> Self {
-        use alloc::alloc::{alloc, Layout};
-        let bottom =
-            unsafe { alloc(Layout::from_size_align(KSTACK_SIZE, KSTACK_SIZE).unwrap()) } as usize;
-        KernelStack(bottom)
+    /// Allocate a kernel stack, `None` if the slots or frames run out
+    pub fn new() -> Option<Self> {
+        let region = &*KSTACK_REGION;
+        let slot = {
+            let mut slots = region.slots.lock();
+            match slots.1.pop() {
+                Some(slot) => slot,
+                None if slots.0 < region.count => {
+                    slots.0 += 1;
+                    slots.0 - 1
+                }
+                None => return None,
+            }
+        };
+        let stack = KernelStack { slot };
+        let vmm = KERNELVM_MANAGER.lock();
+        let mut pt = vmm.kernel_table();
+        let handler = ByFrame::new(GlobalFrameAlloc);
+        for page in Page::range_of(stack.bottom(), stack.top()) {
+            let addr = page.start_address();
+            if handler
+                .try_map(&mut *pt, addr, &MemoryAttr::default())
+                .is_err()
+            {
+                // give back the pages mapped so far, and the slot
+                for page in Page::range_of(stack.bottom(), addr) {
+                    handler.unmap(&mut *pt, page.start_address());
+                }
+                region.slots.lock().1.push(stack.slot);
+                mem::forget(stack);
+                return None;
+            }
+        }
+        Some(stack)
+    }
+    fn bottom(&self) -> usize {
+        KSTACK_REGION.start + self.slot * KSTACK_SLOT_SIZE + PAGE_SIZE
     }
     pub fn top(&self) -> usize {
-        self.0 + KSTACK_SIZE
+        self.bottom() + KSTACK_SIZE
     }
 }
 
 impl Drop for KernelStack {
     fn drop(&mut self) {
-        use alloc::alloc::{dealloc, Layout};
-        unsafe {
-            dealloc(
-                self.0 as _,
-                Layout::from_size_align(KSTACK_SIZE, KSTACK_SIZE).unwrap(),
-            );
+        {
+            let vmm = KERNELVM_MANAGER.lock();
+            let mut pt = vmm.kernel_table();
+            for page in Page::range_of(self.bottom(), self.top()) {
+                ByFrame::new(GlobalFrameAlloc).unmap(&mut *pt, page.start_address());
+            }
         }
+        KSTACK_REGION.slots.lock().1.push(self.slot);
+    }
+}
+
+/// Panic with the current thread if `addr` is in the guard page of a kernel stack.
+/// Called on kernel page faults, and on double faults where the trap can't use the stack.
+pub fn check_kernel_stack_guard(addr: usize) {
+    let region = &*KSTACK_REGION;
+    if addr < region.start || addr >= region.start + region.count * KSTACK_SLOT_SIZE {
+        return;
+    }
+    if (addr - region.start) % KSTACK_SLOT_SIZE >= PAGE_SIZE {
+        return;
     }
+    let tid = current_thread().map(|thread| thread.tid);
+    error!("kernel stack overflow @ {:#x}, thread {:?}", addr, tid);
+    crate::backtrace::backtrace();
+    panic!("kernel stack overflow");
 }
 
 /// Handle page fault at `addr`.
 /// Return true to continue, false to halt.
 pub fn handle_page_fault(addr: usize) -> bool {
     debug!("page fault from kernel @ {:#x}", addr);
+    check_kernel_stack_guard(addr);
 
     let thread = current_thread().unwrap();
     let mut lock = thread.vm.lock();
@@ -146,17 +559,19 @@ pub fn handle_page_fault_ext(addr: usize, access: crate::memory::AccessType) ->
         "page fault from kernel @ {:#x} with access type {:?}",
         addr, access
     );
+    check_kernel_stack_guard(addr);
 
     let thread = current_thread().unwrap();
     let mut lock = thread.vm.lock();
     lock.handle_page_fault_ext(addr, access)
 }
 
+const MACHINE_ALIGN: usize = mem::size_of::<usize>();
+const HEAP_BLOCK: usize = crate::consts::KERNEL_HEAP_SIZE / MACHINE_ALIGN;
+/// The initial heap, enlarged with frames when it runs out
+static mut HEAP: [usize; HEAP_BLOCK] = [0; HEAP_BLOCK];
+
 pub fn init_heap() {
-    use crate::consts::KERNEL_HEAP_SIZE;
-    const MACHINE_ALIGN: usize = mem::size_of::<usize>();
-    const HEAP_BLOCK: usize = KERNEL_HEAP_SIZE / MACHINE_ALIGN;
-    static mut HEAP: [usize; HEAP_BLOCK] = [0; HEAP_BLOCK];
     unsafe {
         HEAP_ALLOCATOR
             .lock()
@@ -164,31 +579,97 @@ pub fn init_heap() {
     }
 }
 
-pub fn enlarge_heap(heap: &mut Heap) {
-    info!("Enlarging heap to avoid oom");
+/// Test whether `addr` is in the initial heap
+pub fn in_initial_heap(addr: usize) -> bool {
+    initial_heap().contains(&addr)
+}
 
-    let mut addrs = [(0, 0); 32];
-    let mut addr_len = 0;
-    let va_offset = PHYSICAL_MEMORY_OFFSET;
-    for _ in 0..16384 {
-        let page = alloc_frame().unwrap();
-        let va = va_offset + page;
-        if addr_len > 0 {
-            let (ref mut addr, ref mut len) = addrs[addr_len - 1];
-            if *addr - PAGE_SIZE == va {
-                *len += PAGE_SIZE;
-                *addr -= PAGE_SIZE;
+/// The address range of the initial heap
+pub fn initial_heap() -> Range<usize> {
+    let start = unsafe { HEAP.as_ptr() as usize };
+    start..start + HEAP_BLOCK * MACHINE_ALIGN
+}
+
+/// The heap grows by this at least
+const HEAP_GROW_MIN: usize = 1 << 20;
+/// The heap shrinks by blocks of the largest order of the frame allocator
+const HEAP_SHRINK_BLOCK: usize = PAGE_SIZE << (MAX_ORDER - 1);
+/// Bytes given back to the frame allocator, still counted as allocated by the heap
+static HEAP_RELEASED: AtomicUsize = AtomicUsize::new(0);
+
+/// Bytes of the heap in use
+fn heap_used(heap: &Heap) -> usize {
+    heap.stats_alloc_actual() - HEAP_RELEASED.load(Ordering::Relaxed)
+}
+
+/// Grow the heap for an allocation of `size` bytes, by half of the memory in use at least.
+/// Return false if no frames are available.
+pub fn enlarge_heap(heap: &mut Heap, size: usize) -> bool {
+    let size = (size + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+    // blocks added to the heap may not be merged, so the allocation needs a block itself
+    let min_order = (size / PAGE_SIZE).next_power_of_two().trailing_zeros() as usize;
+    if min_order >= MAX_ORDER {
+        warn!("heap: allocation of {:#x} bytes is too large", size);
+        return false;
+    }
+    let grow = size.max(heap_used(heap) / 2).max(HEAP_GROW_MIN);
+    info!("Enlarging heap by {:#x} bytes", grow);
+
+    let mut frames = grow / PAGE_SIZE;
+    let mut order = MAX_ORDER - 1;
+    let mut first = true;
+    while frames > 0 {
+        order = order.min(63 - (frames as u64).leading_zeros() as usize);
+        if first {
+            order = order.max(min_order);
+        }
+        // not through GlobalFrameAlloc, shrinking the page cache needs the heap
+        let frame = match FRAME_ALLOCATOR.lock().alloc(order, Zone::Normal) {
+            Some(frame) => frame,
+            None if order > 0 && !(first && order == min_order) => {
+                order -= 1;
                 continue;
             }
-        }
-        addrs[addr_len] = (va, PAGE_SIZE);
-        addr_len += 1;
-    }
-    for (addr, len) in addrs[..addr_len].into_iter() {
+            None => break,
+        };
+        let addr = phys_to_virt(frame * PAGE_SIZE + MEMORY_OFFSET);
+        let len = PAGE_SIZE << order;
         info!("Adding {:#X} {:#X} to heap", addr, len);
         unsafe {
-            heap.init(*addr, *len);
+            heap.add_to_heap(addr, addr + len);
+        }
+        frames = frames.saturating_sub(1 << order);
+        first = false;
+    }
+    if first {
+        warn!("heap: no frames to enlarge the heap");
+    }
+    !first
+}
+
+/// Give large free regions of the heap back to the frame allocator,
+/// when more memory is free than in use.
+pub fn shrink_heap(heap: &mut Heap) {
+    let layout = Layout::from_size_align(HEAP_SHRINK_BLOCK, HEAP_SHRINK_BLOCK).unwrap();
+    loop {
+        let free = heap.stats_total_bytes() - heap.stats_alloc_actual();
+        if free < (2 * HEAP_SHRINK_BLOCK).max(heap_used(heap)) {
+            break;
         }
+        // take the block out of the heap for good
+        let block = match heap.alloc(layout) {
+            Ok(block) => block,
+            Err(_) => break,
+        };
+        let addr = block.as_ptr() as usize;
+        if in_initial_heap(addr) || in_initial_heap(addr + HEAP_SHRINK_BLOCK - 1) {
+            heap.dealloc(block, layout);
+            break;
+        }
+        info!("Releasing {:#X} {:#X} from heap", addr, HEAP_SHRINK_BLOCK);
+        HEAP_RELEASED.fetch_add(HEAP_SHRINK_BLOCK, Ordering::Relaxed);
+        let frame = (virt_to_phys(addr) - MEMORY_OFFSET) / PAGE_SIZE;
+        FRAME_ALLOCATOR.lock().dealloc(frame, MAX_ORDER - 1);
     }
 }
 
@@ -213,6 +694,8 @@ pub fn copy_from_user<T>(addr: *const T) -> Option<T> {
         return None;
     }
     let mut dst: T = unsafe { core::mem::zeroed() };
+    #[cfg(feature = "kasan")]
+    crate::kasan::check_write(&mut dst as *mut T as usize, size_of::<T>());
     match unsafe { read_user(&mut dst, addr) } {
         0 => Some(dst),
         _ => None,
@@ -229,8 +712,36 @@ pub fn copy_to_user<T>(addr: *mut T, src: *const T) -> bool {
     if !access_ok(addr as usize, size_of::<T>()) {
         return false;
     }
+    #[cfg(feature = "kasan")]
+    crate::kasan::check_read(src as usize, size_of::<T>());
     match unsafe { write_user(addr, src) } {
         0 => true,
         _ => false,
     }
 }
+
+/// `MemorySet::check_read_array`, which also checks the kernel heap under the array
+/// with the `kasan` feature, for kernel threads passing their own buffers
+pub unsafe fn check_read_array<S>(
+    vm: &MemorySet,
+    ptr: *const S,
+    count: usize,
+) -> VMResult<&'static [S]> {
+    let slice = vm.check_read_array(ptr, count)?;
+    #[cfg(feature = "kasan")]
+    crate::kasan::check_read(ptr as usize, count * size_of::<S>());
+    Ok(slice)
+}
+
+/// `MemorySet::check_write_array`, which also checks the kernel heap under the array
+/// with the `kasan` feature
+pub unsafe fn check_write_array<S>(
+    vm: &MemorySet,
+    ptr: *mut S,
+    count: usize,
+) -> VMResult<&'static mut [S]> {
+    let slice = vm.check_write_array(ptr, count)?;
+    #[cfg(feature = "kasan")]
+    crate::kasan::check_write(ptr as usize, count * size_of::<S>());
+    Ok(slice)
+}
@@ -27,6 +27,13 @@ pub use structs::*;
 pub use thread::*;
 
 pub fn init() {
+    // objects created and dropped with processes and threads
+    crate::slab::create_arc_cache::<Thread>("thread");
+    crate::slab::create_arc_cache::<Mutex<Process>>("process");
+    crate::slab::create_arc_cache::<Futex>("futex");
+    crate::slab::create_arc_cache::<Mutex<Waiter>>("futex_waiter");
+    crate::fs::create_description_cache();
+
     // create init process
     crate::shell::add_user_shell();
 
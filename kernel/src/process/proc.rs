@@ -3,7 +3,7 @@ use super::{
     Futex, Tid,
 };
 use crate::arch::paging::*;
-use crate::fs::{FileHandle, FileLike, OpenOptions, FOLLOW_MAX_DEPTH};
+use crate::fs::{lock, FileHandle, FileLike, OpenOptions, FOLLOW_MAX_DEPTH};
 use crate::ipc::{SemProc, ShmProc};
 use crate::memory::{
     phys_to_virt, ByFrame, Delay, File, GlobalFrameAlloc, KernelStack, MemoryAttr, MemorySet, Read,
@@ -80,6 +80,9 @@ pub struct Process {
     /// Current working dirctory
     pub cwd: String,
 
+    /// File mode creation mask
+    pub umask: u32,
+
     /// Executable path
     pub exec_path: String,
 
@@ -154,6 +157,22 @@ pub fn process_group(pgid: Pgid) -> Vec<Arc<Mutex<Process>>> {
         .collect::<Vec<_>>()
 }
 
+/// Inodes of the files opened by all processes, and their current directories
+pub fn files_in_use() -> (Vec<Arc<dyn INode>>, Vec<String>) {
+    let mut files = Vec::new();
+    let mut cwds = Vec::new();
+    for proc in PROCESSES.read().values() {
+        let proc = proc.lock();
+        for file in proc.files.values() {
+            if let FileLike::File(file) = file {
+                files.push(file.inode());
+            }
+        }
+        cwds.push(proc.cwd.clone());
+    }
+    (files, cwds)
+}
+
 /// Set pid and put itself to global process table.
 pub fn add_to_process_table(proc: Arc<Mutex<Process>>, pid: Pid) {
     let mut process_table = PROCESSES.write();
@@ -202,6 +221,7 @@ impl Process {
             let file = self.files.remove(fd).unwrap();
             drop(file);
         }
+        lock::release_process(self.pid.get());
 
         // notify parent and fill exit code
         self.eventbus.lock().set(Event::PROCESS_QUIT);
@@ -225,4 +245,55 @@ impl Process {
     pub fn exited(&self) -> bool {
         self.threads.is_empty()
     }
+
+    /// Memory usage and page faults, in the format of `/proc/self/status`
+    pub fn status(&self) -> String {
+        let (size, stats, (minflt, majflt)) = {
+            let mut vm = self.vm.lock();
+            let size: usize = vm.iter().map(|area| area.range().1 - area.range().0).sum();
+            (size, vm.stats(), vm.faults())
+        };
+        let kb = |pages: usize| pages * PAGE_SIZE / 1024;
+        let name = self.exec_path.rsplit('/').next().unwrap_or("");
+        let mut status = String::new();
+        status += &format!("Name:\t{}\n", name);
+        status += &format!("Pid:\t{}\n", self.pid);
+        status += &format!("PPid:\t{}\n", self.parent.0);
+        status += &format!("Threads:\t{}\n", self.threads.len());
+        status += &format!("VmSize:\t{:8} kB\n", size / 1024);
+        status += &format!("VmRSS:\t{:8} kB\n", kb(stats.resident));
+        status += &format!("RssShared:\t{:8} kB\n", kb(stats.shared));
+        status += &format!("VmPss:\t{:8} kB\n", stats.pss / 1024);
+        status += &format!("VmSwap:\t{:8} kB\n", kb(stats.swapped));
+        status += &format!("MinFlt:\t{}\n", minflt);
+        status += &format!("MajFlt:\t{}\n", majflt);
+        status
+    }
+
+    /// Memory usage of each area, in the format of `/proc/self/smaps`
+    pub fn smaps(&self) -> String {
+        let areas: Vec<_> = {
+            let mut vm = self.vm.lock();
+            let stats = vm.area_stats();
+            vm.iter()
+                .map(|area| (area.range(), area.name()))
+                .zip(stats)
+                .collect()
+        };
+        let kb = |pages: usize| pages * PAGE_SIZE / 1024;
+        let mut smaps = String::new();
+        for (((start, end), name), stats) in areas {
+            smaps += &format!("{:08x}-{:08x} {}\n", start, end, name);
+            smaps += &format!("Size:           {:8} kB\n", (end - start) / 1024);
+            smaps += &format!("Rss:            {:8} kB\n", kb(stats.resident));
+            smaps += &format!("Pss:            {:8} kB\n", stats.pss / 1024);
+            smaps += &format!("Shared:         {:8} kB\n", kb(stats.shared));
+            smaps += &format!(
+                "Private:        {:8} kB\n",
+                kb(stats.resident - stats.shared)
+            );
+            smaps += &format!("Swap:           {:8} kB\n", kb(stats.swapped));
+        }
+        smaps
+    }
 }
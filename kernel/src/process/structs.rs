@@ -1,9 +1,10 @@
 use super::abi::{self, ProcInitInfo};
 use crate::arch::paging::*;
-use crate::fs::{FileHandle, FileLike, OpenOptions, FOLLOW_MAX_DEPTH};
+use crate::fs::{page_cache::CachedINode, FileHandle, FileLike, OpenOptions, FOLLOW_MAX_DEPTH};
 use crate::ipc::SemProc;
 use crate::memory::{
     phys_to_virt, ByFrame, Delay, File, GlobalFrameAlloc, KernelStack, MemoryAttr, MemorySet, Read,
+    Write,
 };
 use crate::sync::{SpinLock, SpinNoIrqLock as Mutex};
 use crate::{
@@ -26,7 +27,7 @@ use core::{
 use log::*;
 use pc_keyboard::KeyCode::BackTick;
 use rcore_fs::vfs::INode;
-use rcore_memory::{Page, PAGE_SIZE};
+use rcore_memory::{Page, VMError, VMResult, PAGE_SIZE};
 use spin::RwLock;
 use trapframe::TrapFrame;
 use trapframe::UserContext;
@@ -86,13 +87,13 @@ impl ElfExt for ElfFile<'_> {
                 ph.virtual_addr() as usize,
                 ph.virtual_addr() as usize + ph.mem_size() as usize,
                 ph.flags().to_attr(),
-                File {
-                    file: INodeForMap(inode.clone()),
-                    mem_start: ph.virtual_addr() as usize,
-                    file_start: ph.offset() as usize,
-                    file_end: ph.offset() as usize + ph.file_size() as usize,
-                    allocator: GlobalFrameAlloc,
-                },
+                File::new(
+                    CachedINode(inode.clone()),
+                    ph.virtual_addr() as usize,
+                    ph.offset() as usize,
+                    ph.offset() as usize + ph.file_size() as usize,
+                    GlobalFrameAlloc,
+                ),
                 "elf",
             );
             if ph.virtual_addr() as usize + ph.mem_size() as usize > farthest_memory {
@@ -113,13 +114,13 @@ impl ElfExt for ElfFile<'_> {
                 ph.virtual_addr() as usize + bias,
                 ph.virtual_addr() as usize + ph.mem_size() as usize + bias,
                 ph.flags().to_attr(),
-                File {
-                    file: INodeForMap(inode.clone()),
-                    mem_start: ph.virtual_addr() as usize + bias,
-                    file_start: ph.offset() as usize,
-                    file_end: ph.offset() as usize + ph.file_size() as usize,
-                    allocator: GlobalFrameAlloc,
-                },
+                File::new(
+                    CachedINode(inode.clone()),
+                    ph.virtual_addr() as usize + bias,
+                    ph.offset() as usize,
+                    ph.offset() as usize + ph.file_size() as usize,
+                    GlobalFrameAlloc,
+                ),
                 "elf-interp",
             )
         }
@@ -162,11 +163,27 @@ impl ElfExt for ElfFile<'_> {
     }
 }
 
+/// Direct access to an inode, used by the page cache to load and write back pages
 #[derive(Clone)]
 pub struct INodeForMap(pub Arc<dyn INode>);
 
 impl Read for INodeForMap {
-    fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
-        self.0.read_at(offset, buf).unwrap()
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> VMResult<usize> {
+        self.0.read_at(offset, buf).map_err(|err| {
+            warn!("failed to read a mapped file at {:#x}: {:?}", offset, err);
+            VMError::IOError
+        })
+    }
+}
+
+impl Write for INodeForMap {
+    fn write_at(&self, offset: usize, buf: &[u8]) -> VMResult<usize> {
+        self.0.write_at(offset, buf).map_err(|err| {
+            warn!(
+                "failed to write back a mapped file at {:#x}: {:?}",
+                offset, err
+            );
+            VMError::IOError
+        })
     }
 }
@@ -13,7 +13,7 @@ use crate::arch::{
     paging::*,
 };
 use crate::drivers::IRQ_MANAGER;
-use crate::fs::{FileHandle, FileLike, OpenOptions, FOLLOW_MAX_DEPTH};
+use crate::fs::{page_cache, path, FileHandle, FileLike, OpenOptions};
 use crate::ipc::{SemProc, ShmProc};
 use crate::memory::{
     phys_to_virt, ByFrame, Delay, File, GlobalFrameAlloc, KernelStack, MemoryAttr, MemorySet, Read,
@@ -41,7 +41,7 @@ use log::*;
 use num::FromPrimitive;
 use pc_keyboard::KeyCode::BackTick;
 use rcore_fs::vfs::INode;
-use rcore_memory::{Page, PAGE_SIZE};
+use rcore_memory::{Page, VMResult, PAGE_SIZE};
 use spin::RwLock;
 use trapframe::TrapFrame;
 use trapframe::UserContext;
@@ -122,9 +122,7 @@ impl Thread {
         // Read ELF header
         // 0x3c0: magic number from ld-musl.so
         let mut data = [0u8; 0x3c0];
-        inode
-            .read_at(0, &mut data)
-            .map_err(|_| "failed to read from INode")?;
+        page_cache::read_at(inode, 0, &mut data).map_err(|_| "failed to read from INode")?;
 
         // Parse ELF
         let elf = ElfFile::new(&data)?;
@@ -172,13 +170,11 @@ impl Thread {
         if let Ok(loader_path) = elf.get_interpreter() {
             info!("Handling interpreter... offset={:x}", bias);
             // assuming absolute path
-            let interp_inode = crate::fs::ROOT_INODE
-                .lookup_follow(loader_path, FOLLOW_MAX_DEPTH)
+            let interp_inode = path::lookup(&crate::fs::root_inode(), loader_path)
                 .map_err(|_| "interpreter not found")?;
             // load loader by bias and set aux vector.
             let mut interp_data: [u8; 0x3c0] = unsafe { MaybeUninit::zeroed().assume_init() };
-            interp_inode
-                .read_at(0, &mut interp_data)
+            page_cache::read_at(&interp_inode, 0, &mut interp_data)
                 .map_err(|_| "failed to read from INode")?;
             let elf_interp = ElfFile::new(&interp_data)?;
             elf_interp.append_as_interpreter(&interp_inode, vm, bias);
@@ -236,6 +232,7 @@ impl Thread {
     ) -> Arc<Thread> {
         // get virtual memory info
         let mut vm = MemorySet::new();
+        crate::swap::enable_swap(&mut vm);
         let (entry_addr, ustack_top) = Self::new_user_vm(inode, args, envs, &mut vm).unwrap();
 
         let vm_token = vm.token();
@@ -335,6 +332,7 @@ impl Thread {
                 vm,
                 files,
                 cwd: String::from("/"),
+                umask: 0o022,
                 exec_path: String::from(exec_path),
                 futexes: BTreeMap::default(),
                 semaphores: SemProc::default(),
@@ -362,9 +360,9 @@ impl Thread {
 
     /// Fork a new process from current one
     /// Only current process is persisted
-    pub fn fork(&self, tf: &UserContext) -> Arc<Thread> {
+    pub fn fork(&self, tf: &UserContext) -> VMResult<Arc<Thread>> {
         // clone virtual memory
-        let vm = self.vm.lock().clone();
+        let vm = self.vm.lock().clone()?;
         let vm_token = vm.token();
         let vm = Arc::new(Mutex::new(vm));
 
@@ -378,6 +376,7 @@ impl Thread {
             vm: vm.clone(),
             files: proc.files.clone(), // share open file descriptions
             cwd: proc.cwd.clone(),
+            umask: proc.umask,
             exec_path: proc.exec_path.clone(),
             futexes: BTreeMap::default(),
             semaphores: proc.semaphores.clone(),
@@ -426,7 +425,7 @@ impl Thread {
         proc.children
             .push((child_pid, Arc::downgrade(&new_thread.proc)));
 
-        new_thread
+        Ok(new_thread)
     }
 
     /// Create a new thread in the same process.
@@ -577,6 +576,8 @@ pub fn spawn(thread: Arc<Thread>) {
             if !exit {
                 exit = handle_signal(&thread, cx);
             }
+            // no locks are held here to write files back
+            page_cache::write_back_pressured();
 
             thread.end_running(thread_context);
             if exit {
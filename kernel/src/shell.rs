@@ -1,6 +1,6 @@
 //! Kernel shell
 
-use crate::fs::ROOT_INODE;
+use crate::fs::{initramfs, path, root_inode};
 use crate::process::*;
 use alloc::string::String;
 use alloc::vec::Vec;
@@ -22,9 +22,18 @@ pub fn add_user_shell() {
     #[cfg(not(target_arch = "x86_64"))]
     let init_envs = Vec::new();
 
+    // an initramfs starts from its /init like Linux, which may switch to the disk root
+    if initramfs::loaded() {
+        if let Ok(inode) = path::lookup(&root_inode(), "/init") {
+            let thread = Thread::new_user(&inode, "/init", vec!["/init".into()], init_envs);
+            spawn(thread);
+            return;
+        }
+    }
+
     let init_args: Vec<String> = vec!["busybox".into(), "ash".into()];
 
-    if let Ok(inode) = ROOT_INODE.lookup(init_shell) {
+    if let Ok(inode) = path::lookup(&root_inode(), init_shell) {
         let thread = Thread::new_user(&inode, init_shell, init_args, init_envs);
         spawn(thread);
     } else {
@@ -0,0 +1,525 @@
+//! Slab allocator of small kernel objects
+//!
+//! Allocations no larger than `SLAB_MAX` are served from slabs of `SLAB_SIZE` bytes
+//! taken from the frame allocator, by a cache per size class.
+//! Subsystems can create caches for their own types with `create_cache`,
+//! allocations of the same layout are served by them afterwards.
+//! Each CPU keeps a magazine of free objects per cache, so most allocations don't
+//! take the cache lock.
+//!
+//! Larger allocations, and those before the frame allocator is ready, use the heap,
+//! which grows and shrinks with frames from the frame allocator.
+
+use crate::consts::{MAX_CPU_NUM, MEMORY_OFFSET};
+use crate::memory::{
+    enlarge_heap, in_initial_heap, phys_to_virt, shrink_heap, virt_to_phys, Zone, FRAME_ALLOCATOR,
+};
+use crate::sync::SpinNoIrqLock as Mutex;
+use alloc::{boxed::Box, string::String};
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem::size_of;
+use core::ptr::{null_mut, NonNull};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use rcore_memory::PAGE_SIZE;
+
+/// A slab is a block of `2^SLAB_ORDER` frames
+const SLAB_ORDER: usize = 3;
+pub const SLAB_SIZE: usize = PAGE_SIZE << SLAB_ORDER;
+/// The largest object in slabs
+pub const SLAB_MAX: usize = 4096;
+/// Free objects kept by a CPU per cache
+const MAGAZINE_SIZE: usize = 8;
+/// Caches created by subsystems at most
+const MAX_CACHES: usize = 32;
+/// Start of the objects in a slab, after the header
+const SLAB_HEADER_SIZE: usize = 64;
+
+/// Header at the start of each slab
+struct SlabHeader {
+    cache: *const SlabCache,
+    next: *mut SlabHeader,
+    prev: *mut SlabHeader,
+    free: *mut FreeObject,
+    inuse: usize,
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Slabs of a cache, linked through their headers
+struct Slabs {
+    /// Slabs with both free and allocated objects
+    partial: *mut SlabHeader,
+    /// Slabs with no allocated objects
+    empty: *mut SlabHeader,
+    /// The number of slabs
+    count: usize,
+    /// The number of empty slabs
+    empty_count: usize,
+    /// Objects out of the slabs, including those in magazines
+    inuse: usize,
+}
+
+unsafe impl Send for Slabs {}
+
+struct Magazine {
+    objects: [usize; MAGAZINE_SIZE],
+    count: usize,
+}
+
+const EMPTY_MAGAZINE: Mutex<Magazine> = Mutex::new(Magazine {
+    objects: [0; MAGAZINE_SIZE],
+    count: 0,
+});
+
+/// A cache of objects of the same size
+pub struct SlabCache {
+    name: &'static str,
+    /// Size of the objects requested
+    object_size: usize,
+    /// Size of the slots in slabs, a multiple of `align`
+    size: usize,
+    align: usize,
+    slabs: Mutex<Slabs>,
+    magazines: [Mutex<Magazine>; MAX_CPU_NUM],
+}
+
+impl SlabCache {
+    const fn new(name: &'static str, object_size: usize, align: usize) -> Self {
+        SlabCache {
+            name,
+            object_size,
+            size: (object_size + align - 1) / align * align,
+            align,
+            slabs: Mutex::new(Slabs {
+                partial: null_mut(),
+                empty: null_mut(),
+                count: 0,
+                empty_count: 0,
+                inuse: 0,
+            }),
+            magazines: [EMPTY_MAGAZINE; MAX_CPU_NUM],
+        }
+    }
+
+    /// Size class with slots of `size`, aligned to its largest power of two factor
+    const fn size_class(name: &'static str, size: usize) -> Self {
+        Self::new(name, size, 1 << size.trailing_zeros())
+    }
+
+    /// Objects in a slab
+    fn objects(&self) -> usize {
+        (SLAB_SIZE - self.offset()) / self.size
+    }
+
+    /// Offset of the first object in a slab
+    fn offset(&self) -> usize {
+        SLAB_HEADER_SIZE.max(self.align)
+    }
+
+    fn alloc(&self) -> *mut u8 {
+        let mut magazine = self.magazines[crate::arch::cpu::id()].lock();
+        if magazine.count == 0 {
+            self.refill(&mut magazine);
+            if magazine.count == 0 {
+                return null_mut();
+            }
+        }
+        magazine.count -= 1;
+        magazine.objects[magazine.count] as *mut u8
+    }
+
+    fn dealloc(&self, ptr: *mut u8) {
+        let mut magazine = self.magazines[crate::arch::cpu::id()].lock();
+        if magazine.count == MAGAZINE_SIZE {
+            self.flush(&mut magazine, MAGAZINE_SIZE / 2);
+        }
+        let count = magazine.count;
+        magazine.objects[count] = ptr as usize;
+        magazine.count += 1;
+    }
+
+    /// Fill half of an empty magazine with objects from the slabs
+    fn refill(&self, magazine: &mut Magazine) {
+        let mut slabs = self.slabs.lock();
+        while magazine.count < MAGAZINE_SIZE / 2 {
+            let slab = match self.get_slab(&mut slabs) {
+                Some(slab) => slab,
+                None => break,
+            };
+            unsafe {
+                let object = (*slab).free;
+                (*slab).free = (*object).next;
+                (*slab).inuse += 1;
+                if (*slab).free.is_null() {
+                    // full slabs are in no list
+                    unlink(&mut slabs.partial, slab);
+                }
+                magazine.objects[magazine.count] = object as usize;
+            }
+            magazine.count += 1;
+            slabs.inuse += 1;
+        }
+    }
+
+    /// Get a slab with free objects, moving it to the partial list
+    fn get_slab(&self, slabs: &mut Slabs) -> Option<*mut SlabHeader> {
+        if !slabs.partial.is_null() {
+            return Some(slabs.partial);
+        }
+        let slab = if !slabs.empty.is_null() {
+            let slab = slabs.empty;
+            unlink(&mut slabs.empty, slab);
+            slabs.empty_count -= 1;
+            slab
+        } else {
+            let slab = self.new_slab()?;
+            slabs.count += 1;
+            slab
+        };
+        link(&mut slabs.partial, slab);
+        Some(slab)
+    }
+
+    fn new_slab(&self) -> Option<*mut SlabHeader> {
+        let frame = FRAME_ALLOCATOR.lock().alloc(SLAB_ORDER, Zone::Normal)?;
+        let base = phys_to_virt(frame * PAGE_SIZE + MEMORY_OFFSET);
+        let mut free = null_mut();
+        for i in (0..self.objects()).rev() {
+            let object = (base + self.offset() + i * self.size) as *mut FreeObject;
+            unsafe {
+                (*object).next = free;
+            }
+            free = object;
+        }
+        let slab = base as *mut SlabHeader;
+        unsafe {
+            slab.write(SlabHeader {
+                cache: self,
+                next: null_mut(),
+                prev: null_mut(),
+                free,
+                inuse: 0,
+            });
+        }
+        Some(slab)
+    }
+
+    /// Return `count` objects of a magazine to their slabs
+    fn flush(&self, magazine: &mut Magazine, count: usize) {
+        let mut slabs = self.slabs.lock();
+        for _ in 0..count.min(magazine.count) {
+            magazine.count -= 1;
+            let object = magazine.objects[magazine.count] as *mut FreeObject;
+            let slab = slab_of(object as usize);
+            unsafe {
+                if (*slab).free.is_null() {
+                    link(&mut slabs.partial, slab);
+                }
+                (*object).next = (*slab).free;
+                (*slab).free = object;
+                (*slab).inuse -= 1;
+                if (*slab).inuse == 0 {
+                    unlink(&mut slabs.partial, slab);
+                    link(&mut slabs.empty, slab);
+                    slabs.empty_count += 1;
+                }
+            }
+            slabs.inuse -= 1;
+        }
+        // keep a slab for the next allocations
+        while slabs.empty_count > 1 {
+            self.free_empty_slab(&mut slabs);
+        }
+    }
+
+    fn free_empty_slab(&self, slabs: &mut Slabs) {
+        let slab = slabs.empty;
+        unlink(&mut slabs.empty, slab);
+        slabs.empty_count -= 1;
+        slabs.count -= 1;
+        let frame = (virt_to_phys(slab as usize) - MEMORY_OFFSET) / PAGE_SIZE;
+        FRAME_ALLOCATOR.lock().dealloc(frame, SLAB_ORDER);
+    }
+
+    /// Return objects in magazines and free all empty slabs, return the number of frames freed
+    fn reclaim(&self) -> usize {
+        for magazine in self.magazines.iter() {
+            let mut magazine = magazine.lock();
+            let count = magazine.count;
+            self.flush(&mut magazine, count);
+        }
+        let mut slabs = self.slabs.lock();
+        let count = slabs.empty_count;
+        for _ in 0..count {
+            self.free_empty_slab(&mut slabs);
+        }
+        count << SLAB_ORDER
+    }
+
+    fn stats(&self) -> CacheStats {
+        let cached: usize = self
+            .magazines
+            .iter()
+            .map(|magazine| magazine.lock().count)
+            .sum();
+        let slabs = self.slabs.lock();
+        CacheStats {
+            name: self.name,
+            active: slabs.inuse - cached,
+            total: slabs.count * self.objects(),
+            size: self.object_size,
+            slabs: slabs.count,
+        }
+    }
+}
+
+unsafe impl Sync for SlabCache {}
+
+/// Get the header of the slab containing `addr`
+fn slab_of(addr: usize) -> *mut SlabHeader {
+    (addr & !(SLAB_SIZE - 1)) as *mut SlabHeader
+}
+
+fn link(list: &mut *mut SlabHeader, slab: *mut SlabHeader) {
+    unsafe {
+        (*slab).prev = null_mut();
+        (*slab).next = *list;
+        if !list.is_null() {
+            (**list).prev = slab;
+        }
+    }
+    *list = slab;
+}
+
+fn unlink(list: &mut *mut SlabHeader, slab: *mut SlabHeader) {
+    unsafe {
+        let SlabHeader { next, prev, .. } = *slab;
+        if prev.is_null() {
+            *list = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+        (*slab).next = null_mut();
+        (*slab).prev = null_mut();
+    }
+}
+
+static SIZE_CLASSES: [SlabCache; 12] = [
+    SlabCache::size_class("kmalloc-8", 8),
+    SlabCache::size_class("kmalloc-16", 16),
+    SlabCache::size_class("kmalloc-32", 32),
+    SlabCache::size_class("kmalloc-64", 64),
+    SlabCache::size_class("kmalloc-96", 96),
+    SlabCache::size_class("kmalloc-128", 128),
+    SlabCache::size_class("kmalloc-192", 192),
+    SlabCache::size_class("kmalloc-256", 256),
+    SlabCache::size_class("kmalloc-512", 512),
+    SlabCache::size_class("kmalloc-1024", 1024),
+    SlabCache::size_class("kmalloc-2048", 2048),
+    SlabCache::size_class("kmalloc-4096", 4096),
+];
+
+const NO_CACHE: AtomicPtr<SlabCache> = AtomicPtr::new(null_mut());
+
+/// Caches created by subsystems, never freed
+static CACHES: [AtomicPtr<SlabCache>; MAX_CACHES] = [NO_CACHE; MAX_CACHES];
+static CACHE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether small allocations are served by slabs
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Serve small allocations by slabs from now on, called when the frame allocator is ready
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Create a cache for objects of `T`.
+/// Return `None` if `T` is too large or there are too many caches.
+pub fn create_cache<T>(name: &'static str) -> Option<&'static SlabCache> {
+    create_cache_with_layout(name, Layout::new::<T>())
+}
+
+/// Create a cache for the objects of `Arc<T>`, holding the reference counts and `T`
+pub fn create_arc_cache<T>(name: &'static str) -> Option<&'static SlabCache> {
+    // the layout of `ArcInner`
+    #[repr(C)]
+    struct ArcInner<T> {
+        strong: AtomicUsize,
+        weak: AtomicUsize,
+        data: T,
+    }
+    create_cache_with_layout(name, Layout::new::<ArcInner<T>>())
+}
+
+fn create_cache_with_layout(name: &'static str, layout: Layout) -> Option<&'static SlabCache> {
+    if !slab_layout(layout) {
+        warn!(
+            "slab: {} of {} bytes is too large for slabs",
+            name,
+            layout.size()
+        );
+        return None;
+    }
+    let align = layout.align().max(size_of::<usize>());
+    let cache = Box::leak(Box::new(SlabCache::new(name, layout.size(), align)));
+    let idx = CACHE_COUNT.fetch_add(1, Ordering::SeqCst);
+    if idx >= MAX_CACHES {
+        warn!("slab: too many caches for {}", name);
+        return None;
+    }
+    CACHES[idx].store(cache, Ordering::SeqCst);
+    Some(cache)
+}
+
+/// Whether allocations of `layout` are served by slabs once enabled
+fn slab_layout(layout: Layout) -> bool {
+    layout.size() <= SLAB_MAX && layout.align() <= SLAB_MAX
+}
+
+/// Find the cache for allocations of `layout`
+fn cache_for(layout: Layout) -> &'static SlabCache {
+    for cache in CACHES.iter() {
+        let cache = cache.load(Ordering::Acquire);
+        if cache.is_null() {
+            break;
+        }
+        let cache = unsafe { &*cache };
+        if cache.object_size == layout.size() && cache.align >= layout.align() {
+            return cache;
+        }
+    }
+    SIZE_CLASSES
+        .iter()
+        .find(|cache| cache.size >= layout.size() && cache.align >= layout.align())
+        // a slot of 4096 is aligned to it, and suits any small layout
+        .unwrap_or(&SIZE_CLASSES[SIZE_CLASSES.len() - 1])
+}
+
+/// Return objects in magazines and free empty slabs of all caches.
+/// Return the number of frames freed.
+pub fn reclaim() -> usize {
+    let mut frames: usize = SIZE_CLASSES.iter().map(|cache| cache.reclaim()).sum();
+    for cache in CACHES.iter() {
+        let cache = cache.load(Ordering::Acquire);
+        if cache.is_null() {
+            break;
+        }
+        frames += unsafe { &*cache }.reclaim();
+    }
+    frames
+}
+
+struct CacheStats {
+    name: &'static str,
+    active: usize,
+    total: usize,
+    size: usize,
+    slabs: usize,
+}
+
+/// Apply `f` to statistics of all caches
+fn for_each_stats(mut f: impl FnMut(CacheStats)) {
+    for cache in SIZE_CLASSES.iter() {
+        f(cache.stats());
+    }
+    for cache in CACHES.iter() {
+        let cache = cache.load(Ordering::Acquire);
+        if cache.is_null() {
+            break;
+        }
+        f(unsafe { &*cache }.stats());
+    }
+}
+
+/// The number of frames in slabs
+pub fn slab_frames() -> usize {
+    let mut slabs = 0;
+    for_each_stats(|stats| slabs += stats.slabs);
+    slabs << SLAB_ORDER
+}
+
+/// Usage of each cache in the format of `/proc/slabinfo`
+pub fn slabinfo() -> String {
+    let mut info = String::from(
+        "# name            <active_objs> <num_objs> <objsize> <objperslab> <pagesperslab> : slabdata <active_slabs> <num_slabs>\n",
+    );
+    for_each_stats(|stats| {
+        let per_slab = stats.total / stats.slabs.max(1);
+        info += &format!(
+            "{:<17} {:6} {:6} {:6} {:4} {:4} : slabdata {:6} {:6}\n",
+            stats.name,
+            stats.active,
+            stats.total,
+            stats.size,
+            per_slab,
+            1 << SLAB_ORDER,
+            stats.slabs,
+            stats.slabs
+        );
+    });
+    info
+}
+
+/// The kernel allocator, serving small objects from slabs and the rest from the heap
+pub struct KernelAllocator;
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    #[cfg(not(feature = "kasan"))]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.alloc_raw(layout)
+    }
+
+    #[cfg(not(feature = "kasan"))]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.dealloc_raw(ptr, layout)
+    }
+
+    #[cfg(feature = "kasan")]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        crate::kasan::alloc(layout, |layout| self.alloc_raw(layout))
+    }
+
+    #[cfg(feature = "kasan")]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        crate::kasan::dealloc(ptr, layout, |ptr, layout| self.dealloc_raw(ptr, layout))
+    }
+}
+
+impl KernelAllocator {
+    unsafe fn alloc_raw(&self, layout: Layout) -> *mut u8 {
+        if ENABLED.load(Ordering::Relaxed) && slab_layout(layout) {
+            return cache_for(layout).alloc();
+        }
+        let mut heap = crate::HEAP_ALLOCATOR.lock();
+        if let Ok(ptr) = heap.alloc(layout) {
+            return ptr.as_ptr();
+        }
+        // small objects must stay in the initial heap, see `dealloc_raw`
+        if !slab_layout(layout) && enlarge_heap(&mut heap, layout.size()) {
+            if let Ok(ptr) = heap.alloc(layout) {
+                return ptr.as_ptr();
+            }
+        }
+        null_mut()
+    }
+
+    unsafe fn dealloc_raw(&self, ptr: *mut u8, layout: Layout) {
+        // small objects come from slabs, or from the initial heap before slabs are enabled,
+        // as the heap is never enlarged for them
+        if slab_layout(layout) && !in_initial_heap(ptr as usize) {
+            let slab = slab_of(ptr as usize);
+            (*(*slab).cache).dealloc(ptr);
+        } else {
+            let mut heap = crate::HEAP_ALLOCATOR.lock();
+            heap.dealloc(NonNull::new_unchecked(ptr), layout);
+            if layout.size() >= PAGE_SIZE {
+                shrink_heap(&mut heap);
+            }
+        }
+    }
+}
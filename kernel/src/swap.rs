@@ -0,0 +1,136 @@
+//! Swap space on a file or block device
+//!
+//! The first page of the swap space is the header written by `mkswap`,
+//! following pages are slots holding the swapped out pages.
+//! A swap token is the index of the slot.
+
+use crate::memory::MemorySet;
+use crate::process::THREADS;
+use crate::sync::{SpinLock, SpinNoIrqLock as Mutex};
+use alloc::{sync::Arc, vec::Vec};
+use rcore_fs::vfs::{FileType, INode};
+use rcore_memory::swap::{EnhancedClockSwapManager, Swap, Swapper};
+use rcore_memory::PAGE_SIZE;
+
+/// The signature at the end of the header page
+const SWAP_MAGIC: &[u8] = b"SWAPSPACE2";
+/// Offset of the index of the last usable page in the header
+const LAST_PAGE_OFFSET: usize = 1028;
+
+/// Swapper writing pages to slots of an inode
+pub struct INodeSwapper {
+    inode: Arc<dyn INode>,
+    /// used[i] is true if slot i holds a page, slot 0 is the header
+    used: Vec<bool>,
+}
+
+impl INodeSwapper {
+    /// Check the header of `inode` and create a swapper on it
+    pub fn new(inode: Arc<dyn INode>) -> Option<Self> {
+        let metadata = inode.metadata().ok()?;
+        match metadata.type_ {
+            FileType::File | FileType::BlockDevice => {}
+            _ => return None,
+        }
+        let mut header = [0u8; PAGE_SIZE];
+        if inode.read_at(0, &mut header).ok()? != PAGE_SIZE {
+            return None;
+        }
+        if &header[PAGE_SIZE - SWAP_MAGIC.len()..] != SWAP_MAGIC {
+            return None;
+        }
+        let mut last_page = [0u8; 4];
+        last_page.copy_from_slice(&header[LAST_PAGE_OFFSET..LAST_PAGE_OFFSET + 4]);
+        let last_page = u32::from_le_bytes(last_page) as usize;
+        let count = (last_page + 1).min(metadata.size / PAGE_SIZE);
+        if count < 2 {
+            return None;
+        }
+        let mut used = vec![false; count];
+        used[0] = true;
+        Some(INodeSwapper { inode, used })
+    }
+
+    fn check(&self, token: usize) -> Result<(), ()> {
+        if token != 0 && token < self.used.len() && self.used[token] {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+}
+
+impl Swapper for INodeSwapper {
+    fn swap_out(&mut self, data: &[u8]) -> Result<usize, ()> {
+        let token = self.used.iter().position(|&used| !used).ok_or(())?;
+        match self.inode.write_at(token * PAGE_SIZE, data) {
+            Ok(len) if len == data.len() => {
+                self.used[token] = true;
+                Ok(token)
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn swap_update(&mut self, token: usize, data: &[u8]) -> Result<(), ()> {
+        self.check(token)?;
+        match self.inode.write_at(token * PAGE_SIZE, data) {
+            Ok(len) if len == data.len() => Ok(()),
+            _ => Err(()),
+        }
+    }
+
+    fn swap_in(&mut self, token: usize, data: &mut [u8]) -> Result<(), ()> {
+        self.check(token)?;
+        match self.inode.read_at(token * PAGE_SIZE, data) {
+            Ok(len) if len == data.len() => {
+                self.used[token] = false;
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn swap_free(&mut self, token: usize) {
+        if self.check(token).is_ok() {
+            self.used[token] = false;
+        }
+    }
+}
+
+/// The swap space in use
+pub struct SwapSpace {
+    pub inode: Arc<dyn INode>,
+    pub swapper: Arc<spin::Mutex<dyn Swapper>>,
+}
+
+impl SwapSpace {
+    /// Create a swap state for a memory set using this space
+    pub fn new_swap(&self) -> Swap {
+        Swap::new(EnhancedClockSwapManager::default(), self.swapper.clone())
+    }
+}
+
+lazy_static! {
+    pub static ref SWAP_SPACE: Mutex<Option<SwapSpace>> = Mutex::new(None);
+    /// Held through `swapon` and `swapoff`, which do I/O and can't hold `SWAP_SPACE` meanwhile
+    pub static ref SWAP_CONFIG: SpinLock<()> = SpinLock::new(());
+}
+
+/// Enable swapping for a new memory set if there is a swap space in use
+pub fn enable_swap(vm: &mut MemorySet) {
+    if let Some(space) = SWAP_SPACE.lock().as_ref() {
+        vm.enable_swap(space.new_swap());
+    }
+}
+
+/// Address spaces of all threads, each appears once
+pub fn all_vms() -> Vec<Arc<Mutex<MemorySet>>> {
+    let mut vms: Vec<Arc<Mutex<MemorySet>>> = Vec::new();
+    for thread in THREADS.read().values() {
+        if !vms.iter().any(|vm| Arc::ptr_eq(vm, &thread.vm)) {
+            vms.push(thread.vm.clone());
+        }
+    }
+    vms
+}
@@ -48,8 +48,8 @@ impl Syscall<'_> {
         paddrs: *mut u64,
         count: usize,
     ) -> SysResult {
-        let vaddrs = unsafe { self.vm().check_read_array(vaddrs, count)? };
-        let paddrs = unsafe { self.vm().check_write_array(paddrs, count)? };
+        let vaddrs = unsafe { check_read_array(&self.vm(), vaddrs, count)? };
+        let paddrs = unsafe { check_write_array(&self.vm(), paddrs, count)? };
         for i in 0..count {
             let paddr = self.vm().translate(vaddrs[i] as usize).unwrap_or(0);
             paddrs[i] = paddr as u64;
@@ -11,22 +11,26 @@ use rcore_fs::vfs::Timespec;
 use crate::drivers::SOCKET_ACTIVITY;
 use crate::fs::*;
 use crate::memory::MemorySet;
-use crate::sync::Condvar;
+use crate::sync::{Condvar, EventBus, SpinNoIrqLock as Mutex};
 use crate::trap::TICK_ACTIVITY;
 use alloc::boxed::Box;
 use core::future::Future;
 use core::pin::Pin;
-use core::task::{Context, Poll};
+use core::task::{Context, Poll, Waker};
 
 use bitvec::prelude::{BitSlice, BitVec, Lsb0};
 
 use super::*;
 use crate::fs::epoll::EpollInstance;
 use crate::fs::fcntl::{FD_CLOEXEC, F_SETFD, O_CLOEXEC, O_NONBLOCK};
+use crate::fs::inotify::{self, Inotify, InotifyMask};
+use crate::fs::lock::{self, FileLock, LockOwner, LockType};
+use crate::fs::path::ResolveFlags;
 use crate::fs::FileLike;
-use crate::process::Process;
+use crate::process::{files_in_use, Process, Thread, PROCESSES};
 use crate::syscall::SysError::{EINTR, EINVAL, ESPIPE};
 use rcore_fs::vfs::PollStatus;
+use rcore_memory::PAGE_SIZE;
 
 impl Syscall<'_> {
     pub async fn sys_read(&mut self, fd: usize, base: UserOutPtr<u8>, len: usize) -> SysResult {
@@ -35,7 +39,7 @@ impl Syscall<'_> {
             // we trust pid 0 process
             info!("read: fd: {}, base: {:?}, len: {:#x}", fd, base, len);
         }
-        let slice = unsafe { self.vm().check_write_array(base.ptr(), len)? };
+        let slice = unsafe { check_write_array(&self.vm(), base.ptr(), len)? };
 
         let file_like = proc.get_file_like(fd)?;
         let len = file_like.read(slice).await?;
@@ -48,7 +52,7 @@ impl Syscall<'_> {
             //we trust pid 0 process
             info!("write: fd: {}, base: {:?}, len: {:#x}", fd, base, len);
         }
-        let slice = unsafe { self.vm().check_read_array(base, len)? };
+        let slice = unsafe { check_read_array(&self.vm(), base, len)? };
         let file_like = proc.get_file_like(fd)?;
         let len = file_like.write(slice)?;
         Ok(len)
@@ -66,7 +70,7 @@ impl Syscall<'_> {
             fd, base, len, offset
         );
         let mut proc = self.process();
-        let slice = unsafe { self.vm().check_write_array(base.ptr(), len)? };
+        let slice = unsafe { check_write_array(&self.vm(), base.ptr(), len)? };
         let len = proc.get_file(fd)?.read_at(offset, slice).await?;
         Ok(len)
     }
@@ -83,7 +87,7 @@ impl Syscall<'_> {
             fd, base, len, offset
         );
         let mut proc = self.process();
-        let slice = unsafe { self.vm().check_read_array(base, len)? };
+        let slice = unsafe { check_read_array(&self.vm(), base, len)? };
         let len = proc.get_file(fd)?.write_at(offset, slice)?;
         Ok(len)
     }
@@ -366,7 +370,7 @@ impl Syscall<'_> {
         info!("epoll_pwait: epfd: {}, timeout: {:?}", epfd, timeout_msecs);
 
         let proc = self.process();
-        let events = unsafe { self.vm().check_write_array(events, maxevents)? };
+        let events = unsafe { check_write_array(&self.vm(), events, maxevents)? };
         let epoll_instance = proc.get_epoll_instance(epfd)?;
 
         // add new fds which are registered by epoll_ctl after latest epoll_pwait
@@ -426,6 +430,7 @@ impl Syscall<'_> {
                         FileLike::EpollInstance(_) => {
                             return Err(SysError::EINVAL);
                         }
+                        FileLike::Inotify(_) => {}
                     };
                 }
                 None => {}
@@ -527,6 +532,37 @@ impl Syscall<'_> {
         Ok(num)
     }
 
+    pub fn sys_inotify_init1(&mut self, flags: usize) -> SysResult {
+        info!("inotify_init1: flags: {:#x}", flags);
+        if flags & !(O_NONBLOCK | O_CLOEXEC) != 0 {
+            return Err(SysError::EINVAL);
+        }
+        let inotify = Inotify::new(flags & O_NONBLOCK != 0, flags & O_CLOEXEC != 0);
+        let fd = self.process().add_file(FileLike::Inotify(inotify));
+        Ok(fd)
+    }
+
+    pub fn sys_inotify_add_watch(&mut self, fd: usize, path: *const u8, mask: usize) -> SysResult {
+        let proc = self.process();
+        let path = check_and_clone_cstr(path)?;
+        let mask = InotifyMask::from_bits_truncate(mask as u32);
+        info!(
+            "inotify_add_watch: fd: {}, path: {:?}, mask: {:?}",
+            fd, path, mask
+        );
+        let inotify = proc.get_inotify(fd)?;
+        let follow = !mask.contains(InotifyMask::DONT_FOLLOW);
+        let inode = proc.lookup_inode_at(AT_FDCWD, &path, follow)?;
+        let wd = inotify.add_watch(&inode, mask)?;
+        Ok(wd as usize)
+    }
+
+    pub fn sys_inotify_rm_watch(&mut self, fd: usize, wd: usize) -> SysResult {
+        info!("inotify_rm_watch: fd: {}, wd: {}", fd, wd as i32);
+        self.process().get_inotify(fd)?.rm_watch(wd as i32)?;
+        Ok(0)
+    }
+
     pub async fn sys_readv(
         &mut self,
         fd: usize,
@@ -583,49 +619,147 @@ impl Syscall<'_> {
         flags: usize,
         mode: usize,
     ) -> SysResult {
-        let mut proc = self.process();
         let path = check_and_clone_cstr(path)?;
         let flags = OpenFlags::from_bits_truncate(flags);
         info!(
             "openat: dir_fd: {}, path: {:?}, flags: {:?}, mode: {:#o}",
             dir_fd as isize, path, flags, mode
         );
+        self.openat_impl(dir_fd, path, flags, mode, ResolveFlags::empty())
+    }
 
+    pub fn sys_openat2(
+        &mut self,
+        dir_fd: usize,
+        path: *const u8,
+        how: *const u8,
+        size: usize,
+    ) -> SysResult {
+        let path = check_and_clone_cstr(path)?;
+        if size < size_of::<OpenHow>() {
+            return Err(SysError::EINVAL);
+        }
+        if size > PAGE_SIZE {
+            return Err(SysError::E2BIG);
+        }
+        let how = unsafe { check_read_array(&self.vm(), how, size)? };
+        // fields of later versions are unsupported, unless they're zero
+        if how[size_of::<OpenHow>()..].iter().any(|&b| b != 0) {
+            return Err(SysError::E2BIG);
+        }
+        let how = unsafe { (how.as_ptr() as *const OpenHow).read_unaligned() };
+        info!(
+            "openat2: dir_fd: {}, path: {:?}, how: {:?}",
+            dir_fd as isize, path, how
+        );
+        let flags = OpenFlags::from_bits_truncate(how.flags as usize);
+        let resolve = ResolveFlags::from_bits(how.resolve).ok_or(SysError::EINVAL)?;
+        if how.flags >> 32 != 0
+            || (how.mode != 0 && !flags.contains(OpenFlags::CREATE))
+            || how.mode & !0o7777 != 0
+            || resolve.contains(ResolveFlags::BENEATH | ResolveFlags::IN_ROOT)
+        {
+            return Err(SysError::EINVAL);
+        }
+        self.openat_impl(dir_fd, path, flags, how.mode as usize, resolve)
+    }
+
+    fn openat_impl(
+        &mut self,
+        dir_fd: usize,
+        path: String,
+        flags: OpenFlags,
+        mode: usize,
+        resolve: ResolveFlags,
+    ) -> SysResult {
+        let mut proc = self.process();
+        // only the location is opened, so the other flags are ignored
+        let flags = match flags.contains(OpenFlags::PATH) {
+            true => {
+                flags
+                    & (OpenFlags::PATH
+                        | OpenFlags::CLOEXEC
+                        | OpenFlags::DIRECTORY
+                        | OpenFlags::NOFOLLOW)
+            }
+            false => flags,
+        };
+        let follow = !flags.contains(OpenFlags::NOFOLLOW);
+
+        let (dir_path, file_name) = split_path(&path);
+        let mut dir = None;
         let inode = if flags.contains(OpenFlags::CREATE) {
-            let (dir_path, file_name) = split_path(&path);
             // relative to cwd
-            let dir_inode = proc.lookup_inode_at(dir_fd, dir_path, true)?;
-            match dir_inode.find(file_name) {
+            let dir_inode = proc.resolve_inode_at(dir_fd, dir_path, true, resolve)?;
+            let inode = match dir_inode.find(file_name) {
                 Ok(file_inode) => {
                     if flags.contains(OpenFlags::EXCLUSIVE) {
                         return Err(SysError::EEXIST);
                     }
+                    let file_inode = match file_inode.metadata()?.type_ {
+                        FileType::SymLink if !follow => return Err(SysError::ELOOP),
+                        FileType::SymLink => proc.resolve_inode_at(dir_fd, &path, true, resolve)?,
+                        _ => file_inode,
+                    };
                     if flags.contains(OpenFlags::TRUNCATE) {
-                        if let Err(e) = file_inode.resize(0) {
+                        check_writable(&file_inode)?;
+                        if let Err(e) = page_cache::resize(&file_inode, 0) {
                             // TODO: do something? what about device file?
                         }
+                        inotify::notify(&file_inode, InotifyMask::MODIFY);
+                        inotify::notify_entry(
+                            &dir_inode,
+                            file_name,
+                            &file_inode,
+                            InotifyMask::MODIFY,
+                            0,
+                        );
                     }
                     file_inode
                 }
                 Err(FsError::EntryNotFound) => {
-                    let inode = dir_inode.create(file_name, FileType::File, mode as u32)?;
+                    check_writable(&dir_inode)?;
+                    let mode = mode as u32 & !proc.umask;
+                    let inode = dir_inode.create(file_name, FileType::File, mode)?;
                     TimeSpec::update(&inode);
                     TimeSpec::update(&dir_inode);
+                    inotify::notify_entry(&dir_inode, file_name, &inode, InotifyMask::CREATE, 0);
                     inode
                 }
                 Err(e) => return Err(SysError::from(e)),
-            }
+            };
+            dir = Some(dir_inode);
+            inode
         } else {
-            proc.lookup_inode_at(dir_fd, &path, true)?
+            let inode = proc.resolve_inode_at(dir_fd, &path, follow, resolve)?;
+            // the directory is only needed by watches, which are rare
+            if inotify::watching() {
+                dir = proc.resolve_inode_at(dir_fd, dir_path, true, resolve).ok();
+            }
+            inode
         };
+        let type_ = inode.metadata()?.type_;
+        // a symbolic link is only opened as a location
+        if !follow && type_ == FileType::SymLink && !flags.contains(OpenFlags::PATH) {
+            return Err(SysError::ELOOP);
+        }
+        if flags.contains(OpenFlags::DIRECTORY) && type_ != FileType::Dir {
+            return Err(SysError::ENOTDIR);
+        }
+        if flags.to_options().write {
+            check_writable(&inode)?;
+        }
 
         let file = FileHandle::new(
             inode,
             flags.to_options(),
-            String::from(path),
+            path.clone(),
             false,
             flags.contains(OpenFlags::CLOEXEC),
         );
+        if let Some(dir) = dir {
+            file.set_parent(dir, file_name);
+        }
 
         // for debugging
         if cfg!(debug_assertions) {
@@ -645,7 +779,11 @@ impl Syscall<'_> {
             debug!("files before close {:#?}", proc.files);
         }
 
-        proc.files.remove(&fd).ok_or(SysError::EBADF)?;
+        let file_like = proc.files.remove(&fd).ok_or(SysError::EBADF)?;
+        // POSIX record locks are released on closing any descriptor of the file
+        if let FileLike::File(file) = &file_like {
+            lock::release(&file.inode(), LockOwner::Process(proc.pid.get()));
+        }
         Ok(0)
     }
 
@@ -660,7 +798,6 @@ impl Syscall<'_> {
         mode: usize,
         flags: usize,
     ) -> SysResult {
-        // TODO: check permissions based on uid/gid
         let proc = self.process();
         let path = check_and_clone_cstr(path)?;
         let flags = AtFlags::from_bits_truncate(flags);
@@ -671,8 +808,134 @@ impl Syscall<'_> {
                 dirfd as isize, path, mode, flags
             );
         }
-        let _inode =
+        let inode =
+            proc.lookup_inode_at(dirfd, &path, !flags.contains(AtFlags::SYMLINK_NOFOLLOW))?;
+        // processes run as root, which may read and write anything,
+        // but only execute files with an execute bit
+        if mode & W_OK != 0 {
+            check_writable(&inode)?;
+        }
+        if mode & X_OK != 0 {
+            let metadata = inode.metadata()?;
+            if metadata.type_ != FileType::Dir && metadata.mode & 0o111 == 0 {
+                return Err(SysError::EACCES);
+            }
+        }
+        Ok(0)
+    }
+
+    pub fn sys_chmod(&mut self, path: *const u8, mode: usize) -> SysResult {
+        self.sys_fchmodat(AT_FDCWD, path, mode)
+    }
+
+    pub fn sys_fchmod(&mut self, fd: usize, mode: usize) -> SysResult {
+        info!("fchmod: fd: {}, mode: {:#o}", fd, mode);
+        let mut proc = self.process();
+        let file = proc.get_file(fd)?;
+        set_mode(&file.inode(), mode)?;
+        file.notify(InotifyMask::ATTRIB);
+        Ok(0)
+    }
+
+    pub fn sys_fchmodat(&mut self, dirfd: usize, path: *const u8, mode: usize) -> SysResult {
+        let proc = self.process();
+        let path = check_and_clone_cstr(path)?;
+        info!(
+            "fchmodat: dirfd: {}, path: {:?}, mode: {:#o}",
+            dirfd as isize, path, mode
+        );
+        let inode = proc.lookup_inode_at(dirfd, &path, true)?;
+        set_mode(&inode, mode)?;
+        proc.notify_at(dirfd, &path, &inode, InotifyMask::ATTRIB);
+        Ok(0)
+    }
+
+    pub fn sys_chown(&mut self, path: *const u8, uid: usize, gid: usize) -> SysResult {
+        self.sys_fchownat(AT_FDCWD, path, uid, gid, 0)
+    }
+
+    pub fn sys_fchown(&mut self, fd: usize, uid: usize, gid: usize) -> SysResult {
+        info!(
+            "fchown: fd: {}, uid: {}, gid: {}",
+            fd, uid as i32, gid as i32
+        );
+        let mut proc = self.process();
+        let file = proc.get_file(fd)?;
+        set_owner(&file.inode(), uid, gid)?;
+        file.notify(InotifyMask::ATTRIB);
+        Ok(0)
+    }
+
+    pub fn sys_fchownat(
+        &mut self,
+        dirfd: usize,
+        path: *const u8,
+        uid: usize,
+        gid: usize,
+        flags: usize,
+    ) -> SysResult {
+        let mut proc = self.process();
+        let path = check_and_clone_cstr(path)?;
+        let flags = AtFlags::from_bits_truncate(flags);
+        info!(
+            "fchownat: dirfd: {}, path: {:?}, uid: {}, gid: {}, flags: {:?}",
+            dirfd as isize, path, uid as i32, gid as i32, flags
+        );
+        if path.is_empty() && flags.contains(AtFlags::EMPTY_PATH) {
+            let file = proc.get_file(dirfd)?;
+            set_owner(&file.inode(), uid, gid)?;
+            file.notify(InotifyMask::ATTRIB);
+            return Ok(0);
+        }
+        let inode =
             proc.lookup_inode_at(dirfd, &path, !flags.contains(AtFlags::SYMLINK_NOFOLLOW))?;
+        set_owner(&inode, uid, gid)?;
+        proc.notify_at(dirfd, &path, &inode, InotifyMask::ATTRIB);
+        Ok(0)
+    }
+
+    pub fn sys_umask(&mut self, mask: usize) -> SysResult {
+        let mut proc = self.process();
+        info!("umask: mask: {:#o}", mask);
+        let old = proc.umask;
+        proc.umask = mask as u32 & 0o777;
+        Ok(old as usize)
+    }
+
+    pub fn sys_mknod(&mut self, path: *const u8, mode: usize, dev: usize) -> SysResult {
+        self.sys_mknodat(AT_FDCWD, path, mode, dev)
+    }
+
+    pub fn sys_mknodat(
+        &mut self,
+        dirfd: usize,
+        path: *const u8,
+        mode: usize,
+        dev: usize,
+    ) -> SysResult {
+        let proc = self.process();
+        let path = check_and_clone_cstr(path)?;
+        info!(
+            "mknodat: dirfd: {}, path: {:?}, mode: {:#o}, dev: {:#x}",
+            dirfd as isize, path, mode, dev
+        );
+        let type_ = StatMode::from_bits_truncate(mode as u32) & StatMode::TYPE_MASK;
+        // only regular files, device nodes are in DevFS
+        if type_ != StatMode::NULL && type_ != StatMode::FILE {
+            return Err(SysError::EPERM);
+        }
+
+        let (dir_path, file_name) = split_path(&path);
+        let dir_inode = proc.lookup_inode_at(dirfd, dir_path, true)?;
+        if dir_inode.find(file_name).is_ok() {
+            return Err(SysError::EEXIST);
+        }
+        check_writable(&dir_inode)?;
+        let mode = mode as u32 & 0o7777 & !proc.umask;
+        let inode = dir_inode.create(file_name, FileType::File, mode)?;
+        TimeSpec::update(&inode);
+        TimeSpec::update(&dir_inode);
+        inotify::notify_entry(&dir_inode, file_name, &inode, InotifyMask::CREATE, 0);
         Ok(0)
     }
 
@@ -682,7 +945,7 @@ impl Syscall<'_> {
             // we trust pid 0 process
             info!("getcwd: buf: {:?}, len: {:#x}", buf, len);
         }
-        let buf = unsafe { self.vm().check_write_array(buf, len)? };
+        let buf = unsafe { check_write_array(&self.vm(), buf, len)? };
         if proc.cwd.len() + 1 > len {
             return Err(SysError::ERANGE);
         }
@@ -721,13 +984,36 @@ impl Syscall<'_> {
             dirfd as isize, path, stat_ptr, flags
         );
 
-        let inode =
-            proc.lookup_inode_at(dirfd, &path, !flags.contains(AtFlags::SYMLINK_NOFOLLOW))?;
+        let inode = if path.is_empty() && flags.contains(AtFlags::EMPTY_PATH) {
+            proc.get_file_const(dirfd)?.inode()
+        } else {
+            proc.lookup_inode_at(dirfd, &path, !flags.contains(AtFlags::SYMLINK_NOFOLLOW))?
+        };
         let stat = Stat::from(inode.metadata()?);
         *stat_ref = stat;
         Ok(0)
     }
 
+    pub fn sys_statfs(&mut self, path: *const u8, buf: *mut StatFs) -> SysResult {
+        let proc = self.process();
+        let path = check_and_clone_cstr(path)?;
+        let buf = unsafe { self.vm().check_write_ptr(buf)? };
+        info!("statfs: path: {:?}, buf: {:?}", path, buf as *const StatFs);
+
+        let inode = proc.lookup_inode(&path)?;
+        *buf = StatFs::of(&inode)?;
+        Ok(0)
+    }
+
+    pub fn sys_fstatfs(&mut self, fd: usize, buf: *mut StatFs) -> SysResult {
+        info!("fstatfs: fd: {}, buf: {:?}", fd, buf);
+        let mut proc = self.process();
+        let buf = unsafe { self.vm().check_write_ptr(buf)? };
+        let inode = proc.get_file(fd)?.inode();
+        *buf = StatFs::of(&inode)?;
+        Ok(0)
+    }
+
     pub fn sys_stat(&mut self, path: *const u8, stat_ptr: *mut Stat) -> SysResult {
         self.sys_fstatat(AT_FDCWD, path, stat_ptr, 0)
     }
@@ -745,7 +1031,7 @@ impl Syscall<'_> {
     ) -> SysResult {
         let proc = self.process();
         let path = check_and_clone_cstr(path)?;
-        let slice = unsafe { self.vm().check_write_array(base, len)? };
+        let slice = unsafe { check_write_array(&self.vm(), base, len)? };
         info!(
             "readlinkat: dirfd: {}, path: {:?}, base: {:?}, len: {}",
             dirfd as isize, path, base, len
@@ -753,7 +1039,6 @@ impl Syscall<'_> {
 
         let inode = proc.lookup_inode_at(dirfd, &path, false)?;
         if inode.metadata()?.type_ == FileType::SymLink {
-            // TODO: recursive link resolution and loop detection
             let len = inode.read_at(0, slice)?;
             Ok(len)
         } else {
@@ -786,7 +1071,7 @@ impl Syscall<'_> {
         Ok(0)
     }
 
-    pub fn sys_flock(&mut self, fd: usize, operation: usize) -> SysResult {
+    pub async fn sys_flock(&mut self, fd: usize, operation: usize) -> SysResult {
         bitflags! {
             struct Operation: u8 {
                 const LOCK_SH = 1;
@@ -795,12 +1080,78 @@ impl Syscall<'_> {
                 const LOCK_UN = 8;
             }
         }
-        let operation = Operation::from_bits(operation as u8).unwrap();
+        let operation = Operation::from_bits(operation as u8).ok_or(SysError::EINVAL)?;
         info!("flock: fd: {}, operation: {:?}", fd, operation);
-        let mut proc = self.process();
-        // let file_like = proc.get_file_like(fd)?;
-        let file = proc.get_file(fd)?;
-        Ok(0)
+        let type_ = match operation - Operation::LOCK_NB {
+            Operation::LOCK_SH => Some(LockType::Shared),
+            Operation::LOCK_EX => Some(LockType::Exclusive),
+            Operation::LOCK_UN => None,
+            _ => return Err(SysError::EINVAL),
+        };
+        let (inode, owner) = {
+            let mut proc = self.process();
+            let file = proc.get_file(fd)?;
+            (file.inode(), file.lock_owner())
+        };
+        if operation.contains(Operation::LOCK_NB) || type_.is_none() {
+            lock::flock(&inode, owner, type_, None)?;
+            return Ok(0);
+        }
+        self.wait_lock(owner, |waker| {
+            lock::flock(&inode, owner, type_, Some(waker))
+        })
+        .await
+    }
+
+    /// Take a lock with `try_lock`, given the waker to wake when a lock of the file
+    /// is released, and retried until it no longer fails with `EAGAIN`
+    async fn wait_lock<F>(&self, owner: LockOwner, try_lock: F) -> SysResult
+    where
+        F: FnMut(&Waker) -> Result<(), SysError> + Unpin,
+    {
+        #[must_use = "future does nothing unless polled/`await`-ed"]
+        struct LockFuture<F> {
+            try_lock: F,
+            thread: Arc<Thread>,
+            eventbus: Arc<Mutex<EventBus>>,
+        }
+
+        impl<F> Future for LockFuture<F>
+        where
+            F: FnMut(&Waker) -> Result<(), SysError> + Unpin,
+        {
+            type Output = Result<(), SysError>;
+
+            fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                if self.thread.has_signal_to_handle() {
+                    return Poll::Ready(Err(EINTR));
+                }
+                match (self.try_lock)(cx.waker()) {
+                    Err(SysError::EAGAIN) => {}
+                    result => return Poll::Ready(result),
+                }
+                // wake up on a signal too
+                let waker = cx.waker().clone();
+                self.eventbus.lock().subscribe(Box::new({
+                    move |_| {
+                        waker.wake_by_ref();
+                        true
+                    }
+                }));
+                Poll::Pending
+            }
+        }
+
+        let future = LockFuture {
+            try_lock,
+            thread: self.thread.clone(),
+            eventbus: self.thread.proc.lock().eventbus.clone(),
+        };
+        let result = future.await;
+        if result.is_err() {
+            lock::cancel(owner);
+        }
+        result.map(|_| 0)
     }
 
     pub fn sys_fdatasync(&mut self, fd: usize) -> SysResult {
@@ -813,7 +1164,10 @@ impl Syscall<'_> {
         let proc = self.process();
         let path = check_and_clone_cstr(path)?;
         info!("truncate: path: {:?}, len: {}", path, len);
-        proc.lookup_inode(&path)?.resize(len)?;
+        let inode = proc.lookup_inode(&path)?;
+        check_writable(&inode)?;
+        page_cache::resize(&inode, len)?;
+        proc.notify_at(AT_FDCWD, &path, &inode, InotifyMask::MODIFY);
         Ok(0)
     }
 
@@ -834,7 +1188,7 @@ impl Syscall<'_> {
             fd, buf, buf_size
         );
         let mut proc = self.process();
-        let buf = unsafe { self.vm().check_write_array(buf as *mut u8, buf_size)? };
+        let buf = unsafe { check_write_array(&self.vm(), buf as *mut u8, buf_size)? };
         let file = proc.get_file(fd)?;
         let info = file.metadata()?;
         if info.type_ != FileType::Dir {
@@ -893,15 +1247,15 @@ impl Syscall<'_> {
         );
         use crate::fs::ioctl::*;
         match request {
-            FIOCLEX => self.sys_fcntl(fd, F_SETFD, FD_CLOEXEC),
-            FIONCLEX => self.sys_fcntl(fd, F_SETFD, 0),
+            FIOCLEX => self.fcntl_impl(fd, F_SETFD, FD_CLOEXEC),
+            FIONCLEX => self.fcntl_impl(fd, F_SETFD, 0),
             FIONBIO => {
                 let data = arg1 as *const i32;
                 let val = unsafe { *data };
                 if val == 0 {
-                    self.sys_fcntl(fd, F_SETFD, 0)
+                    self.fcntl_impl(fd, F_SETFD, 0)
                 } else {
-                    self.sys_fcntl(fd, F_SETFD, O_NONBLOCK)
+                    self.fcntl_impl(fd, F_SETFD, O_NONBLOCK)
                 }
             }
             _ => {
@@ -928,29 +1282,7 @@ impl Syscall<'_> {
 
         // BUGFIX: '..' and '.'
         if path.len() > 0 {
-            let cwd = match path.as_bytes()[0] {
-                b'/' => String::from("/"),
-                _ => proc.cwd.clone(),
-            };
-            let mut cwd_vec: Vec<_> = cwd.split("/").filter(|&x| x != "").collect();
-            let path_split = path.split("/").filter(|&x| x != "");
-            for seg in path_split {
-                if seg == ".." {
-                    cwd_vec.pop();
-                } else if seg == "." {
-                    // nothing to do here.
-                } else {
-                    cwd_vec.push(seg);
-                }
-            }
-            proc.cwd = String::from("");
-            for seg in cwd_vec {
-                proc.cwd.push_str("/");
-                proc.cwd.push_str(seg);
-            }
-            if proc.cwd == "" {
-                proc.cwd = String::from("/");
-            }
+            proc.cwd = absolute_path(&proc.cwd, &path);
         }
         Ok(0)
     }
@@ -976,9 +1308,35 @@ impl Syscall<'_> {
 
         let (old_dir_path, old_file_name) = split_path(&oldpath);
         let (new_dir_path, new_file_name) = split_path(&newpath);
-        let old_dir_inode = proc.lookup_inode_at(olddirfd, old_dir_path, false)?;
-        let new_dir_inode = proc.lookup_inode_at(newdirfd, new_dir_path, false)?;
+        let old_dir_inode = proc.lookup_inode_at(olddirfd, old_dir_path, true)?;
+        let new_dir_inode = proc.lookup_inode_at(newdirfd, new_dir_path, true)?;
+        check_writable(&old_dir_inode)?;
+        check_writable(&new_dir_inode)?;
+        let inode = old_dir_inode.find(old_file_name)?;
+        let replaced = new_dir_inode.find(new_file_name).ok();
         old_dir_inode.move_(old_file_name, &new_dir_inode, new_file_name)?;
+
+        let cookie = inotify::next_cookie();
+        inotify::notify_entry(
+            &old_dir_inode,
+            old_file_name,
+            &inode,
+            InotifyMask::MOVED_FROM,
+            cookie,
+        );
+        inotify::notify_entry(
+            &new_dir_inode,
+            new_file_name,
+            &inode,
+            InotifyMask::MOVED_TO,
+            cookie,
+        );
+        inotify::notify(&inode, InotifyMask::MOVE_SELF);
+        if let Some(replaced) = replaced {
+            inotify::notify_unlinked(&replaced);
+            // kept until closed if still open, the inode number may be reused after
+            page_cache::forget(&replaced);
+        }
         Ok(0)
     }
 
@@ -1000,9 +1358,12 @@ impl Syscall<'_> {
         if dir_inode.find(file_name).is_ok() {
             return Err(SysError::EEXIST);
         }
-        let inode = dir_inode.create(file_name, FileType::Dir, mode as u32)?;
+        check_writable(&dir_inode)?;
+        let mode = mode as u32 & !proc.umask;
+        let inode = dir_inode.create(file_name, FileType::Dir, mode)?;
         TimeSpec::update(&inode);
         TimeSpec::update(&dir_inode);
+        inotify::notify_entry(&dir_inode, file_name, &inode, InotifyMask::CREATE, 0);
         Ok(0)
     }
 
@@ -1017,7 +1378,10 @@ impl Syscall<'_> {
         if file_inode.metadata()?.type_ != FileType::Dir {
             return Err(SysError::ENOTDIR);
         }
+        check_writable(&dir_inode)?;
         dir_inode.unlink(file_name)?;
+        inotify::notify_entry(&dir_inode, file_name, &file_inode, InotifyMask::DELETE, 0);
+        inotify::notify_unlinked(&file_inode);
         Ok(0)
     }
 
@@ -1043,9 +1407,19 @@ impl Syscall<'_> {
         );
 
         let (new_dir_path, new_file_name) = split_path(&newpath);
-        let inode = proc.lookup_inode_at(olddirfd, &oldpath, true)?;
+        let follow = flags.contains(AtFlags::SYMLINK_FOLLOW);
+        let inode = proc.lookup_inode_at(olddirfd, &oldpath, follow)?;
         let new_dir_inode = proc.lookup_inode_at(newdirfd, new_dir_path, true)?;
+        check_writable(&new_dir_inode)?;
         new_dir_inode.link(new_file_name, &inode)?;
+        inotify::notify_entry(
+            &new_dir_inode,
+            new_file_name,
+            &inode,
+            InotifyMask::CREATE,
+            0,
+        );
+        inotify::notify(&inode, InotifyMask::ATTRIB);
         Ok(0)
     }
 
@@ -1078,10 +1452,12 @@ impl Syscall<'_> {
             Ok(_) => Err(SysError::EEXIST),
             Err(e) => match e {
                 FsError::EntryNotFound => {
+                    check_writable(&dir_inode)?;
                     let symlink = dir_inode.create(filename, FileType::SymLink, 0o777)?;
                     symlink.write_at(0, target.as_bytes())?;
                     TimeSpec::update(&symlink);
                     TimeSpec::update(&dir_inode);
+                    inotify::notify_entry(&dir_inode, filename, &symlink, InotifyMask::CREATE, 0);
                     Ok(0)
                 }
                 _ => Err(e.into()),
@@ -1104,7 +1480,12 @@ impl Syscall<'_> {
         if file_inode.metadata()?.type_ == FileType::Dir {
             return Err(SysError::EISDIR);
         }
+        check_writable(&dir_inode)?;
         dir_inode.unlink(file_name)?;
+        inotify::notify_entry(&dir_inode, file_name, &file_inode, InotifyMask::DELETE, 0);
+        inotify::notify_unlinked(&file_inode);
+        // kept until closed if still open, the inode number may be reused after
+        page_cache::forget(&file_inode);
         Ok(0)
     }
 
@@ -1116,7 +1497,7 @@ impl Syscall<'_> {
         info!("pipe2: fds: {:?}, flags: {:#x}", fds, flags);
 
         let mut proc = self.process();
-        let fds = unsafe { self.vm().check_write_array(fds, 2)? };
+        let fds = unsafe { check_write_array(&self.vm(), fds, 2)? };
         let (read, write) = Pipe::create_pair();
 
         let read_fd = proc.add_file(FileLike::File(FileHandle::new(
@@ -1171,9 +1552,10 @@ impl Syscall<'_> {
             let epoch = TimeSpec::get_epoch();
             [epoch, epoch]
         } else {
-            let times = unsafe { self.vm().check_read_array(times, 2)? };
+            let times = unsafe { check_read_array(&self.vm(), times, 2)? };
             [times[0], times[1]]
         };
+        let mut path = None;
         let mut inode = if pathname.is_null() {
             let fd = dirfd;
             info!("futimens: fd: {}, times: {:?}", fd, times);
@@ -1189,8 +1571,11 @@ impl Syscall<'_> {
                 fcntl::AT_SYMLINK_NOFOLLOW => false,
                 _ => return Err(EINVAL),
             };
-            proc.lookup_inode_at(dirfd, &pathname, follow)?
+            let inode = proc.lookup_inode_at(dirfd, &pathname, follow)?;
+            path = Some(pathname);
+            inode
         };
+        check_writable(&inode)?;
         let mut metadata = inode.metadata()?;
         if times[0].nsec != UTIME_OMIT {
             if times[0].nsec == UTIME_NOW {
@@ -1211,11 +1596,97 @@ impl Syscall<'_> {
             };
         }
         inode.set_metadata(&metadata)?;
+        match path {
+            Some(path) => proc.notify_at(dirfd, &path, &inode, InotifyMask::ATTRIB),
+            None => proc.get_file(dirfd)?.notify(InotifyMask::ATTRIB),
+        }
         Ok(0)
     }
 
     pub fn sys_sync(&mut self) -> SysResult {
-        ROOT_INODE.fs().sync()?;
+        page_cache::sync_all()?;
+        root_inode().fs().sync()?;
+        Ok(0)
+    }
+
+    pub fn sys_mount(
+        &mut self,
+        source: *const u8,
+        target: *const u8,
+        fstype: *const u8,
+        flags: usize,
+        data: *const u8,
+    ) -> SysResult {
+        let proc = self.process();
+        let source = check_and_clone_cstr(source)?;
+        let target = check_and_clone_cstr(target)?;
+        let fstype = check_and_clone_cstr(fstype)?;
+        let flags = MountFlags::from_bits_truncate(flags);
+        let data = check_and_clone_cstr(data)?;
+        info!(
+            "mount: source: {:?}, target: {:?}, fstype: {:?}, flags: {:?}, data: {:?}",
+            source, target, fstype, flags, data
+        );
+
+        let target_inode = proc.lookup_inode(&target)?;
+        let readonly = flags.contains(MountFlags::RDONLY);
+        if flags.contains(MountFlags::REMOUNT) {
+            mount::remount(&target_inode, readonly)?;
+            return Ok(0);
+        }
+        let target = absolute_path(&proc.cwd, &target);
+        if flags.contains(MountFlags::BIND) {
+            let source_inode = proc.lookup_inode(&source)?;
+            mount::bind(&target_inode, &target, &source_inode, &source, readonly)?;
+        } else {
+            let fs = mount::create_fs(&fstype, &source, &data).ok_or(SysError::ENODEV)??;
+            mount::mount(&target_inode, &target, &source, &fstype, fs, readonly)?;
+        }
+        Ok(0)
+    }
+
+    pub fn sys_umount2(&mut self, target: *const u8, flags: usize) -> SysResult {
+        let target = check_and_clone_cstr(target)?;
+        let flags = UmountFlags::from_bits_truncate(flags);
+        info!("umount2: target: {:?}, flags: {:?}", target, flags);
+
+        let follow = !flags.contains(UmountFlags::NOFOLLOW);
+        let target_inode = self.process().lookup_inode_at(AT_FDCWD, &target, follow)?;
+        // taken without holding the lock of this process
+        let (open_files, cwds) = files_in_use();
+        mount::umount(
+            target_inode,
+            flags.contains(UmountFlags::DETACH),
+            &open_files,
+            &cwds,
+        )?;
+        Ok(0)
+    }
+
+    /// Current directories of all processes are moved to where they are in the new tree.
+    /// Busy while a process has a directory open.
+    pub fn sys_pivot_root(&mut self, new_root: *const u8, put_old: *const u8) -> SysResult {
+        let new_root = check_and_clone_cstr(new_root)?;
+        let put_old = check_and_clone_cstr(put_old)?;
+        info!(
+            "pivot_root: new_root: {:?}, put_old: {:?}",
+            new_root, put_old
+        );
+
+        let (new_root_inode, put_old) = {
+            let proc = self.process();
+            let new_root_inode = proc.lookup_inode(&new_root)?;
+            if proc.lookup_inode(&put_old)?.metadata()?.type_ != FileType::Dir {
+                return Err(SysError::ENOTDIR);
+            }
+            (new_root_inode, absolute_path(&proc.cwd, &put_old))
+        };
+        let (open_files, _) = files_in_use();
+        let pivot = pivot_root(&new_root_inode, &put_old, &open_files)?;
+        for proc in PROCESSES.read().values() {
+            let mut proc = proc.lock();
+            proc.cwd = pivot.path(&proc.cwd);
+        }
         Ok(0)
     }
 
@@ -1314,7 +1785,106 @@ impl Syscall<'_> {
         return Ok(total_written);
     }
 
-    pub fn sys_fcntl(&mut self, fd: usize, cmd: usize, arg: usize) -> SysResult {
+    pub async fn sys_fcntl(&mut self, fd: usize, cmd: usize, arg: usize) -> SysResult {
+        use crate::fs::fcntl::*;
+        match cmd {
+            F_GETLK | F_SETLK | F_SETLKW | F_OFD_GETLK | F_OFD_SETLK | F_OFD_SETLKW => {
+                self.fcntl_lock(fd, cmd, UserInOutPtr::from(arg)).await
+            }
+            _ => self.fcntl_impl(fd, cmd, arg),
+        }
+    }
+
+    /// Test, take or release a record lock with `fcntl`
+    async fn fcntl_lock(
+        &mut self,
+        fd: usize,
+        cmd: usize,
+        mut arg: UserInOutPtr<Flock>,
+    ) -> SysResult {
+        use crate::fs::fcntl::*;
+        let mut flock = arg.read()?;
+        info!("fcntl: fd: {}, cmd: {}, lock: {:?}", fd, cmd, flock);
+        let ofd = match cmd {
+            F_OFD_GETLK | F_OFD_SETLK | F_OFD_SETLKW => true,
+            _ => false,
+        };
+        if ofd && flock.pid != 0 {
+            return Err(SysError::EINVAL);
+        }
+        let type_ = match flock.type_ as usize {
+            F_RDLCK => Some(LockType::Shared),
+            F_WRLCK => Some(LockType::Exclusive),
+            F_UNLCK => None,
+            _ => return Err(SysError::EINVAL),
+        };
+        let (inode, owner, start, end) = {
+            let mut proc = self.process();
+            let pid = proc.pid.get();
+            let file = proc.get_file(fd)?;
+            let owner = match ofd {
+                true => file.lock_owner(),
+                false => LockOwner::Process(pid),
+            };
+            let options = file.options();
+            if cmd != F_GETLK && cmd != F_OFD_GETLK {
+                match type_ {
+                    Some(LockType::Shared) if !options.read => return Err(SysError::EBADF),
+                    Some(LockType::Exclusive) if !options.write => return Err(SysError::EBADF),
+                    _ => {}
+                }
+            }
+            let base = match flock.whence {
+                0 => 0,
+                1 => file.seek(SeekFrom::Current(0))? as i64,
+                2 => file.metadata()?.size as i64,
+                _ => return Err(SysError::EINVAL),
+            };
+            let (start, end) = flock.range(base)?;
+            (file.inode(), owner, start, end)
+        };
+
+        if cmd == F_GETLK || cmd == F_OFD_GETLK {
+            let type_ = type_.ok_or(SysError::EINVAL)?;
+            let request = FileLock {
+                owner,
+                type_,
+                start,
+                end,
+            };
+            flock = match lock::test(&inode, &request)? {
+                Some(other) => Flock::from(&other),
+                None => Flock {
+                    type_: F_UNLCK as i16,
+                    ..flock
+                },
+            };
+            arg.write(flock)?;
+            return Ok(0);
+        }
+        let type_ = match type_ {
+            Some(type_) => type_,
+            None => {
+                lock::unlock(&inode, owner, start, end)?;
+                return Ok(0);
+            }
+        };
+        let request = FileLock {
+            owner,
+            type_,
+            start,
+            end,
+        };
+        if cmd == F_SETLK || cmd == F_OFD_SETLK {
+            lock::lock(&inode, request, None)?;
+            return Ok(0);
+        }
+        self.wait_lock(owner, |waker| lock::lock(&inode, request, Some(waker)))
+            .await
+    }
+
+    /// `fcntl` for commands other than record locks
+    pub fn fcntl_impl(&mut self, fd: usize, cmd: usize, arg: usize) -> SysResult {
         info!("fcntl: fd: {}, cmd: {:#x}, arg: {}", fd, cmd, arg);
         let mut proc = self.process();
         let file_like = proc.get_file_like(fd)?;
@@ -1347,6 +1917,21 @@ impl Syscall<'_> {
                 //TODO
             }
             FileLike::EpollInstance(_) => Ok(0),
+            FileLike::Inotify(inotify) => {
+                use crate::fs::fcntl::*;
+                match cmd {
+                    F_SETFD => {
+                        inotify.fd_cloexec = (arg & 1) != 0;
+                        Ok(0)
+                    }
+                    F_GETFD => Ok(inotify.fd_cloexec as usize),
+                    F_SETFL => {
+                        inotify.nonblock = (arg & O_NONBLOCK) != 0;
+                        Ok(0)
+                    }
+                    _ => Ok(0),
+                }
+            }
         }
     }
 }
@@ -1383,16 +1968,59 @@ impl Process {
         dirfd: usize,
         path: &str,
         follow: bool,
+    ) -> Result<Arc<dyn INode>, SysError> {
+        self.resolve_inode_at(dirfd, path, follow, ResolveFlags::empty())
+    }
+
+    /// Lookup INode like `lookup_inode_at`, with the restrictions of `resolve`
+    pub fn resolve_inode_at(
+        &self,
+        dirfd: usize,
+        path: &str,
+        follow: bool,
+        resolve: ResolveFlags,
     ) -> Result<Arc<dyn INode>, SysError> {
         debug!(
             "lookup_inode_at: dirfd: {:?}, cwd: {:?}, path: {:?}, follow: {:?}",
             dirfd as isize, self.cwd, path, follow
         );
+        if resolve.contains(ResolveFlags::NO_MAGICLINKS)
+            && (path == "/proc/self/exe" || path.starts_with("/proc/self/fd/"))
+        {
+            return Err(SysError::ELOOP);
+        }
         // hard code special path
         match path {
             "/proc/self/exe" => {
                 return Ok(Arc::new(Pseudo::new(&self.exec_path, FileType::SymLink)));
             }
+            "/proc/meminfo" => {
+                return Ok(Arc::new(Pseudo::new(
+                    &crate::memory::meminfo(),
+                    FileType::File,
+                )));
+            }
+            "/proc/slabinfo" => {
+                return Ok(Arc::new(Pseudo::new(
+                    &crate::slab::slabinfo(),
+                    FileType::File,
+                )));
+            }
+            "/proc/buddyinfo" => {
+                return Ok(Arc::new(Pseudo::new(
+                    &crate::memory::buddyinfo(),
+                    FileType::File,
+                )));
+            }
+            "/proc/mounts" | "/proc/self/mounts" => {
+                return Ok(Arc::new(Pseudo::new(&mount::mounts(), FileType::File)));
+            }
+            "/proc/self/status" => {
+                return Ok(Arc::new(Pseudo::new(&self.status(), FileType::File)));
+            }
+            "/proc/self/smaps" => {
+                return Ok(Arc::new(Pseudo::new(&self.smaps(), FileType::File)));
+            }
             _ => {}
         }
         let (fd_dir_path, fd_name) = split_path(&path);
@@ -1405,23 +2033,93 @@ impl Process {
             _ => {}
         }
 
-        let follow_max_depth = if follow { FOLLOW_MAX_DEPTH } else { 0 };
-        if dirfd == AT_FDCWD {
-            Ok(ROOT_INODE
-                .lookup(&self.cwd)?
-                .lookup_follow(path, follow_max_depth)?)
+        let start = if dirfd == AT_FDCWD {
+            crate::fs::path::lookup(&root_inode(), &self.cwd)?
         } else {
-            let file = match self.files.get(&dirfd).ok_or(SysError::EBADF)? {
-                FileLike::File(file) => file,
+            match self.files.get(&dirfd).ok_or(SysError::EBADF)? {
+                FileLike::File(file) => file.inode(),
                 _ => return Err(SysError::EBADF),
-            };
-            Ok(file.lookup_follow(path, follow_max_depth)?)
-        }
+            }
+        };
+        Ok(crate::fs::path::resolve(&start, path, follow, resolve)?)
     }
 
     pub fn lookup_inode(&self, path: &str) -> Result<Arc<dyn INode>, SysError> {
         self.lookup_inode_at(AT_FDCWD, path, true)
     }
+
+    /// Report `mask` happened to `inode` found at `path`, to the watches of it and its directory
+    fn notify_at(&self, dirfd: usize, path: &str, inode: &Arc<dyn INode>, mask: InotifyMask) {
+        if !inotify::watching() {
+            return;
+        }
+        inotify::notify(inode, mask);
+        let (dir_path, file_name) = split_path(path);
+        if let Ok(dir_inode) = self.lookup_inode_at(dirfd, dir_path, true) {
+            inotify::notify_entry(&dir_inode, file_name, inode, mask, 0);
+        }
+    }
+}
+
+/// Fail with EROFS if `inode` is on a read-only mount
+fn check_writable(inode: &Arc<dyn INode>) -> Result<(), SysError> {
+    if mount::read_only(inode) {
+        return Err(SysError::EROFS);
+    }
+    Ok(())
+}
+
+/// Change the permission bits of `inode`
+fn set_mode(inode: &Arc<dyn INode>, mode: usize) -> Result<(), SysError> {
+    check_writable(inode)?;
+    let mut metadata = inode.metadata()?;
+    metadata.mode = (mode & 0o7777) as u16;
+    metadata.ctime = TimeSpec::get_epoch().into();
+    inode.set_metadata(&metadata)?;
+    Ok(())
+}
+
+/// Change the owner of `inode`, an id of -1 is left unchanged
+fn set_owner(inode: &Arc<dyn INode>, uid: usize, gid: usize) -> Result<(), SysError> {
+    check_writable(inode)?;
+    let mut metadata = inode.metadata()?;
+    if uid as u32 != u32::MAX {
+        metadata.uid = uid as u32 as usize;
+    }
+    if gid as u32 != u32::MAX {
+        metadata.gid = gid as u32 as usize;
+    }
+    metadata.ctime = TimeSpec::get_epoch().into();
+    inode.set_metadata(&metadata)?;
+    Ok(())
+}
+
+/// Resolve `.` and `..` in `path` relative to the absolute path `cwd`
+fn absolute_path(cwd: &str, path: &str) -> String {
+    let cwd = match path.as_bytes().first() {
+        Some(b'/') => "/",
+        _ => cwd,
+    };
+    let mut cwd_vec: Vec<_> = cwd.split("/").filter(|&x| x != "").collect();
+    let path_split = path.split("/").filter(|&x| x != "");
+    for seg in path_split {
+        if seg == ".." {
+            cwd_vec.pop();
+        } else if seg == "." {
+            // nothing to do here.
+        } else {
+            cwd_vec.push(seg);
+        }
+    }
+    let mut path = String::new();
+    for seg in cwd_vec {
+        path.push_str("/");
+        path.push_str(seg);
+    }
+    if path == "" {
+        path = String::from("/");
+    }
+    path
 }
 
 /// Split a `path` str to `(base_path, file_name)`
@@ -1465,6 +2163,34 @@ bitflags! {
     struct AtFlags: usize {
         const EMPTY_PATH = 0x1000;
         const SYMLINK_NOFOLLOW = 0x100;
+        const SYMLINK_FOLLOW = 0x400;
+    }
+}
+
+/// Test for write permission, in `access`
+const W_OK: usize = 2;
+/// Test for execute permission, in `access`
+const X_OK: usize = 1;
+
+bitflags! {
+    struct MountFlags: usize {
+        /// mount read-only
+        const RDONLY = 1;
+        /// change the flags of an existing mount
+        const REMOUNT = 32;
+        /// mount a directory at another place
+        const BIND = 4096;
+    }
+}
+
+bitflags! {
+    struct UmountFlags: usize {
+        /// abort unfinished requests, unsupported
+        const FORCE = 1;
+        /// detach from the tree even if busy
+        const DETACH = 2;
+        /// don't follow the target if it's a symbolic link
+        const NOFOLLOW = 8;
     }
 }
 
@@ -1484,11 +2210,81 @@ bitflags! {
         const TRUNCATE = 1 << 9;
         /// append on each write
         const APPEND = 1 << 10;
+        /// fail if not a directory
+        #[cfg(not(target_arch = "aarch64"))]
+        const DIRECTORY = 1 << 16;
+        #[cfg(target_arch = "aarch64")]
+        const DIRECTORY = 1 << 14;
+        /// fail if the file is a symbolic link
+        #[cfg(not(target_arch = "aarch64"))]
+        const NOFOLLOW = 1 << 17;
+        #[cfg(target_arch = "aarch64")]
+        const NOFOLLOW = 1 << 15;
         /// close on exec
         const CLOEXEC = 1 << 19;
+        /// only open the location, for fd-relative syscalls
+        const PATH = 1 << 21;
     }
 }
 
+/// A record lock in `fcntl`, the `struct flock` of C
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Flock {
+    type_: i16,
+    whence: i16,
+    start: i64,
+    len: i64,
+    pid: i32,
+}
+
+impl Flock {
+    /// The range of bytes locked, with `start` counted from `base`
+    fn range(&self, base: i64) -> Result<(u64, u64), SysError> {
+        let start = base.checked_add(self.start).ok_or(SysError::EINVAL)?;
+        let (start, end) = match self.len {
+            0 => (start, None),
+            len if len > 0 => (start, Some(start.checked_add(len).ok_or(SysError::EINVAL)?)),
+            len => (start.checked_add(len).ok_or(SysError::EINVAL)?, Some(start)),
+        };
+        if start < 0 {
+            return Err(SysError::EINVAL);
+        }
+        Ok((start as u64, end.map_or(u64::max_value(), |end| end as u64)))
+    }
+}
+
+impl From<&FileLock> for Flock {
+    fn from(lock: &FileLock) -> Self {
+        use crate::fs::fcntl::{F_RDLCK, F_WRLCK};
+        Flock {
+            type_: match lock.type_ {
+                LockType::Shared => F_RDLCK as i16,
+                LockType::Exclusive => F_WRLCK as i16,
+            },
+            whence: 0,
+            start: lock.start as i64,
+            len: match lock.end {
+                end if end == u64::max_value() => 0,
+                end => (end - lock.start) as i64,
+            },
+            pid: match lock.owner {
+                LockOwner::Process(pid) => pid as i32,
+                LockOwner::File(_) => -1,
+            },
+        }
+    }
+}
+
+/// How to open a file with `openat2`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
 impl OpenFlags {
     fn readable(&self) -> bool {
         let b = self.bits() & 0b11;
@@ -1500,8 +2296,8 @@ impl OpenFlags {
     }
     fn to_options(&self) -> OpenOptions {
         OpenOptions {
-            read: self.readable(),
-            write: self.writable(),
+            read: self.readable() && !self.contains(OpenFlags::PATH),
+            write: self.writable() && !self.contains(OpenFlags::PATH),
             append: self.contains(OpenFlags::APPEND),
             nonblock: false,
         }
@@ -1721,6 +2517,137 @@ pub struct Stat {
     ctime: Timespec,
 }
 
+#[cfg(not(target_arch = "mips"))]
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct StatFs {
+    /// type of file system
+    type_: usize,
+    /// optimal transfer block size
+    bsize: usize,
+    /// total data blocks in file system
+    blocks: usize,
+    /// free blocks in file system
+    bfree: usize,
+    /// free blocks available to unprivileged user
+    bavail: usize,
+    /// total inodes in file system
+    files: usize,
+    /// free inodes in file system
+    ffree: usize,
+    /// file system ID
+    fsid: [i32; 2],
+    /// maximum length of file names
+    namelen: usize,
+    /// fragment size
+    frsize: usize,
+    /// mount flags of file system
+    flags: usize,
+    /// padding
+    _spare: [usize; 4],
+}
+
+#[cfg(target_arch = "mips")]
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct StatFs {
+    /// type of file system
+    type_: usize,
+    /// optimal transfer block size
+    bsize: usize,
+    /// fragment size
+    frsize: usize,
+    /// total data blocks in file system
+    blocks: usize,
+    /// free blocks in file system
+    bfree: usize,
+    /// total inodes in file system
+    files: usize,
+    /// free inodes in file system
+    ffree: usize,
+    /// free blocks available to unprivileged user
+    bavail: usize,
+    /// file system ID
+    fsid: [i32; 2],
+    /// maximum length of file names
+    namelen: usize,
+    /// mount flags of file system
+    flags: usize,
+    /// padding
+    _spare: [usize; 5],
+}
+
+/// Magic numbers of file system types, as in statfs(2)
+const SFS_MAGIC: usize = 0x2f8d_be2a;
+const EXT2_MAGIC: usize = 0xef53;
+const MSDOS_MAGIC: usize = 0x4d44;
+const OVERLAYFS_MAGIC: usize = 0x794c_7630;
+const RAMFS_MAGIC: usize = 0x8584_58f6;
+const TMPFS_MAGIC: usize = 0x0102_1994;
+const DEVFS_MAGIC: usize = 0x1373;
+const PROC_MAGIC: usize = 0x9fa0;
+const PIPEFS_MAGIC: usize = 0x5049_5045;
+
+/// The mount flags are valid
+const ST_VALID: usize = 0x20;
+/// Mounted read-only
+const ST_RDONLY: usize = 1;
+
+impl StatFs {
+    /// Describe the file system `inode` belongs to
+    fn of(inode: &Arc<dyn INode>) -> Result<Self, SysError> {
+        let mount = match mount::mount_info(inode) {
+            Some(mount) => mount,
+            None => {
+                // pseudo files and pipes aren't on a mounted file system
+                let type_ = if inode.as_any_ref().is::<Pseudo>() {
+                    PROC_MAGIC
+                } else {
+                    PIPEFS_MAGIC
+                };
+                return Ok(StatFs {
+                    type_,
+                    bsize: PAGE_SIZE,
+                    frsize: PAGE_SIZE,
+                    namelen: 255,
+                    flags: ST_VALID,
+                    ..StatFs::default()
+                });
+            }
+        };
+        let type_ = match mount.fstype.as_str() {
+            "sfs" => SFS_MAGIC,
+            "ext2" => EXT2_MAGIC,
+            "vfat" => MSDOS_MAGIC,
+            "overlay" => OVERLAYFS_MAGIC,
+            "ramfs" => RAMFS_MAGIC,
+            "tmpfs" => TMPFS_MAGIC,
+            "devfs" => DEVFS_MAGIC,
+            _ => 0,
+        };
+        let dev = inode.metadata()?.dev;
+        let info = mount.info;
+        Ok(StatFs {
+            type_,
+            bsize: info.bsize,
+            blocks: info.blocks,
+            bfree: info.bfree,
+            bavail: info.bavail,
+            files: info.files,
+            ffree: info.ffree,
+            fsid: [dev as i32, (dev as u64 >> 32) as i32],
+            namelen: info.namemax,
+            frsize: info.frsize,
+            flags: if mount.readonly {
+                ST_VALID | ST_RDONLY
+            } else {
+                ST_VALID
+            },
+            ..StatFs::default()
+        })
+    }
+}
+
 bitflags! {
     pub struct StatMode: u32 {
         const NULL  = 0;
@@ -1898,7 +2825,7 @@ impl IoVecs {
         vm: &MemorySet,
         readv: bool,
     ) -> Result<Self, SysError> {
-        let iovs = vm.check_read_array(iov_ptr, iov_count)?.to_vec();
+        let iovs = check_read_array(&vm, iov_ptr, iov_count)?.to_vec();
         let mut slices = vec![];
         slices.reserve(iovs.len());
         // check all bufs in iov
@@ -1908,9 +2835,9 @@ impl IoVecs {
                 continue;
             }
             if readv {
-                vm.check_write_array(iov.base, iov.len)?;
+                check_write_array(&vm, iov.base, iov.len)?;
             } else {
-                vm.check_read_array(iov.base, iov.len)?;
+                check_read_array(&vm, iov.base, iov.len)?;
             }
             slices.push(slice::from_raw_parts_mut(iov.base, iov.len));
         }
@@ -1998,7 +2925,7 @@ impl FdSet {
             if len > MAX_FDSET_SIZE {
                 return Err(SysError::EINVAL);
             }
-            let slice = unsafe { vm.check_write_array(addr, len)? };
+            let slice = unsafe { check_write_array(&vm, addr, len)? };
             let bitset: &'static mut BitSlice<Lsb0, u32> = slice.into();
             debug!("bitset {:?}", bitset);
 
@@ -1,4 +1,5 @@
 use crate::lkm::manager::ModuleManager;
+use crate::memory::check_read_array;
 use crate::syscall::{check_and_clone_cstr, SysResult, Syscall};
 
 impl Syscall<'_> {
@@ -9,7 +10,7 @@ impl Syscall<'_> {
         param_values: *const u8,
     ) -> SysResult {
         let _proc = self.process();
-        let modimg = unsafe { self.vm().check_read_array(module_image, len)? };
+        let modimg = unsafe { check_read_array(&self.vm(), module_image, len)? };
         let copied_param_values = check_and_clone_cstr(param_values)?;
 
         ModuleManager::with(|kmm| kmm.init_module(modimg, &copied_param_values))
@@ -1,10 +1,12 @@
 use rcore_fs::vfs::MMapArea;
-use rcore_memory::memory_set::handler::{Delay, File, Linear, Shared};
+use rcore_memory::memory_set::handler::{Delay, File, Huge, Linear, Shared};
 use rcore_memory::memory_set::MemoryAttr;
-use rcore_memory::PAGE_SIZE;
+use rcore_memory::{VMError, HUGE_PAGE_SIZE, PAGE_SIZE};
 
 use super::*;
+use crate::fs::{mount, page_cache, FileLike};
 use crate::memory::GlobalFrameAlloc;
+use crate::swap::{all_vms, INodeSwapper, SwapSpace, SWAP_CONFIG, SWAP_SPACE};
 
 impl Syscall<'_> {
     pub fn sys_mmap(
@@ -25,6 +27,18 @@ impl Syscall<'_> {
 
         let mut proc = self.process();
         let mut addr = addr;
+        let mut len = len;
+        let huge = flags.contains(MmapFlags::HUGETLB);
+        if huge {
+            if !flags.contains(MmapFlags::ANONYMOUS) || flags.contains(MmapFlags::SHARED) {
+                // no hugetlbfs, only private anonymous memory
+                return Err(SysError::EINVAL);
+            }
+            if flags.contains(MmapFlags::FIXED) && addr % HUGE_PAGE_SIZE != 0 {
+                return Err(SysError::EINVAL);
+            }
+            len = (len + HUGE_PAGE_SIZE - 1) & !(HUGE_PAGE_SIZE - 1);
+        }
         if addr == 0 {
             // although NULL can be a valid address
             // but in C, NULL is regarded as allocation failure
@@ -32,9 +46,29 @@ impl Syscall<'_> {
             addr = PAGE_SIZE;
         }
 
+        // a shared mapping writes back to the file, so it must be writable,
+        // checked before a fixed mapping removes the old one
+        if flags.contains(MmapFlags::SHARED)
+            && !flags.contains(MmapFlags::ANONYMOUS)
+            && prot.contains(MmapProt::WRITE)
+        {
+            if let FileLike::File(file) = proc.get_file_like(fd)? {
+                if !file.options().write {
+                    return Err(SysError::EACCES);
+                }
+                if mount::read_only(&file.inode()) {
+                    return Err(SysError::EROFS);
+                }
+            }
+        }
+
         if flags.contains(MmapFlags::FIXED) {
             // we have to map it to addr, so remove the old mapping first
             self.vm().pop_with_split(addr, addr + len);
+        } else if huge {
+            // leave room to align the area to huge pages
+            addr = self.vm().find_free_area(addr, len + HUGE_PAGE_SIZE);
+            addr = (addr + HUGE_PAGE_SIZE - 1) & !(HUGE_PAGE_SIZE - 1);
         } else {
             addr = self.vm().find_free_area(addr, len);
         }
@@ -49,6 +83,15 @@ impl Syscall<'_> {
                     "mmap_anon_shared",
                 );
                 return Ok(addr);
+            } else if huge {
+                self.vm().try_push(
+                    addr,
+                    addr + len,
+                    prot.to_attr(),
+                    Huge::new(GlobalFrameAlloc),
+                    "mmap_anon_huge",
+                )?;
+                return Ok(addr);
             } else {
                 self.vm().push(
                     addr,
@@ -74,22 +117,28 @@ impl Syscall<'_> {
     }
 
     pub fn sys_mprotect(&mut self, addr: usize, len: usize, prot: usize) -> SysResult {
-        let prot = MmapProt::from_bits_truncate(prot);
+        let prot = MmapProt::from_bits(prot).ok_or(SysError::EINVAL)?;
         info!(
             "mprotect: addr={:#x}, size={:#x}, prot={:?}",
             addr, len, prot
         );
-        let _attr = prot.to_attr();
-
-        // TODO: properly set the attribute of the area
-        //        now some mut ptr check is fault
-        let vm = self.vm();
-        let memory_area = vm
-            .iter()
-            .find(|area| area.is_overlap_with(addr, addr + len));
-        if memory_area.is_none() {
-            return Err(SysError::ENOMEM);
+        if addr % PAGE_SIZE != 0 {
+            return Err(SysError::EINVAL);
+        }
+        // no area grows, so there is nothing to extend the range to
+        if prot.intersects(MmapProt::GROWSDOWN | MmapProt::GROWSUP) {
+            return Err(SysError::EINVAL);
         }
+        if len == 0 {
+            return Ok(0);
+        }
+        let end = addr.checked_add(len).ok_or(SysError::ENOMEM)?;
+        self.vm()
+            .protect(addr, end, |attr| prot.apply_to(attr))
+            .map_err(|err| match err {
+                VMError::AccessDenied => SysError::EACCES,
+                _ => SysError::ENOMEM,
+            })?;
         Ok(0)
     }
 
@@ -98,6 +147,227 @@ impl Syscall<'_> {
         self.vm().pop_with_split(addr, addr + len);
         Ok(0)
     }
+
+    pub fn sys_mremap(
+        &mut self,
+        old_addr: usize,
+        old_size: usize,
+        new_size: usize,
+        flags: usize,
+        new_addr: usize,
+    ) -> SysResult {
+        let flags = MremapFlags::from_bits(flags).ok_or(SysError::EINVAL)?;
+        info!(
+            "mremap: old_addr={:#x}, old_size={:#x}, new_size={:#x}, flags={:?}, new_addr={:#x}",
+            old_addr, old_size, new_size, flags, new_addr
+        );
+        if old_addr % PAGE_SIZE != 0 || new_size == 0 {
+            return Err(SysError::EINVAL);
+        }
+        // duplicating a shared mapping with old_size 0 is not supported
+        if old_size == 0 {
+            return Err(SysError::EINVAL);
+        }
+        let old_end = old_addr.checked_add(old_size).ok_or(SysError::EFAULT)?;
+        let new_addr = if flags.contains(MremapFlags::FIXED) {
+            if !flags.contains(MremapFlags::MAYMOVE) || new_addr % PAGE_SIZE != 0 {
+                return Err(SysError::EINVAL);
+            }
+            // the new range must not overlap with the old one
+            let new_end = new_addr.checked_add(new_size).ok_or(SysError::EINVAL)?;
+            if new_addr < old_end && old_addr < new_end {
+                return Err(SysError::EINVAL);
+            }
+            Some(new_addr)
+        } else {
+            None
+        };
+        let may_move = flags.contains(MremapFlags::MAYMOVE);
+        let addr = self
+            .vm()
+            .remap(old_addr, old_size, new_size, new_addr, may_move)?;
+        Ok(addr)
+    }
+
+    pub fn sys_msync(&mut self, addr: usize, len: usize, flags: usize) -> SysResult {
+        let flags = MsyncFlags::from_bits(flags).ok_or(SysError::EINVAL)?;
+        info!(
+            "msync: addr={:#x}, size={:#x}, flags={:?}",
+            addr, len, flags
+        );
+        if addr % PAGE_SIZE != 0 || flags.contains(MsyncFlags::ASYNC | MsyncFlags::SYNC) {
+            return Err(SysError::EINVAL);
+        }
+        let end = addr.checked_add(len).ok_or(SysError::ENOMEM)?;
+        if len == 0 {
+            return Ok(0);
+        }
+        // write back now even for MS_ASYNC
+        self.vm().sync(addr, end).map_err(|err| match err {
+            VMError::IOError => SysError::EIO,
+            _ => SysError::ENOMEM,
+        })?;
+        Ok(0)
+    }
+
+    pub fn sys_madvise(&mut self, addr: usize, len: usize, advice: usize) -> SysResult {
+        info!(
+            "madvise: addr={:#x}, size={:#x}, advice={}",
+            addr, len, advice
+        );
+        if addr % PAGE_SIZE != 0 {
+            return Err(SysError::EINVAL);
+        }
+        let end = addr.checked_add(len).ok_or(SysError::EINVAL)?;
+        if len == 0 {
+            return Ok(0);
+        }
+        match advice {
+            MADV_NORMAL | MADV_RANDOM | MADV_SEQUENTIAL => Ok(0),
+            MADV_WILLNEED => {
+                self.vm()
+                    .prefetch(addr, end)
+                    .map_err(|_| SysError::ENOMEM)?;
+                Ok(0)
+            }
+            // MADV_FREE allows freeing pages lazily, but we have no reclaimer yet
+            MADV_DONTNEED | MADV_FREE => {
+                self.vm().discard(addr, end).map_err(|err| match err {
+                    // pages of locked areas can't be dropped
+                    VMError::AccessDenied => SysError::EINVAL,
+                    _ => SysError::ENOMEM,
+                })?;
+                Ok(0)
+            }
+            // anonymous memory is populated at page faults with frames of PAGE_SIZE,
+            // and only MAP_HUGETLB commits huge pages up front
+            MADV_HUGEPAGE | MADV_NOHUGEPAGE => Ok(0),
+            _ => self.unimplemented("madvise advice", Ok(0)),
+        }
+    }
+
+    pub fn sys_mincore(&mut self, addr: usize, len: usize, mut vec: UserOutPtr<u8>) -> SysResult {
+        info!("mincore: addr={:#x}, size={:#x}, vec={:?}", addr, len, vec);
+        if addr % PAGE_SIZE != 0 {
+            return Err(SysError::EINVAL);
+        }
+        let end = addr.checked_add(len).ok_or(SysError::ENOMEM)?;
+        if len == 0 {
+            return Ok(0);
+        }
+        let residency = self
+            .vm()
+            .residency(addr, end)
+            .map_err(|_| SysError::ENOMEM)?;
+        let residency: Vec<u8> = residency.into_iter().map(|present| present as u8).collect();
+        vec.write_array(&residency)?;
+        Ok(0)
+    }
+
+    pub fn sys_mlock(&mut self, addr: usize, len: usize) -> SysResult {
+        self.sys_mlock2(addr, len, 0)
+    }
+
+    pub fn sys_mlock2(&mut self, addr: usize, len: usize, flags: usize) -> SysResult {
+        info!(
+            "mlock2: addr={:#x}, size={:#x}, flags={:#x}",
+            addr, len, flags
+        );
+        // MLOCK_ONFAULT only delays populating, so it is treated as a plain lock
+        if flags & !MLOCK_ONFAULT != 0 {
+            return Err(SysError::EINVAL);
+        }
+        let end = addr.checked_add(len).ok_or(SysError::EINVAL)?;
+        if len == 0 {
+            return Ok(0);
+        }
+        self.vm().lock(addr, end).map_err(|_| SysError::ENOMEM)?;
+        Ok(0)
+    }
+
+    pub fn sys_munlock(&mut self, addr: usize, len: usize) -> SysResult {
+        info!("munlock: addr={:#x}, size={:#x}", addr, len);
+        let end = addr.checked_add(len).ok_or(SysError::EINVAL)?;
+        if len == 0 {
+            return Ok(0);
+        }
+        self.vm().unlock(addr, end).map_err(|_| SysError::ENOMEM)?;
+        Ok(0)
+    }
+
+    pub fn sys_mlockall(&mut self, flags: usize) -> SysResult {
+        info!("mlockall: flags={:#x}", flags);
+        let flags = MlockallFlags::from_bits(flags).ok_or(SysError::EINVAL)?;
+        if !flags.intersects(MlockallFlags::CURRENT | MlockallFlags::FUTURE) {
+            return Err(SysError::EINVAL);
+        }
+        self.vm().lock_all(
+            flags.contains(MlockallFlags::CURRENT),
+            flags.contains(MlockallFlags::FUTURE),
+        );
+        Ok(0)
+    }
+
+    pub fn sys_munlockall(&mut self) -> SysResult {
+        info!("munlockall");
+        self.vm().unlock_all();
+        Ok(0)
+    }
+
+    pub fn sys_swapon(&mut self, path: *const u8, flags: usize) -> SysResult {
+        let path = check_and_clone_cstr(path)?;
+        info!("swapon: path={:?}, flags={:#x}", path, flags);
+        // swap priority and discard flags are ignored with a single swap space
+        let inode = self.process().lookup_inode(&path)?;
+        // the swapper bypasses the page cache, so the header written by mkswap must reach the file
+        page_cache::sync(&inode)?;
+        let _config = SWAP_CONFIG.lock();
+        if SWAP_SPACE.lock().is_some() {
+            return Err(SysError::EBUSY);
+        }
+        let swapper = INodeSwapper::new(inode.clone()).ok_or(SysError::EINVAL)?;
+        let new_space = SwapSpace {
+            inode,
+            swapper: Arc::new(spin::Mutex::new(swapper)),
+        };
+        let mut space = SWAP_SPACE.lock();
+        for vm in all_vms() {
+            vm.lock().enable_swap(new_space.new_swap());
+        }
+        *space = Some(new_space);
+        Ok(0)
+    }
+
+    pub fn sys_swapoff(&mut self, path: *const u8) -> SysResult {
+        let path = check_and_clone_cstr(path)?;
+        info!("swapoff: path={:?}", path);
+        let metadata = self.process().lookup_inode(&path)?.metadata()?;
+        let _config = SWAP_CONFIG.lock();
+        let in_use = match SWAP_SPACE.lock().as_ref() {
+            Some(space) => {
+                let swap_metadata = space.inode.metadata()?;
+                (swap_metadata.dev, swap_metadata.inode) == (metadata.dev, metadata.inode)
+            }
+            None => false,
+        };
+        if !in_use {
+            return Err(SysError::EINVAL);
+        }
+        // new memory sets are made without swap, while pages are read back without the lock
+        let space = SWAP_SPACE.lock().take().unwrap();
+        let vms = all_vms();
+        for (i, vm) in vms.iter().enumerate() {
+            if let Err(err) = vm.lock().disable_swap() {
+                // swap stays on for all, as it was
+                for vm in vms[..i].iter() {
+                    vm.lock().enable_swap(space.new_swap());
+                }
+                *SWAP_SPACE.lock() = Some(space);
+                return Err(err.into());
+            }
+        }
+        Ok(0)
+    }
 }
 
 bitflags! {
@@ -110,6 +380,10 @@ bitflags! {
         const WRITE = 1 << 1;
         /// Data can be executed
         const EXEC = 1 << 2;
+        /// Extend change to start of growsdown vma (mprotect only)
+        const GROWSDOWN = 0x01000000;
+        /// Extend change to end of growsup vma (mprotect only)
+        const GROWSUP = 0x02000000;
     }
 }
 
@@ -124,6 +398,8 @@ bitflags! {
         const FIXED = 1 << 4;
         /// The mapping is not backed by any file. (non-POSIX)
         const ANONYMOUS = 0x800;
+        /// Allocate the mapping using huge pages
+        const HUGETLB = 0x80000;
     }
 }
 
@@ -138,17 +414,74 @@ bitflags! {
         const FIXED = 1 << 4;
         /// The mapping is not backed by any file. (non-POSIX)
         const ANONYMOUS = 1 << 5;
+        /// Allocate the mapping using huge pages
+        const HUGETLB = 0x40000;
+    }
+}
+
+bitflags! {
+    pub struct MremapFlags: usize {
+        /// The mapping may be moved to a new address
+        const MAYMOVE = 1;
+        /// Move the mapping to the given new address
+        const FIXED = 2;
+    }
+}
+
+bitflags! {
+    pub struct MsyncFlags: usize {
+        /// Schedule the write back and return
+        const ASYNC = 1;
+        /// Invalidate other mappings of the same file
+        const INVALIDATE = 2;
+        /// Write back and wait for it to complete
+        const SYNC = 4;
+    }
+}
+
+bitflags! {
+    pub struct MlockallFlags: usize {
+        /// Lock all pages currently mapped
+        const CURRENT = 1;
+        /// Lock all pages mapped in the future
+        const FUTURE = 2;
+        /// Lock pages when they are faulted in
+        const ONFAULT = 4;
     }
 }
 
+/// Lock pages when they are faulted in (mlock2 only)
+const MLOCK_ONFAULT: usize = 1;
+
+const MADV_NORMAL: usize = 0;
+const MADV_RANDOM: usize = 1;
+const MADV_SEQUENTIAL: usize = 2;
+const MADV_WILLNEED: usize = 3;
+const MADV_DONTNEED: usize = 4;
+const MADV_FREE: usize = 8;
+const MADV_HUGEPAGE: usize = 14;
+const MADV_NOHUGEPAGE: usize = 15;
+
 impl MmapProt {
     pub fn to_attr(self) -> MemoryAttr {
-        let mut attr = MemoryAttr::default().user();
-        if self.contains(MmapProt::EXEC) {
-            attr = attr.execute();
+        self.apply_to(MemoryAttr::default().user())
+    }
+
+    /// Replace the access permissions of `attr`, keeping others such as mmio.
+    /// PROT_WRITE implies PROT_READ, and PROT_EXEC is kept as is,
+    /// since JITs need both writable and executable pages.
+    pub fn apply_to(self, attr: MemoryAttr) -> MemoryAttr {
+        let mut attr = attr.accessible();
+        if !self.intersects(MmapProt::READ | MmapProt::WRITE | MmapProt::EXEC) {
+            attr = attr.inaccessible();
+        }
+        attr = match self.contains(MmapProt::WRITE) {
+            true => attr.writable(),
+            false => attr.readonly(),
+        };
+        match self.contains(MmapProt::EXEC) {
+            true => attr.execute(),
+            false => attr.noexec(),
         }
-        // TODO: see sys_mprotect
-        //        if !self.contains(MmapProt::WRITE) { attr = attr.readonly(); }
-        attr
     }
 }
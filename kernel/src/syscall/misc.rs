@@ -7,6 +7,7 @@ use crate::syscall::SysError::ETIMEDOUT;
 use crate::trap::TICK_ACTIVITY;
 use core::mem::size_of;
 use core::sync::atomic::{AtomicI32, Ordering};
+use rcore_memory::PAGE_SIZE;
 
 impl Syscall<'_> {
     #[cfg(target_arch = "x86_64")]
@@ -27,7 +28,7 @@ impl Syscall<'_> {
 
         let offset = 65;
         let strings = ["Linux", "orz", "0.1.0", "1", ARCH, "domain"];
-        let buf = unsafe { self.vm().check_write_array(buf, strings.len() * offset)? };
+        let buf = unsafe { check_write_array(&self.vm(), buf, strings.len() * offset)? };
 
         for i in 0..strings.len() {
             unsafe {
@@ -42,7 +43,7 @@ impl Syscall<'_> {
             "sched_getaffinity: pid: {}, size: {}, mask: {:?}",
             pid, size, mask
         );
-        let mask = unsafe { self.vm().check_write_array(mask, size / size_of::<u32>())? };
+        let mask = unsafe { check_write_array(&self.vm(), mask, size / size_of::<u32>())? };
 
         // we only have 4 cpu at most.
         // so just set it.
@@ -56,7 +57,13 @@ impl Syscall<'_> {
     pub fn sys_sysinfo(&mut self, sys_info: *mut SysInfo) -> SysResult {
         let sys_info = unsafe { self.vm().check_write_ptr(sys_info)? };
 
-        let sysinfo = SysInfo::default();
+        let (total, free) = crate::memory::frame_stats();
+        let sysinfo = SysInfo {
+            totalram: total as u64,
+            freeram: free as u64,
+            mem_unit: PAGE_SIZE as u32,
+            ..SysInfo::default()
+        };
         *sys_info = sysinfo;
         Ok(0)
     }
@@ -185,7 +192,7 @@ impl Syscall<'_> {
 
     pub fn sys_getrandom(&mut self, buf: *mut u8, len: usize, _flag: u32) -> SysResult {
         //info!("getrandom: buf: {:?}, len: {:?}, falg {:?}", buf, len,flag);
-        let slice = unsafe { self.vm().check_write_array(buf, len)? };
+        let slice = unsafe { check_write_array(&self.vm(), buf, len)? };
         let mut i = 0;
         for elm in slice {
             unsafe {
@@ -3,7 +3,7 @@
 use crate::arch::cpu;
 use crate::arch::syscall::*;
 use crate::fs::epoll::EpollEvent;
-use crate::memory::{copy_from_user, MemorySet};
+use crate::memory::{check_read_array, check_write_array, copy_from_user, MemorySet};
 use crate::process::*;
 use crate::signal::{Signal, SignalAction, SignalFrame, SignalStack, SignalUserContext, Sigset};
 use crate::sync::{Condvar, MutexGuard, SpinNoIrq};
@@ -124,6 +124,9 @@ impl Syscall<'_> {
             }
             SYS_WRITE => self.sys_write(args[0], args[1] as *const u8, args[2]),
             SYS_OPENAT => self.sys_openat(args[0], args[1] as *const u8, args[2], args[3]),
+            SYS_OPENAT2 => {
+                self.sys_openat2(args[0], args[1] as *const u8, args[2] as *const u8, args[3])
+            }
             SYS_CLOSE => self.sys_close(args[0]),
             SYS_FSTAT => self.sys_fstat(args[0], args[1] as *mut Stat),
             SYS_NEWFSTATAT => {
@@ -145,8 +148,8 @@ impl Syscall<'_> {
                 self.sys_sendfile(args[0], args[1], UserInOutPtr::from(args[2]), args[3])
                     .await
             }
-            SYS_FCNTL => self.sys_fcntl(args[0], args[1], args[2]),
-            SYS_FLOCK => self.sys_flock(args[0], args[1]),
+            SYS_FCNTL => self.sys_fcntl(args[0], args[1], args[2]).await,
+            SYS_FLOCK => self.sys_flock(args[0], args[1]).await,
             SYS_FSYNC => self.sys_fsync(args[0]),
             SYS_FDATASYNC => self.sys_fdatasync(args[0]),
             SYS_TRUNCATE => self.sys_truncate(args[0] as *const u8, args[1]),
@@ -172,10 +175,13 @@ impl Syscall<'_> {
             SYS_READLINKAT => {
                 self.sys_readlinkat(args[0], args[1] as *const u8, args[2] as *mut u8, args[3])
             }
-            SYS_FCHMOD => self.unimplemented("fchmod", Ok(0)),
-            SYS_FCHMODAT => self.unimplemented("fchmodat", Ok(0)),
-            SYS_FCHOWN => self.unimplemented("fchown", Ok(0)),
-            SYS_FCHOWNAT => self.unimplemented("fchownat", Ok(0)),
+            SYS_FCHMOD => self.sys_fchmod(args[0], args[1]),
+            SYS_FCHMODAT => self.sys_fchmodat(args[0], args[1] as *const u8, args[2]),
+            SYS_FCHOWN => self.sys_fchown(args[0], args[1], args[2]),
+            SYS_FCHOWNAT => {
+                self.sys_fchownat(args[0], args[1] as *const u8, args[2], args[3], args[4])
+            }
+            SYS_MKNODAT => self.sys_mknodat(args[0], args[1] as *const u8, args[2], args[3]),
             SYS_FACCESSAT => self.sys_faccessat(args[0], args[1] as *const u8, args[2], args[3]),
             SYS_DUP3 => self.sys_dup3(args[0], args[1], args[2]),
             SYS_PIPE2 => self.sys_pipe2(args[0] as *mut u32, args[1]), // TODO: handle `flags`
@@ -217,6 +223,11 @@ impl Syscall<'_> {
                 .await
             } // ignore sigmask
             SYS_EPOLL_CREATE1 => self.sys_epoll_create1(args[0]),
+            SYS_INOTIFY_INIT1 => self.sys_inotify_init1(args[0]),
+            SYS_INOTIFY_ADD_WATCH => {
+                self.sys_inotify_add_watch(args[0], args[1] as *const u8, args[2])
+            }
+            SYS_INOTIFY_RM_WATCH => self.sys_inotify_rm_watch(args[0], args[1]),
             SYS_EPOLL_CTL => {
                 self.sys_epoll_ctl(args[0], args[1], args[2], args[3] as *mut EpollEvent)
             }
@@ -231,18 +242,35 @@ impl Syscall<'_> {
 
             SYS_SOCKETPAIR => self.unimplemented("socketpair", Err(SysError::EACCES)),
             // file system
-            SYS_STATFS => self.unimplemented("statfs", Err(SysError::EACCES)),
-            SYS_FSTATFS => self.unimplemented("fstatfs", Err(SysError::EACCES)),
+            SYS_STATFS => self.sys_statfs(args[0] as *const u8, args[1] as *mut StatFs),
+            SYS_FSTATFS => self.sys_fstatfs(args[0], args[1] as *mut StatFs),
             SYS_SYNC => self.sys_sync(),
-            SYS_MOUNT => self.unimplemented("mount", Err(SysError::EACCES)),
-            SYS_UMOUNT2 => self.unimplemented("umount2", Err(SysError::EACCES)),
+            SYS_MOUNT => self.sys_mount(
+                args[0] as *const u8,
+                args[1] as *const u8,
+                args[2] as *const u8,
+                args[3],
+                args[4] as *const u8,
+            ),
+            SYS_UMOUNT2 => self.sys_umount2(args[0] as *const u8, args[1]),
+            SYS_PIVOT_ROOT => self.sys_pivot_root(args[0] as *const u8, args[1] as *const u8),
 
             // memory
             SYS_BRK => self.unimplemented("brk", Err(SysError::ENOMEM)),
             SYS_MMAP => self.sys_mmap(args[0], args[1], args[2], args[3], args[4], args[5]),
             SYS_MPROTECT => self.sys_mprotect(args[0], args[1], args[2]),
             SYS_MUNMAP => self.sys_munmap(args[0], args[1]),
-            SYS_MADVISE => self.unimplemented("madvise", Ok(0)),
+            SYS_MREMAP => self.sys_mremap(args[0], args[1], args[2], args[3], args[4]),
+            SYS_MSYNC => self.sys_msync(args[0], args[1], args[2]),
+            SYS_SWAPON => self.sys_swapon(args[0] as *const u8, args[1]),
+            SYS_SWAPOFF => self.sys_swapoff(args[0] as *const u8),
+            SYS_MADVISE => self.sys_madvise(args[0], args[1], args[2]),
+            SYS_MINCORE => self.sys_mincore(args[0], args[1], UserOutPtr::from(args[2])),
+            SYS_MLOCK => self.sys_mlock(args[0], args[1]),
+            SYS_MLOCK2 => self.sys_mlock2(args[0], args[1], args[2]),
+            SYS_MUNLOCK => self.sys_munlock(args[0], args[1]),
+            SYS_MLOCKALL => self.sys_mlockall(args[0]),
+            SYS_MUNLOCKALL => self.sys_munlockall(),
 
             // signal
             SYS_RT_SIGACTION => self.sys_rt_sigaction(
@@ -379,7 +407,7 @@ impl Syscall<'_> {
             SYS_GETPID => self.sys_getpid(),
             SYS_GETTID => self.sys_gettid(),
             SYS_UNAME => self.sys_uname(args[0] as *mut u8),
-            SYS_UMASK => self.unimplemented("umask", Ok(0o777)),
+            SYS_UMASK => self.sys_umask(args[0]),
             //        SYS_GETRLIMIT => self.sys_getrlimit(),
             SYS_SETRLIMIT => self.unimplemented("setrlimit", Ok(0)),
             SYS_GETRUSAGE => self.sys_getrusage(args[0], args[1] as *mut RUsage),
@@ -527,6 +555,7 @@ impl Syscall<'_> {
             SYS_EPOLL_WAIT => {
                 self.sys_epoll_wait(args[0], args[1] as *mut EpollEvent, args[2], args[3])
             }
+            SYS_INOTIFY_INIT => self.sys_inotify_init1(0),
 
             _ => return None,
         };
@@ -563,14 +592,16 @@ impl Syscall<'_> {
             SYS_UNLINK => self.sys_unlink(args[0] as *const u8),
             SYS_SYMLINK => self.sys_symlink(args[0] as *const u8, args[1] as *const u8),
             SYS_READLINK => self.sys_readlink(args[0] as *const u8, args[1] as *mut u8, args[2]),
-            SYS_CHMOD => self.unimplemented("chmod", Ok(0)),
-            SYS_CHOWN => self.unimplemented("chown", Ok(0)),
+            SYS_CHMOD => self.sys_chmod(args[0] as *const u8, args[1]),
+            SYS_CHOWN => self.sys_chown(args[0] as *const u8, args[1], args[2]),
+            SYS_MKNOD => self.sys_mknod(args[0] as *const u8, args[1], args[2]),
             SYS_ARCH_PRCTL => self.sys_arch_prctl(args[0] as i32, args[1]),
             SYS_TIME => self.sys_time(args[0] as *mut u64),
             SYS_EPOLL_CREATE => self.sys_epoll_create(args[0]),
             SYS_EPOLL_WAIT => {
                 self.sys_epoll_wait(args[0], args[1] as *mut EpollEvent, args[2], args[3])
             }
+            SYS_INOTIFY_INIT => self.sys_inotify_init1(0),
             _ => return None,
         };
         Some(ret)
@@ -699,8 +730,13 @@ impl fmt::Display for SysError {
 }
 
 impl From<VMError> for SysError {
-    fn from(_: VMError) -> Self {
-        SysError::EFAULT
+    fn from(err: VMError) -> Self {
+        match err {
+            VMError::InvalidPtr => SysError::EFAULT,
+            VMError::NoMemory => SysError::ENOMEM,
+            VMError::AccessDenied => SysError::EACCES,
+            VMError::IOError => SysError::EIO,
+        }
     }
 }
 
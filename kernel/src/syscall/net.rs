@@ -56,7 +56,7 @@ impl Syscall<'_> {
             fd, level, optname
         );
         let mut proc = self.process();
-        let data = unsafe { self.vm().check_read_array(optval, optlen)? };
+        let data = unsafe { check_read_array(&self.vm(), optval, optlen)? };
         let socket = proc.get_socket(fd)?;
         socket.setsockopt(level, optname, data)
     }
@@ -127,7 +127,7 @@ impl Syscall<'_> {
 
         let mut proc = self.process();
 
-        let slice = unsafe { self.vm().check_read_array(base, len)? };
+        let slice = unsafe { check_read_array(&self.vm(), base, len)? };
         let endpoint = if addr.is_null() {
             None
         } else {
@@ -155,7 +155,7 @@ impl Syscall<'_> {
 
         let mut proc = self.process();
 
-        let mut slice = unsafe { self.vm().check_write_array(base, len)? };
+        let mut slice = unsafe { check_write_array(&self.vm(), base, len)? };
         let socket = proc.get_socket(fd)?;
         let (result, endpoint) = socket.read(&mut slice);
 
@@ -468,7 +468,7 @@ impl SockAddr {
 
         let written_len = min(max_addr_len, full_len);
         if written_len > 0 {
-            let target = vm.check_write_array(addr as *mut u8, written_len)?;
+            let target = check_write_array(&vm, addr as *mut u8, written_len)?;
             let source = slice::from_raw_parts(&self as *const SockAddr as *const u8, written_len);
             target.copy_from_slice(source);
         }
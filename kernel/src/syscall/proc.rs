@@ -21,7 +21,7 @@ use core::{
 impl Syscall<'_> {
     /// Fork the current process. Return the child's PID.
     pub fn sys_fork(&mut self) -> SysResult {
-        let new_thread = self.thread.fork(self.context);
+        let new_thread = self.thread.fork(self.context)?;
         let pid = new_thread.proc.lock().pid.get();
         info!("fork: {} -> {}", self.process().pid, pid);
         spawn(new_thread);
@@ -234,16 +234,10 @@ impl Syscall<'_> {
         let close_fds = proc
             .files
             .iter()
-            .filter_map(|(fd, file_like)| {
-                if let FileLike::File(file) = file_like {
-                    if file.fd_cloexec {
-                        Some(*fd)
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
+            .filter_map(|(fd, file_like)| match file_like {
+                FileLike::File(file) if file.fd_cloexec => Some(*fd),
+                FileLike::Inotify(inotify) if inotify.fd_cloexec => Some(*fd),
+                _ => None,
             })
             .collect::<Vec<_>>();
         for fd in close_fds {
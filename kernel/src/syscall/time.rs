@@ -49,6 +49,11 @@ impl Syscall<'_> {
         let tick = unsafe { crate::trap::wall_tick() as u64 };
 
         let usec = (tick - tick_base) * USEC_PER_TICK as u64;
+        // page faults of children are not accumulated yet
+        let (minflt, majflt) = match who as isize {
+            RUSAGE_CHILDREN => (0, 0),
+            _ => self.vm().faults(),
+        };
         let new_rusage = RUsage {
             utime: TimeVal {
                 sec: (usec / USEC_PER_SEC) as usize,
@@ -58,6 +63,9 @@ impl Syscall<'_> {
                 sec: (usec / USEC_PER_SEC) as usize,
                 usec: (usec % USEC_PER_SEC) as usize,
             },
+            minflt,
+            majflt,
+            ..RUsage::default()
         };
         *rusage = new_rusage;
         Ok(0)
@@ -108,7 +116,7 @@ fn get_epoch_usec() -> u64 {
 }
 
 #[repr(C)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone)]
 pub struct TimeVal {
     sec: usize,
     usec: usize,
@@ -178,11 +186,28 @@ impl Into<Timespec> for TimeSpec {
     }
 }
 
-// ignore other fields for now
+const RUSAGE_CHILDREN: isize = -1;
+
+// only times and page faults are filled for now
 #[repr(C)]
+#[derive(Debug, Default)]
 pub struct RUsage {
     utime: TimeVal,
     stime: TimeVal,
+    maxrss: usize,
+    ixrss: usize,
+    idrss: usize,
+    isrss: usize,
+    minflt: usize,
+    majflt: usize,
+    nswap: usize,
+    inblock: usize,
+    oublock: usize,
+    msgsnd: usize,
+    msgrcv: usize,
+    nsignals: usize,
+    nvcsw: usize,
+    nivcsw: usize,
 }
 
 #[repr(C)]